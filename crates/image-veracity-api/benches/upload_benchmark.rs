@@ -0,0 +1,263 @@
+//! Uploads through the HTTP router, from request to response, against an in-memory repository
+//! and a Trillian client that accepts every leaf. Catches regressions anywhere on the path an
+//! upload takes: reading the multipart body, sniffing its type, hashing and storing it.
+
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aide::openapi::OpenApi;
+use async_trait::async_trait;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkId, Criterion, SamplingMode, Throughput,
+};
+use eyre::Result;
+use glob::glob;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use tokio::runtime::Runtime;
+
+use image_veracity_api::repository::{MemoryImageRepository, SharedImageRepository};
+use image_veracity_api::server::routes::server_routes;
+use image_veracity_api::state::{AppStateBuilder, DuplicateUploads};
+use trillian::client::{
+    EntryAndProof, LogRoot, QueuedLeaf, SignedRoot, TreeUpdate, TrillianClientApiMethods,
+};
+use trillian::{TrillianChargeTo, TrillianLogLeaf, TrillianProof, TrillianTree, TrillianTreeType};
+
+const BOUNDARY: &str = "veracity-bench";
+
+/// Trillian client that queues every leaf without a server
+#[derive(Clone, Default)]
+struct MockTrillianClient;
+
+#[async_trait]
+impl TrillianClientApiMethods for MockTrillianClient {
+    fn set_timeout(&mut self, _timeout: Option<Duration>) {}
+    async fn add_leaf(
+        &mut self,
+        _id: &i64,
+        _data: &[u8],
+        _extra_data: &[u8],
+        _identity_hash: Option<&[u8]>,
+        _charge_to: Option<TrillianChargeTo>,
+    ) -> Result<QueuedLeaf> {
+        Ok(QueuedLeaf {
+            leaf: TrillianLogLeaf::default(),
+            already_existed: false,
+        })
+    }
+    async fn add_leaves(
+        &mut self,
+        _id: &i64,
+        leaves: Vec<(Vec<u8>, Vec<u8>)>,
+        _charge_to: Option<TrillianChargeTo>,
+    ) -> Vec<Result<QueuedLeaf>> {
+        leaves
+            .iter()
+            .map(|_| {
+                Ok(QueuedLeaf {
+                    leaf: TrillianLogLeaf::default(),
+                    already_existed: false,
+                })
+            })
+            .collect()
+    }
+    async fn add_sequenced_leaves(
+        &mut self,
+        _id: &i64,
+        leaves: Vec<TrillianLogLeaf>,
+        _charge_to: Option<TrillianChargeTo>,
+    ) -> Result<Vec<Result<QueuedLeaf>>> {
+        Ok(leaves
+            .into_iter()
+            .map(|leaf| {
+                Ok(QueuedLeaf {
+                    leaf,
+                    already_existed: false,
+                })
+            })
+            .collect())
+    }
+    async fn create_tree(
+        &mut self,
+        _name: &str,
+        _description: &str,
+        _tree_type: TrillianTreeType,
+    ) -> Result<TrillianTree> {
+        Ok(TrillianTree::default())
+    }
+    async fn list_trees(&mut self) -> Result<Vec<TrillianTree>> {
+        Ok(vec![TrillianTree::default()])
+    }
+    async fn get_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+        Ok(TrillianTree::default())
+    }
+    async fn update_tree(&mut self, _id: &i64, _update: TreeUpdate) -> Result<TrillianTree> {
+        Ok(TrillianTree::default())
+    }
+    async fn freeze_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+        Ok(TrillianTree::default())
+    }
+    async fn delete_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+        Ok(TrillianTree::default())
+    }
+    async fn undelete_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+        Ok(TrillianTree::default())
+    }
+    async fn get_tree_size(&mut self, _id: &i64) -> Result<i64> {
+        Ok(0)
+    }
+    async fn get_latest_root(&mut self, _id: &i64) -> Result<LogRoot> {
+        Ok(LogRoot::default())
+    }
+    async fn get_latest_signed_log_root(
+        &mut self,
+        _id: &i64,
+        _first_tree_size: Option<i64>,
+    ) -> Result<SignedRoot> {
+        Ok(SignedRoot::default())
+    }
+    async fn get_inclusion_proof_by_hash(
+        &mut self,
+        _id: &i64,
+        _leaf_hash: &[u8],
+        _tree_size: i64,
+    ) -> Result<Vec<TrillianProof>> {
+        Ok(vec![])
+    }
+    async fn get_inclusion_proof(
+        &mut self,
+        _id: &i64,
+        _leaf_index: i64,
+        _tree_size: i64,
+    ) -> Result<TrillianProof> {
+        Ok(TrillianProof::default())
+    }
+    async fn get_entry_and_proof(
+        &mut self,
+        _id: &i64,
+        _leaf_index: i64,
+        _tree_size: i64,
+    ) -> Result<EntryAndProof> {
+        Ok(EntryAndProof::default())
+    }
+    async fn get_consistency_proof(
+        &mut self,
+        _id: &i64,
+        _first_tree_size: i64,
+        _second_tree_size: i64,
+    ) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
+    async fn get_leaves_by_range(
+        &mut self,
+        _id: &i64,
+        _start_index: i64,
+        _count: i64,
+    ) -> Result<Vec<TrillianLogLeaf>> {
+        Ok(vec![])
+    }
+}
+
+/// Serve the router on a free port. Repeated uploads of an image are answered with the stored
+/// one, so every iteration still reads and hashes the whole body.
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let images: SharedImageRepository = Arc::new(MemoryImageRepository::default());
+    let state = AppStateBuilder::default()
+        .trillian(Box::from(MockTrillianClient))
+        .trillian_host("http://localhost:8090".to_string())
+        .trillian_tree(0)
+        // Connected lazily and never used with the in-memory repository
+        .create_postgres_client("postgresql://root@localhost:26257/veracity?sslmode=disable")
+        .images(Some(images))
+        .duplicate_uploads(DuplicateUploads::Existing)
+        .build()
+        .await
+        .unwrap();
+
+    tokio::spawn(async move {
+        let mut api = OpenApi::default();
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(
+                server_routes(state)
+                    .finish_api(&mut api)
+                    .into_make_service(),
+            )
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+/// A multipart form with the image in its `image` field
+fn upload_form(file_name: &str, image: &[u8]) -> Vec<u8> {
+    let mut form = format!(
+        "--{BOUNDARY}\r\n\
+        Content-Disposition: form-data; name=\"image\"; filename=\"{file_name}\"\r\n\r\n"
+    )
+    .into_bytes();
+    form.extend_from_slice(image);
+    form.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+    form
+}
+
+fn upload_benchmark(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("a runtime to serve uploads");
+    let addr = runtime.block_on(start_server());
+    let client = Client::new();
+
+    let mut group = c.benchmark_group("upload");
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(20);
+
+    let pattern = concat!(env!("CARGO_MANIFEST_DIR"), "/../../resources/test/*");
+    for path in glob(pattern)
+        .expect("Failed to read glob pattern")
+        .flatten()
+    {
+        let image = std::fs::read(&path).expect("image file should be readable");
+        let name = file_name(&path);
+        let form = upload_form(&name, &image);
+        group.throughput(Throughput::Bytes(image.len() as u64));
+        group.bench_with_input(BenchmarkId::new("multipart", &name), &form, |b, form| {
+            b.iter(|| {
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("http://{addr}/v1/"))
+                    .header(
+                        "content-type",
+                        format!("multipart/form-data; boundary={BOUNDARY}"),
+                    )
+                    .body(Body::from(form.clone()))
+                    .unwrap();
+                let status = runtime.block_on(async {
+                    let response = client.request(request).await.unwrap();
+                    let status = response.status();
+                    hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    status
+                });
+                assert!(
+                    matches!(status, StatusCode::CREATED | StatusCode::OK),
+                    "upload of {name} failed with {status}"
+                );
+            });
+        });
+    }
+
+    group.finish();
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("globbed paths name a file")
+        .to_string_lossy()
+        .into_owned()
+}
+
+criterion_group!(benches, upload_benchmark);
+criterion_main!(benches);
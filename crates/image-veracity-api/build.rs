@@ -0,0 +1,8 @@
+fn main() {
+    std::env::set_var("PROTOC", protobuf_src::protoc());
+    tonic_build::configure()
+        .build_client(false)
+        .out_dir("src/protobuf")
+        .compile(&["proto/veracity.proto"], &["proto/"])
+        .unwrap();
+}
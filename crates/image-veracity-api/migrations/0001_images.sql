@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS images (
+    c_hash BYTES NOT NULL PRIMARY KEY,
+    p_hash BYTES NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS images_p_hash_index ON images (p_hash);
@@ -0,0 +1,13 @@
+-- Columns added after the original schema. CockroachDB cannot use a column in the transaction
+-- that adds it, so indexes and backfills over these come in a later migration.
+ALTER TABLE images ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE images ADD COLUMN IF NOT EXISTS leaf_index INT8;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS merkle_leaf_hash BYTES;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS queue_timestamp TIMESTAMPTZ;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS integrate_timestamp TIMESTAMPTZ;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS attestation_format STRING;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS attested BOOL NOT NULL DEFAULT false;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS attestation JSONB;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS status STRING NOT NULL DEFAULT 'pending';
+ALTER TABLE images ADD COLUMN IF NOT EXISTS status_checked_at TIMESTAMPTZ;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS submitted_by STRING;
@@ -0,0 +1,118 @@
+CREATE TABLE IF NOT EXISTS trillian_outbox (
+    c_hash BYTES NOT NULL PRIMARY KEY,
+    p_hash BYTES NOT NULL,
+    attempts INT8 NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_error STRING,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS trillian_outbox_next_attempt_at_index ON trillian_outbox (next_attempt_at);
+
+CREATE TABLE IF NOT EXISTS spent_upload_tokens (
+    token_id STRING NOT NULL PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS maintenance_jobs (
+    id UUID NOT NULL PRIMARY KEY,
+    kind STRING NOT NULL,
+    status STRING NOT NULL,
+    parallelism INT8 NOT NULL,
+    last_error STRING,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS maintenance_job_ranges (
+    job_id UUID NOT NULL REFERENCES maintenance_jobs (id),
+    range_start INT8 NOT NULL,
+    range_end INT8 NOT NULL,
+    next_index INT8 NOT NULL,
+    PRIMARY KEY (job_id, range_start)
+);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id UUID NOT NULL PRIMARY KEY,
+    name STRING NOT NULL,
+    key_hash BYTES NOT NULL UNIQUE,
+    scopes STRING[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS trillian_audit (
+    id INT8 NOT NULL PRIMARY KEY,
+    next_leaf_index INT8 NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webhooks (
+    id UUID NOT NULL PRIMARY KEY,
+    owner STRING NOT NULL,
+    url STRING NOT NULL,
+    secret STRING NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    INDEX (owner)
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id UUID NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    webhook_id UUID NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    payload JSONB NOT NULL,
+    attempts INT8 NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_error STRING,
+    INDEX (next_attempt_at)
+);
+
+CREATE TABLE IF NOT EXISTS resumable_uploads (
+    id UUID NOT NULL PRIMARY KEY,
+    owner STRING,
+    upload_length INT8 NOT NULL,
+    upload_offset INT8 NOT NULL DEFAULT 0,
+    attestation_format STRING,
+    attestation STRING,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    INDEX (expires_at)
+);
+
+CREATE TABLE IF NOT EXISTS resumable_upload_chunks (
+    upload_id UUID NOT NULL REFERENCES resumable_uploads (id) ON DELETE CASCADE,
+    chunk_offset INT8 NOT NULL,
+    data BYTES NOT NULL,
+    PRIMARY KEY (upload_id, chunk_offset)
+);
+
+CREATE TABLE IF NOT EXISTS image_thumbnails (
+    c_hash BYTES NOT NULL PRIMARY KEY REFERENCES images (c_hash) ON DELETE CASCADE,
+    data BYTES NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS log_roots (
+    id UUID NOT NULL PRIMARY KEY DEFAULT gen_random_uuid(),
+    tree_id INT8 NOT NULL,
+    tree_size INT8 NOT NULL,
+    root_hash BYTES NOT NULL,
+    consistent BOOL NOT NULL,
+    observed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    INDEX (tree_id, observed_at)
+);
+
+CREATE TABLE IF NOT EXISTS checkpoint_cosignatures (
+    tree_id INT8 NOT NULL,
+    tree_size INT8 NOT NULL,
+    root_hash BYTES NOT NULL,
+    witness STRING NOT NULL,
+    signature STRING NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (tree_id, tree_size, witness)
+);
+
+CREATE TABLE IF NOT EXISTS witness_sizes (
+    tree_id INT8 NOT NULL,
+    witness STRING NOT NULL,
+    tree_size INT8 NOT NULL,
+    PRIMARY KEY (tree_id, witness)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE trillian_outbox ADD COLUMN IF NOT EXISTS charge_to STRING;
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS role STRING;
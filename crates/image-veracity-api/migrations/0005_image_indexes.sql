@@ -0,0 +1,8 @@
+CREATE INDEX IF NOT EXISTS images_created_at_index ON images (created_at, c_hash);
+
+-- Images without an outbox entry were already sent to Trillian
+UPDATE images SET status = CASE
+    WHEN integrate_timestamp IS NOT NULL THEN 'integrated' ELSE 'queued' END
+WHERE status = 'pending' AND c_hash NOT IN (SELECT c_hash FROM trillian_outbox);
+
+CREATE INDEX IF NOT EXISTS images_status_index ON images (status, status_checked_at);
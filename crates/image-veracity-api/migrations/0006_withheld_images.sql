@@ -0,0 +1,5 @@
+-- Images taken down are kept, since their leaves stay in the log, but marked withheld along
+-- with the tombstone logged for them
+ALTER TABLE images ADD COLUMN IF NOT EXISTS withheld_at TIMESTAMPTZ;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS withheld_reason STRING;
+ALTER TABLE images ADD COLUMN IF NOT EXISTS tombstone_leaf_hash BYTES;
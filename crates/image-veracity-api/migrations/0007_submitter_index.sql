@@ -0,0 +1,2 @@
+-- Listing and counting the images stored with one API key
+CREATE INDEX IF NOT EXISTS images_submitted_by_index ON images (submitted_by, created_at, c_hash);
@@ -0,0 +1,2 @@
+-- Keys of a tenant use its tree and schema, keys without one the default ones
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS tenant STRING;
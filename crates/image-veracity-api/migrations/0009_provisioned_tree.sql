@@ -0,0 +1,7 @@
+-- Tree created on startup when no TRILLIAN_TREE_ID is given, at most one row
+CREATE TABLE IF NOT EXISTS provisioned_tree (
+    singleton BOOL NOT NULL PRIMARY KEY DEFAULT true CHECK (singleton),
+    tree_id INT8 NOT NULL,
+    display_name STRING NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+-- Provenance context uploaded with an image, and the copy of it still to be logged in the
+-- leaf's extra data when metadata is logged
+ALTER TABLE images ADD COLUMN IF NOT EXISTS metadata JSONB;
+ALTER TABLE trillian_outbox ADD COLUMN IF NOT EXISTS metadata BYTES;
@@ -0,0 +1,3 @@
+-- Near-duplicate cluster a clustering job put each image in, named by the crypto hash of the
+-- image that started it
+ALTER TABLE images ADD COLUMN IF NOT EXISTS cluster_id BYTES;
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS images_cluster_id_index ON images (cluster_id);
@@ -0,0 +1,353 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Report, Result};
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::state::ConnectionPool;
+
+/// Prefix of generated keys, so leaked keys are easy to recognise
+const KEY_PREFIX: &str = "ivk_";
+
+/// What an API key may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Store images and mint pre-signed upload tokens
+    Upload,
+    /// Look up and list stored images
+    Read,
+    /// Inspect configuration and maintenance jobs without changing them
+    Audit,
+    /// Manage keys and maintenance jobs; implies every other scope
+    Admin,
+}
+
+/// Named bundles of scopes handed out to keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Client devices and services submitting images
+    Uploader,
+    /// Read-only access to images, configuration and maintenance jobs
+    Auditor,
+    /// Everything, including key management and maintenance
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Uploader => "uploader",
+            Role::Auditor => "auditor",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Scopes a key with this role may hold
+    pub fn scopes(&self) -> &'static [Scope] {
+        match self {
+            Role::Uploader => &[Scope::Upload, Scope::Read],
+            Role::Auditor => &[Scope::Read, Scope::Audit],
+            Role::Admin => &[Scope::Upload, Scope::Read, Scope::Audit, Scope::Admin],
+        }
+    }
+
+    /// Whether every scope in `scopes` belongs to this role
+    pub fn allows(&self, scopes: &[Scope]) -> bool {
+        scopes.iter().all(|scope| self.scopes().contains(scope))
+    }
+}
+
+impl FromStr for Role {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uploader" => Ok(Role::Uploader),
+            "auditor" => Ok(Role::Auditor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(eyre!("unknown role {s}")),
+        }
+    }
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Upload => "upload",
+            Scope::Read => "read",
+            Scope::Audit => "audit",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upload" => Ok(Scope::Upload),
+            "read" => Ok(Scope::Read),
+            "audit" => Ok(Scope::Audit),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(eyre!("unknown scope {s}")),
+        }
+    }
+}
+
+/// The key a request was authenticated with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyIdentity {
+    /// `None` for the bootstrap admin key, which is not stored
+    pub id: Option<Uuid>,
+    pub name: String,
+    /// Keys created before roles were introduced have none
+    pub role: Option<Role>,
+    pub scopes: Vec<Scope>,
+    /// Tenant whose tree and schema the key uses, the default ones when `None`
+    pub tenant: Option<String>,
+}
+
+impl ApiKeyIdentity {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes
+            .iter()
+            .any(|granted| *granted == scope || *granted == Scope::Admin)
+    }
+}
+
+/// A stored API key, without its secret
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ApiKeyRecord {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    pub scopes: Vec<Scope>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A row of the `api_keys` table, before its role and scopes are parsed
+struct ApiKeyRow {
+    id: Uuid,
+    name: String,
+    role: Option<String>,
+    scopes: Vec<String>,
+    tenant: Option<String>,
+    created_at: DateTime<Utc>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Authenticates requests by their `X-Auth-Key` header.
+///
+/// Authentication is enforced once a bootstrap admin key is configured. That key is compared in
+/// constant time and never stored; every other key lives in the `api_keys` table as a SHA-256
+/// digest, which is enough for random keys of this length.
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    admin_key: Option<Arc<str>>,
+}
+
+impl ApiKeys {
+    pub fn new(admin_key: impl Into<String>) -> Self {
+        ApiKeys {
+            admin_key: Some(Arc::from(admin_key.into())),
+        }
+    }
+
+    /// Whether requests need an API key at all
+    pub fn is_enabled(&self) -> bool {
+        self.admin_key.is_some()
+    }
+
+    /// Look up the identity behind `key`, `None` if it is unknown or revoked
+    pub async fn authenticate(
+        &self,
+        pool: &ConnectionPool,
+        key: &str,
+    ) -> Result<Option<ApiKeyIdentity>> {
+        if let Some(admin_key) = &self.admin_key {
+            if verify_slices_are_equal(key.as_bytes(), admin_key.as_bytes()).is_ok() {
+                return Ok(Some(ApiKeyIdentity {
+                    id: None,
+                    name: "bootstrap admin".to_string(),
+                    role: Some(Role::Admin),
+                    scopes: vec![Scope::Admin],
+                    tenant: None,
+                }));
+            }
+        }
+        if !key.starts_with(KEY_PREFIX) {
+            return Ok(None);
+        }
+
+        let row = sqlx::query!(
+            "SELECT id, name, role, scopes, tenant FROM api_keys \
+            WHERE key_hash = $1 AND revoked_at IS NULL",
+            hash_key(key),
+        )
+        .fetch_optional(pool)
+        .await?;
+        row.map(|row| {
+            Ok(ApiKeyIdentity {
+                id: Some(row.id),
+                name: row.name,
+                role: parse_role(row.role.as_deref())?,
+                scopes: parse_scopes(&row.scopes)?,
+                tenant: row.tenant,
+            })
+        })
+        .transpose()
+    }
+}
+
+/// Store a new key and return it together with its secret, which is not kept.
+/// `scopes` must be allowed by `role`, and `tenant` must be configured if set.
+pub async fn create(
+    pool: &ConnectionPool,
+    name: &str,
+    role: Role,
+    scopes: &[Scope],
+    tenant: Option<&str>,
+) -> Result<(ApiKeyRecord, String)> {
+    let key = generate();
+    let scopes = scopes.iter().map(Scope::as_str).collect::<Vec<_>>();
+    let row = sqlx::query_as!(
+        ApiKeyRow,
+        "INSERT INTO api_keys (id, name, key_hash, role, scopes, tenant) \
+        VALUES ($1, $2, $3, $4, $5, $6) \
+        RETURNING id, name, role, scopes, tenant, created_at, revoked_at",
+        Uuid::new_v4(),
+        name,
+        hash_key(&key),
+        role.as_str(),
+        &scopes as &[&str],
+        tenant,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok((ApiKeyRecord::try_from(row)?, key))
+}
+
+/// All keys, revoked ones included, newest first
+pub async fn list(pool: &ConnectionPool) -> Result<Vec<ApiKeyRecord>> {
+    let rows = sqlx::query_as!(
+        ApiKeyRow,
+        "SELECT id, name, role, scopes, tenant, created_at, revoked_at FROM api_keys \
+        ORDER BY created_at DESC",
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter().map(ApiKeyRecord::try_from).collect()
+}
+
+/// Revoke a key, `false` if there is no active key with this ID
+pub async fn revoke(pool: &ConnectionPool, id: Uuid) -> Result<bool> {
+    let revoked = sqlx::query!(
+        "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+        id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(revoked.rows_affected() > 0)
+}
+
+impl TryFrom<ApiKeyRow> for ApiKeyRecord {
+    type Error = Report;
+
+    fn try_from(row: ApiKeyRow) -> Result<Self, Self::Error> {
+        Ok(ApiKeyRecord {
+            id: row.id,
+            name: row.name,
+            role: parse_role(row.role.as_deref())?,
+            scopes: parse_scopes(&row.scopes)?,
+            tenant: row.tenant,
+            created_at: row.created_at,
+            revoked_at: row.revoked_at,
+        })
+    }
+}
+
+fn parse_scopes(scopes: &[String]) -> Result<Vec<Scope>> {
+    scopes.iter().map(|scope| scope.parse()).collect()
+}
+
+fn parse_role(role: Option<&str>) -> Result<Option<Role>> {
+    role.map(str::parse).transpose()
+}
+
+fn generate() -> String {
+    let mut secret = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .expect("system random source available");
+    format!("{KEY_PREFIX}{}", BASE64_URL_SAFE_NO_PAD.encode(secret))
+}
+
+fn hash_key(key: &str) -> Vec<u8> {
+    digest(&SHA256, key.as_bytes()).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_scope_implies_the_others() {
+        let admin = ApiKeyIdentity {
+            id: None,
+            name: "admin".to_string(),
+            role: Some(Role::Admin),
+            scopes: vec![Scope::Admin],
+            tenant: None,
+        };
+        let reader = ApiKeyIdentity {
+            id: None,
+            name: "reader".to_string(),
+            role: None,
+            scopes: vec![Scope::Read],
+            tenant: None,
+        };
+        assert!(admin.has_scope(Scope::Upload));
+        assert!(reader.has_scope(Scope::Read));
+        assert!(!reader.has_scope(Scope::Upload));
+        assert!(!reader.has_scope(Scope::Admin));
+    }
+
+    #[test]
+    fn roles_bound_scopes() {
+        assert!(Role::Uploader.allows(&[Scope::Upload]));
+        assert!(!Role::Uploader.allows(&[Scope::Upload, Scope::Audit]));
+        assert!(!Role::Auditor.allows(&[Scope::Admin]));
+        assert!(Role::Admin.allows(Role::Auditor.scopes()));
+        assert!(Role::Admin.allows(&[]));
+    }
+
+    #[test]
+    fn generated_keys_are_unique_and_prefixed() {
+        let (first, second) = (generate(), generate());
+        assert!(first.starts_with(KEY_PREFIX));
+        assert_ne!(first, second);
+        assert_ne!(hash_key(&first), hash_key(&second));
+    }
+}
@@ -0,0 +1,285 @@
+use std::sync::Arc;
+
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use ciborium::value::Value as Cbor;
+use ring::digest::{digest, SHA256};
+use serde_json::json;
+use webpki::{EndEntityCert, Time, TlsClientTrustAnchors, TrustAnchor};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{AttestationError, AttestationFormat, AttestationVerdict};
+
+/// Certificate extension holding the attestation nonce
+const NONCE_EXTENSION_OID: &str = "1.2.840.113635.100.8.2";
+/// DER framing of the nonce extension: SEQUENCE { [1] { OCTET STRING (32 bytes) } }
+const NONCE_EXTENSION_PREFIX: [u8; 6] = [0x30, 0x24, 0xa1, 0x22, 0x04, 0x20];
+
+const PRODUCTION_AAGUID: &[u8; 16] = b"appattest\0\0\0\0\0\0\0";
+const DEVELOPMENT_AAGUID: &[u8; 16] = b"appattestdevelop";
+
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+];
+
+/// Verifies Apple App Attest attestation objects.
+///
+/// The client data hash the app passes to `attestKey` must be the SHA-256 of the uploaded file.
+#[derive(Clone)]
+pub struct AppAttest {
+    /// `<team id>.<bundle id>` of the capture app
+    app_id: String,
+    /// DER encoded Apple App Attestation Root CA
+    root_ca: Arc<Vec<u8>>,
+    allow_development: bool,
+}
+
+impl AppAttest {
+    /// `root_ca` is the Apple App Attestation Root CA certificate, PEM or DER
+    pub fn new(
+        app_id: impl Into<String>,
+        root_ca: &[u8],
+        allow_development: bool,
+    ) -> Result<Self, AttestationError> {
+        let root_ca = match std::str::from_utf8(root_ca) {
+            Ok(pem) if pem.contains("-----BEGIN CERTIFICATE-----") => {
+                let body: String = pem
+                    .lines()
+                    .filter(|line| !line.starts_with("-----"))
+                    .collect();
+                BASE64_STANDARD
+                    .decode(body.trim())
+                    .map_err(|_| AttestationError::InvalidKey)?
+            }
+            _ => root_ca.to_vec(),
+        };
+        TrustAnchor::try_from_cert_der(&root_ca).map_err(|_| AttestationError::InvalidKey)?;
+        Ok(AppAttest {
+            app_id: app_id.into(),
+            root_ca: Arc::new(root_ca),
+            allow_development,
+        })
+    }
+
+    pub fn verify(
+        &self,
+        attestation: &str,
+        upload_digest: &[u8; 32],
+        now: DateTime<Utc>,
+    ) -> Result<AttestationVerdict, AttestationError> {
+        let attestation = BASE64_STANDARD
+            .decode(attestation.trim())
+            .map_err(|_| AttestationError::Malformed)?;
+        let object: Cbor = ciborium::de::from_reader(attestation.as_slice())
+            .map_err(|_| AttestationError::Malformed)?;
+
+        if field(&object, "fmt").and_then(Cbor::as_text) != Some("apple-appattest") {
+            return Err(AttestationError::Malformed);
+        }
+        let auth_data = field(&object, "authData")
+            .and_then(Cbor::as_bytes)
+            .ok_or(AttestationError::Malformed)?;
+        let certificates: Vec<&[u8]> = field(&object, "attStmt")
+            .and_then(|statement| field(statement, "x5c"))
+            .and_then(Cbor::as_array)
+            .ok_or(AttestationError::Malformed)?
+            .iter()
+            .map(|certificate| certificate.as_bytes().map(Vec::as_slice))
+            .collect::<Option<_>>()
+            .ok_or(AttestationError::Malformed)?;
+        let (leaf, intermediates) = certificates
+            .split_first()
+            .ok_or(AttestationError::Malformed)?;
+
+        self.verify_chain(leaf, intermediates, now)?;
+
+        let (_, certificate) =
+            X509Certificate::from_der(leaf).map_err(|_| AttestationError::Malformed)?;
+        let nonce = certificate
+            .extensions()
+            .iter()
+            .find(|extension| extension.oid.to_id_string() == NONCE_EXTENSION_OID)
+            .and_then(|extension| extension.value.strip_prefix(&NONCE_EXTENSION_PREFIX[..]))
+            .ok_or(AttestationError::Malformed)?;
+        if nonce != expected_nonce(auth_data, upload_digest) {
+            return Err(AttestationError::BindingMismatch);
+        }
+
+        let public_key = certificate.public_key().subject_public_key.data.as_ref();
+        let key_id = digest(&SHA256, public_key);
+        let development = check_auth_data(auth_data, &self.app_id, key_id.as_ref())?;
+        if development && !self.allow_development {
+            return Err(AttestationError::WrongApp);
+        }
+
+        Ok(AttestationVerdict {
+            format: AttestationFormat::AppleAppAttest,
+            attested: true,
+            details: json!({
+                "app_id": self.app_id,
+                "key_id": BASE64_STANDARD.encode(key_id),
+                "environment": if development { "development" } else { "production" },
+            }),
+        })
+    }
+
+    fn verify_chain(
+        &self,
+        leaf: &[u8],
+        intermediates: &[&[u8]],
+        now: DateTime<Utc>,
+    ) -> Result<(), AttestationError> {
+        let anchor = TrustAnchor::try_from_cert_der(&self.root_ca)
+            .map_err(|_| AttestationError::InvalidKey)?;
+        let time = Time::from_seconds_since_unix_epoch(
+            now.timestamp()
+                .try_into()
+                .map_err(|_| AttestationError::Stale)?,
+        );
+        EndEntityCert::try_from(leaf)
+            .and_then(|leaf| {
+                leaf.verify_is_valid_tls_client_cert(
+                    SIGNATURE_ALGORITHMS,
+                    &TlsClientTrustAnchors(&[anchor]),
+                    intermediates,
+                    time,
+                )
+            })
+            .map_err(|_| AttestationError::InvalidSignature)
+    }
+}
+
+/// Text-keyed entry of a CBOR map
+fn field<'a>(map: &'a Cbor, key: &str) -> Option<&'a Cbor> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+/// Nonce Apple certifies: SHA-256 of the authenticator data followed by the client data hash
+fn expected_nonce(auth_data: &[u8], client_data_hash: &[u8; 32]) -> [u8; 32] {
+    let mut message = auth_data.to_vec();
+    message.extend_from_slice(client_data_hash);
+    digest(&SHA256, &message)
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are 32 bytes")
+}
+
+/// Check the authenticator data of a new App Attest key, returning whether it comes from the
+/// development environment
+fn check_auth_data(
+    auth_data: &[u8],
+    app_id: &str,
+    key_id: &[u8],
+) -> Result<bool, AttestationError> {
+    // rpIdHash (32) | flags (1) | counter (4) | aaguid (16) | credentialId length (2) | credentialId
+    if auth_data.len() < 55 {
+        return Err(AttestationError::Malformed);
+    }
+    if auth_data[..32] != *digest(&SHA256, app_id.as_bytes()).as_ref() {
+        return Err(AttestationError::WrongApp);
+    }
+    if auth_data[33..37] != [0, 0, 0, 0] {
+        return Err(AttestationError::Malformed);
+    }
+    let development = match &auth_data[37..53] {
+        aaguid if aaguid == PRODUCTION_AAGUID => false,
+        aaguid if aaguid == DEVELOPMENT_AAGUID => true,
+        _ => return Err(AttestationError::Malformed),
+    };
+    let credential_length = u16::from_be_bytes([auth_data[53], auth_data[54]]) as usize;
+    if auth_data.get(55..55 + credential_length) != Some(key_id) {
+        return Err(AttestationError::BindingMismatch);
+    }
+    Ok(development)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_ID: &str = "0352187391.org.example.camera";
+
+    fn auth_data(app_id: &str, aaguid: &[u8; 16], key_id: &[u8]) -> Vec<u8> {
+        let mut data = digest(&SHA256, app_id.as_bytes()).as_ref().to_vec();
+        data.push(0x40);
+        data.extend([0, 0, 0, 0]);
+        data.extend(aaguid);
+        data.extend((key_id.len() as u16).to_be_bytes());
+        data.extend(key_id);
+        data
+    }
+
+    #[test]
+    fn accepts_matching_auth_data() {
+        let key_id = [0x11; 32];
+        assert_eq!(
+            check_auth_data(
+                &auth_data(APP_ID, PRODUCTION_AAGUID, &key_id),
+                APP_ID,
+                &key_id
+            ),
+            Ok(false)
+        );
+        assert_eq!(
+            check_auth_data(
+                &auth_data(APP_ID, DEVELOPMENT_AAGUID, &key_id),
+                APP_ID,
+                &key_id
+            ),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_auth_data() {
+        let key_id = [0x11; 32];
+        let data = auth_data(APP_ID, PRODUCTION_AAGUID, &key_id);
+        assert_eq!(
+            check_auth_data(&data, "0352187391.org.example.other", &key_id),
+            Err(AttestationError::WrongApp)
+        );
+        assert_eq!(
+            check_auth_data(&data, APP_ID, &[0x22; 32]),
+            Err(AttestationError::BindingMismatch)
+        );
+        assert_eq!(
+            check_auth_data(&data[..40], APP_ID, &key_id),
+            Err(AttestationError::Malformed)
+        );
+    }
+
+    #[test]
+    fn nonce_covers_auth_data_and_client_data() {
+        let nonce = expected_nonce(b"auth", &[0xAB; 32]);
+        assert_ne!(nonce, expected_nonce(b"auth", &[0xCD; 32]));
+        assert_ne!(nonce, expected_nonce(b"other", &[0xAB; 32]));
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        let mut object = vec![];
+        ciborium::ser::into_writer(
+            &Cbor::Map(vec![(
+                Cbor::Text("fmt".into()),
+                Cbor::Text("packed".into()),
+            )]),
+            &mut object,
+        )
+        .unwrap();
+        let verifier = AppAttest {
+            app_id: APP_ID.to_string(),
+            root_ca: Arc::new(vec![]),
+            allow_development: false,
+        };
+        assert_eq!(
+            verifier.verify(&BASE64_STANDARD.encode(object), &[0; 32], Utc::now()),
+            Err(AttestationError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+pub use app_attest::AppAttest;
+pub use play_integrity::PlayIntegrity;
+
+mod app_attest;
+mod play_integrity;
+
+/// Device attestation formats accepted alongside an upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AttestationFormat {
+    /// Android Play Integrity token, verified locally with the app's decryption and
+    /// verification keys
+    PlayIntegrity,
+    /// Apple App Attest attestation object, base64 encoded
+    AppleAppAttest,
+}
+
+impl AttestationFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttestationFormat::PlayIntegrity => "play_integrity",
+            AttestationFormat::AppleAppAttest => "apple_app_attest",
+        }
+    }
+}
+
+impl Display for AttestationFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AttestationFormat {
+    type Err = AttestationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "play_integrity" => Ok(AttestationFormat::PlayIntegrity),
+            "apple_app_attest" => Ok(AttestationFormat::AppleAppAttest),
+            _ => Err(AttestationError::UnknownFormat),
+        }
+    }
+}
+
+/// Outcome of checking an attestation that was validly signed and bound to the image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AttestationVerdict {
+    pub format: AttestationFormat,
+    /// Whether the platform vouched for both the device and the app
+    pub attested: bool,
+    /// Platform specific verdict, stored as reported
+    pub details: Value,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AttestationError {
+    #[error("unknown attestation format")]
+    UnknownFormat,
+    #[error("attestation format {0} is not enabled")]
+    NotEnabled(AttestationFormat),
+    #[error("attestation key is invalid")]
+    InvalidKey,
+    #[error("attestation is malformed")]
+    Malformed,
+    #[error("attestation signature or certificate chain is invalid")]
+    InvalidSignature,
+    #[error("attestation is not bound to this image")]
+    BindingMismatch,
+    #[error("attestation was issued for a different app")]
+    WrongApp,
+    #[error("attestation is too old or from the future")]
+    Stale,
+}
+
+/// Configured attestation verifiers. Formats without a verifier are rejected.
+///
+/// Every format binds the attestation to the upload through the SHA-256 of the uploaded file:
+/// the Play Integrity request nonce, or the App Attest client data hash.
+#[derive(Clone, Default)]
+pub struct Attestations {
+    pub play_integrity: Option<PlayIntegrity>,
+    pub app_attest: Option<AppAttest>,
+}
+
+impl Attestations {
+    pub fn is_enabled(&self) -> bool {
+        self.play_integrity.is_some() || self.app_attest.is_some()
+    }
+
+    /// Enabled formats, for the startup summary
+    pub fn formats(&self) -> Vec<AttestationFormat> {
+        let mut formats = vec![];
+        if self.play_integrity.is_some() {
+            formats.push(AttestationFormat::PlayIntegrity);
+        }
+        if self.app_attest.is_some() {
+            formats.push(AttestationFormat::AppleAppAttest);
+        }
+        formats
+    }
+
+    /// Verify `attestation` for the file whose SHA-256 is `upload_digest`
+    pub fn verify(
+        &self,
+        format: AttestationFormat,
+        attestation: &str,
+        upload_digest: &[u8; 32],
+        now: DateTime<Utc>,
+    ) -> Result<AttestationVerdict, AttestationError> {
+        match format {
+            AttestationFormat::PlayIntegrity => self
+                .play_integrity
+                .as_ref()
+                .ok_or(AttestationError::NotEnabled(format))?
+                .verify(attestation, upload_digest, now),
+            AttestationFormat::AppleAppAttest => self
+                .app_attest
+                .as_ref()
+                .ok_or(AttestationError::NotEnabled(format))?
+                .verify(attestation, upload_digest, now),
+        }
+    }
+}
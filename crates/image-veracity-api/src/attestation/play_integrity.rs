@@ -0,0 +1,333 @@
+use aes_kw::KekAes256;
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::prelude::{Engine as _, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde_json::Value;
+
+use super::{AttestationError, AttestationFormat, AttestationVerdict};
+
+/// DER prefix of a SubjectPublicKeyInfo holding an uncompressed P-256 point
+const P256_SPKI_PREFIX: [u8; 26] = [
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// How old an integrity verdict may be when the image is uploaded
+const MAX_VERDICT_AGE_MINUTES: i64 = 10;
+/// Tolerated clock skew for verdicts that appear to come from the future
+const MAX_CLOCK_SKEW_MINUTES: i64 = 1;
+
+/// Nonces are base64url; Play Integrity does not prescribe padding
+const NONCE_ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Verifies Play Integrity tokens locally, without calling Google.
+///
+/// Tokens are JWE (A256KW, A256GCM) wrapping a JWS (ES256) of the integrity verdict, using the
+/// response encryption keys downloaded from the Play Console.
+#[derive(Clone)]
+pub struct PlayIntegrity {
+    decryption_key: [u8; 32],
+    verification_key: Vec<u8>,
+    package_name: String,
+}
+
+impl PlayIntegrity {
+    /// Keys as shown in the Play Console: a base64 AES-256 decryption key and a base64 DER
+    /// encoded P-256 verification key
+    pub fn new(
+        decryption_key: &str,
+        verification_key: &str,
+        package_name: impl Into<String>,
+    ) -> Result<Self, AttestationError> {
+        let decryption_key = BASE64_STANDARD
+            .decode(decryption_key.trim())
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or(AttestationError::InvalidKey)?;
+        let verification_key = BASE64_STANDARD
+            .decode(verification_key.trim())
+            .map_err(|_| AttestationError::InvalidKey)?;
+        let verification_key = match verification_key.strip_prefix(&P256_SPKI_PREFIX[..]) {
+            Some(point) if point.len() == 65 => point.to_vec(),
+            _ => return Err(AttestationError::InvalidKey),
+        };
+        Ok(PlayIntegrity {
+            decryption_key,
+            verification_key,
+            package_name: package_name.into(),
+        })
+    }
+
+    pub fn verify(
+        &self,
+        token: &str,
+        upload_digest: &[u8; 32],
+        now: DateTime<Utc>,
+    ) -> Result<AttestationVerdict, AttestationError> {
+        let jws = self.decrypt(token.trim())?;
+        let details = self.verify_signature(&jws)?;
+
+        let request = &details["requestDetails"];
+        if request["requestPackageName"].as_str() != Some(self.package_name.as_str()) {
+            return Err(AttestationError::WrongApp);
+        }
+        let nonce = request["nonce"]
+            .as_str()
+            .and_then(|nonce| NONCE_ENGINE.decode(nonce).ok())
+            .ok_or(AttestationError::Malformed)?;
+        if nonce != upload_digest {
+            return Err(AttestationError::BindingMismatch);
+        }
+        // Reported as a string in current verdicts
+        let timestamp = match &request["timestampMillis"] {
+            Value::String(millis) => millis.parse::<i64>().ok(),
+            millis => millis.as_i64(),
+        }
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+        .ok_or(AttestationError::Malformed)?;
+        if now - timestamp > Duration::minutes(MAX_VERDICT_AGE_MINUTES)
+            || timestamp - now > Duration::minutes(MAX_CLOCK_SKEW_MINUTES)
+        {
+            return Err(AttestationError::Stale);
+        }
+
+        let app_recognized =
+            details["appIntegrity"]["appRecognitionVerdict"].as_str() == Some("PLAY_RECOGNIZED");
+        let device_verdicts = details["deviceIntegrity"]["deviceRecognitionVerdict"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let device_trusted = device_verdicts.iter().any(|verdict| {
+            matches!(
+                verdict.as_str(),
+                Some("MEETS_DEVICE_INTEGRITY" | "MEETS_STRONG_INTEGRITY")
+            )
+        });
+
+        Ok(AttestationVerdict {
+            format: AttestationFormat::PlayIntegrity,
+            attested: app_recognized && device_trusted,
+            details,
+        })
+    }
+
+    /// Decrypt the JWE layer, returning the compact JWS inside
+    fn decrypt(&self, token: &str) -> Result<String, AttestationError> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [header, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+            return Err(AttestationError::Malformed);
+        };
+        let decode = |part: &str| {
+            BASE64_URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| AttestationError::Malformed)
+        };
+        let header: Value =
+            serde_json::from_slice(&decode(header)?).map_err(|_| AttestationError::Malformed)?;
+        if header["alg"] != "A256KW" || header["enc"] != "A256GCM" {
+            return Err(AttestationError::Malformed);
+        }
+
+        let content_key = KekAes256::from(self.decryption_key)
+            .unwrap_vec(&decode(encrypted_key)?)
+            .map_err(|_| AttestationError::InvalidSignature)?;
+        let key =
+            UnboundKey::new(&AES_256_GCM, &content_key).map_err(|_| AttestationError::Malformed)?;
+        let nonce = Nonce::try_assume_unique_for_key(&decode(iv)?)
+            .map_err(|_| AttestationError::Malformed)?;
+
+        let mut sealed = decode(ciphertext)?;
+        sealed.extend(decode(tag)?);
+        // The protected header is authenticated as its base64url encoding
+        let protected = token.split('.').next().unwrap_or_default();
+        let jws = LessSafeKey::new(key)
+            .open_in_place(nonce, Aad::from(protected.as_bytes()), &mut sealed)
+            .map_err(|_| AttestationError::InvalidSignature)?;
+        String::from_utf8(jws.to_vec()).map_err(|_| AttestationError::Malformed)
+    }
+
+    /// Check the JWS signature, returning its JSON payload
+    fn verify_signature(&self, jws: &str) -> Result<Value, AttestationError> {
+        let (signed, signature) = jws.rsplit_once('.').ok_or(AttestationError::Malformed)?;
+        let (header, payload) = signed.split_once('.').ok_or(AttestationError::Malformed)?;
+        let decode = |part: &str| {
+            BASE64_URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| AttestationError::Malformed)
+        };
+
+        let header: Value =
+            serde_json::from_slice(&decode(header)?).map_err(|_| AttestationError::Malformed)?;
+        if header["alg"] != "ES256" {
+            return Err(AttestationError::Malformed);
+        }
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &self.verification_key)
+            .verify(signed.as_bytes(), &decode(signature)?)
+            .map_err(|_| AttestationError::InvalidSignature)?;
+
+        serde_json::from_slice(&decode(payload)?).map_err(|_| AttestationError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+
+    use super::*;
+
+    const PACKAGE: &str = "org.example.camera";
+
+    /// Play Console style keys plus the private half of the verification key
+    struct Keys {
+        decryption_key: [u8; 32],
+        signing_key: EcdsaKeyPair,
+    }
+
+    impl Keys {
+        fn new() -> Keys {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(
+                &ECDSA_P256_SHA256_FIXED_SIGNING,
+                &SystemRandom::new(),
+            )
+            .unwrap();
+            Keys {
+                decryption_key: [0x42; 32],
+                signing_key: EcdsaKeyPair::from_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    pkcs8.as_ref(),
+                )
+                .unwrap(),
+            }
+        }
+
+        fn verifier(&self) -> PlayIntegrity {
+            let mut spki = P256_SPKI_PREFIX.to_vec();
+            spki.extend(self.signing_key.public_key().as_ref());
+            PlayIntegrity::new(
+                &BASE64_STANDARD.encode(self.decryption_key),
+                &BASE64_STANDARD.encode(spki),
+                PACKAGE,
+            )
+            .unwrap()
+        }
+
+        /// Sign and encrypt a verdict the way Google does
+        fn token(&self, verdict: &Value) -> String {
+            let header = BASE64_URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256"}"#);
+            let payload = BASE64_URL_SAFE_NO_PAD.encode(verdict.to_string());
+            let signed = format!("{header}.{payload}");
+            let signature = self
+                .signing_key
+                .sign(&SystemRandom::new(), signed.as_bytes())
+                .unwrap();
+            let jws = format!("{signed}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature));
+
+            let content_key = [0x17; 32];
+            let encrypted_key = KekAes256::from(self.decryption_key)
+                .wrap_vec(&content_key)
+                .unwrap();
+            let iv = [0x01; 12];
+            let protected = BASE64_URL_SAFE_NO_PAD.encode(r#"{"alg":"A256KW","enc":"A256GCM"}"#);
+            let mut sealed = jws.into_bytes();
+            LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &content_key).unwrap())
+                .seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(iv),
+                    Aad::from(protected.as_bytes()),
+                    &mut sealed,
+                )
+                .unwrap();
+            let tag = sealed.split_off(sealed.len() - 16);
+            format!(
+                "{protected}.{}.{}.{}.{}",
+                BASE64_URL_SAFE_NO_PAD.encode(encrypted_key),
+                BASE64_URL_SAFE_NO_PAD.encode(iv),
+                BASE64_URL_SAFE_NO_PAD.encode(sealed),
+                BASE64_URL_SAFE_NO_PAD.encode(tag)
+            )
+        }
+    }
+
+    fn verdict(digest: &[u8; 32], now: DateTime<Utc>, device: &[&str]) -> Value {
+        json!({
+            "requestDetails": {
+                "requestPackageName": PACKAGE,
+                "nonce": BASE64_URL_SAFE_NO_PAD.encode(digest),
+                "timestampMillis": now.timestamp_millis().to_string(),
+            },
+            "appIntegrity": { "appRecognitionVerdict": "PLAY_RECOGNIZED" },
+            "deviceIntegrity": { "deviceRecognitionVerdict": device },
+        })
+    }
+
+    #[test]
+    fn verifies_trusted_device() {
+        let keys = Keys::new();
+        let now = Utc::now();
+        let digest = [0xAB; 32];
+        let token = keys.token(&verdict(&digest, now, &["MEETS_DEVICE_INTEGRITY"]));
+
+        let verdict = keys.verifier().verify(&token, &digest, now).unwrap();
+        assert!(verdict.attested);
+        assert_eq!(verdict.format, AttestationFormat::PlayIntegrity);
+    }
+
+    #[test]
+    fn untrusted_device_is_not_attested() {
+        let keys = Keys::new();
+        let now = Utc::now();
+        let digest = [0xAB; 32];
+        let token = keys.token(&verdict(&digest, now, &[]));
+
+        assert!(
+            !keys
+                .verifier()
+                .verify(&token, &digest, now)
+                .unwrap()
+                .attested
+        );
+    }
+
+    #[test]
+    fn rejects_other_images_and_stale_verdicts() {
+        let keys = Keys::new();
+        let now = Utc::now();
+        let digest = [0xAB; 32];
+        let token = keys.token(&verdict(&digest, now, &["MEETS_DEVICE_INTEGRITY"]));
+        let verifier = keys.verifier();
+
+        assert_eq!(
+            verifier.verify(&token, &[0xCD; 32], now),
+            Err(AttestationError::BindingMismatch)
+        );
+        assert_eq!(
+            verifier.verify(&token, &digest, now + Duration::hours(1)),
+            Err(AttestationError::Stale)
+        );
+    }
+
+    #[test]
+    fn rejects_tokens_for_other_keys() {
+        let now = Utc::now();
+        let digest = [0xAB; 32];
+        let token = Keys::new().token(&verdict(&digest, now, &["MEETS_DEVICE_INTEGRITY"]));
+
+        // Same decryption key, different signing key
+        assert_eq!(
+            Keys::new().verifier().verify(&token, &digest, now),
+            Err(AttestationError::InvalidSignature)
+        );
+        assert_eq!(
+            Keys::new().verifier().verify("a.b.c", &digest, now),
+            Err(AttestationError::Malformed)
+        );
+    }
+}
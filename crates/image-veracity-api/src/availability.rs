@@ -0,0 +1,78 @@
+//! Degraded mode while Trillian cannot be reached.
+//!
+//! The server starts even if Trillian is down, serving lookups from the database but refusing
+//! uploads, and keeps probing the log so uploads resume as soon as it answers again.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::state::AppState;
+
+/// How often Trillian is probed
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether Trillian was reachable when last checked
+#[derive(Clone, Debug)]
+pub struct Availability {
+    trillian: Arc<AtomicBool>,
+}
+
+impl Default for Availability {
+    fn default() -> Self {
+        Availability::new(true)
+    }
+}
+
+impl Availability {
+    pub fn new(trillian_available: bool) -> Self {
+        Availability {
+            trillian: Arc::new(AtomicBool::new(trillian_available)),
+        }
+    }
+
+    pub fn trillian_available(&self) -> bool {
+        self.trillian.load(Ordering::Relaxed)
+    }
+
+    /// Record a probe result, returning whether availability changed
+    fn set_trillian_available(&self, available: bool) -> bool {
+        self.trillian.swap(available, Ordering::Relaxed) != available
+    }
+}
+
+/// Probe Trillian until the process exits, switching uploads off and on as it goes and comes back
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let probe = state.trillian.clone().get_tree(&state.trillian_tree).await;
+        let available = probe.is_ok();
+        if !state.availability.set_trillian_available(available) {
+            continue;
+        }
+        match probe {
+            Ok(_) => info!("Trillian is reachable again, accepting uploads"),
+            Err(err) => warn!("Trillian is unreachable, refusing uploads: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_changes_only() {
+        let availability = Availability::new(false);
+        assert!(!availability.trillian_available());
+        assert!(availability.set_trillian_available(true));
+        assert!(!availability.set_trillian_available(true));
+        assert!(availability.trillian_available());
+        assert!(availability.set_trillian_available(false));
+    }
+}
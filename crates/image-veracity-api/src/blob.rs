@@ -0,0 +1,794 @@
+//! Storage for original image bytes, keyed by crypto hash.
+//!
+//! Originals are only kept when a store is configured. Each is written once, after its image
+//! record claims the crypto hash, so the bytes under a key are always the file that was logged.
+//!
+//! Any store can be wrapped in an [`EncryptedStore`], which seals each original through
+//! [`Envelopes`] with an AES-GCM data key of its own and keeps that key wrapped by a key
+//! encryption key, held in AWS KMS or given to the server. Rotating the key encryption key only
+//! rewraps data keys, so originals are never decrypted and written again in bulk.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use aes_kw::KekAes256;
+use async_trait::async_trait;
+use aws_sdk_kms::primitives::Blob;
+use aws_sdk_s3::primitives::ByteStream;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use eyre::{ensure, eyre, Result};
+use reqwest::Url;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::hash::cryptographic::CryptographicHash;
+
+pub type SharedBlobStore = Arc<dyn BlobStore>;
+
+#[async_trait]
+pub trait BlobStore: Debug + Send + Sync {
+    /// Store the original bytes of the image with crypto hash `key`
+    async fn put(&self, key: &CryptographicHash, data: Vec<u8>) -> Result<()>;
+
+    /// Original bytes of the image with crypto hash `key`, `None` if they were never stored
+    async fn get(&self, key: &CryptographicHash) -> Result<Option<Vec<u8>>>;
+
+    /// Remove the original bytes of an image, succeeding if they were already gone
+    async fn delete(&self, key: &CryptographicHash) -> Result<()>;
+
+    /// Whether originals are encrypted before they are stored
+    fn encrypts(&self) -> bool {
+        false
+    }
+
+    /// Bring the stored original of `key` under the current key encryption key, returning
+    /// whether it was rewritten. Stores that do not encrypt have nothing to do.
+    async fn rewrap(&self, _key: &CryptographicHash) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Open the store named by `url`: `file:///path/to/dir` or `s3://bucket/optional/prefix`.
+/// S3 credentials and region come from the usual AWS environment, and `s3_endpoint` points the
+/// client at an S3-compatible service such as MinIO.
+pub async fn from_url(url: &str, s3_endpoint: Option<String>) -> Result<SharedBlobStore> {
+    let parsed = Url::parse(url).map_err(|err| eyre!("invalid blob store URL {url}: {err}"))?;
+    match parsed.scheme() {
+        "file" => {
+            let root = parsed
+                .to_file_path()
+                .map_err(|_| eyre!("blob store URL {url} is not an absolute path"))?;
+            Ok(Arc::new(FilesystemStore::new(root).await?))
+        }
+        "s3" => {
+            let bucket = parsed
+                .host_str()
+                .filter(|bucket| !bucket.is_empty())
+                .ok_or_else(|| eyre!("blob store URL {url} names no bucket"))?;
+            Ok(Arc::new(
+                S3Store::new(bucket, parsed.path(), s3_endpoint).await,
+            ))
+        }
+        other => Err(eyre!("unsupported blob store scheme {other}")),
+    }
+}
+
+/// Hex key of an image, which both stores name objects by
+fn object_name(key: &CryptographicHash) -> String {
+    key.to_hex()
+}
+
+/// Originals kept as files under a directory, fanned out by the first byte of the hash so no
+/// single directory grows too large
+#[derive(Debug, Clone)]
+pub struct FilesystemStore {
+    root: PathBuf,
+}
+
+impl FilesystemStore {
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root).await?;
+        Ok(FilesystemStore { root })
+    }
+
+    fn path(&self, key: &CryptographicHash) -> PathBuf {
+        let name = object_name(key);
+        self.root.join(&name[..2]).join(name)
+    }
+}
+
+#[async_trait]
+impl BlobStore for FilesystemStore {
+    async fn put(&self, key: &CryptographicHash, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key);
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir).await?;
+        // Write beside the final name and rename, so readers never see a partial file
+        let partial = dir.join(format!(".{}.partial", Uuid::new_v4()));
+        if let Err(err) = fs::write(&partial, data).await {
+            let _ = fs::remove_file(&partial).await;
+            return Err(err.into());
+        }
+        if let Err(err) = fs::rename(&partial, &path).await {
+            let _ = fs::remove_file(&partial).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &CryptographicHash) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete(&self, key: &CryptographicHash) -> Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Originals kept as objects in an S3 or S3-compatible bucket
+#[derive(Debug, Clone)]
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Store {
+    /// Objects are written under `prefix` in `bucket`. A custom `endpoint` is addressed with
+    /// path-style URLs, which S3-compatible services expect.
+    pub async fn new(bucket: &str, prefix: &str, endpoint: Option<String>) -> Self {
+        let sdk_config = aws_config::load_from_env().await;
+        let mut config = aws_sdk_s3::config::Builder::from(&sdk_config);
+        if let Some(endpoint) = endpoint {
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+        let prefix = prefix.trim_matches('/');
+        S3Store {
+            client: aws_sdk_s3::Client::from_conf(config.build()),
+            bucket: bucket.to_string(),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{prefix}/")
+            },
+        }
+    }
+
+    fn object_key(&self, key: &CryptographicHash) -> String {
+        format!("{}{}", self.prefix, object_name(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &CryptographicHash, data: Vec<u8>) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .body(ByteStream::from(data))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &CryptographicHash) -> Result<Option<Vec<u8>>> {
+        let object = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(err) => {
+                let err = err.into_service_error();
+                return if err.is_no_such_key() {
+                    Ok(None)
+                } else {
+                    Err(err.into())
+                };
+            }
+        };
+        Ok(Some(object.body.collect().await?.into_bytes().to_vec()))
+    }
+
+    async fn delete(&self, key: &CryptographicHash) -> Result<()> {
+        // S3 reports success for keys that do not exist
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.object_key(key))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Marks a sealed original. Uploads are checked to start with the magic bytes of an image, so
+/// no plaintext original starts with it.
+const ENVELOPE_MAGIC: &[u8; 4] = b"VEN1";
+/// Length of the AES-256 data keys originals are sealed with
+const DATA_KEY_LEN: usize = 32;
+
+/// Wraps and unwraps the data keys originals are sealed with
+#[async_trait]
+pub trait KeyWrapper: Debug + Send + Sync {
+    /// ID of the key encryption key new data keys are wrapped with
+    fn current_key(&self) -> &str;
+
+    /// Wrap `data_key` with the current key encryption key
+    async fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>>;
+
+    /// Unwrap a data key wrapped with the key encryption key `key_id`
+    async fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The key wrapper configured by `kms_key_id`, an AWS KMS key ID or ARN, or else by
+/// `local_keys`. `None` when neither is set, and originals are stored as they are.
+pub async fn key_wrapper(
+    kms_key_id: Option<String>,
+    local_keys: Option<&str>,
+) -> Result<Option<Arc<dyn KeyWrapper>>> {
+    match (kms_key_id, local_keys) {
+        (Some(_), Some(_)) => Err(eyre!("set either a KMS key or local keys, not both")),
+        (Some(key_id), None) => Ok(Some(Arc::new(KmsKeys::new(key_id).await))),
+        (None, Some(keys)) => Ok(Some(Arc::new(LocalKeys::parse(keys)?))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Data keys wrapped by a symmetric AWS KMS key, with credentials and region from the usual
+/// AWS environment. KMS keeps the key material of rotated keys, so data keys wrapped under an
+/// older key or key ID still unwrap.
+#[derive(Debug, Clone)]
+pub struct KmsKeys {
+    client: aws_sdk_kms::Client,
+    key_id: String,
+}
+
+impl KmsKeys {
+    pub async fn new(key_id: impl Into<String>) -> Self {
+        let sdk_config = aws_config::load_from_env().await;
+        KmsKeys {
+            client: aws_sdk_kms::Client::new(&sdk_config),
+            key_id: key_id.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyWrapper for KmsKeys {
+    fn current_key(&self) -> &str {
+        &self.key_id
+    }
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>> {
+        let encrypted = self
+            .client
+            .encrypt()
+            .key_id(&self.key_id)
+            .plaintext(Blob::new(data_key))
+            .send()
+            .await?;
+        let wrapped = encrypted
+            .ciphertext_blob()
+            .ok_or_else(|| eyre!("KMS returned no wrapped key"))?;
+        Ok(wrapped.as_ref().to_vec())
+    }
+
+    async fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        let decrypted = self
+            .client
+            .decrypt()
+            .key_id(key_id)
+            .ciphertext_blob(Blob::new(wrapped))
+            .send()
+            .await?;
+        let data_key = decrypted
+            .plaintext()
+            .ok_or_else(|| eyre!("KMS returned no data key"))?;
+        Ok(data_key.as_ref().to_vec())
+    }
+}
+
+/// Data keys wrapped with AES key wrap under keys given to the server, for deployments without
+/// a KMS. The first key wraps new data keys; the others are kept to unwrap older ones until
+/// every original has been rewrapped off them.
+#[derive(Clone)]
+pub struct LocalKeys {
+    current: String,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl Debug for LocalKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalKeys")
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LocalKeys {
+    /// Keys listed as `id:base64_key`, separated by commas, the current one first
+    pub fn parse(keys: &str) -> Result<Self> {
+        let mut parsed = HashMap::new();
+        let mut current = None;
+        for entry in keys
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| eyre!("blob encryption keys are listed as id:base64_key"))?;
+            let key = BASE64_STANDARD
+                .decode(key)
+                .ok()
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .ok_or_else(|| eyre!("blob encryption key {id} is not 32 bytes of base64"))?;
+            ensure!(
+                parsed.insert(id.to_string(), key).is_none(),
+                "blob encryption key {id} is listed twice"
+            );
+            current.get_or_insert_with(|| id.to_string());
+        }
+        Ok(LocalKeys {
+            current: current.ok_or_else(|| eyre!("no blob encryption keys are listed"))?,
+            keys: parsed,
+        })
+    }
+
+    fn key(&self, key_id: &str) -> Result<KekAes256> {
+        self.keys
+            .get(key_id)
+            .map(|key| KekAes256::from(*key))
+            .ok_or_else(|| eyre!("blob encryption key {key_id} is not configured"))
+    }
+}
+
+#[async_trait]
+impl KeyWrapper for LocalKeys {
+    fn current_key(&self) -> &str {
+        &self.current
+    }
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>> {
+        self.key(&self.current)?
+            .wrap_vec(data_key)
+            .map_err(|err| eyre!("could not wrap a data key: {err}"))
+    }
+
+    async fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>> {
+        self.key(key_id)?
+            .unwrap_vec(wrapped)
+            .map_err(|err| eyre!("could not unwrap a data key with {key_id}: {err}"))
+    }
+}
+
+/// An original sealed with a data key of its own
+#[derive(Debug, PartialEq, Eq)]
+struct Envelope {
+    /// Key encryption key the data key is wrapped with
+    key_id: String,
+    wrapped_key: Vec<u8>,
+    nonce: [u8; NONCE_LEN],
+    /// AES-GCM ciphertext followed by its tag
+    sealed: Vec<u8>,
+}
+
+impl Envelope {
+    /// Magic bytes, then the key ID and wrapped key each after a two byte big-endian length,
+    /// then the nonce and the sealed original. Fields too long for their length are refused
+    /// rather than truncated.
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        for field in [self.key_id.as_bytes(), &self.wrapped_key[..]] {
+            let len = u16::try_from(field.len())
+                .map_err(|_| eyre!("envelope field of {} bytes is too long", field.len()))?;
+            bytes.extend_from_slice(&len.to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.sealed);
+        Ok(bytes)
+    }
+
+    /// `None` for an original stored before encryption was turned on
+    fn from_bytes(bytes: &[u8]) -> Result<Option<Self>> {
+        let Some(mut rest) = bytes.strip_prefix(&ENVELOPE_MAGIC[..]) else {
+            return Ok(None);
+        };
+        let mut field = || -> Result<Vec<u8>> {
+            ensure!(rest.len() >= 2, "truncated encrypted original");
+            let (len, tail) = rest.split_at(2);
+            let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+            ensure!(tail.len() >= len, "truncated encrypted original");
+            let (field, tail) = tail.split_at(len);
+            rest = tail;
+            Ok(field.to_vec())
+        };
+        let key_id = String::from_utf8(field()?)?;
+        let wrapped_key = field()?;
+        ensure!(rest.len() >= NONCE_LEN, "truncated encrypted original");
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        Ok(Some(Envelope {
+            key_id,
+            wrapped_key,
+            nonce: nonce.try_into()?,
+            sealed: sealed.to_vec(),
+        }))
+    }
+}
+
+/// Seal `data` with `data_key`, authenticating the crypto hash it is stored under so sealed
+/// originals cannot be swapped between keys
+fn seal(
+    data_key: &[u8],
+    nonce: [u8; NONCE_LEN],
+    key: &CryptographicHash,
+    mut data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let sealing_key = UnboundKey::new(&AES_256_GCM, data_key)
+        .map_err(|_| eyre!("data keys are {DATA_KEY_LEN} bytes"))?;
+    LessSafeKey::new(sealing_key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(key.as_ref()),
+            &mut data,
+        )
+        .map_err(|_| eyre!("could not encrypt original {key}"))?;
+    Ok(data)
+}
+
+fn open(data_key: &[u8], envelope: Envelope, key: &CryptographicHash) -> Result<Vec<u8>> {
+    let opening_key = UnboundKey::new(&AES_256_GCM, data_key)
+        .map_err(|_| eyre!("data keys are {DATA_KEY_LEN} bytes"))?;
+    let mut data = envelope.sealed;
+    let len = LessSafeKey::new(opening_key)
+        .open_in_place(
+            Nonce::assume_unique_for_key(envelope.nonce),
+            Aad::from(key.as_ref()),
+            &mut data,
+        )
+        .map_err(|_| eyre!("original {key} does not decrypt"))?
+        .len();
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Seals originals with AES-256-GCM under a random data key each, kept beside them wrapped by
+/// a [`KeyWrapper`]
+#[derive(Debug, Clone)]
+pub struct Envelopes {
+    keys: Arc<dyn KeyWrapper>,
+    random: SystemRandom,
+}
+
+impl Envelopes {
+    pub fn new(keys: Arc<dyn KeyWrapper>) -> Self {
+        Envelopes {
+            keys,
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// ID of the key encryption key new data keys are wrapped with
+    pub fn current_key(&self) -> &str {
+        self.keys.current_key()
+    }
+
+    /// Seal `data`, the original of the image with crypto hash `key`
+    pub async fn seal(&self, key: &CryptographicHash, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.envelope(key, data).await?.to_bytes()
+    }
+
+    /// The original sealed in `stored`, which is returned as it is when it was stored before
+    /// encryption was turned on
+    pub async fn open(&self, key: &CryptographicHash, stored: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(envelope) = Envelope::from_bytes(&stored)? else {
+            return Ok(stored);
+        };
+        let data_key = self
+            .keys
+            .unwrap(&envelope.key_id, &envelope.wrapped_key)
+            .await?;
+        open(&data_key, envelope, key)
+    }
+
+    /// `stored` with its data key wrapped under the current key encryption key, or sealed when
+    /// it was stored as it is. `None` when it is already under the current key.
+    pub async fn rewrap(
+        &self,
+        key: &CryptographicHash,
+        stored: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        let envelope = match Envelope::from_bytes(&stored)? {
+            Some(envelope) if envelope.key_id == self.current_key() => return Ok(None),
+            Some(envelope) => {
+                let data_key = self
+                    .keys
+                    .unwrap(&envelope.key_id, &envelope.wrapped_key)
+                    .await?;
+                // The original stays sealed under the same data key
+                Envelope {
+                    key_id: self.current_key().to_string(),
+                    wrapped_key: self.keys.wrap(&data_key).await?,
+                    ..envelope
+                }
+            }
+            None => self.envelope(key, stored).await?,
+        };
+        envelope.to_bytes().map(Some)
+    }
+
+    async fn envelope(&self, key: &CryptographicHash, data: Vec<u8>) -> Result<Envelope> {
+        let mut data_key = [0; DATA_KEY_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        self.random
+            .fill(&mut data_key)
+            .and_then(|_| self.random.fill(&mut nonce))
+            .map_err(|_| eyre!("could not generate a data key"))?;
+        Ok(Envelope {
+            key_id: self.current_key().to_string(),
+            wrapped_key: self.keys.wrap(&data_key).await?,
+            nonce,
+            sealed: seal(&data_key, nonce, key, data)?,
+        })
+    }
+}
+
+/// A store whose originals are sealed by [`Envelopes`] before they reach `inner`. Originals
+/// stored before encryption was turned on are read as they are until a rewrap job encrypts them.
+#[derive(Debug, Clone)]
+pub struct EncryptedStore {
+    inner: SharedBlobStore,
+    envelopes: Envelopes,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: SharedBlobStore, keys: Arc<dyn KeyWrapper>) -> Self {
+        EncryptedStore {
+            inner,
+            envelopes: Envelopes::new(keys),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for EncryptedStore {
+    async fn put(&self, key: &CryptographicHash, data: Vec<u8>) -> Result<()> {
+        let sealed = self.envelopes.seal(key, data).await?;
+        self.inner.put(key, sealed).await
+    }
+
+    async fn get(&self, key: &CryptographicHash) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(key).await? {
+            Some(stored) => self.envelopes.open(key, stored).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, key: &CryptographicHash) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    fn encrypts(&self) -> bool {
+        true
+    }
+
+    async fn rewrap(&self, key: &CryptographicHash) -> Result<bool> {
+        let Some(stored) = self.inner.get(key).await? else {
+            return Ok(false);
+        };
+        match self.envelopes.rewrap(key, stored).await? {
+            Some(rewrapped) => {
+                self.inner.put(key, rewrapped).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn filesystem_store_round_trips_originals() {
+        let root = std::env::temp_dir().join(format!("blob-store-{}", Uuid::new_v4()));
+        let store = FilesystemStore::new(&root).await.unwrap();
+        let key = CryptographicHash::try_from(vec![0xab; 32]).unwrap();
+
+        assert_eq!(store.get(&key).await.unwrap(), None);
+        store.put(&key, b"original".to_vec()).await.unwrap();
+        assert!(root.join("ab").join("ab".repeat(32)).is_file());
+        assert_eq!(store.get(&key).await.unwrap(), Some(b"original".to_vec()));
+        store.delete(&key).await.unwrap();
+        store.delete(&key).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), None);
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    fn local_keys(keys: &[(&str, u8)]) -> Arc<dyn KeyWrapper> {
+        let listed: Vec<String> = keys
+            .iter()
+            .map(|(id, byte)| format!("{id}:{}", BASE64_STANDARD.encode([*byte; 32])))
+            .collect();
+        Arc::new(LocalKeys::parse(&listed.join(",")).unwrap())
+    }
+
+    #[tokio::test]
+    async fn sealed_originals_open_only_under_their_key() {
+        let envelopes = Envelopes::new(local_keys(&[("1", 1)]));
+        let key = CryptographicHash::try_from(vec![0xab; 32]).unwrap();
+        let other = CryptographicHash::try_from(vec![0xcd; 32]).unwrap();
+
+        let sealed = envelopes.seal(&key, b"original".to_vec()).await.unwrap();
+        assert!(sealed.starts_with(ENVELOPE_MAGIC));
+        assert!(!sealed.windows(8).any(|window| window == b"original"));
+        assert_eq!(
+            envelopes.open(&key, sealed.clone()).await.unwrap(),
+            b"original"
+        );
+        // A sealed original moved to another key does not open
+        assert!(envelopes.open(&other, sealed).await.is_err());
+        // Originals stored before encryption are returned as they are
+        assert_eq!(
+            envelopes.open(&key, b"plaintext".to_vec()).await.unwrap(),
+            b"plaintext"
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrapping_moves_data_keys_to_the_current_key() {
+        let key = CryptographicHash::try_from(vec![0xab; 32]).unwrap();
+        let old = Envelopes::new(local_keys(&[("1", 1)]));
+        let sealed = old.seal(&key, b"original".to_vec()).await.unwrap();
+
+        let rotated = Envelopes::new(local_keys(&[("2", 2), ("1", 1)]));
+        let rewrapped = rotated.rewrap(&key, sealed).await.unwrap().unwrap();
+        assert_eq!(rotated.rewrap(&key, rewrapped.clone()).await.unwrap(), None);
+        let plaintext = rotated
+            .rewrap(&key, b"plaintext".to_vec())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(plaintext.starts_with(ENVELOPE_MAGIC));
+
+        // The retired key is no longer needed
+        let current = Envelopes::new(local_keys(&[("2", 2)]));
+        assert_eq!(
+            current.open(&key, rewrapped.clone()).await.unwrap(),
+            b"original"
+        );
+        assert_eq!(current.open(&key, plaintext).await.unwrap(), b"plaintext");
+        assert!(old.open(&key, rewrapped).await.is_err());
+    }
+
+    #[test]
+    fn envelopes_refuse_fields_too_long_to_frame() {
+        let envelope = Envelope {
+            key_id: "1".to_string(),
+            wrapped_key: vec![0; 40],
+            nonce: [0; NONCE_LEN],
+            sealed: b"sealed".to_vec(),
+        };
+        let bytes = envelope.to_bytes().unwrap();
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap(), Some(envelope));
+
+        let oversized = Envelope {
+            key_id: "k".repeat(u16::MAX as usize + 1),
+            wrapped_key: vec![0; 40],
+            nonce: [0; NONCE_LEN],
+            sealed: vec![],
+        };
+        assert!(oversized.to_bytes().is_err());
+        let oversized = Envelope {
+            key_id: "1".to_string(),
+            wrapped_key: vec![0; u16::MAX as usize + 1],
+            nonce: [0; NONCE_LEN],
+            sealed: vec![],
+        };
+        assert!(oversized.to_bytes().is_err());
+    }
+
+    #[test]
+    fn local_keys_are_listed_current_first() {
+        let key = BASE64_STANDARD.encode([7; 32]);
+        let keys = LocalKeys::parse(&format!("new:{key}, old:{key}")).unwrap();
+        assert_eq!(keys.current_key(), "new");
+        assert!(keys.key("old").is_ok());
+        assert!(LocalKeys::parse("").is_err());
+        assert!(LocalKeys::parse(&format!("new:{key},new:{key}")).is_err());
+        assert!(LocalKeys::parse("new:c2hvcnQ=").is_err());
+        assert!(LocalKeys::parse(&key).is_err());
+    }
+
+    #[tokio::test]
+    async fn encrypted_originals_are_not_stored_in_plaintext() {
+        let root = std::env::temp_dir().join(format!("blob-store-{}", Uuid::new_v4()));
+        let inner: SharedBlobStore = Arc::new(FilesystemStore::new(&root).await.unwrap());
+        let store = EncryptedStore::new(inner.clone(), local_keys(&[("1", 1)]));
+        let key = CryptographicHash::try_from(vec![0xab; 32]).unwrap();
+        let other = CryptographicHash::try_from(vec![0xcd; 32]).unwrap();
+
+        store.put(&key, b"original".to_vec()).await.unwrap();
+        let sealed = inner.get(&key).await.unwrap().unwrap();
+        assert!(sealed.starts_with(ENVELOPE_MAGIC));
+        assert!(!sealed.windows(8).any(|window| window == b"original"));
+        assert_eq!(store.get(&key).await.unwrap(), Some(b"original".to_vec()));
+
+        // A sealed original moved to another key does not open
+        inner.put(&other, sealed).await.unwrap();
+        assert!(store.get(&other).await.is_err());
+
+        // Originals stored before encryption are still served
+        inner.put(&other, b"plaintext".to_vec()).await.unwrap();
+        assert_eq!(
+            store.get(&other).await.unwrap(),
+            Some(b"plaintext".to_vec())
+        );
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rewrapping_moves_originals_to_the_current_key() {
+        let root = std::env::temp_dir().join(format!("blob-store-{}", Uuid::new_v4()));
+        let inner: SharedBlobStore = Arc::new(FilesystemStore::new(&root).await.unwrap());
+        let key = CryptographicHash::try_from(vec![0xab; 32]).unwrap();
+        let plaintext = CryptographicHash::try_from(vec![0xcd; 32]).unwrap();
+        let old = EncryptedStore::new(inner.clone(), local_keys(&[("1", 1)]));
+        old.put(&key, b"original".to_vec()).await.unwrap();
+        inner.put(&plaintext, b"plaintext".to_vec()).await.unwrap();
+
+        let rotated = EncryptedStore::new(inner.clone(), local_keys(&[("2", 2), ("1", 1)]));
+        assert!(rotated.rewrap(&key).await.unwrap());
+        assert!(!rotated.rewrap(&key).await.unwrap());
+        assert!(rotated.rewrap(&plaintext).await.unwrap());
+        assert!(inner
+            .get(&plaintext)
+            .await
+            .unwrap()
+            .unwrap()
+            .starts_with(ENVELOPE_MAGIC));
+
+        // The retired key is no longer needed
+        let current = EncryptedStore::new(inner, local_keys(&[("2", 2)]));
+        assert_eq!(current.get(&key).await.unwrap(), Some(b"original".to_vec()));
+        assert_eq!(
+            current.get(&plaintext).await.unwrap(),
+            Some(b"plaintext".to_vec())
+        );
+        assert!(old.get(&key).await.is_err());
+
+        fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn store_urls_pick_the_backend() {
+        let root = std::env::temp_dir().join(format!("blob-store-{}", Uuid::new_v4()));
+        let url = Url::from_file_path(&root).unwrap();
+        assert!(from_url(url.as_str(), None).await.is_ok());
+        assert!(from_url("s3:///prefix", None).await.is_err());
+        assert!(from_url("ftp://example.com/images", None).await.is_err());
+        fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use metrics::increment_counter;
+use moka::future::Cache;
+
+use crate::record::ImageRecord;
+use crate::state::{AppState, ImageKey};
+
+pub mod shared;
+
+pub use shared::RedisCache;
+
+/// Lookups kept when the cache is enabled without a capacity
+pub const DEFAULT_IMAGE_CACHE_CAPACITY: u64 = 10_000;
+/// How long a lookup is served from the cache when enabled without a TTL
+pub const DEFAULT_IMAGE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Point in the invalidations of a key a lookup started at, see [`ImageCache::generation`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Generation {
+    local: u64,
+    /// Version of the key in the shared cache, `None` when it could not be read
+    shared: Option<u64>,
+}
+
+/// Recent single-image lookups by crypto or perceptual hash, including images that were not
+/// found.
+///
+/// Lookups are kept in process and, when Redis is configured, shared with other instances.
+/// Entries expire after a TTL, and are dropped as soon as any instance sharing the cache stores
+/// an image or changes its status. Nothing is cached by default.
+#[derive(Clone, Default)]
+pub struct ImageCache {
+    records: Option<Cache<ImageKey, Option<ImageRecord>>>,
+    shared: Option<RedisCache>,
+    /// Bumped on every invalidation, so a lookup that raced one is not cached
+    generation: Arc<AtomicU64>,
+}
+
+impl ImageCache {
+    /// Keep up to `capacity` lookups for `ttl` each, a capacity of 0 caches nothing in process
+    pub fn new(capacity: u64, ttl: Duration) -> Self {
+        let records = (capacity > 0).then(|| {
+            Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(ttl)
+                .build()
+        });
+        ImageCache {
+            records,
+            shared: None,
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Share lookups and invalidations with other instances through `shared`
+    pub fn with_shared(mut self, shared: RedisCache) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.records.is_some() || self.shared.is_some()
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared.is_some()
+    }
+
+    /// Mark the start of a lookup of `key`, to pass to [`ImageCache::insert`] with its result
+    pub async fn generation(&self, key: &ImageKey) -> Generation {
+        let local = self.local_generation();
+        let shared = match &self.shared {
+            Some(shared) => shared.version(key).await,
+            None => None,
+        };
+        Generation { local, shared }
+    }
+
+    fn local_generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The cached result of looking up `key`, `Some(None)` when the image was not found
+    pub async fn get(&self, key: &ImageKey) -> Option<Option<ImageRecord>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        if let Some(cached) = self.records.as_ref().and_then(|records| records.get(key)) {
+            increment_counter!("veracity_image_cache_hits_total", "layer" => "local");
+            return Some(cached);
+        }
+        if let Some(shared) = &self.shared {
+            let generation = self.local_generation();
+            if let Some(cached) = shared.get(key).await {
+                increment_counter!("veracity_image_cache_hits_total", "layer" => "shared");
+                self.insert_local(key, cached.clone(), generation).await;
+                return Some(cached);
+            }
+        }
+        increment_counter!("veracity_image_cache_misses_total");
+        None
+    }
+
+    /// Cache the result of a lookup that started at `generation`, unless an image was stored or
+    /// updated since. The shared cache checks its own version of the key, since the image may
+    /// have been stored or updated by another instance.
+    pub async fn insert(&self, key: ImageKey, record: Option<ImageRecord>, generation: Generation) {
+        if self.local_generation() != generation.local {
+            return;
+        }
+        if let (Some(shared), Some(version)) = (&self.shared, generation.shared) {
+            shared.insert(&key, &record, version).await;
+        }
+        self.insert_local(&key, record, generation.local).await;
+    }
+
+    async fn insert_local(&self, key: &ImageKey, record: Option<ImageRecord>, generation: u64) {
+        let Some(records) = &self.records else {
+            return;
+        };
+        if self.local_generation() != generation {
+            return;
+        }
+        records.insert(key.clone(), record).await;
+        // An invalidation may have landed between the check and the insert
+        if self.local_generation() != generation {
+            records.invalidate(key).await;
+        }
+    }
+
+    /// Forget lookups of the image with these hashes, including earlier misses, after it was
+    /// stored or its status changed. Instances sharing the cache forget them too.
+    pub async fn invalidate(&self, crypto_hash: &[u8], perceptual_hash: &[u8]) {
+        let Some(keys) = keys(crypto_hash, perceptual_hash) else {
+            return;
+        };
+        self.forget(&keys).await;
+        if let Some(shared) = &self.shared {
+            shared.invalidate(&keys).await;
+        }
+    }
+
+    /// Drop lookups of `keys` from this process only
+    async fn forget(&self, keys: &[ImageKey; 2]) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        if let Some(records) = &self.records {
+            for key in keys {
+                records.invalidate(key).await;
+            }
+        }
+    }
+}
+
+/// Both lookup keys of an image, `None` when either hash has the wrong length
+fn keys(crypto_hash: &[u8], perceptual_hash: &[u8]) -> Option<[ImageKey; 2]> {
+    Some([
+        ImageKey::CryptoHash(crypto_hash.try_into().ok()?),
+        ImageKey::PerceptualHash(perceptual_hash.try_into().ok()?),
+    ])
+}
+
+/// Apply invalidations published by other instances, when the cache is shared
+pub async fn run(state: AppState) {
+    if let Some(shared) = state.image_cache.shared.clone() {
+        shared.listen(&state.image_cache).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::cryptographic::CryptographicHash;
+    use crate::hash::perceptual::PerceptualHash;
+    use crate::hash::VeracityHash;
+    use crate::record::{IntegrationStatus, LeafDetails};
+
+    fn record(n: u8) -> ImageRecord {
+        ImageRecord {
+            hash: VeracityHash {
+                crypto_hash: CryptographicHash::try_from(vec![n; 32]).unwrap(),
+                perceptual_hash: PerceptualHash::try_from(vec![n; 32]).unwrap(),
+            },
+            status: IntegrationStatus::Pending,
+            leaf: LeafDetails::default(),
+            attested: false,
+            attestation: None,
+            withheld: None,
+            metadata: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn storing_an_image_forgets_earlier_misses() {
+        let cache = ImageCache::new(10, DEFAULT_IMAGE_CACHE_TTL);
+        let generation = cache.generation(&ImageKey::CryptoHash([1; 32])).await;
+        cache
+            .insert(ImageKey::CryptoHash([1; 32]), None, generation)
+            .await;
+        cache
+            .insert(ImageKey::PerceptualHash([1; 32]), None, generation)
+            .await;
+        assert!(matches!(
+            cache.get(&ImageKey::CryptoHash([1; 32])).await,
+            Some(None)
+        ));
+
+        cache.invalidate(&[1; 32], &[1; 32]).await;
+        assert!(cache.get(&ImageKey::CryptoHash([1; 32])).await.is_none());
+        assert!(cache
+            .get(&ImageKey::PerceptualHash([1; 32]))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn lookups_that_raced_an_invalidation_are_not_cached() {
+        let cache = ImageCache::new(10, DEFAULT_IMAGE_CACHE_TTL);
+        let generation = cache.generation(&ImageKey::CryptoHash([1; 32])).await;
+        cache.invalidate(&[1; 32], &[1; 32]).await;
+        cache
+            .insert(ImageKey::CryptoHash([1; 32]), None, generation)
+            .await;
+        assert!(cache.get(&ImageKey::CryptoHash([1; 32])).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn status_changes_forget_only_that_image() {
+        let cache = ImageCache::new(10, DEFAULT_IMAGE_CACHE_TTL);
+        let generation = cache.generation(&ImageKey::CryptoHash([1; 32])).await;
+        cache
+            .insert(ImageKey::CryptoHash([1; 32]), Some(record(1)), generation)
+            .await;
+        cache
+            .insert(
+                ImageKey::PerceptualHash([1; 32]),
+                Some(record(1)),
+                generation,
+            )
+            .await;
+        cache
+            .insert(ImageKey::CryptoHash([2; 32]), Some(record(2)), generation)
+            .await;
+
+        cache.invalidate(&[1; 32], &[1; 32]).await;
+        assert!(cache.get(&ImageKey::CryptoHash([1; 32])).await.is_none());
+        assert!(cache
+            .get(&ImageKey::PerceptualHash([1; 32]))
+            .await
+            .is_none());
+        assert!(cache.get(&ImageKey::CryptoHash([2; 32])).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn disabled_cache_keeps_nothing() {
+        let cache = ImageCache::default();
+        let key = ImageKey::CryptoHash([1; 32]);
+        let generation = cache.generation(&key).await;
+        cache.insert(key, None, generation).await;
+        assert!(!cache.is_enabled());
+        assert!(cache.get(&ImageKey::CryptoHash([1; 32])).await.is_none());
+    }
+}
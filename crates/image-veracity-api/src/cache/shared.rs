@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use eyre::Result;
+use futures::StreamExt;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Client, Script};
+use tracing::{debug, error, warn};
+
+use super::{keys, ImageCache};
+use crate::record::ImageRecord;
+use crate::state::ImageKey;
+
+/// Channel every instance publishes invalidations to, as `<crypto hash>:<perceptual hash>` hex
+const INVALIDATION_CHANNEL: &str = "veracity:image-invalidations";
+/// How long to wait before subscribing again after the subscription drops
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+/// How long the version of a lookup key is kept after its last invalidation, far longer than
+/// any lookup takes
+const VERSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Store a lookup (`ARGV[2]`, for `ARGV[3]` seconds) at `KEYS[1]` only if the version of its key
+/// at `KEYS[2]` is still the one read before the lookup started (`ARGV[1]`)
+const INSERT_SCRIPT: &str = r"
+if tonumber(redis.call('GET', KEYS[2]) or '0') == tonumber(ARGV[1]) then
+    redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+end
+return 0
+";
+
+/// Image lookups kept in Redis so instances share them, with invalidations broadcast over
+/// pub/sub. Redis failures are logged and treated as misses, so lookups fall back to the
+/// database.
+///
+/// Every lookup key has a version that invalidations bump, and a lookup is only stored if the
+/// version is unchanged since it started, so an instance that read the database before another
+/// stored or updated the image cannot put the old record back.
+#[derive(Clone)]
+pub struct RedisCache {
+    client: Client,
+    connection: ConnectionManager,
+    insert: Script,
+    ttl: Duration,
+}
+
+impl RedisCache {
+    /// Connect to the Redis server at `url`, keeping lookups there for `ttl`
+    pub async fn connect(url: &str, ttl: Duration) -> Result<Self> {
+        let client = Client::open(url)?;
+        let connection = ConnectionManager::new(client.clone()).await?;
+        debug!("Connected to Redis");
+        Ok(RedisCache {
+            client,
+            connection,
+            insert: Script::new(INSERT_SCRIPT),
+            ttl,
+        })
+    }
+
+    pub(super) async fn get(&self, key: &ImageKey) -> Option<Option<ImageRecord>> {
+        let value: Option<String> = match self.connection.clone().get(redis_key(key)).await {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Could not read cached image from Redis: {}", err);
+                return None;
+            }
+        };
+        match serde_json::from_str(&value?) {
+            Ok(record) => Some(record),
+            Err(err) => {
+                warn!("Could not decode cached image from Redis: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Current version of `key`, `None` if Redis could not be reached
+    pub(super) async fn version(&self, key: &ImageKey) -> Option<u64> {
+        let version: redis::RedisResult<Option<u64>> =
+            self.connection.clone().get(version_key(key)).await;
+        match version {
+            Ok(version) => Some(version.unwrap_or(0)),
+            Err(err) => {
+                warn!("Could not read cached image version from Redis: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Store the result of a lookup that started at `version` of `key`, unless the key was
+    /// invalidated since
+    pub(super) async fn insert(&self, key: &ImageKey, record: &Option<ImageRecord>, version: u64) {
+        let value = match serde_json::to_string(record) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Could not encode image for Redis: {}", err);
+                return;
+            }
+        };
+        let stored: redis::RedisResult<()> = self
+            .insert
+            .key(redis_key(key))
+            .key(version_key(key))
+            .arg(version)
+            .arg(value)
+            .arg(self.ttl.as_secs().max(1))
+            .invoke_async(&mut self.connection.clone())
+            .await;
+        if let Err(err) = stored {
+            warn!("Could not cache image in Redis: {}", err);
+        }
+    }
+
+    /// Delete the shared entries for `keys`, bump their versions so lookups still running are
+    /// not stored, and tell other instances to drop their own
+    pub(super) async fn invalidate(&self, keys: &[ImageKey; 2]) {
+        let mut connection = self.connection.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for key in keys {
+            pipe.del(redis_key(key))
+                .ignore()
+                .incr(version_key(key), 1)
+                .ignore()
+                .expire(version_key(key), VERSION_TTL.as_secs() as usize)
+                .ignore();
+        }
+        let deleted: redis::RedisResult<()> = pipe.query_async(&mut connection).await;
+        if let Err(err) = deleted {
+            warn!("Could not delete cached image from Redis: {}", err);
+        }
+        let published: redis::RedisResult<()> = connection
+            .publish(INVALIDATION_CHANNEL, invalidation_message(keys))
+            .await;
+        if let Err(err) = published {
+            warn!("Could not publish image invalidation to Redis: {}", err);
+        }
+    }
+
+    /// Drop lookups from `cache` as other instances invalidate them, resubscribing whenever the
+    /// subscription drops
+    pub(super) async fn listen(&self, cache: &ImageCache) {
+        loop {
+            if let Err(err) = self.subscribe(cache).await {
+                error!("Redis invalidation subscription failed: {}", err);
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn subscribe(&self, cache: &ImageCache) -> Result<()> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+        debug!("Subscribed to Redis image invalidations");
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            match parse_invalidation(&payload) {
+                Some(keys) => cache.forget(&keys).await,
+                None => warn!("Ignoring invalid image invalidation {:?}", payload),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn redis_key(key: &ImageKey) -> String {
+    match key {
+        ImageKey::CryptoHash(hash) => format!("veracity:image:c:{}", hex::encode(hash)),
+        ImageKey::PerceptualHash(hash) => format!("veracity:image:p:{}", hex::encode(hash)),
+    }
+}
+
+/// Key of the version of lookups of `key`
+fn version_key(key: &ImageKey) -> String {
+    format!("{}:version", redis_key(key))
+}
+
+fn invalidation_message([crypto_hash, perceptual_hash]: &[ImageKey; 2]) -> String {
+    format!(
+        "{}:{}",
+        hex::encode(key_hash(crypto_hash)),
+        hex::encode(key_hash(perceptual_hash))
+    )
+}
+
+fn key_hash(key: &ImageKey) -> &[u8; 32] {
+    match key {
+        ImageKey::CryptoHash(hash) | ImageKey::PerceptualHash(hash) => hash,
+    }
+}
+
+fn parse_invalidation(payload: &str) -> Option<[ImageKey; 2]> {
+    let (crypto_hash, perceptual_hash) = payload.split_once(':')?;
+    keys(
+        &hex::decode(crypto_hash).ok()?,
+        &hex::decode(perceptual_hash).ok()?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalidations_round_trip() {
+        let keys = keys(&[1; 32], &[2; 32]).unwrap();
+        let parsed = parse_invalidation(&invalidation_message(&keys)).unwrap();
+        assert_eq!(parsed, keys);
+    }
+
+    #[test]
+    fn versions_are_kept_per_lookup_key() {
+        let [crypto_hash, perceptual_hash] = keys(&[1; 32], &[1; 32]).unwrap();
+        assert_ne!(version_key(&crypto_hash), version_key(&perceptual_hash));
+        assert_ne!(version_key(&crypto_hash), redis_key(&crypto_hash));
+    }
+
+    #[test]
+    fn malformed_invalidations_are_ignored() {
+        assert!(parse_invalidation("").is_none());
+        assert!(parse_invalidation("zz:zz").is_none());
+        assert!(parse_invalidation(&format!("{}:{}", hex::encode([1; 32]), "01")).is_none());
+    }
+}
@@ -0,0 +1,311 @@
+//! Log checkpoints in the transparency-dev signed note format.
+//!
+//! A checkpoint names the log, its size, and its root hash, and is signed under this server's
+//! own Ed25519 key so standard witnesses and monitors can follow the log without speaking
+//! Trillian. Keys use the `golang.org/x/mod/sumdb/note` encoding, so the same tooling can
+//! generate them and verify checkpoints. Witnesses add their own signature lines, either plain
+//! Ed25519 note signatures or C2SP `cosignature/v1` timestamped cosignatures.
+
+use std::sync::Arc;
+
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use eyre::{bail, eyre, Result};
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+/// Algorithm byte the note format prefixes Ed25519 keys with
+const ALG_ED25519: u8 = 1;
+/// Algorithm byte of witness keys that sign timestamped `cosignature/v1` messages
+const ALG_COSIGNATURE_V1: u8 = 4;
+const PRIVATE_KEY_PREFIX: &str = "PRIVATE+KEY+";
+/// Start of every signature line in a note
+const SIGNATURE_PREFIX: &str = "\u{2014} ";
+
+/// Latest state of the log, as a witness sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Unique identity of the log, conventionally the name of its signing key
+    pub origin: String,
+    pub tree_size: i64,
+    pub root_hash: Vec<u8>,
+}
+
+impl Checkpoint {
+    /// Text of the note, the part covered by signatures
+    pub fn body(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n",
+            self.origin,
+            self.tree_size,
+            BASE64_STANDARD.encode(&self.root_hash)
+        )
+    }
+}
+
+/// Signs notes with an Ed25519 key
+#[derive(Clone)]
+pub struct NoteSigner {
+    name: String,
+    key_hash: [u8; 4],
+    key_pair: Arc<Ed25519KeyPair>,
+}
+
+impl NoteSigner {
+    /// Read a key written as `PRIVATE+KEY+<name>+<hash>+<base64 key>`, as generated by
+    /// `note.GenerateKey`
+    pub fn from_private_key(key: &str) -> Result<Self> {
+        let rest = key
+            .trim()
+            .strip_prefix(PRIVATE_KEY_PREFIX)
+            .ok_or_else(|| eyre!("signing key does not start with {PRIVATE_KEY_PREFIX}"))?;
+        let (name, hash, decoded) = split_key("signing", rest)?;
+        let seed = match decoded.split_first() {
+            Some((&ALG_ED25519, seed)) if seed.len() == 32 => seed,
+            _ => bail!("signing key is not an Ed25519 key"),
+        };
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|err| eyre!("invalid signing key: {err}"))?;
+
+        let key_hash = key_hash(name, ALG_ED25519, key_pair.public_key().as_ref());
+        if hash != hex::encode(key_hash) {
+            bail!("signing key hash {hash} does not match the key");
+        }
+        Ok(NoteSigner {
+            name: name.to_string(),
+            key_hash,
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Public key witnesses are configured with, `<name>+<hash>+<base64 key>`
+    pub fn verifier_key(&self) -> String {
+        let mut key = vec![ALG_ED25519];
+        key.extend_from_slice(self.key_pair.public_key().as_ref());
+        format!(
+            "{}+{}+{}",
+            self.name,
+            hex::encode(self.key_hash),
+            BASE64_STANDARD.encode(key)
+        )
+    }
+
+    /// `text` followed by a blank line and this key's signature line
+    pub fn sign(&self, text: &str) -> String {
+        let mut signature = self.key_hash.to_vec();
+        signature.extend_from_slice(self.key_pair.sign(text.as_bytes()).as_ref());
+        format!(
+            "{text}\n{SIGNATURE_PREFIX}{} {}\n",
+            self.name,
+            BASE64_STANDARD.encode(signature)
+        )
+    }
+
+    /// Signed checkpoint for a tree of `tree_size` leaves with `root_hash`, with this key's name
+    /// as the origin
+    pub fn checkpoint(&self, tree_size: i64, root_hash: &[u8]) -> String {
+        self.sign(
+            &Checkpoint {
+                origin: self.name.clone(),
+                tree_size,
+                root_hash: root_hash.to_vec(),
+            }
+            .body(),
+        )
+    }
+}
+
+/// Checks signature lines made by one key, such as a witness's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteVerifier {
+    name: String,
+    key_hash: [u8; 4],
+    algorithm: u8,
+    public_key: Vec<u8>,
+}
+
+impl NoteVerifier {
+    /// Read a verifier key written as `<name>+<hash>+<base64 key>`, either an Ed25519 note key
+    /// or a `cosignature/v1` witness key
+    pub fn from_verifier_key(key: &str) -> Result<Self> {
+        let (name, hash, decoded) = split_key("verifier", key.trim())?;
+        let (algorithm, public_key) = match decoded.split_first() {
+            Some((&algorithm, public_key))
+                if matches!(algorithm, ALG_ED25519 | ALG_COSIGNATURE_V1)
+                    && public_key.len() == 32 =>
+            {
+                (algorithm, public_key)
+            }
+            _ => bail!("verifier key is not an Ed25519 or cosignature/v1 key"),
+        };
+        let key_hash = key_hash(name, algorithm, public_key);
+        if hash != hex::encode(key_hash) {
+            bail!("verifier key hash {hash} does not match the key");
+        }
+        Ok(NoteVerifier {
+            name: name.to_string(),
+            key_hash,
+            algorithm,
+            public_key: public_key.to_vec(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether `line` is a signature line by this key over the note text `body`
+    pub fn verify(&self, body: &str, line: &str) -> bool {
+        let signature = line
+            .trim_end_matches('\n')
+            .strip_prefix(SIGNATURE_PREFIX)
+            .and_then(|line| line.split_once(' '))
+            .filter(|(name, _)| *name == self.name)
+            .and_then(|(_, encoded)| BASE64_STANDARD.decode(encoded).ok());
+        let signature = match signature {
+            Some(signature) => signature,
+            None => return false,
+        };
+        if signature.len() < 4 || signature[..4] != self.key_hash {
+            return false;
+        }
+        let key = UnparsedPublicKey::new(&ED25519, &self.public_key);
+        match (self.algorithm, &signature[4..]) {
+            (ALG_ED25519, signature) => key.verify(body.as_bytes(), signature).is_ok(),
+            (ALG_COSIGNATURE_V1, signature) if signature.len() == 72 => {
+                let (timestamp, signature) = signature.split_at(8);
+                let timestamp = u64::from_be_bytes(timestamp.try_into().expect("8 bytes"));
+                let message = format!("cosignature/v1\ntime {timestamp}\n{body}");
+                key.verify(message.as_bytes(), signature).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Name, hex key hash, and decoded key of a `<name>+<hash>+<base64 key>` key
+fn split_key<'a>(kind: &str, key: &'a str) -> Result<(&'a str, &'a str, Vec<u8>)> {
+    let (name, rest) = key
+        .split_once('+')
+        .ok_or_else(|| eyre!("{kind} key has no name"))?;
+    let (hash, encoded) = rest
+        .split_once('+')
+        .ok_or_else(|| eyre!("{kind} key has no key hash"))?;
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c == '+') {
+        bail!("{kind} key name {name:?} is not valid");
+    }
+    let decoded = BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|err| eyre!("{kind} key is not base64: {err}"))?;
+    Ok((name, hash, decoded))
+}
+
+/// First four bytes of SHA-256 over the key name, a newline, and the encoded public key
+fn key_hash(name: &str, algorithm: u8, public_key: &[u8]) -> [u8; 4] {
+    let mut input = format!("{name}\n").into_bytes();
+    input.push(algorithm);
+    input.extend_from_slice(public_key);
+    let hash = digest(&SHA256, &input);
+    hash.as_ref()[..4]
+        .try_into()
+        .expect("SHA-256 is longer than 4 bytes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example key and note from golang.org/x/mod/sumdb/note
+    const PRIVATE_KEY: &str =
+        "PRIVATE+KEY+PeterNeumann+c74f20a3+AYEKFALVFGyNhPJEMzD1QIDr+Y7hfZx09iUvxdXHKDFz";
+
+    #[test]
+    fn notes_match_the_reference_implementation() {
+        let signer = NoteSigner::from_private_key(PRIVATE_KEY).unwrap();
+        assert_eq!(
+            signer.verifier_key(),
+            "PeterNeumann+c74f20a3+ARpc2QcUPDhMQegwxbzhKqiBfsVkmqq/LDE4izWy10TW"
+        );
+        let text = "If you think cryptography is the answer to your problem,\n\
+            then you don't know what your problem is.\n";
+        assert_eq!(
+            signer.sign(text),
+            format!(
+                "{text}\n\u{2014} PeterNeumann \
+                x08go/ZJkuBS9UG/SffcvIAQxVBtiFupLLr8pAcElZInNIuGUgYN1FFYC2pZSNXgKvqfqdngotpRZb6KE6RyyBwJnAM=\n"
+            )
+        );
+    }
+
+    #[test]
+    fn checkpoints_carry_the_size_and_root() {
+        let signer = NoteSigner::from_private_key(PRIVATE_KEY).unwrap();
+        let note = signer.checkpoint(42, &[0xab; 32]);
+        let (body, signature) = note.split_once("\n\n").unwrap();
+        assert_eq!(
+            body,
+            format!("PeterNeumann\n42\n{}", BASE64_STANDARD.encode([0xab; 32]))
+        );
+        assert!(signature.starts_with("\u{2014} PeterNeumann "));
+    }
+
+    #[test]
+    fn verifiers_check_note_signatures() {
+        let verifier = NoteVerifier::from_verifier_key(
+            "PeterNeumann+c74f20a3+ARpc2QcUPDhMQegwxbzhKqiBfsVkmqq/LDE4izWy10TW",
+        )
+        .unwrap();
+        let note = NoteSigner::from_private_key(PRIVATE_KEY)
+            .unwrap()
+            .checkpoint(42, &[0xab; 32]);
+        let (body, line) = note.split_once("\n\n").unwrap();
+        let body = format!("{body}\n");
+        assert!(verifier.verify(&body, line));
+        assert!(!verifier.verify(&body.replace("42", "43"), line));
+    }
+
+    #[test]
+    fn verifiers_check_cosignatures() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let public_key = key_pair.public_key().as_ref();
+        let key_hash = key_hash("witness.example", ALG_COSIGNATURE_V1, public_key);
+        let mut encoded = vec![ALG_COSIGNATURE_V1];
+        encoded.extend_from_slice(public_key);
+        let verifier = NoteVerifier::from_verifier_key(&format!(
+            "witness.example+{}+{}",
+            hex::encode(key_hash),
+            BASE64_STANDARD.encode(encoded)
+        ))
+        .unwrap();
+
+        let body = "example.com/log\n42\nq6urq6urq6urq6urq6urq6urq6urq6urq6urq6urq6s=\n";
+        let timestamp = 1_700_000_000u64;
+        let mut signature = key_hash.to_vec();
+        signature.extend_from_slice(&timestamp.to_be_bytes());
+        signature.extend_from_slice(
+            key_pair
+                .sign(format!("cosignature/v1\ntime {timestamp}\n{body}").as_bytes())
+                .as_ref(),
+        );
+        let line = format!(
+            "\u{2014} witness.example {}\n",
+            BASE64_STANDARD.encode(signature)
+        );
+        assert!(verifier.verify(body, &line));
+        assert!(!verifier.verify(&body.replace("42", "43"), &line));
+        assert!(!verifier.verify(body, &line.replace("witness.example", "other")));
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert!(NoteSigner::from_private_key("PeterNeumann+c74f20a3+AYEK").is_err());
+        // Wrong key hash
+        assert!(NoteSigner::from_private_key(
+            "PRIVATE+KEY+PeterNeumann+00000000+AYEKFALVFGyNhPJEMzD1QIDr+Y7hfZx09iUvxdXHKDFz"
+        )
+        .is_err());
+    }
+}
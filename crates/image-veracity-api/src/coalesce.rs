@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use metrics::increment_counter;
+use tracing::trace;
+
+/// Coalesces concurrent identical requests ("singleflight").
+///
+/// While a call for a key is in flight, further calls for the same key wait on its result instead
+/// of issuing their own DB or Trillian request. The flight is forgotten as soon as it completes, so
+/// nothing is cached beyond the lifetime of the original call.
+pub struct Coalescer<K, V: Clone> {
+    name: &'static str,
+    in_flight: Arc<Mutex<HashMap<K, Flight<V>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+struct Flight<V: Clone> {
+    id: u64,
+    result: Shared<BoxFuture<'static, V>>,
+}
+
+impl<K, V: Clone> Clone for Coalescer<K, V> {
+    fn clone(&self) -> Self {
+        Coalescer {
+            name: self.name,
+            in_flight: self.in_flight.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<K, V> Coalescer<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Create a coalescer, `name` labels its metrics
+    pub fn new(name: &'static str) -> Self {
+        Coalescer {
+            name,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Run `call` for `key`, or join an identical call that is already in flight
+    pub async fn run<F, Fut>(&self, key: K, call: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V> + Send + 'static,
+    {
+        let (id, result) = {
+            let mut in_flight = self.in_flight.lock().expect("coalescer lock poisoned");
+            match in_flight.get(&key) {
+                Some(flight) => {
+                    trace!("coalescing {} lookup", self.name);
+                    increment_counter!("veracity_coalesced_requests_total", "lookup" => self.name);
+                    (flight.id, flight.result.clone())
+                }
+                None => {
+                    increment_counter!("veracity_leader_requests_total", "lookup" => self.name);
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let result = call().boxed().shared();
+                    in_flight.insert(
+                        key.clone(),
+                        Flight {
+                            id,
+                            result: result.clone(),
+                        },
+                    );
+                    (id, result)
+                }
+            }
+        };
+
+        let value = result.await;
+
+        // Only forget our own flight; a newer one may have started for the same key
+        let mut in_flight = self.in_flight.lock().expect("coalescer lock poisoned");
+        if in_flight.get(&key).is_some_and(|flight| flight.id == id) {
+            in_flight.remove(&key);
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn identical_calls_run_once() {
+        let coalescer: Coalescer<u8, usize> = Coalescer::new("test");
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let lookups = (0..16).map(|_| {
+            let coalescer = coalescer.clone();
+            let calls = calls.clone();
+            tokio::spawn(async move {
+                coalescer
+                    .run(1, || async move {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        calls.fetch_add(1, Ordering::SeqCst) + 1
+                    })
+                    .await
+            })
+        });
+        for lookup in futures::future::join_all(lookups).await {
+            assert_eq!(lookup.unwrap(), 1);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The finished flight is forgotten, so a later call runs again
+        let calls_after = calls.clone();
+        let value = coalescer
+            .run(1, || async move {
+                calls_after.fetch_add(1, Ordering::SeqCst) + 1
+            })
+            .await;
+        assert_eq!(value, 2);
+    }
+
+    #[tokio::test]
+    async fn different_keys_do_not_coalesce() {
+        let coalescer: Coalescer<u8, u8> = Coalescer::new("test");
+        let (a, b) = tokio::join!(
+            coalescer.run(1, || async { 1 }),
+            coalescer.run(2, || async { 2 })
+        );
+        assert_eq!((a, b), (1, 2));
+    }
+}
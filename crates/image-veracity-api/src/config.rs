@@ -0,0 +1,85 @@
+use std::env;
+use std::path::Path;
+
+use eyre::{eyre, Result, WrapErr};
+
+/// Set environment variables from a file of `KEY=VALUE` lines, skipping those already set so
+/// the real environment always wins. Blank lines and lines starting with `#` are ignored and
+/// values may be wrapped in matching quotes.
+///
+/// Call before any threads are started.
+pub fn load_env_file(path: &Path) -> Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("could not read config file {}", path.display()))?;
+    for (key, value) in parse_env_file(&contents)
+        .wrap_err_with(|| format!("invalid config file {}", path.display()))?
+    {
+        if env::var_os(key).is_none() {
+            env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+fn parse_env_file(contents: &str) -> Result<Vec<(&str, &str)>> {
+    let mut settings = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| eyre!("line {} is not KEY=VALUE", number + 1))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(eyre!("line {} has no key", number + 1));
+        }
+        settings.push((key, unquote(value.trim())));
+    }
+    Ok(settings)
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_env_files() {
+        let settings = parse_env_file(
+            "# Trillian\nTRILLIAN_ADDRESS = http://localhost:8090\n\n\
+            DATABASE_URL=\"postgresql://root@localhost:26257/veracity?sslmode=disable\"\n\
+            PUBLIC_ID_SECRET='a=b'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                ("TRILLIAN_ADDRESS", "http://localhost:8090"),
+                (
+                    "DATABASE_URL",
+                    "postgresql://root@localhost:26257/veracity?sslmode=disable"
+                ),
+                ("PUBLIC_ID_SECRET", "a=b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_lines_without_a_key() {
+        assert!(parse_env_file("TRILLIAN_ADDRESS").is_err());
+        assert!(parse_env_file("=value").is_err());
+    }
+}
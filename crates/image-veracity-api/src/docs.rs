@@ -5,12 +5,40 @@ use aide::{
         routing::{get, get_with},
         ApiRouter, IntoApiResponse,
     },
-    openapi::OpenApi,
+    openapi::{Info, OpenApi},
     redoc::Redoc,
 };
-use axum::{response::IntoResponse, Extension};
+use axum::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use serde_json::json;
+use tracing::error;
+
+use crate::{errors::AppError, extractors::Json, server::routes::ApiVersion, state::AppState};
+
+/// Path the OpenAPI document is served at as JSON, under `/docs`
+const SPEC_JSON_PATH: &str = "/openapi.json";
+/// Path the OpenAPI document is served at as YAML, under `/docs`
+const SPEC_YAML_PATH: &str = "/openapi.yaml";
 
-use crate::{extractors::Json, state::AppState};
+/// An empty OpenAPI document versioned like the crate, for the routes to be documented into. The
+/// API versions served are listed under `x-api-versions`.
+pub fn openapi() -> OpenApi {
+    let mut info = Info {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Info::default()
+    };
+    info.extensions.insert(
+        "x-api-versions".to_string(),
+        json!(ApiVersion::ALL.map(ApiVersion::as_str)),
+    );
+    OpenApi {
+        info,
+        ..OpenApi::default()
+    }
+}
 
 pub fn docs_routes(state: AppState) -> ApiRouter {
     // We infer the return types for these routes
@@ -21,17 +49,27 @@ pub fn docs_routes(state: AppState) -> ApiRouter {
     // with a 200 status.
     aide::gen::infer_responses(true);
 
+    let redoc = || {
+        Redoc::new(format!("/docs{SPEC_JSON_PATH}"))
+            .with_title("Image Veracity")
+            .axum_handler()
+    };
     let router = ApiRouter::new()
         .api_route_with(
             "/",
-            get_with(
-                Redoc::new("/docs/private/api.json")
-                    .with_title("Image Veracity")
-                    .axum_handler(),
-                |op| op.description("This documentation page."),
-            ),
+            get_with(redoc(), |op| op.description("This documentation page.")),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/redoc",
+            get_with(redoc(), |op| {
+                op.description("This documentation page, at a path that stays put.")
+            }),
             |p| p.security_requirement("ApiKey"),
         )
+        .route(SPEC_JSON_PATH, get(serve_docs))
+        .route(SPEC_YAML_PATH, get(serve_docs_yaml))
+        // Kept for clients that fetched the document before it had stable paths
         .route("/private/api.json", get(serve_docs))
         .with_state(state);
 
@@ -45,3 +83,29 @@ pub fn docs_routes(state: AppState) -> ApiRouter {
 async fn serve_docs(Extension(api): Extension<Arc<OpenApi>>) -> impl IntoApiResponse {
     Json(api).into_response()
 }
+
+async fn serve_docs_yaml(Extension(api): Extension<Arc<OpenApi>>) -> impl IntoApiResponse {
+    match serde_yaml::to_string(api.as_ref()) {
+        Ok(yaml) => ([(CONTENT_TYPE, "application/yaml")], yaml).into_response(),
+        Err(err) => {
+            error!("Could not render the OpenAPI document as YAML: {}", err);
+            AppError::new("Could not render the API documentation")
+                .with_status(StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_is_versioned_like_the_crate() {
+        let api = openapi();
+        assert_eq!(api.info.version, env!("CARGO_PKG_VERSION"));
+        let yaml = serde_yaml::to_string(&api).unwrap();
+        assert!(yaml.contains(&format!("version: {}", env!("CARGO_PKG_VERSION"))));
+        assert_eq!(api.info.extensions["x-api-versions"], json!(["v1"]));
+    }
+}
@@ -8,15 +8,23 @@ use thiserror::Error;
 use tracing::{error, instrument};
 use uuid::Uuid;
 
+use crate::extractors::Json;
+use crate::hash_pool::Overloaded;
+use crate::server::request_id;
+
 /// A default error response for most API errors.
 #[derive(Debug, Error, Serialize, JsonSchema)]
 pub struct AppError {
     /// An error message.
     pub error: String,
-    /// A unique error ID.
+    /// What went wrong, for clients to branch on. The message may change, the code does not.
+    pub code: ErrorCode,
+    /// The ID of the request that failed, also sent in the `X-Request-Id` response header.
     pub error_id: Uuid,
     #[serde(skip)]
     pub status: StatusCode,
+    /// Whether the same request may succeed if retried later, after the `Retry-After` delay.
+    pub retryable: bool,
     /// Optional Additional error details.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error_details: Option<Value>,
@@ -35,14 +43,27 @@ impl AppError {
     pub fn new(error: &str) -> Self {
         Self {
             error: error.to_string(),
-            error_id: Uuid::new_v4(),
+            code: ErrorCode::InvalidRequest,
+            error_id: request_id::current().unwrap_or_else(Uuid::new_v4),
             status: StatusCode::BAD_REQUEST,
+            retryable: false,
             error_details: None,
         }
     }
 
+    /// Set the response status, and the code that goes with it unless [`AppError::with_code`]
+    /// gives a more specific one. Statuses reporting a transient failure mark the error
+    /// retryable.
     pub fn with_status(mut self, status: StatusCode) -> Self {
         self.status = status;
+        self.code = ErrorCode::for_status(status);
+        self.retryable = is_retryable(status);
+        self
+    }
+
+    /// Set the error code, after the status
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
         self
     }
 
@@ -52,19 +73,161 @@ impl AppError {
     }
 }
 
+/// Machine-readable reason an API request failed, serialized in SCREAMING_SNAKE_CASE
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The request was malformed or failed validation.
+    InvalidRequest,
+    /// No valid API key was sent.
+    Unauthenticated,
+    /// The API key lacks the scope or tenant the request needs.
+    Forbidden,
+    /// The image or resource does not exist.
+    NotFound,
+    /// The feature is not enabled on this server.
+    NotEnabled,
+    /// The request conflicts with the current state of a resource.
+    Conflict,
+    /// The image is already stored.
+    Duplicate,
+    /// The upload is larger than allowed.
+    TooLarge,
+    /// The upload is not an allowed image type.
+    ImageUnsupported,
+    /// The image could not be decoded or hashed.
+    HashFailed,
+    /// The device attestation did not verify.
+    AttestationInvalid,
+    /// The upload token is invalid, expired or already spent.
+    UploadTokenInvalid,
+    /// Too many requests were made with this key or from this address.
+    RateLimited,
+    /// A server this one depends on failed to answer.
+    UpstreamFailed,
+    /// Trillian could not be reached or refused the request.
+    LogUnavailable,
+    /// The database could not be reached.
+    DatabaseUnavailable,
+    /// The blob store for original images could not be reached.
+    StorageUnavailable,
+    /// Too many uploads are being handled, try again shortly.
+    Overloaded,
+    /// The server cannot take the request right now.
+    Unavailable,
+    /// The server failed unexpectedly.
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code of an error that only has a status
+    pub fn for_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthenticated,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::TooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::ImageUnsupported,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::AttestationInvalid,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            StatusCode::NOT_IMPLEMENTED => ErrorCode::NotEnabled,
+            StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => ErrorCode::UpstreamFailed,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            status if status.is_server_error() => ErrorCode::Internal,
+            _ => ErrorCode::InvalidRequest,
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     #[instrument]
     fn into_response(self) -> axum::response::Response {
         error!("");
         let status = self.status;
-        let mut res = axum::Json(self).into_response();
+        let mut res = Json(self).into_response();
         *res.status_mut() = status;
         res
     }
 }
 
+/// Whether a response with this status reports a transient failure worth retrying
+pub fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
 impl From<Report> for AppError {
     fn from(value: Report) -> Self {
         AppError::new(&value.to_string())
     }
 }
+
+impl From<Overloaded> for AppError {
+    fn from(value: Overloaded) -> Self {
+        AppError::new(&value.to_string())
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)
+            .with_code(ErrorCode::Overloaded)
+    }
+}
+
+/// Failure while looking up stored image records.
+/// Cloneable so a single result can be shared between coalesced lookups.
+#[derive(Debug, Clone, Error)]
+pub enum LookupError {
+    #[error("could not get a database connection")]
+    Connection,
+    #[error("database query failed")]
+    Query,
+    #[error("stored record was not valid")]
+    InvalidRecord,
+}
+
+impl From<sqlx::Error> for LookupError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_) => {
+                error!("{}", err);
+                LookupError::Connection
+            }
+            err => {
+                error!("Error getting from database: {}", err);
+                LookupError::Query
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_follow_the_status_unless_given() {
+        let err = AppError::new("no such image").with_status(StatusCode::NOT_FOUND);
+        assert_eq!(err.code, ErrorCode::NotFound);
+        let err = AppError::new("Could not hash image")
+            .with_status(StatusCode::INTERNAL_SERVER_ERROR)
+            .with_code(ErrorCode::HashFailed);
+        assert_eq!(err.code, ErrorCode::HashFailed);
+        assert_eq!(AppError::new("bad").code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn codes_are_serialized_in_screaming_snake_case() {
+        let err = AppError::new("log is down")
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)
+            .with_code(ErrorCode::LogUnavailable);
+        let body = serde_json::to_value(&err).unwrap();
+        assert_eq!(body["code"], "LOG_UNAVAILABLE");
+        assert_eq!(body["retryable"], true);
+    }
+}
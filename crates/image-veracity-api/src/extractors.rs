@@ -1,14 +1,37 @@
-use aide::operation::OperationIo;
-use axum::response::IntoResponse;
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+
+use aide::operation::{OperationInput, OperationIo};
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::rejection::BytesRejection;
+use axum::extract::{ConnectInfo, FromRequest, FromRequestParts};
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum_jsonschema::JsonSchemaRejection;
-use axum_macros::FromRequest;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json::json;
+use tracing::error;
 
-use crate::errors::AppError;
+use crate::api_key::{ApiKeyIdentity, Scope};
+use crate::errors::{AppError, ErrorCode};
+use crate::server::auth::unauthorized;
+use crate::server::negotiate;
+use crate::state::AppState;
 
-#[derive(FromRequest, OperationIo)]
-#[from_request(via(axum_jsonschema::Json), rejection(AppError))]
+/// A JSON request or response body, or CBOR or MessagePack when the client asks for it.
+///
+/// Request bodies are decoded by their `Content-Type`; JSON bodies are also checked against the
+/// schema of `T`. Responses are encoded in the format picked by [`negotiate_format`], where
+/// hashes are sent as raw bytes rather than hex.
+///
+/// [`negotiate_format`]: crate::server::negotiate::negotiate_format
+#[derive(OperationIo)]
 #[aide(
     input_with = "axum_jsonschema::Json<T>",
     output_with = "axum_jsonschema::Json<T>",
@@ -16,12 +39,149 @@ use crate::errors::AppError;
 )]
 pub struct Json<T>(pub T);
 
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Json<T>
+where
+    T: DeserializeOwned + JsonSchema + Send + 'static,
+    axum_jsonschema::Json<T>: FromRequest<S, B, Rejection = JsonSchemaRejection>,
+    Bytes: FromRequest<S, B, Rejection = BytesRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let format = Format::of_request(req.headers());
+        if format == Format::Json {
+            let axum_jsonschema::Json(value) =
+                axum_jsonschema::Json::from_request(req, state).await?;
+            return Ok(Json(value));
+        }
+        let body = Bytes::from_request(req, state).await.map_err(|rejection| {
+            AppError::new(&rejection.body_text()).with_status(rejection.status())
+        })?;
+        format
+            .deserialize(&body)
+            .map(Json)
+            .map_err(|err| AppError::new("invalid request").with_details(json!(err.to_string())))
+    }
+}
+
 impl<T> IntoResponse for Json<T>
 where
     T: Serialize,
 {
     fn into_response(self) -> axum::response::Response {
-        axum::Json(self.0).into_response()
+        let format = negotiate::response_format();
+        if format == Format::Json {
+            return axum::Json(self.0).into_response();
+        }
+        match format.serialize(&self.0) {
+            Ok(body) => (
+                [(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(format.content_type()),
+                )],
+                body,
+            )
+                .into_response(),
+            Err(err) => {
+                error!(
+                    "Could not encode response as {}: {}",
+                    format.content_type(),
+                    err
+                );
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// Encodings request and response bodies can use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// Format named by a media type, ignoring parameters. Wildcards mean JSON.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "application/cbor" => Some(Format::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            _ => None,
+        }
+    }
+
+    /// Format of a request body by its `Content-Type`, JSON unless it names another format
+    pub fn of_request(headers: &HeaderMap) -> Self {
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Format::from_media_type)
+            .unwrap_or_default()
+    }
+
+    /// Format an `Accept` header prefers, by quality and then by order. JSON when the header is
+    /// missing or names no known format.
+    pub fn from_accept(headers: &HeaderMap) -> Self {
+        let mut preferred = (Format::Json, 0.0);
+        for media_range in headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let format = match Format::from_media_type(media_range) {
+                Some(format) => format,
+                None => continue,
+            };
+            let quality = media_range
+                .split(';')
+                .skip(1)
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > preferred.1 {
+                preferred = (format, quality);
+            }
+        }
+        preferred.0
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> eyre::Result<Vec<u8>> {
+        Ok(match self {
+            Format::Json => serde_json::to_vec(value)?,
+            Format::Cbor => {
+                let mut body = vec![];
+                ciborium::ser::into_writer(value, &mut body)?;
+                body
+            }
+            Format::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, body: &[u8]) -> eyre::Result<T> {
+        Ok(match self {
+            Format::Json => serde_json::from_slice(body)?,
+            Format::Cbor => ciborium::de::from_reader(body)?,
+            Format::MessagePack => rmp_serde::from_slice(body)?,
+        })
     }
 }
 
@@ -36,3 +196,170 @@ impl From<JsonSchemaRejection> for AppError {
         }
     }
 }
+
+/// Header carrying a client's API key
+pub const AUTH_KEY_HEADER: &str = "X-Auth-Key";
+
+/// Scope a handler requires, see [`Authorized`]
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+/// Marker types naming the scope an [`Authorized`] extractor requires
+pub mod scope {
+    use super::RequiredScope;
+    use crate::api_key::Scope;
+
+    pub struct Upload;
+    pub struct Read;
+    pub struct Audit;
+    pub struct Admin;
+
+    impl RequiredScope for Upload {
+        const SCOPE: Scope = Scope::Upload;
+    }
+
+    impl RequiredScope for Read {
+        const SCOPE: Scope = Scope::Read;
+    }
+
+    impl RequiredScope for Audit {
+        const SCOPE: Scope = Scope::Audit;
+    }
+
+    impl RequiredScope for Admin {
+        const SCOPE: Scope = Scope::Admin;
+    }
+}
+
+/// Rejects requests whose API key lacks the scope `S`.
+/// Holds the key identified by [`authenticate`], or `None` when API keys are not enabled. Uploads
+/// and lookups are then open to every request, while audit and admin routes are refused.
+///
+/// [`authenticate`]: crate::server::auth::authenticate
+pub struct Authorized<S>(pub Option<ApiKeyIdentity>, PhantomData<S>);
+
+#[async_trait]
+impl<S: RequiredScope + Send> FromRequestParts<AppState> for Authorized<S> {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if !state.api_keys.is_enabled() {
+            return match S::SCOPE {
+                Scope::Upload | Scope::Read => Ok(Authorized(None, PhantomData)),
+                // Nobody could be told apart from an admin
+                Scope::Audit | Scope::Admin => Err(AppError::new(&format!(
+                    "the {} scope needs API keys, set ADMIN_API_KEY",
+                    S::SCOPE
+                ))
+                .with_status(StatusCode::FORBIDDEN)
+                .with_code(ErrorCode::NotEnabled)
+                .into_response()),
+            };
+        }
+        match parts.extensions.get::<ApiKeyIdentity>() {
+            Some(identity) if identity.has_scope(S::SCOPE) => {
+                Ok(Authorized(Some(identity.clone()), PhantomData))
+            }
+            Some(_) => Err(
+                AppError::new(&format!("API key lacks the {} scope", S::SCOPE))
+                    .with_status(StatusCode::FORBIDDEN)
+                    .into_response(),
+            ),
+            None => Err(unauthorized().into_response()),
+        }
+    }
+}
+
+impl<S> OperationInput for Authorized<S> {}
+
+/// State for the tenant of the request's API key, see [`AppState::for_tenant`], or the default
+/// state for keys without a tenant. Rejects keys whose tenant is no longer configured.
+pub struct TenantState(pub AppState);
+
+#[async_trait]
+impl FromRequestParts<AppState> for TenantState {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let tenant = parts
+            .extensions
+            .get::<ApiKeyIdentity>()
+            .and_then(|identity| identity.tenant.as_deref());
+        match tenant {
+            None => Ok(TenantState(state.clone())),
+            Some(tenant) => state.for_tenant(tenant).map(TenantState).ok_or_else(|| {
+                AppError::new(&format!("API key belongs to unknown tenant {}", tenant))
+                    .with_status(StatusCode::FORBIDDEN)
+            }),
+        }
+    }
+}
+
+impl OperationInput for TenantState {}
+
+/// Who a request is accounted to: its API key, or the client address for requests without one
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Submitter {
+    ApiKey(String),
+    Ip(IpAddr),
+}
+
+impl Submitter {
+    /// Identify the submitter from the key attached by [`authenticate`] or the connection info,
+    /// `None` if neither is known
+    ///
+    /// [`authenticate`]: crate::server::auth::authenticate
+    pub fn from_extensions(extensions: &Extensions) -> Option<Self> {
+        if let Some(identity) = extensions.get::<ApiKeyIdentity>() {
+            return Some(Submitter::from_identity(identity));
+        }
+        extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| Submitter::Ip(addr.ip()))
+    }
+
+    /// Submitter for requests made with the key `identity`
+    pub fn from_identity(identity: &ApiKeyIdentity) -> Self {
+        Submitter::ApiKey(match identity.id {
+            Some(id) => id.to_string(),
+            None => identity.name.clone(),
+        })
+    }
+
+    /// The API key requests were made with, `None` for clients identified by address
+    pub fn api_key(&self) -> Option<&str> {
+        match self {
+            Submitter::ApiKey(key) => Some(key),
+            Submitter::Ip(_) => None,
+        }
+    }
+
+    /// User string Trillian charges quota to, in the style of CT personalities
+    pub fn quota_user(&self) -> String {
+        match self {
+            Submitter::ApiKey(key) => format!("@api_key/{key}"),
+            Submitter::Ip(ip) => format!("@ip/{ip}"),
+        }
+    }
+}
+
+/// The [`Submitter`] of a request, if known
+pub struct SubmittedBy(pub Option<Submitter>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SubmittedBy {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(SubmittedBy(Submitter::from_extensions(&parts.extensions)))
+    }
+}
+
+impl OperationInput for SubmittedBy {}
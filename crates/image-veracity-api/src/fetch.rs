@@ -0,0 +1,265 @@
+//! Server-side downloads of images by URL.
+//!
+//! Only hosts on an allowlist are fetched, and only once every address they resolve to is
+//! public, so the endpoint cannot be pointed at the server's own network. Connections are pinned
+//! to the checked address and redirects are followed by hand, re-checking every hop.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use thiserror::Error;
+use tracing::debug;
+
+/// Longest a fetch may take, redirects included
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_REDIRECTS: usize = 3;
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("{0} is not on the fetch allowlist")]
+    HostNotAllowed(String),
+    #[error("{0} does not resolve to a public address")]
+    NonPublicAddress(String),
+    #[error("too many redirects")]
+    TooManyRedirects,
+    #[error("image is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("remote server responded {0}")]
+    Status(u16),
+    #[error("could not fetch image: {0}")]
+    Request(String),
+    #[error("timed out fetching image")]
+    Timeout,
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(err: reqwest::Error) -> Self {
+        FetchError::Request(err.to_string())
+    }
+}
+
+/// Downloads images from allowlisted hosts. Entries are host names, which also match their
+/// subdomains when written as `*.example.com`.
+#[derive(Clone, Debug)]
+pub struct UrlFetcher {
+    allowed_hosts: Arc<[String]>,
+    max_bytes: usize,
+}
+
+impl UrlFetcher {
+    pub fn new<I, S>(allowed_hosts: I, max_bytes: usize) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        UrlFetcher {
+            allowed_hosts: allowed_hosts
+                .into_iter()
+                .map(|host| host.as_ref().trim().to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            max_bytes,
+        }
+    }
+
+    pub fn allows_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => host == *allowed,
+            })
+    }
+
+    /// Download the body at `url`, up to the size limit
+    pub async fn fetch(&self, url: &str) -> Result<Vec<u8>, FetchError> {
+        let url = Url::parse(url).map_err(|err| FetchError::InvalidUrl(err.to_string()))?;
+        tokio::time::timeout(FETCH_TIMEOUT, self.follow(url))
+            .await
+            .map_err(|_| FetchError::Timeout)?
+    }
+
+    async fn follow(&self, mut url: Url) -> Result<Vec<u8>, FetchError> {
+        for _ in 0..=MAX_REDIRECTS {
+            let addr = self.check(&url).await?;
+            let mut res = pinned_client(&url, addr)?.get(url.clone()).send().await?;
+
+            if res.status().is_redirection() {
+                let location = res
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(FetchError::Status(res.status().as_u16()))?;
+                url = url
+                    .join(location)
+                    .map_err(|err| FetchError::InvalidUrl(err.to_string()))?;
+                debug!("following redirect to {}", url);
+                continue;
+            }
+            if !res.status().is_success() {
+                return Err(FetchError::Status(res.status().as_u16()));
+            }
+            if res
+                .content_length()
+                .is_some_and(|length| length > self.max_bytes as u64)
+            {
+                return Err(FetchError::TooLarge(self.max_bytes));
+            }
+
+            let mut body = vec![];
+            while let Some(chunk) = res.chunk().await? {
+                if body.len() + chunk.len() > self.max_bytes {
+                    return Err(FetchError::TooLarge(self.max_bytes));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            return Ok(body);
+        }
+        Err(FetchError::TooManyRedirects)
+    }
+
+    /// Check `url` may be fetched and return the address to connect to
+    async fn check(&self, url: &Url) -> Result<SocketAddr, FetchError> {
+        let host = http_host(url)?;
+        if !self.allows_host(host) {
+            return Err(FetchError::HostNotAllowed(host.to_owned()));
+        }
+        resolve_public(url).await
+    }
+}
+
+/// Resolve the host of an `http` or `https` URL, failing unless every address it resolves to
+/// is public. Connect to the returned address with [`pinned_client`] so a second lookup cannot
+/// swap in another one.
+pub(crate) async fn resolve_public(url: &Url) -> Result<SocketAddr, FetchError> {
+    let host = http_host(url)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    // IPv6 literals keep their brackets in the URL but not in lookups
+    let lookup_host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((lookup_host, port))
+        .await
+        .map_err(|err| FetchError::Request(err.to_string()))?
+        .collect();
+    match addrs.first() {
+        Some(addr) if addrs.iter().all(|addr| is_public(addr.ip())) => Ok(*addr),
+        _ => Err(FetchError::NonPublicAddress(host.to_owned())),
+    }
+}
+
+/// Client that connects to `addr` for the host of `url` and does not follow redirects
+pub(crate) fn pinned_client(url: &Url, addr: SocketAddr) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .redirect(Policy::none())
+        .resolve(url.host_str().unwrap_or_default(), addr)
+        .build()
+}
+
+fn http_host(url: &Url) -> Result<&str, FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::InvalidUrl(format!(
+            "unsupported scheme {}",
+            url.scheme()
+        )));
+    }
+    url.host_str()
+        .ok_or_else(|| FetchError::InvalidUrl("URL has no host".to_string()))
+}
+
+/// Whether `ip` is routable on the public internet, ruling out loopback, private, link-local
+/// (including cloud metadata endpoints), shared, and reserved ranges
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space for carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                // Reserved for future use
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local
+                || (first & 0xfe00) == 0xfc00
+                // Link-local
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist_matches_hosts_and_subdomains() {
+        let fetcher = UrlFetcher::new(["images.example.org", "*.cdn.example.com"], 1024);
+        assert!(fetcher.allows_host("images.example.org"));
+        assert!(fetcher.allows_host("Images.Example.org"));
+        assert!(fetcher.allows_host("eu.cdn.example.com"));
+        assert!(!fetcher.allows_host("cdn.example.com"));
+        assert!(!fetcher.allows_host("evilcdn.example.com"));
+        assert!(!fetcher.allows_host("example.org"));
+    }
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} should not be public");
+        }
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:2800:220:1::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn rejects_urls_before_connecting() {
+        let fetcher = UrlFetcher::new(["127.0.0.1", "example.com"], 1024);
+        assert!(matches!(
+            fetcher.fetch("http://127.0.0.1/image.png").await,
+            Err(FetchError::NonPublicAddress(_))
+        ));
+        assert!(matches!(
+            fetcher.fetch("http://internal.example/image.png").await,
+            Err(FetchError::HostNotAllowed(_))
+        ));
+        assert!(matches!(
+            fetcher.fetch("file:///etc/passwd").await,
+            Err(FetchError::InvalidUrl(_))
+        ));
+    }
+}
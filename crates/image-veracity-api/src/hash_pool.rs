@@ -0,0 +1,121 @@
+//! Thread pool images are decoded and hashed on.
+//!
+//! Hashing is CPU bound, so it runs on a rayon pool of its own rather than the global one. A
+//! burst of uploads then cannot starve other rayon users, and the pool can be sized to the CPU
+//! quota the server is given instead of the CPUs the host has. Parallel JPEG decoding started
+//! from the pool stays on it.
+//!
+//! Every job holds an image in memory until it is done, so only so many may be queued or running
+//! at once. Work beyond that is refused with [`Overloaded`] instead of piling up buffers.
+
+use std::sync::{Arc, OnceLock};
+
+use eyre::{ensure, Result};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+/// Jobs queued or running on the pool at once unless configured otherwise
+pub const DEFAULT_QUEUE_DEPTH: usize = 64;
+
+/// The pool's queue was full, so the work was refused rather than buffered
+#[derive(Debug, Error)]
+#[error("too many images are waiting to be hashed, try again shortly")]
+pub struct Overloaded;
+
+/// A rayon pool for hashing and other image decoding, with a bounded queue
+#[derive(Clone, Debug)]
+pub struct HashPool {
+    pool: Arc<ThreadPool>,
+    queue: Arc<Semaphore>,
+}
+
+impl Default for HashPool {
+    /// A pool with one thread per CPU, shared by every state not given a pool of its own
+    fn default() -> Self {
+        static DEFAULT: OnceLock<HashPool> = OnceLock::new();
+        DEFAULT
+            .get_or_init(|| {
+                HashPool::new(None, DEFAULT_QUEUE_DEPTH)
+                    .expect("the default hashing pool can be built")
+            })
+            .clone()
+    }
+}
+
+impl HashPool {
+    /// A pool of `threads` threads, or one per CPU when unset, taking up to `queue_depth` jobs
+    pub fn new(threads: Option<usize>, queue_depth: usize) -> Result<Self> {
+        ensure!(
+            queue_depth > 0,
+            "the hashing queue must hold at least one job"
+        );
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads.unwrap_or(0))
+            .thread_name(|index| format!("hash-{index}"))
+            .build()?;
+        Ok(HashPool {
+            pool: Arc::new(pool),
+            queue: Arc::new(Semaphore::new(queue_depth)),
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Run `work` on the pool, waiting for its result without blocking the async workers.
+    /// Fails straight away, dropping `work`, when the queue is full.
+    pub async fn run<T, F>(&self, work: F) -> Result<T, Overloaded>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = self
+            .queue
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Overloaded)?;
+        let (send, recv) = tokio::sync::oneshot::channel();
+        self.pool.spawn(move || {
+            let result = work();
+            // Free the slot before the result is handed back, so a caller can queue again
+            drop(slot);
+            let _ = send.send(result);
+        });
+        Ok(recv.await.expect("Panic on the hashing pool"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn work_runs_on_the_sized_pool() {
+        let pool = HashPool::new(Some(2), DEFAULT_QUEUE_DEPTH).unwrap();
+        assert_eq!(pool.threads(), 2);
+        let thread = pool
+            .run(|| std::thread::current().name().map(str::to_string))
+            .await
+            .unwrap();
+        assert!(thread.is_some_and(|name| name.starts_with("hash-")));
+        assert_eq!(pool.run(rayon::current_num_threads).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn full_queues_refuse_work() {
+        let pool = HashPool::new(Some(1), 1).unwrap();
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let busy = pool.clone();
+        let running = tokio::spawn(async move { busy.run(move || wait.recv()).await });
+        while pool.queue.available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(pool.run(|| ()).await.is_err());
+        release.send(()).unwrap();
+        assert!(running.await.unwrap().is_ok());
+        assert!(pool.run(|| ()).await.is_ok());
+        assert!(HashPool::new(None, 0).is_err());
+    }
+}
@@ -0,0 +1,708 @@
+//! Resumable maintenance jobs.
+//!
+//! A job covers a span of indexes split into ranges. Each range records how far it got after
+//! every chunk, so a job interrupted by a restart or an error resumes where it stopped instead
+//! of starting over. Ranges are worked on concurrently, up to the job's parallelism.
+//!
+//! Backfills walk Trillian leaf indexes. Re-hashing and clustering walk stored images by the
+//! index of their leaf, so images not yet integrated are left for a later job.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Report, Result};
+use futures::{stream, TryStreamExt};
+use metrics::increment_counter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Row};
+use tracing::{debug, error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::hash::cryptographic::CryptographicHash;
+use crate::hash::perceptual::PerceptualHash;
+use crate::hash::{hash_image, VeracityHash};
+use crate::reconcile::record_logged_leaves;
+use crate::state::{AppState, PerceptualIndex};
+
+/// Indexes processed per checkpoint
+const CHUNK_SIZE: i64 = 256;
+/// Nearest clustered images an image is compared with when it is clustered
+const CLUSTER_NEIGHBOURS: i64 = 16;
+
+/// Kinds of maintenance job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// Walk Trillian leaves by index and add any image missing from the database
+    TrillianBackfill,
+    /// Hash the kept originals again and update perceptual hashes the hashing code now computes
+    /// differently
+    Rehash,
+    /// Put each image in the cluster of its nearest already clustered near-duplicate, or start
+    /// a new one
+    Cluster,
+    /// Seal kept originals under the current blob encryption key, after it is rotated or
+    /// encryption is turned on
+    Rewrap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Completed,
+    /// Stopped on an error, can be resumed
+    Failed,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::TrillianBackfill => "trillian_backfill",
+            JobKind::Rehash => "rehash",
+            JobKind::Cluster => "cluster",
+            JobKind::Rewrap => "rewrap",
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trillian_backfill" => Ok(JobKind::TrillianBackfill),
+            "rehash" => Ok(JobKind::Rehash),
+            "cluster" => Ok(JobKind::Cluster),
+            "rewrap" => Ok(JobKind::Rewrap),
+            _ => Err(eyre!("unknown job kind {s}")),
+        }
+    }
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "running" => Ok(JobStatus::Running),
+            "completed" => Ok(JobStatus::Completed),
+            "failed" => Ok(JobStatus::Failed),
+            _ => Err(eyre!("unknown job status {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct JobProgress {
+    pub id: Uuid,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// Most ranges worked on at once
+    pub parallelism: i64,
+    /// Indexes processed so far across all ranges
+    pub processed: i64,
+    pub total: i64,
+    pub ranges_completed: i64,
+    pub ranges_total: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One shard of a job, covering indexes `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct JobRange {
+    start: i64,
+    end: i64,
+    /// First index not yet processed
+    next: i64,
+}
+
+/// A job and its ranges' progress summed up, before its kind and status are parsed
+struct JobRow {
+    id: Uuid,
+    kind: String,
+    status: String,
+    parallelism: i64,
+    processed: i64,
+    total: i64,
+    ranges_completed: i64,
+    ranges_total: i64,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// Record a job over `start..end` split into ranges of `shard_size` and start running it
+#[instrument(skip(state))]
+pub async fn create(
+    state: &AppState,
+    kind: JobKind,
+    start: i64,
+    end: i64,
+    shard_size: i64,
+    parallelism: i64,
+) -> Result<Uuid> {
+    let id = Uuid::new_v4();
+    let mut tx = state.db_pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO maintenance_jobs (id, kind, status, parallelism) VALUES ($1, $2, $3, $4)",
+        id,
+        kind.as_str(),
+        JobStatus::Running.as_str(),
+        parallelism,
+    )
+    .execute(&mut *tx)
+    .await?;
+    for (range_start, range_end) in shard(start, end, shard_size) {
+        sqlx::query!(
+            "INSERT INTO maintenance_job_ranges (job_id, range_start, range_end, next_index) \
+            VALUES ($1, $2, $3, $2)",
+            id,
+            range_start,
+            range_end,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    info!(
+        "Created {} job {} over {}..{}",
+        kind.as_str(),
+        id,
+        start,
+        end
+    );
+    tokio::spawn(run(state.clone(), id));
+    Ok(id)
+}
+
+/// Mark a failed job as running again and continue it from its checkpoints
+pub async fn resume(state: &AppState, id: Uuid) -> Result<bool> {
+    let resumed = sqlx::query!(
+        "UPDATE maintenance_jobs SET status = $2, last_error = NULL, updated_at = now() \
+        WHERE id = $1 AND status = $3",
+        id,
+        JobStatus::Running.as_str(),
+        JobStatus::Failed.as_str(),
+    )
+    .execute(&state.db_pool)
+    .await?
+    .rows_affected();
+    if resumed > 0 {
+        tokio::spawn(run(state.clone(), id));
+    }
+    Ok(resumed > 0)
+}
+
+/// Continue every job that was running when the process last stopped
+pub async fn resume_running(state: AppState) {
+    let ids = sqlx::query_scalar!(
+        "SELECT id FROM maintenance_jobs WHERE status = $1",
+        JobStatus::Running.as_str(),
+    )
+    .fetch_all(&state.db_pool)
+    .await;
+    match ids {
+        Ok(ids) => {
+            for id in ids {
+                info!("Resuming maintenance job {}", id);
+                tokio::spawn(run(state.clone(), id));
+            }
+        }
+        Err(err) => error!("Could not resume maintenance jobs: {}", err),
+    }
+}
+
+pub async fn progress(state: &AppState, id: Uuid) -> Result<Option<JobProgress>> {
+    Ok(jobs(state, Some(id), 1).await?.pop())
+}
+
+/// Most recent jobs first
+pub async fn list(state: &AppState, limit: i64) -> Result<Vec<JobProgress>> {
+    jobs(state, None, limit).await
+}
+
+/// Progress of the job `id`, or of the most recent jobs when `None`
+async fn jobs(state: &AppState, id: Option<Uuid>, limit: i64) -> Result<Vec<JobProgress>> {
+    let rows = sqlx::query_as!(
+        JobRow,
+        r#"SELECT j.id, j.kind, j.status, j.parallelism, j.last_error,
+        j.created_at, j.updated_at,
+        COALESCE(SUM(r.next_index - r.range_start), 0)::INT8 AS "processed!",
+        COALESCE(SUM(r.range_end - r.range_start), 0)::INT8 AS "total!",
+        COALESCE(SUM(CASE WHEN r.next_index >= r.range_end THEN 1 ELSE 0 END), 0)::INT8
+        AS "ranges_completed!",
+        COUNT(r.range_start) AS "ranges_total!"
+        FROM maintenance_jobs j LEFT JOIN maintenance_job_ranges r ON r.job_id = j.id
+        WHERE $1::UUID IS NULL OR j.id = $1
+        GROUP BY j.id ORDER BY j.created_at DESC LIMIT $2"#,
+        id,
+        limit,
+    )
+    .fetch_all(&state.db_pool)
+    .await?;
+    rows.into_iter().map(JobProgress::try_from).collect()
+}
+
+/// Up to `limit` images in the same cluster as the image with crypto hash `hash`, including it,
+/// in log order
+pub async fn cluster(
+    state: &AppState,
+    hash: &CryptographicHash,
+    limit: i64,
+) -> Result<Vec<VeracityHash>> {
+    let rows = sqlx::query!(
+        "SELECT c_hash, p_hash FROM images \
+        WHERE cluster_id = (SELECT cluster_id FROM images WHERE c_hash = $1) \
+        ORDER BY leaf_index LIMIT $2",
+        &hash.as_ref()[..],
+        limit,
+    )
+    .fetch_all(&state.db_pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| stored_hash(row.c_hash, row.p_hash))
+        .collect()
+}
+
+/// Work through the unfinished ranges of a job, then record how it ended
+async fn run(state: AppState, id: Uuid) {
+    let result = run_ranges(&state, id).await;
+    let (status, last_error) = match &result {
+        Ok(()) => (JobStatus::Completed, None),
+        Err(err) => {
+            error!("Maintenance job {} failed: {}", id, err);
+            (JobStatus::Failed, Some(err.to_string()))
+        }
+    };
+    let recorded = sqlx::query!(
+        "UPDATE maintenance_jobs SET status = $2, last_error = $3, updated_at = now() \
+        WHERE id = $1",
+        id,
+        status.as_str(),
+        last_error,
+    )
+    .execute(&state.db_pool)
+    .await;
+    match recorded {
+        Ok(_) => info!("Maintenance job {} is {}", id, status.as_str()),
+        Err(err) => error!(
+            "Could not record the end of maintenance job {}: {}",
+            id, err
+        ),
+    }
+}
+
+async fn run_ranges(state: &AppState, id: Uuid) -> Result<()> {
+    let job = sqlx::query!(
+        "SELECT kind, parallelism FROM maintenance_jobs WHERE id = $1",
+        id,
+    )
+    .fetch_one(&state.db_pool)
+    .await?;
+    let kind: JobKind = job.kind.parse()?;
+    let parallelism = job.parallelism;
+    let ranges = sqlx::query!(
+        "SELECT range_start, range_end, next_index FROM maintenance_job_ranges \
+        WHERE job_id = $1 AND next_index < range_end ORDER BY range_start",
+        id,
+    )
+    .fetch_all(&state.db_pool)
+    .await?
+    .into_iter()
+    .map(|row| JobRange {
+        start: row.range_start,
+        end: row.range_end,
+        next: row.next_index,
+    })
+    .collect::<Vec<_>>();
+
+    debug!("Job {} has {} ranges left", id, ranges.len());
+    stream::iter(ranges.into_iter().map(Ok))
+        .try_for_each_concurrent(parallelism.max(1) as usize, |range| {
+            run_range(state, id, kind, range)
+        })
+        .await
+}
+
+/// Process one range chunk by chunk, checkpointing after each
+async fn run_range(state: &AppState, id: Uuid, kind: JobKind, mut range: JobRange) -> Result<()> {
+    while range.next < range.end {
+        let count = CHUNK_SIZE.min(range.end - range.next);
+        let mut tx = state.db_pool.begin().await?;
+        let processed = match kind {
+            JobKind::TrillianBackfill => {
+                let leaves = state
+                    .trillian
+                    .clone()
+                    .get_leaves_by_range(&state.trillian_tree, range.next, count)
+                    .await?;
+                if leaves.is_empty() {
+                    return Err(eyre!("Trillian returned no leaves at index {}", range.next));
+                }
+                record_logged_leaves(&mut tx, state.perceptual_index, &leaves).await?;
+                leaves.len() as i64
+            }
+            JobKind::Rehash => {
+                rehash_images(state, &mut tx, range.next, range.next + count).await?;
+                count
+            }
+            JobKind::Cluster => {
+                cluster_images(state, &mut tx, range.next, range.next + count).await?;
+                count
+            }
+            JobKind::Rewrap => {
+                rewrap_originals(state, &mut tx, range.next, range.next + count).await?;
+                count
+            }
+        };
+        range.next += processed;
+        sqlx::query!(
+            "UPDATE maintenance_job_ranges SET next_index = $3 \
+            WHERE job_id = $1 AND range_start = $2",
+            id,
+            range.start,
+            range.next,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE maintenance_jobs SET updated_at = now() WHERE id = $1",
+            id,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+/// How an image's stored hashes compare with those its original hashes to now
+#[derive(Debug, PartialEq, Eq)]
+enum Rehashed {
+    Unchanged,
+    /// Only the perceptual hash moved, so it can be updated
+    Changed,
+    /// The crypto hash the image is logged and stored under moved, so it cannot follow
+    Mismatched,
+}
+
+fn rehashed(stored: &VeracityHash, current: &VeracityHash) -> Rehashed {
+    if current.crypto_hash != stored.crypto_hash {
+        Rehashed::Mismatched
+    } else if current.perceptual_hash == stored.perceptual_hash {
+        Rehashed::Unchanged
+    } else {
+        Rehashed::Changed
+    }
+}
+
+/// Hash the originals of images logged at `start..end` again, updating perceptual hashes that
+/// changed. Images without a kept original or whose new perceptual hash belongs to another
+/// image are left as they are.
+async fn rehash_images(
+    state: &AppState,
+    tx: &mut PgConnection,
+    start: i64,
+    end: i64,
+) -> Result<()> {
+    let blob_store = state
+        .blob_store
+        .as_ref()
+        .ok_or_else(|| eyre!("original images are not kept"))?;
+    let rows = sqlx::query!(
+        "SELECT c_hash, p_hash FROM images WHERE leaf_index >= $1 AND leaf_index < $2",
+        start,
+        end,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for row in rows {
+        let stored = stored_hash(row.c_hash, row.p_hash)?;
+        let original = match blob_store.get(&stored.crypto_hash).await? {
+            Some(original) => original,
+            None => {
+                debug!("No original is kept for {}", stored.crypto_hash);
+                continue;
+            }
+        };
+        let current = match state.hash_pool.run(move || hash_image(&original)).await? {
+            Ok(current) => current,
+            Err(err) => {
+                warn!("Could not hash {} again: {}", stored.crypto_hash, err);
+                continue;
+            }
+        };
+        match rehashed(&stored, &current) {
+            Rehashed::Unchanged => {}
+            Rehashed::Mismatched => {
+                warn!(
+                    "The original of {} now hashes to {}",
+                    stored.crypto_hash, current.crypto_hash
+                );
+                increment_counter!("veracity_rehash_mismatched_total");
+            }
+            Rehashed::Changed => {
+                let c_hash = &stored.crypto_hash.as_ref()[..];
+                let p_hash = &current.perceptual_hash.as_ref()[..];
+                let updated = match state.perceptual_index {
+                    PerceptualIndex::Scan => {
+                        sqlx::query!(
+                            "UPDATE images SET p_hash = $2 WHERE c_hash = $1 \
+                            AND NOT EXISTS (SELECT 1 FROM images WHERE p_hash = $2)",
+                            c_hash,
+                            p_hash,
+                        )
+                        .execute(&mut *tx)
+                        .await?
+                    }
+                    // p_vec only exists once the pgvector index is set up, so it is not checked
+                    // at compile time
+                    PerceptualIndex::PgVector => {
+                        sqlx::query(
+                            "UPDATE images SET p_hash = $2, \
+                            p_vec = ('x' || encode($2::BYTEA, 'hex'))::bit(256) \
+                            WHERE c_hash = $1 \
+                            AND NOT EXISTS (SELECT 1 FROM images WHERE p_hash = $2)",
+                        )
+                        .bind(c_hash)
+                        .bind(p_hash)
+                        .execute(&mut *tx)
+                        .await?
+                    }
+                }
+                .rows_affected();
+                if updated > 0 {
+                    increment_counter!("veracity_rehash_updated_total");
+                } else {
+                    warn!(
+                        "{} now shares its perceptual hash with another image, skipped",
+                        stored.crypto_hash
+                    );
+                    increment_counter!("veracity_rehash_skipped_total");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Cluster of an image: that of its closest clustered neighbour within `max_distance`, or a new
+/// one named after the image
+fn cluster_of(
+    image: &VeracityHash,
+    neighbours: &[(PerceptualHash, Vec<u8>)],
+    max_distance: u32,
+) -> Vec<u8> {
+    neighbours
+        .iter()
+        .map(|(p_hash, cluster_id)| (image.perceptual_hash.hamming_distance(p_hash), cluster_id))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, cluster_id)| cluster_id.clone())
+        .unwrap_or_else(|| image.crypto_hash.as_ref().to_vec())
+}
+
+/// Cluster the unclustered images logged at `start..end` by perceptual hash, within the
+/// near-duplicate distance. Nearest neighbours come from the pgvector index.
+async fn cluster_images(
+    state: &AppState,
+    tx: &mut PgConnection,
+    start: i64,
+    end: i64,
+) -> Result<()> {
+    if state.perceptual_index != PerceptualIndex::PgVector || state.near_duplicate_distance == 0 {
+        return Err(eyre!(
+            "clustering needs pgvector and a near-duplicate distance"
+        ));
+    }
+    let rows = sqlx::query!(
+        "SELECT c_hash, p_hash FROM images \
+        WHERE leaf_index >= $1 AND leaf_index < $2 AND cluster_id IS NULL ORDER BY leaf_index",
+        start,
+        end,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for row in rows {
+        let image = stored_hash(row.c_hash, row.p_hash)?;
+        // p_vec only exists once the pgvector index is set up, so it is not checked at compile
+        // time
+        let neighbours = sqlx::query(
+            "SELECT p_hash, cluster_id FROM images WHERE cluster_id IS NOT NULL \
+            ORDER BY p_vec <~> ('x' || $1::TEXT)::bit(256) LIMIT $2",
+        )
+        .bind(image.perceptual_hash.to_hex())
+        .bind(CLUSTER_NEIGHBOURS)
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok((
+                PerceptualHash::try_from(row.try_get::<Vec<u8>, _>("p_hash")?)?,
+                row.try_get::<Vec<u8>, _>("cluster_id")?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+        sqlx::query!(
+            "UPDATE images SET cluster_id = $2 WHERE c_hash = $1",
+            &image.crypto_hash.as_ref()[..],
+            cluster_of(&image, &neighbours, state.near_duplicate_distance),
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Move the kept originals of images logged at `start..end` under the current key encryption
+/// key, encrypting any stored before encryption was turned on
+async fn rewrap_originals(
+    state: &AppState,
+    tx: &mut PgConnection,
+    start: i64,
+    end: i64,
+) -> Result<()> {
+    let blob_store = state
+        .blob_store
+        .as_ref()
+        .ok_or_else(|| eyre!("original images are not kept"))?;
+    let hashes = sqlx::query_scalar!(
+        "SELECT c_hash FROM images WHERE leaf_index >= $1 AND leaf_index < $2",
+        start,
+        end,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for hash in hashes {
+        if blob_store
+            .rewrap(&CryptographicHash::try_from(hash)?)
+            .await?
+        {
+            increment_counter!("veracity_originals_rewrapped_total");
+        }
+    }
+    Ok(())
+}
+
+fn stored_hash(c_hash: Vec<u8>, p_hash: Vec<u8>) -> Result<VeracityHash> {
+    Ok(VeracityHash {
+        crypto_hash: CryptographicHash::try_from(c_hash)?,
+        perceptual_hash: PerceptualHash::try_from(p_hash)?,
+    })
+}
+
+impl TryFrom<JobRow> for JobProgress {
+    type Error = Report;
+
+    fn try_from(row: JobRow) -> Result<Self, Self::Error> {
+        Ok(JobProgress {
+            id: row.id,
+            kind: row.kind.parse()?,
+            status: row.status.parse()?,
+            parallelism: row.parallelism,
+            processed: row.processed,
+            total: row.total,
+            ranges_completed: row.ranges_completed,
+            ranges_total: row.ranges_total,
+            last_error: row.last_error,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+/// Split `start..end` into consecutive ranges of at most `shard_size`
+fn shard(start: i64, end: i64, shard_size: i64) -> Vec<(i64, i64)> {
+    let shard_size = shard_size.max(1);
+    let mut ranges = vec![];
+    let mut range_start = start;
+    while range_start < end {
+        let range_end = range_start.saturating_add(shard_size).min(end);
+        ranges.push((range_start, range_end));
+        range_start = range_end;
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_cover_the_span() {
+        assert_eq!(shard(0, 10, 4), vec![(0, 4), (4, 8), (8, 10)]);
+        assert_eq!(shard(5, 6, 100), vec![(5, 6)]);
+        assert_eq!(shard(3, 3, 4), vec![]);
+        assert_eq!(shard(0, 2, 0), vec![(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn kinds_round_trip_through_strings() {
+        for kind in [
+            JobKind::TrillianBackfill,
+            JobKind::Rehash,
+            JobKind::Cluster,
+            JobKind::Rewrap,
+        ] {
+            assert_eq!(kind.as_str().parse::<JobKind>().ok(), Some(kind));
+        }
+        for status in [JobStatus::Running, JobStatus::Completed, JobStatus::Failed] {
+            assert_eq!(status.as_str().parse::<JobStatus>().ok(), Some(status));
+        }
+    }
+
+    fn hash(crypto: u8, perceptual: u8) -> VeracityHash {
+        VeracityHash {
+            crypto_hash: CryptographicHash::try_from(vec![crypto; 32]).unwrap(),
+            perceptual_hash: PerceptualHash::try_from(vec![perceptual; 32]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn only_perceptual_hashes_are_rehashed() {
+        assert_eq!(rehashed(&hash(1, 2), &hash(1, 2)), Rehashed::Unchanged);
+        assert_eq!(rehashed(&hash(1, 2), &hash(1, 3)), Rehashed::Changed);
+        assert_eq!(rehashed(&hash(1, 2), &hash(4, 2)), Rehashed::Mismatched);
+    }
+
+    #[test]
+    fn images_join_the_closest_cluster_in_reach() {
+        let image = hash(1, 0);
+        // 0b1 differs from 0 in one bit per byte, 0b11 in two
+        let near = PerceptualHash::try_from(vec![0b1; 32]).unwrap();
+        let nearer =
+            PerceptualHash::try_from(vec![0b1; 16].into_iter().chain([0; 16]).collect::<Vec<_>>())
+                .unwrap();
+        let far = PerceptualHash::try_from(vec![0b11; 32]).unwrap();
+        assert_eq!(cluster_of(&image, &[], 40), vec![1; 32]);
+        assert_eq!(
+            cluster_of(
+                &image,
+                &[(far.clone(), vec![2; 32]), (near.clone(), vec![3; 32])],
+                40
+            ),
+            vec![3; 32]
+        );
+        assert_eq!(
+            cluster_of(&image, &[(near, vec![3; 32]), (nearer, vec![4; 32])], 40),
+            vec![4; 32]
+        );
+        assert_eq!(cluster_of(&image, &[(far, vec![2; 32])], 40), vec![1; 32]);
+    }
+}
@@ -1,11 +1,39 @@
 #![feature(type_alias_impl_trait)]
 
+pub mod api_key;
+pub mod attestation;
+pub mod availability;
+pub mod blob;
+pub mod cache;
+pub mod checkpoint;
+pub mod coalesce;
+pub mod config;
 pub mod docs;
 pub mod errors;
 pub mod extractors;
-pub mod hash;
+pub mod fetch;
+pub mod hash_pool;
+pub mod jobs;
+pub mod map;
+pub mod metadata;
+pub mod monitor;
+pub mod outbox;
+mod protobuf;
+pub mod provision;
+pub mod public_id;
+pub mod reconcile;
+pub mod record;
+pub mod repository;
 pub mod server;
+pub mod startup;
 pub mod state;
+pub mod tenant;
+pub mod tombstone;
+pub mod upload_token;
+pub mod webhooks;
+pub mod witness;
+
+pub use veracity_hash as hash;
 
 #[macro_use]
 extern crate derive_builder;
@@ -1,14 +1,14 @@
 use std::env;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use aide::{
-    axum::ApiRouter,
-    openapi::{OpenApi, Tag},
-    transform::TransformOpenApi,
-};
+use aide::{axum::ApiRouter, openapi::Tag, transform::TransformOpenApi};
 use axum::http::StatusCode;
-use axum::Extension;
+use axum::{middleware, Extension};
+use clap::{Args, CommandFactory, Parser, ValueEnum};
 use eyre::{Report, Result};
 use tokio::signal;
 use tokio::time::Instant;
@@ -17,20 +17,293 @@ use tracing::{debug, error, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
-use image_veracity_api::state::{AppState, AppStateBuilder};
-use image_veracity_api::{docs::docs_routes, errors::AppError, extractors::Json, server::routes};
+use trillian::client::TlsOptions;
+
+use image_veracity_api::api_key::ApiKeys;
+use image_veracity_api::attestation::{AppAttest, Attestations, PlayIntegrity};
+use image_veracity_api::availability;
+use image_veracity_api::blob::{self, EncryptedStore, SharedBlobStore};
+use image_veracity_api::cache::{
+    self, ImageCache, RedisCache, DEFAULT_IMAGE_CACHE_CAPACITY, DEFAULT_IMAGE_CACHE_TTL,
+};
+use image_veracity_api::checkpoint::NoteSigner;
+use image_veracity_api::config;
+use image_veracity_api::fetch::UrlFetcher;
+use image_veracity_api::hash_pool::{self, HashPool};
+use image_veracity_api::jobs;
+use image_veracity_api::map::{self, VeracityMap};
+use image_veracity_api::monitor;
+use image_veracity_api::outbox;
+use image_veracity_api::provision;
+use image_veracity_api::public_id::PublicIds;
+use image_veracity_api::reconcile;
+use image_veracity_api::repository;
+use image_veracity_api::server::image_types::AllowedImageTypes;
+use image_veracity_api::server::load_shed::{UploadSlots, DEFAULT_MAX_CONCURRENT_UPLOADS};
+use image_veracity_api::server::rate_limit::{Quota, RateLimiter};
+use image_veracity_api::server::tls::{self, TlsSettings, TlsVersion};
+use image_veracity_api::server::{auth, grpc, negotiate, request_id, retry};
+use image_veracity_api::startup::{
+    redact_connection_string, ResolvedConfig, StartupSummary, REDACTED,
+};
+use image_veracity_api::state::{
+    AppState, AppStateBuilder, ConnectionPool, DuplicateUploads, PerceptualIndex,
+    DEFAULT_NEAR_DUPLICATE_DISTANCE,
+};
+use image_veracity_api::tenant;
+use image_veracity_api::upload_token::UploadTokens;
+use image_veracity_api::webhooks;
+use image_veracity_api::witness::{self, Witness};
+use image_veracity_api::{
+    docs::{self, docs_routes},
+    errors::{AppError, ErrorCode},
+    extractors::Json,
+    server::routes,
+};
+
+/// Image veracity API server.
+/// Every option can also be set through the environment variable named in its help.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// File of KEY=VALUE lines setting environment variables that are not already set
+    #[arg(short, long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Address to serve HTTP on
+    #[arg(long, env = "LISTEN_ADDRESS", default_value = "127.0.0.1:3000")]
+    listen: SocketAddr,
+    /// Address of the Trillian log server
+    #[arg(long, env = "TRILLIAN_ADDRESS")]
+    trillian_address: String,
+    /// Trillian tree images are logged to. A tree is created on first start when unset, and
+    /// reused after that.
+    #[arg(long, env = "TRILLIAN_TREE_ID")]
+    tree_id: Option<i64>,
+    /// Display name of the tree created when no tree ID is given
+    #[arg(long, env = "TRILLIAN_TREE_NAME", default_value = provision::DEFAULT_TREE_NAME)]
+    tree_name: String,
+    /// PEM CA bundle to trust for an `https://` Trillian address instead of the system roots
+    #[arg(long, env = "TRILLIAN_CA_CERT_PATH")]
+    trillian_ca_cert: Option<PathBuf>,
+    /// PEM client certificate to present to Trillian, for mutual TLS
+    #[arg(
+        long,
+        env = "TRILLIAN_CLIENT_CERT_PATH",
+        requires = "trillian_client_key"
+    )]
+    trillian_client_cert: Option<PathBuf>,
+    /// PEM private key for the Trillian client certificate
+    #[arg(
+        long,
+        env = "TRILLIAN_CLIENT_KEY_PATH",
+        requires = "trillian_client_cert"
+    )]
+    trillian_client_key: Option<PathBuf>,
+    /// Name to expect in Trillian's certificate, the host of its address by default
+    #[arg(long, env = "TRILLIAN_TLS_DOMAIN")]
+    trillian_tls_domain: Option<String>,
+    /// PostgreSQL or CockroachDB connection string
+    #[arg(long, env = "DATABASE_URL")]
+    database_url: String,
+    /// Replica connection string for GET and search queries, the primary serves them when unset
+    #[arg(long, env = "DATABASE_READ_URL")]
+    database_read_url: Option<String>,
+    /// Times to try reaching the database on startup before exiting, backing off up to 30
+    /// seconds between tries
+    #[arg(long, env = "DATABASE_CONNECT_ATTEMPTS", default_value_t = 10)]
+    database_connect_attempts: u32,
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// PEM certificate chain to serve HTTPS with, instead of plain HTTP
+    #[arg(long, env = "TLS_CERT_PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for the certificate
+    #[arg(long, env = "TLS_KEY_PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Oldest TLS version to accept
+    #[arg(long, env = "TLS_MIN_VERSION", value_enum, default_value = "1.2")]
+    tls_min_version: TlsVersion,
+    /// Address to redirect plain HTTP requests to HTTPS from, when serving HTTPS
+    #[arg(long, env = "HTTP_REDIRECT_ADDRESS", requires = "tls_cert")]
+    http_redirect_listen: Option<SocketAddr>,
+    /// Address to serve the gRPC API on, which is disabled when unset
+    #[arg(long, env = "GRPC_LISTEN_ADDRESS")]
+    grpc_listen: Option<SocketAddr>,
+    /// Threads to hash images on, one per CPU when unset. Match it to the container's CPU quota.
+    #[arg(long, env = "HASH_THREADS")]
+    hash_threads: Option<usize>,
+    /// Images that may wait for or be on the hashing threads at once, more are refused with a 503
+    #[arg(long, env = "HASH_QUEUE_DEPTH", default_value_t = hash_pool::DEFAULT_QUEUE_DEPTH)]
+    hash_queue_depth: usize,
+    /// How perceptual hashes are indexed for similarity search, `scan` or `pgvector`
+    #[arg(long, env = "PERCEPTUAL_INDEX", default_value = "scan")]
+    perceptual_index: PerceptualIndex,
+    /// Uploads list stored images within this Hamming distance, 0 turns the check off
+    #[arg(
+        long,
+        env = "NEAR_DUPLICATE_DISTANCE",
+        default_value_t = DEFAULT_NEAR_DUPLICATE_DISTANCE
+    )]
+    near_duplicate_distance: u32,
+    /// Answer uploads of an image already stored with a 409 (`conflict`) or the stored image
+    /// (`existing`)
+    #[arg(long, env = "DUPLICATE_UPLOADS", default_value = "conflict")]
+    duplicate_uploads: DuplicateUploads,
+    /// Log metadata sent with uploads after the perceptual hash in the leaf's extra data
+    #[arg(long, env = "LOG_UPLOAD_METADATA")]
+    log_upload_metadata: bool,
+    /// Comma-separated media types uploads may have, checked against their magic bytes
+    #[arg(long, env = "ALLOWED_IMAGE_TYPES")]
+    allowed_image_types: Option<String>,
+    /// Single-image lookups to cache in process, 0 turns the cache off
+    #[arg(
+        long,
+        env = "IMAGE_CACHE_CAPACITY",
+        default_value_t = DEFAULT_IMAGE_CACHE_CAPACITY
+    )]
+    image_cache_capacity: u64,
+    /// Seconds cached lookups are kept for
+    #[arg(
+        long,
+        env = "IMAGE_CACHE_TTL_SECONDS",
+        default_value_t = DEFAULT_IMAGE_CACHE_TTL.as_secs()
+    )]
+    image_cache_ttl_seconds: u64,
+    /// Redis server to share the image cache with other instances through
+    #[arg(long, env = "REDIS_URL")]
+    redis_url: Option<String>,
+    /// Secret opaque listing IDs are keyed by, changing it changes every public ID
+    #[arg(long, env = "PUBLIC_ID_SECRET", hide_env_values = true)]
+    public_id_secret: Option<String>,
+    /// Secret upload tokens are signed with, which are not issued when unset
+    #[arg(long, env = "UPLOAD_TOKEN_SECRET", hide_env_values = true)]
+    upload_token_secret: Option<String>,
+    /// Comma-separated hosts images may be fetched from by URL, `*.example.com` for subdomains
+    #[arg(long, env = "FETCH_ALLOWED_HOSTS")]
+    fetch_allowed_hosts: Option<String>,
+    /// Where original images are kept, `file:///dir` or `s3://bucket/prefix`; not kept when unset
+    #[arg(long, env = "BLOB_STORE_URL")]
+    blob_store_url: Option<String>,
+    /// Endpoint of an S3-compatible service to use instead of AWS for `s3://` blob stores
+    #[arg(long, env = "S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+    /// KMS key that wraps the data keys originals are sealed with
+    #[arg(long, env = "BLOB_KMS_KEY_ID", conflicts_with = "blob_encryption_keys")]
+    blob_kms_key_id: Option<String>,
+    /// Comma-separated `id:base64_key` pairs wrapping the data keys originals are sealed with,
+    /// the first wraps new ones
+    #[arg(long, env = "BLOB_ENCRYPTION_KEYS", hide_env_values = true)]
+    blob_encryption_keys: Option<String>,
+    /// Where image records are kept instead of the database, `sqlite:///path/to/images.db` with
+    /// the sqlite feature
+    #[arg(long, env = "IMAGE_REPOSITORY_URL")]
+    image_repository_url: Option<String>,
+    /// Note signing key, `PRIVATE+KEY+<name>+<hash>+<key>`, for checkpoints served to witnesses
+    #[arg(long, env = "CHECKPOINT_SIGNING_KEY", hide_env_values = true)]
+    checkpoint_signing_key: Option<String>,
+    /// Comma-separated `<verifier key> <URL>` witnesses to collect checkpoint cosignatures from
+    #[arg(long, env = "WITNESSES", requires = "checkpoint_signing_key")]
+    witnesses: Option<String>,
+    /// Seconds between checks that the log only ever grows; the monitor is off when unset
+    #[arg(long, env = "LOG_MONITOR_INTERVAL_SECONDS")]
+    log_monitor_interval_seconds: Option<u64>,
+    /// Seconds between additions of new images to the verifiable perceptual hash map, which is
+    /// not kept when unset
+    #[arg(long, env = "VERACITY_MAP_INTERVAL_SECONDS")]
+    veracity_map_interval_seconds: Option<u64>,
+    /// Comma-separated `<name>=<tree ID>[:<schema>]` tenants, each logging to its own tree and
+    /// keeping images in its own schema
+    #[arg(long, env = "TENANTS")]
+    tenants: Option<String>,
+    /// Bootstrap admin API key, setting it turns on API key authentication
+    #[arg(long, env = "ADMIN_API_KEY", hide_env_values = true)]
+    admin_api_key: Option<String>,
+    /// Uploads per minute allowed from each client IP
+    #[arg(long, env = "UPLOAD_RATE_LIMIT_PER_IP")]
+    upload_rate_limit_per_ip: Option<u32>,
+    /// Uploads per minute allowed with each API key
+    #[arg(long, env = "UPLOAD_RATE_LIMIT_PER_KEY")]
+    upload_rate_limit_per_key: Option<u32>,
+    /// Uploads in progress at once, over which they are refused with a 503; 0 lets any number in
+    #[arg(
+        long,
+        env = "MAX_CONCURRENT_UPLOADS",
+        default_value_t = DEFAULT_MAX_CONCURRENT_UPLOADS
+    )]
+    max_concurrent_uploads: usize,
+    #[command(flatten)]
+    attestation: AttestationArgs,
+}
+
+/// Settings of the attestation formats, each enabled when its settings are present
+#[derive(Args, Debug)]
+struct AttestationArgs {
+    /// Android package name Play Integrity verdicts must be for
+    #[arg(
+        long,
+        env = "PLAY_INTEGRITY_PACKAGE_NAME",
+        requires_all = ["play_integrity_decryption_key", "play_integrity_verification_key"]
+    )]
+    play_integrity_package_name: Option<String>,
+    /// Base64 key Play Integrity verdicts are decrypted with
+    #[arg(long, env = "PLAY_INTEGRITY_DECRYPTION_KEY", hide_env_values = true)]
+    play_integrity_decryption_key: Option<String>,
+    /// Base64 public key Play Integrity verdicts are verified with
+    #[arg(long, env = "PLAY_INTEGRITY_VERIFICATION_KEY")]
+    play_integrity_verification_key: Option<String>,
+    /// `<team ID>.<bundle ID>` App Attest attestations must be for
+    #[arg(long, env = "APP_ATTEST_APP_ID", requires = "app_attest_root_ca")]
+    app_attest_app_id: Option<String>,
+    /// PEM root certificate App Attest attestations chain to
+    #[arg(long, env = "APP_ATTEST_ROOT_CA_PATH")]
+    app_attest_root_ca: Option<PathBuf>,
+    /// Accept attestations from the App Attest development environment
+    #[arg(long, env = "APP_ATTEST_ALLOW_DEVELOPMENT")]
+    app_attest_allow_development: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per event, for log collectors
+    Json,
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    // The config file only sets variables that are unset, so read it before the other options
+    // fall back to the environment, and before the runtime starts threads
+    let config_file = Cli::command()
+        .mut_args(|arg| arg.required(false))
+        .ignore_errors(true)
+        .disable_help_flag(true)
+        .disable_version_flag(true)
+        .get_matches()
+        .get_one::<PathBuf>("config")
+        .cloned();
+    if let Some(path) = config_file {
+        config::load_env_file(&path)?;
+    }
+    let cli = Cli::parse();
+
+    tokio::runtime::Runtime::new()?.block_on(serve(cli))
+}
+
+async fn serve(cli: Cli) -> Result<()> {
     let start = Instant::now();
 
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "image_veracity=debug,trillian_client=debug,hyper=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "image_veracity=debug,trillian_client=debug,hyper=info".into());
+    match cli.log_format {
+        LogFormat::Text => tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_subscriber::fmt::layer())
+            .init(),
+        LogFormat::Json => tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+    }
 
     aide::gen::on_error(|error| {
         error!("{error}");
@@ -38,62 +311,337 @@ async fn main() -> Result<()> {
 
     aide::gen::extract_schemas(true);
 
-    let trillian_address = env::var("TRILLIAN_ADDRESS").map_err(|err| {
-        error!("Could not get TRILLIAN_ADDRESS: {}", err);
-        Report::from(err)
+    let Cli {
+        listen: addr,
+        trillian_address,
+        tree_id,
+        tree_name,
+        trillian_ca_cert,
+        trillian_client_cert,
+        trillian_client_key,
+        trillian_tls_domain,
+        database_url: db_connection_uri,
+        database_read_url,
+        database_connect_attempts,
+        tls_cert,
+        tls_key,
+        tls_min_version,
+        http_redirect_listen,
+        grpc_listen,
+        hash_threads,
+        hash_queue_depth,
+        perceptual_index,
+        near_duplicate_distance,
+        duplicate_uploads,
+        log_upload_metadata: log_metadata,
+        allowed_image_types,
+        image_cache_capacity,
+        image_cache_ttl_seconds,
+        redis_url,
+        public_id_secret,
+        upload_token_secret,
+        fetch_allowed_hosts,
+        blob_store_url,
+        s3_endpoint,
+        blob_kms_key_id,
+        blob_encryption_keys,
+        image_repository_url,
+        checkpoint_signing_key,
+        witnesses,
+        log_monitor_interval_seconds,
+        veracity_map_interval_seconds,
+        tenants,
+        admin_api_key,
+        upload_rate_limit_per_ip,
+        upload_rate_limit_per_key,
+        max_concurrent_uploads,
+        attestation,
+        ..
+    } = cli;
+    let tls_settings = tls_cert
+        .zip(tls_key)
+        .map(|(cert_path, key_path)| TlsSettings {
+            cert_path,
+            key_path,
+            min_version: tls_min_version,
+        });
+
+    let trillian_tls = TlsOptions {
+        ca_certificate: trillian_ca_cert
+            .as_ref()
+            .map(fs::read)
+            .transpose()
+            .map_err(|err| {
+                error!("Could not read TRILLIAN_CA_CERT_PATH: {}", err);
+                err
+            })?,
+        client_identity: match (&trillian_client_cert, trillian_client_key) {
+            (Some(cert), Some(key)) => Some((fs::read(cert)?, fs::read(key)?)),
+            _ => None,
+        },
+        domain_name: trillian_tls_domain,
+    };
+
+    // Uploads are hashed on their own pool so they cannot starve other rayon users
+    let hash_pool = HashPool::new(hash_threads, hash_queue_depth).map_err(|err| {
+        error!("Could not start the hashing pool: {}", err);
+        err
     })?;
-    let tree_id = env::var("TRILLIAN_TREE_ID")
-        .map_err(|err| {
-            error!("Could not get TRILLIAN_TREE_ID: {}", err);
-            Report::from(err)
-        })?
-        .parse::<i64>()
+    info!(
+        "Hashing images on {} threads, queueing up to {}",
+        hash_pool.threads(),
+        hash_queue_depth
+    );
+
+    let image_types = match allowed_image_types {
+        Some(types) => AllowedImageTypes::parse(&types).map_err(|err| {
+            error!("Could not parse ALLOWED_IMAGE_TYPES: {}", err);
+            err
+        })?,
+        None => AllowedImageTypes::default(),
+    };
+
+    let image_cache_ttl = Duration::from_secs(image_cache_ttl_seconds);
+    let mut image_cache = ImageCache::new(image_cache_capacity, image_cache_ttl);
+    // Instances configured with the same Redis server share lookups and invalidations. The cache
+    // is optional, so the server starts without it when Redis cannot be reached.
+    if let Some(redis_url) = redis_url {
+        match RedisCache::connect(&redis_url, image_cache_ttl).await {
+            Ok(shared) => image_cache = image_cache.with_shared(shared),
+            Err(err) => error!(
+                "Could not connect to Redis, not sharing the image cache: {}",
+                err
+            ),
+        }
+    }
+
+    let public_ids = match public_id_secret {
+        Some(secret) => PublicIds::hmac(secret.as_bytes()),
+        None => PublicIds::default(),
+    };
+
+    let upload_tokens = upload_token_secret.map(|secret| UploadTokens::new(secret.as_bytes()));
+
+    let url_fetcher =
+        fetch_allowed_hosts.map(|hosts| UrlFetcher::new(hosts.split(','), routes::MAX_UPLOAD_SIZE));
+
+    let blob_store = match blob_store_url {
+        Some(url) => Some(blob::from_url(&url, s3_endpoint).await.map_err(|err| {
+            error!("Could not open BLOB_STORE_URL: {}", err);
+            err
+        })?),
+        None => None,
+    };
+    // Originals are stored as they are when neither encryption setting is given
+    let blob_keys = blob::key_wrapper(blob_kms_key_id, blob_encryption_keys.as_deref())
+        .await
         .map_err(|err| {
-            error!("Could not parse TRILLIAN_TREE_ID: {}", err);
-            Report::from(err)
+            error!("Could not read the blob encryption keys: {}", err);
+            err
         })?;
+    let blob_store = match (blob_store, blob_keys) {
+        (Some(store), Some(keys)) => {
+            info!("Encrypting originals under key {}", keys.current_key());
+            Some(Arc::new(EncryptedStore::new(store, keys)) as SharedBlobStore)
+        }
+        (store, _) => store,
+    };
+
+    let images = match image_repository_url {
+        Some(url) => Some(repository::from_url(&url).map_err(|err| {
+            error!("Could not open IMAGE_REPOSITORY_URL: {}", err);
+            err
+        })?),
+        None => None,
+    };
 
-    let db_connection_uri = env::var("DATABASE_URL")
-        .expect("$DATABASE_URL is not set")
-        .to_owned();
+    let checkpoint_signer = match checkpoint_signing_key {
+        Some(key) => {
+            let signer = NoteSigner::from_private_key(&key).map_err(|err| {
+                error!("Could not read CHECKPOINT_SIGNING_KEY: {}", err);
+                err
+            })?;
+            info!("Signing checkpoints with {}", signer.verifier_key());
+            Some(signer)
+        }
+        None => None,
+    };
 
-    let state = AppStateBuilder::default()
+    // The witnesses cosign checkpoints signed by `checkpoint_signer`, which clap requires
+    let witnesses = match witnesses {
+        Some(list) => Witness::parse_list(&list).map_err(|err| {
+            error!("Could not parse WITNESSES: {}", err);
+            err
+        })?,
+        None => vec![],
+    };
+
+    let log_monitor_interval = log_monitor_interval_seconds.map(Duration::from_secs);
+    let veracity_map_interval = veracity_map_interval_seconds.map(Duration::from_secs);
+
+    let tenants = match tenants {
+        Some(spec) => tenant::parse_tenants(&spec).map_err(|err| {
+            error!("Could not parse TENANTS: {}", err);
+            err
+        })?,
+        None => Default::default(),
+    };
+
+    let api_keys = match admin_api_key {
+        Some(admin_key) => ApiKeys::new(admin_key),
+        None => ApiKeys::default(),
+    };
+
+    let rate_limiter = RateLimiter::new(
+        upload_rate_limit_per_ip.map(Quota::per_minute),
+        upload_rate_limit_per_key.map(Quota::per_minute),
+    );
+
+    let mut state = AppStateBuilder::default()
         .create_trillian_client(&trillian_address)
-        .trillian_tree(tree_id)
+        .trillian_tls(trillian_tls)
+        .trillian_tree(tree_id.unwrap_or_default())
         .create_postgres_client(&db_connection_uri)
+        .create_postgres_read_client(database_read_url.as_deref())
+        .db_connect_attempts(database_connect_attempts)
+        .perceptual_index(perceptual_index)
+        .image_types(image_types)
+        .duplicate_uploads(duplicate_uploads)
+        .hash_pool(hash_pool)
+        .log_metadata(log_metadata)
+        .near_duplicate_distance(near_duplicate_distance)
+        .image_cache(image_cache)
+        .public_ids(public_ids)
+        .upload_tokens(upload_tokens)
+        .url_fetcher(url_fetcher)
+        .blob_store(blob_store)
+        .images(images)
+        .tenants(tenants)
+        .checkpoint_signer(checkpoint_signer)
+        .witnesses(witnesses)
+        .log_monitor_interval(log_monitor_interval)
+        .api_keys(api_keys)
+        .rate_limiter(rate_limiter)
+        .upload_slots(UploadSlots::new(max_concurrent_uploads))
+        .attestations(attestations(attestation)?)
         .build()
         .await?;
-    let mut api = OpenApi::default();
+    let mut api = docs::openapi();
 
     // Ensure tables at startup as well as db connection works
-    create_db_tables(&state).await;
+    create_db_tables(&state).await?;
+
+    // Create the tree to log to on first start, so no tree has to be made by hand
+    if tree_id.is_none() {
+        state.trillian_tree = provision::provision_tree(&state, &tree_name)
+            .await
+            .map_err(|err| {
+                error!("Could not provision a Trillian tree: {}", err);
+                err
+            })?;
+    }
+    state.veracity_map = veracity_map_interval.map(|_| VeracityMap::new(state.trillian_tree));
+
+    // Refuse uploads while Trillian is unreachable and resume them when it is back
+    tokio::spawn(availability::run(state.clone()));
+    // Queue stored images to Trillian, track when they are integrated and notify API key holders
+    // in the background, for the default tree and every tenant's
+    for tree_state in std::iter::once(state.clone()).chain(state.tenant_states()) {
+        tokio::spawn(outbox::run(tree_state.clone()));
+        tokio::spawn(reconcile::run(tree_state.clone()));
+        tokio::spawn(webhooks::run(tree_state));
+    }
+    // Collect witness cosignatures of the latest checkpoint. Every tree would be checkpointed
+    // under the signing key's name as origin, which witnesses take to be one log, so only the
+    // default tree is offered to them.
+    tokio::spawn(witness::run(state.clone()));
+    // Verify every new log root is consistent with the last one seen, on every tree
+    if let Some(interval) = state.log_monitor_interval {
+        for tree_state in std::iter::once(state.clone()).chain(state.tenant_states()) {
+            tokio::spawn(monitor::run(tree_state, interval));
+        }
+    }
+    // Add stored images to the verifiable perceptual hash map
+    if let (Some(map), Some(interval)) = (state.veracity_map.clone(), veracity_map_interval) {
+        tokio::spawn(map::run(state.clone(), map, interval));
+    }
+    // Drop cached lookups as other instances invalidate them
+    if state.image_cache.is_shared() {
+        tokio::spawn(cache::run(state.clone()));
+    }
+    // Pick up maintenance jobs interrupted by the last shutdown
+    tokio::spawn(jobs::resume_running(state.clone()));
+    // Serve backend integrations over gRPC alongside the HTTP API
+    if let Some(grpc_addr) = grpc_listen {
+        let grpc_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = grpc::serve(grpc_addr, grpc_state, shutdown_signal()).await {
+                error!("gRPC server stopped: {}", err);
+            }
+        });
+    }
 
     let cors = CorsLayer::new()
         // allow any methods to access the resource
         .allow_methods(Any)
         // allow requests from any origin
-        .allow_origin(Any);
+        .allow_origin(Any)
+        // let browsers read response headers such as X-Request-Id
+        .expose_headers(Any);
+
+    let config = ResolvedConfig {
+        listen_address: addr.to_string(),
+        grpc_listen_address: grpc_listen.map(|addr| addr.to_string()),
+        trillian_address,
+        trillian_tree_id: state.trillian_tree,
+        trillian_ca_cert_path: trillian_ca_cert.map(|path| path.display().to_string()),
+        trillian_client_cert_path: trillian_client_cert.map(|path| path.display().to_string()),
+        database_url: redact_connection_string(&db_connection_uri),
+        database_read_url: database_read_url.as_deref().map(redact_connection_string),
+        database_password: env::var("DATABASE_PASSWORD")
+            .ok()
+            .map(|_| REDACTED.to_string()),
+        database_root_cert_path: env::var("DATABASE_ROOT_CERT_PATH").ok(),
+    };
+    let summary = StartupSummary::collect(&state, config).await;
+    summary.log();
 
     let app = app(&state)
         .finish_api_with(&mut api, api_docs)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            retry::retry_after,
+        ))
+        .layer(middleware::from_fn(negotiate::negotiate_format))
+        .layer(middleware::from_fn(request_id::request_id))
         .layer(cors)
         .layer(Extension(Arc::new(api)))
+        .layer(Extension(Arc::new(summary)))
         .with_state(state);
 
     // send it
-    let addr = if let Ok(addr) = env::var("LISTEN_ADDRESS") {
-        addr.parse()?
-    } else {
-        SocketAddr::from(([127, 0, 0, 1], 3000))
-    };
     debug!("Listening on {}", addr);
     let startup_duration = start.elapsed();
     info!("Startup time: {:?}", startup_duration);
-    match axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-    {
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let served = match tls_settings {
+        Some(settings) => {
+            if let Some(redirect_addr) = http_redirect_listen {
+                tokio::spawn(tls::redirect_http(redirect_addr, addr.port()));
+            }
+            tls::serve(addr, settings, make_service, shutdown_signal()).await
+        }
+        None => axum::Server::bind(&addr)
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .map_err(Report::from),
+    };
+    match served {
         Ok(_) => info!("Server shut down successfully"),
         Err(e) => error!("Could not shutdown server: {}", e.to_string()),
     };
@@ -106,32 +654,111 @@ fn app(state: &AppState) -> ApiRouter<AppState> {
         .nest_api_service("/docs", docs_routes(state.clone()))
 }
 
-async fn create_db_tables(state: &AppState) {
-    let pool = &state.db_pool.clone();
-    let conn = pool.get().await.expect("database connection");
-    // Create the "images" table.
-    match conn
-        .execute(
-            "CREATE TABLE IF NOT EXISTS images (c_hash BYTES NOT NULL PRIMARY KEY, p_hash BYTES NOT NULL)",
-            &[],
-        )
-        .await {
-        Ok(result) => {
-            info!("Create table result {}", result);
+/// Apply the migrations in `migrations/` that have not run yet, to the default schema and then
+/// to each tenant's
+async fn create_db_tables(state: &AppState) -> Result<()> {
+    migrate(state).await?;
+    for (name, tenant) in state.tenants() {
+        let create_schema = format!("CREATE SCHEMA IF NOT EXISTS {}", tenant.schema);
+        if let Err(err) = sqlx::query(&create_schema).execute(&state.db_pool).await {
+            error!("Could not create schema for tenant {}: {}", name, err);
+            return Err(err.into());
         }
-        Err(err) => error!("{}", err)
-    };
-    match conn
-        .execute(
-            "CREATE UNIQUE INDEX IF NOT EXISTS images_p_hash_index ON images (p_hash)",
-            &[],
-        )
-        .await
-    {
-        Ok(result) => {
-            info!("Create p_hash index result {}", result);
+        if let Some(tenant_state) = state.for_tenant(name) {
+            migrate(&tenant_state).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn migrate(state: &AppState) -> Result<()> {
+    let mut migrator = sqlx::migrate!();
+    // CockroachDB has no advisory locks to hold while migrating
+    migrator.set_locking(false);
+    match migrator.run(&state.db_pool).await {
+        Ok(()) => info!("Database migrations applied"),
+        // Image routes keep working without the database when images are kept elsewhere
+        Err(err) if state.images.backend() != "postgres" => {
+            error!("Could not migrate the database: {}", err);
+            return Ok(());
+        }
+        Err(err) => {
+            error!("Could not migrate the database: {}", err);
+            return Err(err.into());
+        }
+    }
+
+    if state.perceptual_index == PerceptualIndex::PgVector {
+        create_pgvector_index(&state.db_pool).await;
+    }
+    Ok(())
+}
+
+/// Add a pgvector bit column mirroring p_hash with an HNSW Hamming-distance index.
+/// Requires PostgreSQL with the pgvector extension (0.7 or later) available.
+async fn create_pgvector_index(pool: &ConnectionPool) {
+    run_schema_statements(
+        pool,
+        &[
+            ("Create vector extension", "CREATE EXTENSION IF NOT EXISTS vector"),
+            (
+                "Add p_vec column",
+                "ALTER TABLE images ADD COLUMN IF NOT EXISTS p_vec bit(256)",
+            ),
+            (
+                "Backfill p_vec column",
+                "UPDATE images SET p_vec = ('x' || encode(p_hash, 'hex'))::bit(256) WHERE p_vec IS NULL",
+            ),
+            (
+                "Create p_vec index",
+                "CREATE INDEX IF NOT EXISTS images_p_vec_index ON images USING hnsw (p_vec bit_hamming_ops)",
+            ),
+        ],
+    )
+    .await;
+}
+
+/// Enable each attestation format whose settings are present. Invalid settings fail startup
+/// rather than silently rejecting every attested upload.
+fn attestations(args: AttestationArgs) -> Result<Attestations> {
+    let mut attestations = Attestations::default();
+
+    if let (Some(package_name), Some(decryption_key), Some(verification_key)) = (
+        args.play_integrity_package_name,
+        args.play_integrity_decryption_key,
+        args.play_integrity_verification_key,
+    ) {
+        attestations.play_integrity = Some(
+            PlayIntegrity::new(&decryption_key, &verification_key, package_name).map_err(
+                |err| {
+                    error!("Could not load Play Integrity keys: {}", err);
+                    Report::from(err)
+                },
+            )?,
+        );
+    }
+
+    if let (Some(app_id), Some(root_ca)) = (args.app_attest_app_id, args.app_attest_root_ca) {
+        let root_ca = fs::read(root_ca)?;
+        let allow_development = args.app_attest_allow_development;
+        attestations.app_attest = Some(
+            AppAttest::new(app_id, &root_ca, allow_development).map_err(|err| {
+                error!("Could not load App Attest root certificate: {}", err);
+                Report::from(err)
+            })?,
+        );
+    }
+
+    Ok(attestations)
+}
+
+/// Run idempotent schema statements in order, logging rather than failing on errors
+async fn run_schema_statements(pool: &ConnectionPool, statements: &[(&str, &str)]) {
+    for (description, statement) in statements {
+        match sqlx::query(statement).execute(pool).await {
+            Ok(result) => info!("{} result {}", description, result.rows_affected()),
+            Err(err) => error!("{}: {}", description, err),
         }
-        Err(err) => error!("{}", err),
     }
 }
 
@@ -175,17 +802,23 @@ fn api_docs(api: TransformOpenApi) -> TransformOpenApi {
             aide::openapi::SecurityScheme::ApiKey {
                 location: aide::openapi::ApiKeyLocation::Header,
                 name: "X-Auth-Key".into(),
-                description: Some("A key that is ignored.".into()),
+                description: Some(
+                    "API key with the scope an operation needs: upload, read or admin. \
+                    Only required when the server has API keys enabled."
+                        .into(),
+                ),
                 extensions: Default::default(),
             },
         )
         .default_response_with::<Json<AppError>, _>(|res| {
             res.example(AppError {
                 error: "some error happened".to_string(),
+                code: ErrorCode::Internal,
                 error_details: None,
                 error_id: Uuid::nil(),
                 // This is not visible.
                 status: StatusCode::IM_A_TEAPOT,
+                retryable: false,
             })
         })
 }
@@ -0,0 +1,353 @@
+//! Verifiable map from perceptual hashes to crypto hashes.
+//!
+//! Every stored image is a leaf of a sparse Merkle tree, keyed by the SHA-256 of its perceptual
+//! hash and holding its crypto hash. A proof from the map answers whether a perceptual hash is
+//! known either way: an inclusion proof leads from the crypto hash to the root, and a
+//! non-inclusion proof shows the key's leaf is empty. Nodes are hashed the way Trillian maps
+//! hash them, with the CONIKS hasher under the log's tree ID, so the map commits to the log it
+//! mirrors.
+//!
+//! The map is kept in memory and rebuilt from the database when the server starts. Each batch
+//! of images read from the database is added as one revision.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Result};
+use metrics::gauge;
+use ring::digest::{digest, SHA256};
+use smt::hasher::coniks::ConiksHasher;
+use smt::hasher::{NodeHasher, BIT_LENGTH};
+use smt::node::id::ID;
+use smt::node::{Node, NodesRow};
+use smt::proof::{self, Proof};
+use smt::revision::RevisionedTree;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, instrument};
+
+use crate::state::AppState;
+
+/// Height of the tiles proofs are read from
+const TILE_HEIGHT: usize = 8;
+/// Most images added to the map per revision
+const BATCH_SIZE: i64 = 1000;
+/// Images are only added once they are this old, so rows inserted by transactions that commit
+/// out of order are not skipped by the cursor
+const SETTLE_SECONDS: i64 = 60;
+
+/// An image read into the map, with the cursor position it moves to
+struct ImagePosition {
+    c_hash: Vec<u8>,
+    p_hash: Vec<u8>,
+    created_at: DateTime<Utc>,
+}
+
+/// Latest state of the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapRoot {
+    pub revision: u64,
+    pub root_hash: [u8; 32],
+}
+
+/// Answer to whether a perceptual hash is in the map, with its proof against a root
+#[derive(Debug)]
+pub struct MapProof {
+    pub root: MapRoot,
+    /// Crypto hash of the image with the perceptual hash, None if there is none
+    pub crypto_hash: Option<[u8; 32]>,
+    pub proof: Proof,
+}
+
+/// Sparse Merkle map from perceptual hashes to crypto hashes, shared by every request
+#[derive(Clone)]
+pub struct VeracityMap {
+    inner: Arc<RwLock<Inner>>,
+}
+
+struct Inner {
+    tree: RevisionedTree<ConiksHasher>,
+    /// Crypto hash of every perceptual hash in the tree
+    values: HashMap<[u8; 32], [u8; 32]>,
+}
+
+impl VeracityMap {
+    /// An empty map whose nodes commit to the given Trillian tree
+    pub fn new(tree_id: i64) -> Self {
+        VeracityMap {
+            inner: Arc::new(RwLock::new(Inner {
+                tree: RevisionedTree::new(ConiksHasher, tree_id),
+                values: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn root(&self) -> MapRoot {
+        self.inner.read().expect("map lock poisoned").root()
+    }
+
+    /// Number of perceptual hashes in the map
+    pub fn len(&self) -> usize {
+        self.inner.read().expect("map lock poisoned").values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add (perceptual hash, crypto hash) entries as the next revision, returning how many
+    /// changed the map. Nothing is added if any entry fails, and no revision is made if none
+    /// changes the map.
+    pub fn insert(&self, entries: &[([u8; 32], [u8; 32])]) -> Result<usize> {
+        let mut inner = self.inner.write().expect("map lock poisoned");
+        let changed: HashMap<[u8; 32], [u8; 32]> = entries
+            .iter()
+            .filter(|(p_hash, c_hash)| inner.values.get(p_hash) != Some(c_hash))
+            .copied()
+            .collect();
+        if changed.is_empty() {
+            return Ok(0);
+        }
+
+        let tree_id = inner.tree.tree_id();
+        let leaves = changed
+            .iter()
+            .map(|(p_hash, c_hash)| {
+                let id = key(p_hash);
+                let hash = inner.tree.hasher().hash_leaf(tree_id, &id, c_hash);
+                Arc::new(Node::new(id, hash))
+            })
+            .collect();
+        let leaves = NodesRow::try_new(leaves).map_err(|err| eyre!(err))?;
+        let mut batch = inner.tree.begin();
+        batch.update(leaves).map_err(|err| eyre!(err))?;
+        batch.commit();
+
+        let count = changed.len();
+        inner.values.extend(changed);
+        Ok(count)
+    }
+
+    /// Prove whether the perceptual hash is in the latest revision of the map
+    pub fn prove(&self, p_hash: &[u8; 32]) -> Result<MapProof> {
+        let inner = self.inner.read().expect("map lock poisoned");
+        let revision = inner.tree.revision();
+        let key = key(p_hash);
+        let tiles = inner
+            .tree
+            .path_tiles(&key, TILE_HEIGHT, revision)
+            .map_err(|err| eyre!(err))?;
+        let proof = proof::prove(inner.tree.hasher(), inner.tree.tree_id(), &tiles, &key)
+            .map_err(|err| eyre!(err))?;
+        Ok(MapProof {
+            root: inner.root(),
+            crypto_hash: inner.values.get(p_hash).copied(),
+            proof,
+        })
+    }
+}
+
+impl Inner {
+    fn root(&self) -> MapRoot {
+        let revision = self.tree.revision();
+        MapRoot {
+            revision,
+            root_hash: self
+                .tree
+                .root(revision)
+                .expect("latest revision has a root"),
+        }
+    }
+}
+
+/// Leaf a perceptual hash is kept at, the SHA-256 of the hash so that leaves are spread evenly
+/// however similar the images are
+pub fn key(p_hash: &[u8; 32]) -> ID {
+    ID::new_id(digest(&SHA256, p_hash).as_ref(), BIT_LENGTH)
+}
+
+/// Check a proof that the perceptual hash maps to the crypto hash, or is absent if None, in the
+/// map with the given root hash
+pub fn verify(
+    tree_id: i64,
+    root_hash: &[u8; 32],
+    p_hash: &[u8; 32],
+    crypto_hash: Option<&[u8; 32]>,
+    proof: &Proof,
+) -> Result<()> {
+    proof::verify(
+        &ConiksHasher,
+        tree_id,
+        root_hash,
+        &key(p_hash),
+        crypto_hash.map(|c_hash| &c_hash[..]),
+        proof,
+    )
+    .map_err(|err| eyre!(err))
+}
+
+/// Add stored images to the map every `interval` until the process exits
+pub async fn run(state: AppState, map: VeracityMap, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Images up to here are in the map, in (created_at, c_hash) order
+    let mut cursor: Option<(DateTime<Utc>, Vec<u8>)> = None;
+    loop {
+        interval.tick().await;
+        match sync(&state, &map, &mut cursor).await {
+            Ok(0) => {}
+            Ok(added) => debug!("Added {} images to the veracity map", added),
+            Err(err) => error!("Veracity map: {}", err),
+        }
+        gauge!("veracity_map_size", map.len() as f64);
+    }
+}
+
+/// Add the images stored since the cursor to the map, one revision per batch
+#[instrument(skip_all)]
+async fn sync(
+    state: &AppState,
+    map: &VeracityMap,
+    cursor: &mut Option<(DateTime<Utc>, Vec<u8>)>,
+) -> Result<usize> {
+    let mut added = 0;
+    loop {
+        let rows = match cursor.as_ref() {
+            None => {
+                sqlx::query_as!(
+                    ImagePosition,
+                    "SELECT c_hash, p_hash, created_at FROM images \
+                    WHERE created_at < now() - $2::INT8 * INTERVAL '1 second' \
+                    ORDER BY created_at, c_hash LIMIT $1",
+                    BATCH_SIZE,
+                    SETTLE_SECONDS,
+                )
+                .fetch_all(&state.db_pool)
+                .await?
+            }
+            Some((created_at, c_hash)) => {
+                sqlx::query_as!(
+                    ImagePosition,
+                    "SELECT c_hash, p_hash, created_at FROM images \
+                    WHERE (created_at, c_hash) > ($1::TIMESTAMPTZ, $2::BYTEA) \
+                    AND created_at < now() - $4::INT8 * INTERVAL '1 second' \
+                    ORDER BY created_at, c_hash LIMIT $3",
+                    created_at,
+                    c_hash,
+                    BATCH_SIZE,
+                    SETTLE_SECONDS,
+                )
+                .fetch_all(&state.db_pool)
+                .await?
+            }
+        };
+        let Some(last) = rows.last() else {
+            return Ok(added);
+        };
+        let next = (last.created_at, last.c_hash.clone());
+
+        let entries = rows
+            .iter()
+            .map(|row| {
+                Ok((
+                    row.p_hash
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| eyre!("perceptual hash is not 32 bytes"))?,
+                    row.c_hash
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| eyre!("crypto hash is not 32 bytes"))?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let batch = map.clone();
+        added += tokio::task::spawn_blocking(move || batch.insert(&entries)).await??;
+        *cursor = Some(next);
+
+        if rows.len() < BATCH_SIZE as usize {
+            return Ok(added);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TREE_ID: i64 = 42;
+
+    fn entry(n: u8) -> ([u8; 32], [u8; 32]) {
+        ([n; 32], [n.wrapping_add(100); 32])
+    }
+
+    #[test]
+    fn proves_inclusion_and_absence() {
+        let map = VeracityMap::new(TREE_ID);
+        assert_eq!(map.insert(&[entry(1), entry(2), entry(3)]).unwrap(), 3);
+        let root = map.root();
+        assert_eq!(root.revision, 1);
+
+        let (p_hash, c_hash) = entry(2);
+        let proof = map.prove(&p_hash).unwrap();
+        assert_eq!(proof.root, root);
+        assert_eq!(proof.crypto_hash, Some(c_hash));
+        verify(
+            TREE_ID,
+            &root.root_hash,
+            &p_hash,
+            Some(&c_hash),
+            &proof.proof,
+        )
+        .unwrap();
+        assert!(verify(TREE_ID, &root.root_hash, &p_hash, None, &proof.proof).is_err());
+        assert!(verify(
+            TREE_ID + 1,
+            &root.root_hash,
+            &p_hash,
+            Some(&c_hash),
+            &proof.proof
+        )
+        .is_err());
+
+        let absent = [9; 32];
+        let proof = map.prove(&absent).unwrap();
+        assert_eq!(proof.crypto_hash, None);
+        verify(TREE_ID, &root.root_hash, &absent, None, &proof.proof).unwrap();
+        assert!(verify(
+            TREE_ID,
+            &root.root_hash,
+            &absent,
+            Some(&c_hash),
+            &proof.proof
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn empty_map_proves_absence() {
+        let map = VeracityMap::new(TREE_ID);
+        let root = map.root();
+        assert_eq!(root.revision, 0);
+        let proof = map.prove(&[1; 32]).unwrap();
+        verify(TREE_ID, &root.root_hash, &[1; 32], None, &proof.proof).unwrap();
+    }
+
+    #[test]
+    fn unchanged_entries_make_no_revision() {
+        let map = VeracityMap::new(TREE_ID);
+        map.insert(&[entry(1), entry(2)]).unwrap();
+        let root = map.root();
+        assert_eq!(map.insert(&[entry(1)]).unwrap(), 0);
+        assert_eq!(map.root(), root);
+
+        // Batches add up to the same map as a single insert
+        assert_eq!(map.insert(&[entry(2), entry(3)]).unwrap(), 1);
+        let single = VeracityMap::new(TREE_ID);
+        single.insert(&[entry(1), entry(2), entry(3)]).unwrap();
+        assert_eq!(map.root().root_hash, single.root().root_hash);
+        assert_eq!(map.root().revision, 2);
+        assert_eq!(map.len(), 3);
+    }
+}
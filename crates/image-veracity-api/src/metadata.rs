@@ -0,0 +1,164 @@
+//! Provenance context uploaded alongside an image.
+//!
+//! Clients may send a `metadata` JSON part with a multipart upload, such as a caption, where
+//! the image came from and claims about how it was captured. It is validated against
+//! [`UploadMetadata`], stored with the image, and when configured logged after the perceptual
+//! hash in the leaf's extra data so it travels with the hashes to mirrors and auditors.
+
+use chrono::{DateTime, Utc};
+use eyre::{bail, eyre, Result};
+use reqwest::Url;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Largest metadata part accepted, in bytes
+pub const MAX_METADATA_BYTES: usize = 4096;
+/// Longest caption accepted, in characters
+const MAX_CAPTION_CHARS: usize = 1000;
+/// Longest device description accepted, in characters
+const MAX_DEVICE_CHARS: usize = 200;
+
+/// What the uploader says about an image. Nothing in it is verified beyond its shape.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UploadMetadata {
+    /// What the image shows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    /// Where the image was published or obtained, an `http` or `https` URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    /// How the image was captured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capture: Option<CaptureClaims>,
+}
+
+/// Claims about when, where and with what an image was captured
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureClaims {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<DateTime<Utc>>,
+    /// Camera or phone model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Degrees north, from -90 to 90
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    /// Degrees east, from -180 to 180
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+}
+
+impl UploadMetadata {
+    /// Read and validate a metadata part
+    pub fn parse(json: &[u8]) -> Result<Self> {
+        if json.len() > MAX_METADATA_BYTES {
+            bail!("must be at most {MAX_METADATA_BYTES} bytes");
+        }
+        let metadata: UploadMetadata =
+            serde_json::from_slice(json).map_err(|err| eyre!("is not valid metadata: {err}"))?;
+        metadata.validate()?;
+        Ok(metadata)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self
+            .caption
+            .as_ref()
+            .is_some_and(|caption| caption.chars().count() > MAX_CAPTION_CHARS)
+        {
+            bail!("caption must be at most {MAX_CAPTION_CHARS} characters");
+        }
+        if let Some(source_url) = &self.source_url {
+            let url = Url::parse(source_url).map_err(|err| eyre!("source_url {err}"))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("source_url must be an http or https URL");
+            }
+        }
+        let Some(capture) = &self.capture else {
+            return Ok(());
+        };
+        if capture
+            .device
+            .as_ref()
+            .is_some_and(|device| device.chars().count() > MAX_DEVICE_CHARS)
+        {
+            bail!("capture.device must be at most {MAX_DEVICE_CHARS} characters");
+        }
+        if capture.latitude.is_some() != capture.longitude.is_some() {
+            bail!("capture.latitude and capture.longitude must be sent together");
+        }
+        if capture
+            .latitude
+            .is_some_and(|latitude| !(-90.0..=90.0).contains(&latitude))
+        {
+            bail!("capture.latitude must be between -90 and 90");
+        }
+        if capture
+            .longitude
+            .is_some_and(|longitude| !(-180.0..=180.0).contains(&longitude))
+        {
+            bail!("capture.longitude must be between -180 and 180");
+        }
+        Ok(())
+    }
+
+    /// Compact JSON logged after the perceptual hash in the leaf's extra data
+    pub fn to_extra_data(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("metadata serializes to JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_round_trips_through_extra_data() {
+        let metadata = UploadMetadata::parse(
+            br#"{
+                "caption": "Flooding on Main Street",
+                "source_url": "https://example.com/news/42",
+                "capture": {
+                    "captured_at": "2023-06-20T12:00:00Z",
+                    "latitude": 51.5,
+                    "longitude": -0.1
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            metadata
+                .capture
+                .as_ref()
+                .and_then(|capture| capture.latitude),
+            Some(51.5)
+        );
+        let logged: UploadMetadata = serde_json::from_slice(&metadata.to_extra_data()).unwrap();
+        assert_eq!(logged, metadata);
+    }
+
+    #[test]
+    fn metadata_is_validated() {
+        for json in [
+            r#"{"author": "someone"}"#,
+            r#"{"caption": 42}"#,
+            r#"{"source_url": "ftp://example.com/photo.jpg"}"#,
+            r#"{"source_url": "not a url"}"#,
+            r#"{"capture": {"latitude": 91, "longitude": 0}}"#,
+            r#"{"capture": {"latitude": 10}}"#,
+            "[]",
+        ] {
+            assert!(UploadMetadata::parse(json.as_bytes()).is_err(), "{json}");
+        }
+        let caption = "a".repeat(MAX_CAPTION_CHARS + 1);
+        assert!(
+            UploadMetadata::parse(format!(r#"{{"caption": "{caption}"}}"#).as_bytes()).is_err()
+        );
+        assert_eq!(
+            UploadMetadata::parse(b"{}").unwrap(),
+            UploadMetadata::default()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Built-in monitor for the Trillian log this server writes to.
+//!
+//! Every poll fetches the latest signed root and checks it against the last root seen: a larger
+//! tree has to come with a valid consistency proof, and a tree of the same size must have the
+//! same root hash. Each new root is recorded in `log_roots`, so the history survives restarts
+//! and can be audited later. Anything else is an inconsistency, which is logged, counted, and
+//! sent to every registered webhook.
+
+use std::time::Duration;
+
+use eyre::Result;
+use metrics::{gauge, increment_counter};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, instrument};
+
+use trillian::client::LogRoot;
+
+use crate::state::AppState;
+use crate::webhooks::{self, InconsistencyEvent};
+
+/// Check the log every `interval` until the process exits
+pub async fn run(state: AppState, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(err) = check_latest_root(&state).await {
+            error!("Log monitor: {}", err);
+        }
+    }
+}
+
+/// Compare the latest root with the last one seen, recording it if it is new
+#[instrument(skip_all)]
+async fn check_latest_root(state: &AppState) -> Result<()> {
+    let previous = sqlx::query_as!(
+        LogRoot,
+        "SELECT tree_size, root_hash FROM log_roots WHERE tree_id = $1 \
+        ORDER BY observed_at DESC LIMIT 1",
+        state.trillian_tree,
+    )
+    .fetch_optional(&state.db_pool)
+    .await?;
+
+    // Ask for the proof from the last size seen with the root, so both describe the same tree
+    let signed = state
+        .trillian
+        .clone()
+        .get_latest_signed_log_root(
+            &state.trillian_tree,
+            previous.as_ref().map(|previous| previous.tree_size),
+        )
+        .await?;
+    let proof = signed.consistency_proof.unwrap_or_default();
+    let root = LogRoot::try_from(signed.root)?;
+    gauge!("veracity_log_tree_size", root.tree_size as f64);
+
+    let problem = match &previous {
+        None => None,
+        Some(previous) if *previous == root => return Ok(()),
+        Some(previous) if root.tree_size < previous.tree_size => {
+            Some("the tree shrank".to_string())
+        }
+        Some(previous) if root.tree_size == previous.tree_size => {
+            Some("the root hash changed without the tree growing".to_string())
+        }
+        Some(previous) => veracity_verify::verify_consistency(
+            previous.tree_size as u64,
+            root.tree_size as u64,
+            &previous.root_hash,
+            &root.root_hash,
+            &proof,
+        )
+        .err()
+        .map(|err| format!("the consistency proof does not verify: {err}")),
+    };
+
+    let mut tx = state.db_pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO log_roots (tree_id, tree_size, root_hash, consistent) VALUES ($1, $2, $3, $4)",
+        state.trillian_tree,
+        root.tree_size,
+        &root.root_hash,
+        problem.is_none(),
+    )
+    .execute(&mut *tx)
+    .await?;
+    match (&previous, problem) {
+        (Some(previous), Some(reason)) => {
+            error!(
+                "Log {} is inconsistent: {} (size {} root {} after size {} root {})",
+                state.trillian_tree,
+                reason,
+                root.tree_size,
+                hex::encode(&root.root_hash),
+                previous.tree_size,
+                hex::encode(&previous.root_hash)
+            );
+            increment_counter!("veracity_log_inconsistencies_total");
+            let event = InconsistencyEvent {
+                event: "log.inconsistent".to_string(),
+                tree_id: state.trillian_tree,
+                previous_tree_size: previous.tree_size,
+                previous_root_hash: hex::encode(&previous.root_hash),
+                tree_size: root.tree_size,
+                root_hash: hex::encode(&root.root_hash),
+                reason,
+            };
+            webhooks::broadcast(&mut tx, &event).await?;
+        }
+        (Some(previous), None) => debug!(
+            "Log {} grew consistently from {} to {} leaves",
+            state.trillian_tree, previous.tree_size, root.tree_size
+        ),
+        (None, _) => info!(
+            "Log monitor started tracking tree {} at size {}",
+            state.trillian_tree, root.tree_size
+        ),
+    }
+    tx.commit().await?;
+    Ok(())
+}
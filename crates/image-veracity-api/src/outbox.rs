@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use eyre::Result;
+use metrics::increment_counter;
+use sqlx::PgConnection;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, instrument, warn};
+
+use trillian::TrillianChargeTo;
+
+use crate::extractors::Submitter;
+use crate::hash::VeracityHash;
+use crate::metadata::UploadMetadata;
+use crate::record::{IntegrationStatus, LeafDetails};
+use crate::state::AppState;
+
+/// How often the worker looks for entries that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Most entries claimed per poll
+const BATCH_SIZE: i64 = 32;
+/// How long a claimed entry is hidden from other workers, in case this one dies mid-publish
+const CLAIM_LEASE_SECONDS: i64 = 300;
+const BASE_RETRY_SECONDS: i64 = 1;
+const MAX_RETRY_SECONDS: i64 = 600;
+
+/// Record that `hash` still has to be queued to Trillian, charging its quota to `submitter`,
+/// with `metadata` logged after the perceptual hash in its extra data.
+/// Call inside the transaction that stores the image so neither exists without the other.
+pub async fn enqueue(
+    tx: &mut PgConnection,
+    hash: &VeracityHash,
+    submitter: Option<&Submitter>,
+    metadata: Option<&UploadMetadata>,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO trillian_outbox (c_hash, p_hash, charge_to, metadata) \
+        VALUES ($1, $2, $3, $4)",
+        &hash.crypto_hash.as_ref()[..],
+        &hash.perceptual_hash.as_ref()[..],
+        submitter.map(Submitter::quota_user),
+        metadata.map(UploadMetadata::to_extra_data),
+    )
+    .execute(tx)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// Queue outbox entries to Trillian until the process exits.
+/// Failed entries are retried with exponential backoff. Retrying an entry that did reach Trillian
+/// is safe: leaves are identified by the crypto hash, so the log returns the existing leaf for an
+/// image it already holds.
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match publish_due(&state).await {
+            Ok(0) => {}
+            Ok(published) => debug!("Published {} outbox entries", published),
+            Err(err) => error!("Outbox worker: {}", err),
+        }
+    }
+}
+
+/// Claim due entries, queue them to Trillian and store the returned leaf details
+#[instrument(skip_all)]
+async fn publish_due(state: &AppState) -> Result<usize> {
+    let rows = sqlx::query!(
+        "UPDATE trillian_outbox \
+        SET next_attempt_at = now() + $2::INT8 * INTERVAL '1 second' \
+        WHERE c_hash IN (\
+            SELECT c_hash FROM trillian_outbox WHERE next_attempt_at <= now() \
+            ORDER BY next_attempt_at LIMIT $1\
+        ) \
+        RETURNING c_hash, p_hash, charge_to, attempts, metadata",
+        BATCH_SIZE,
+        CLAIM_LEASE_SECONDS,
+    )
+    .fetch_all(&state.db_pool)
+    .await?;
+
+    let mut trillian = state.trillian.clone();
+    let mut published = 0;
+    for row in rows {
+        let c_hash = row.c_hash;
+        let p_hash = row.p_hash;
+        let charge_to = row
+            .charge_to
+            .map(|user| TrillianChargeTo { user: vec![user] });
+        let attempts = row.attempts;
+        let mut extra_data = p_hash.clone();
+        if let Some(metadata) = row.metadata {
+            extra_data.extend_from_slice(&metadata);
+        }
+
+        match trillian
+            .add_leaf(
+                &state.trillian_tree,
+                &c_hash,
+                &extra_data,
+                Some(c_hash.as_slice()),
+                charge_to,
+            )
+            .await
+        {
+            Ok(queued) => {
+                if queued.already_existed {
+                    debug!(
+                        "{} was already in the log at index {}",
+                        hex::encode(&c_hash),
+                        queued.leaf.leaf_index
+                    );
+                    increment_counter!("veracity_outbox_duplicates_total");
+                }
+                let mut tx = state.db_pool.begin().await?;
+                record_leaf(&mut tx, &c_hash, &LeafDetails::from(&queued.leaf)).await?;
+                tx.commit().await?;
+                state.image_cache.invalidate(&c_hash, &p_hash).await;
+                state.status_events.publish(&c_hash);
+                increment_counter!("veracity_outbox_published_total");
+                published += 1;
+            }
+            Err(err) => {
+                let delay = retry_delay(attempts);
+                warn!(
+                    "Could not queue {} to Trillian (attempt {}), retrying in {}s: {}",
+                    hex::encode(&c_hash),
+                    attempts + 1,
+                    delay,
+                    err
+                );
+                increment_counter!("veracity_outbox_failures_total");
+                sqlx::query!(
+                    "UPDATE trillian_outbox \
+                    SET attempts = attempts + 1, \
+                    next_attempt_at = now() + $2::INT8 * INTERVAL '1 second', \
+                    last_error = $3 \
+                    WHERE c_hash = $1",
+                    &c_hash,
+                    delay,
+                    err.to_string(),
+                )
+                .execute(&state.db_pool)
+                .await?;
+            }
+        }
+    }
+    Ok(published)
+}
+
+/// Store the leaf details on the image and drop its outbox entry
+async fn record_leaf(
+    tx: &mut PgConnection,
+    c_hash: &[u8],
+    leaf: &LeafDetails,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE images SET status = $2, leaf_index = $3, merkle_leaf_hash = decode($4, 'hex'), \
+        queue_timestamp = $5, integrate_timestamp = $6 WHERE c_hash = $1",
+        c_hash,
+        IntegrationStatus::of_leaf(leaf).as_str(),
+        leaf.leaf_index,
+        leaf.merkle_leaf_hash.as_deref(),
+        leaf.queue_timestamp,
+        leaf.integrate_timestamp,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM trillian_outbox WHERE c_hash = $1", c_hash)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+/// Seconds to wait before retrying an entry that has already failed `attempts` times
+pub(crate) fn retry_delay(attempts: i64) -> i64 {
+    let exponent = attempts.clamp(0, 20) as u32;
+    (BASE_RETRY_SECONDS << exponent).min(MAX_RETRY_SECONDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_backs_off_to_a_cap() {
+        assert_eq!(retry_delay(0), 1);
+        assert_eq!(retry_delay(1), 2);
+        assert_eq!(retry_delay(5), 32);
+        assert_eq!(retry_delay(10), MAX_RETRY_SECONDS);
+        assert_eq!(retry_delay(i64::MAX), MAX_RETRY_SECONDS);
+    }
+}
@@ -0,0 +1,4 @@
+#![allow(warnings)]
+#![allow(clippy)]
+#![allow(unknown_lints)]
+pub mod veracity;
@@ -0,0 +1,67 @@
+//! Trillian tree for deployments started without `TRILLIAN_TREE_ID`.
+//!
+//! The first instance to start creates and initializes a `LOG` tree through the admin service
+//! and records its ID in `provisioned_tree`, where every later start finds it again. Instances
+//! racing to create it each make a tree, but only one is recorded and the others are deleted.
+
+use eyre::{eyre, Result};
+use tracing::{info, instrument, warn};
+
+use trillian::TrillianTreeType;
+
+use crate::state::AppState;
+
+/// Display name of a provisioned tree when none is configured
+pub const DEFAULT_TREE_NAME: &str = "image-veracity";
+
+/// ID of the tree provisioned for this deployment, creating it named `name` on first start
+#[instrument(skip(state))]
+pub async fn provision_tree(state: &AppState, name: &str) -> Result<i64> {
+    if let Some(tree_id) = provisioned_tree(state).await? {
+        info!("Using provisioned Trillian tree {}", tree_id);
+        return Ok(tree_id);
+    }
+
+    let mut trillian = state.trillian.clone();
+    let tree = trillian
+        .create_tree(
+            name,
+            "Images logged by image-veracity",
+            TrillianTreeType::Log,
+        )
+        .await?;
+    let recorded = sqlx::query!(
+        "INSERT INTO provisioned_tree (tree_id, display_name) VALUES ($1, $2) \
+        ON CONFLICT (singleton) DO NOTHING",
+        tree.tree_id,
+        name,
+    )
+    .execute(&state.db_pool)
+    .await?;
+    if recorded.rows_affected() == 1 {
+        info!(
+            "Created Trillian tree {}, set TRILLIAN_TREE_ID to pin it",
+            tree.tree_id
+        );
+        return Ok(tree.tree_id);
+    }
+
+    // Another instance recorded its tree first
+    let tree_id = provisioned_tree(state)
+        .await?
+        .ok_or_else(|| eyre!("provisioned Trillian tree disappeared"))?;
+    warn!(
+        "Deleting Trillian tree {} as another instance provisioned {} first",
+        tree.tree_id, tree_id
+    );
+    if let Err(err) = trillian.delete_tree(&tree.tree_id).await {
+        warn!("Could not delete unused tree {}: {}", tree.tree_id, err);
+    }
+    Ok(tree_id)
+}
+
+async fn provisioned_tree(state: &AppState) -> Result<Option<i64>> {
+    Ok(sqlx::query_scalar!("SELECT tree_id FROM provisioned_tree")
+        .fetch_optional(&state.db_pool)
+        .await?)
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::hash::cryptographic::CryptographicHash;
+
+/// How images are identified on endpoints that enumerate the registry.
+///
+/// Lookups by hash are unaffected: whoever holds an image can always compute its hashes and ask
+/// for it directly. Opaque IDs only stop the listing from handing out every stored hash.
+#[derive(Clone, Default)]
+pub enum PublicIds {
+    /// Identify images by their hex crypto hash
+    #[default]
+    CryptoHash,
+    /// Identify images by a keyed HMAC of their crypto hash and hide hashes from listings
+    Hmac(Arc<HmacIds>),
+}
+
+pub struct HmacIds {
+    id_key: hmac::Key,
+    cursor_key: LessSafeKey,
+}
+
+impl PublicIds {
+    /// Opaque IDs keyed by `secret`. The same secret always yields the same IDs.
+    pub fn hmac(secret: &[u8]) -> PublicIds {
+        let root = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let derive = |label: &[u8]| hmac::sign(&root, label);
+
+        let id_key = hmac::Key::new(hmac::HMAC_SHA256, derive(b"image-veracity id").as_ref());
+        let cursor_key = LessSafeKey::new(
+            UnboundKey::new(
+                &CHACHA20_POLY1305,
+                derive(b"image-veracity cursor").as_ref(),
+            )
+            .expect("HMAC-SHA256 output is a valid ChaCha20-Poly1305 key"),
+        );
+        PublicIds::Hmac(Arc::new(HmacIds { id_key, cursor_key }))
+    }
+
+    /// Whether listings may include the stored hashes
+    pub fn reveals_hashes(&self) -> bool {
+        matches!(self, PublicIds::CryptoHash)
+    }
+
+    /// Public ID of the image with this crypto hash
+    pub fn id(&self, crypto_hash: &CryptographicHash) -> String {
+        match self {
+            PublicIds::CryptoHash => crypto_hash.to_hex(),
+            PublicIds::Hmac(ids) => {
+                BASE64_URL_SAFE_NO_PAD.encode(hmac::sign(&ids.id_key, crypto_hash.as_ref()))
+            }
+        }
+    }
+
+    /// Encode a pagination cursor. With opaque IDs the cursor is encrypted, since it has to carry
+    /// the crypto hash of the last image on the page.
+    pub fn seal_cursor(&self, cursor: &str) -> String {
+        match self {
+            PublicIds::CryptoHash => BASE64_URL_SAFE_NO_PAD.encode(cursor),
+            PublicIds::Hmac(ids) => {
+                let mut nonce = [0u8; NONCE_LEN];
+                SystemRandom::new()
+                    .fill(&mut nonce)
+                    .expect("system random source available");
+                let mut sealed = cursor.as_bytes().to_vec();
+                ids.cursor_key
+                    .seal_in_place_append_tag(
+                        Nonce::assume_unique_for_key(nonce),
+                        Aad::empty(),
+                        &mut sealed,
+                    )
+                    .expect("cursor fits in a single ChaCha20-Poly1305 message");
+                sealed.splice(0..0, nonce);
+                BASE64_URL_SAFE_NO_PAD.encode(sealed)
+            }
+        }
+    }
+
+    /// Decode a cursor from [`PublicIds::seal_cursor`], `None` if it was tampered with
+    pub fn open_cursor(&self, cursor: &str) -> Option<String> {
+        let mut decoded = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        match self {
+            PublicIds::CryptoHash => String::from_utf8(decoded).ok(),
+            PublicIds::Hmac(ids) => {
+                if decoded.len() < NONCE_LEN {
+                    return None;
+                }
+                let mut sealed = decoded.split_off(NONCE_LEN);
+                let nonce = Nonce::try_assume_unique_for_key(&decoded).ok()?;
+                let opened = ids
+                    .cursor_key
+                    .open_in_place(nonce, Aad::empty(), &mut sealed)
+                    .ok()?;
+                String::from_utf8(opened.to_vec()).ok()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_ids_are_stable_and_keyed() {
+        let hash = CryptographicHash::try_from(vec![7u8; 32]).unwrap();
+        let ids = PublicIds::hmac(b"secret");
+
+        assert_eq!(ids.id(&hash), PublicIds::hmac(b"secret").id(&hash));
+        assert_ne!(ids.id(&hash), PublicIds::hmac(b"other secret").id(&hash));
+        assert_ne!(ids.id(&hash), hash.to_hex());
+        assert_eq!(PublicIds::CryptoHash.id(&hash), hash.to_hex());
+    }
+
+    #[test]
+    fn hmac_cursors_are_encrypted() {
+        let ids = PublicIds::hmac(b"secret");
+        let cursor = "2023-06-20T11:54:15.123456Z|abcdef";
+
+        let sealed = ids.seal_cursor(cursor);
+        assert!(
+            !String::from_utf8_lossy(&BASE64_URL_SAFE_NO_PAD.decode(&sealed).unwrap())
+                .contains("abcdef")
+        );
+        assert_eq!(ids.open_cursor(&sealed).as_deref(), Some(cursor));
+
+        assert_eq!(PublicIds::hmac(b"other secret").open_cursor(&sealed), None);
+        assert_eq!(
+            ids.open_cursor(&PublicIds::CryptoHash.seal_cursor(cursor)),
+            None
+        );
+        assert_eq!(ids.open_cursor("AAAA"), None);
+    }
+}
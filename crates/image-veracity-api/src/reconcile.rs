@@ -0,0 +1,358 @@
+use std::time::Duration;
+
+use eyre::Result;
+use metrics::increment_counter;
+use sqlx::PgConnection;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, instrument, warn};
+
+use trillian::TrillianLogLeaf;
+
+use crate::hash::cryptographic::CryptographicHash;
+use crate::hash::perceptual::PerceptualHash;
+use crate::hash::VeracityHash;
+use crate::record::{IntegrationStatus, LeafDetails};
+use crate::state::{AppState, PerceptualIndex};
+use crate::tombstone;
+use crate::webhooks::{self, IntegrationEvent};
+
+/// How often queued leaves are checked against the latest tree
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Most images checked per poll, least recently checked first
+const BATCH_SIZE: i64 = 64;
+/// Most log leaves audited per poll
+const AUDIT_BATCH_SIZE: i64 = 256;
+
+/// Reconcile the database with the Trillian log until the process exits.
+///
+/// The database is the source of truth for what was uploaded and the outbox gets every stored
+/// image into the log. This worker covers the other direction: it moves queued images to
+/// integrated as Trillian includes their leaves, and walks the log to find leaves that have no
+/// image row, such as those queued before a failed insert.
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match reconcile_queued(&state).await {
+            Ok(0) => {}
+            Ok(integrated) => debug!("{} queued leaves were integrated", integrated),
+            Err(err) => error!("Reconciliation worker: {}", err),
+        }
+        match audit_log(&state).await {
+            Ok(0) => {}
+            Ok(adopted) => warn!("Adopted {} log leaves without an image row", adopted),
+            Err(err) => error!("Log audit: {}", err),
+        }
+    }
+}
+
+/// Look up inclusion proofs for queued images and record the leaves that have been integrated,
+/// notifying the webhooks of whoever submitted them
+#[instrument(skip_all)]
+async fn reconcile_queued(state: &AppState) -> Result<usize> {
+    let rows = sqlx::query!(
+        "SELECT c_hash, p_hash, submitted_by FROM images WHERE status = $1 \
+        ORDER BY status_checked_at NULLS FIRST LIMIT $2",
+        IntegrationStatus::Queued.as_str(),
+        BATCH_SIZE,
+    )
+    .fetch_all(&state.db_pool)
+    .await?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let mut trillian = state.trillian.clone();
+    let root = trillian.get_latest_root(&state.trillian_tree).await?;
+
+    let mut integrated = 0;
+    for row in &rows {
+        let c_hash = &row.c_hash;
+        let p_hash = &row.p_hash;
+        let leaf_hash = leaf_hash(c_hash);
+
+        let proofs = trillian
+            .get_inclusion_proof_by_hash(&state.trillian_tree, &leaf_hash, root.tree_size)
+            .await?;
+        let leaf = match proofs.first() {
+            Some(proof) => trillian
+                .get_leaves_by_range(&state.trillian_tree, proof.leaf_index, 1)
+                .await?
+                .first()
+                .map(LeafDetails::from)
+                .filter(|leaf| leaf.integrate_timestamp.is_some()),
+            None => None,
+        };
+
+        match leaf {
+            Some(leaf) => {
+                let mut tx = state.db_pool.begin().await?;
+                sqlx::query!(
+                    "UPDATE images SET status = $2, leaf_index = $3, \
+                    merkle_leaf_hash = $4, queue_timestamp = COALESCE(queue_timestamp, $5), \
+                    integrate_timestamp = $6, status_checked_at = now() WHERE c_hash = $1",
+                    c_hash,
+                    IntegrationStatus::Integrated.as_str(),
+                    leaf.leaf_index,
+                    &leaf_hash[..],
+                    leaf.queue_timestamp,
+                    leaf.integrate_timestamp,
+                )
+                .execute(&mut *tx)
+                .await?;
+                if let Some(owner) = &row.submitted_by {
+                    if let Some(hash) = logged_hash(c_hash, p_hash) {
+                        let event =
+                            IntegrationEvent::new(hash, &leaf, root.tree_size, &root.root_hash);
+                        webhooks::enqueue(&mut tx, owner, &event).await?;
+                    }
+                }
+                tx.commit().await?;
+                state.image_cache.invalidate(c_hash, p_hash).await;
+                state.status_events.publish(c_hash);
+                increment_counter!("veracity_leaves_integrated_total");
+                integrated += 1;
+            }
+            None => {
+                sqlx::query!(
+                    "UPDATE images SET status_checked_at = now() WHERE c_hash = $1",
+                    c_hash,
+                )
+                .execute(&state.db_pool)
+                .await?;
+            }
+        }
+    }
+    Ok(integrated)
+}
+
+/// Check log leaves past the audit checkpoint against the images table.
+/// Leaves without an image row are adopted as integrated images, since the log cannot forget
+/// them; images still marked pending or queued are brought up to date.
+#[instrument(skip_all)]
+async fn audit_log(state: &AppState) -> Result<usize> {
+    let next_index = sqlx::query_scalar!("SELECT next_leaf_index FROM trillian_audit WHERE id = 1")
+        .fetch_optional(&state.db_pool)
+        .await?
+        .unwrap_or(0);
+
+    let mut trillian = state.trillian.clone();
+    let tree_size = trillian.get_tree_size(&state.trillian_tree).await?;
+    if next_index >= tree_size {
+        return Ok(0);
+    }
+    let leaves = trillian
+        .get_leaves_by_range(
+            &state.trillian_tree,
+            next_index,
+            AUDIT_BATCH_SIZE.min(tree_size - next_index),
+        )
+        .await?;
+    if leaves.is_empty() {
+        return Ok(0);
+    }
+
+    let mut tx = state.db_pool.begin().await?;
+    let adopted = record_logged_leaves(&mut tx, state.perceptual_index, &leaves).await?;
+    sqlx::query!(
+        "INSERT INTO trillian_audit (id, next_leaf_index) VALUES (1, $1) \
+        ON CONFLICT (id) DO UPDATE SET next_leaf_index = excluded.next_leaf_index",
+        next_index + leaves.len() as i64,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(adopted)
+}
+
+/// What a logged leaf does to the images table
+#[derive(Debug, PartialEq, Eq)]
+enum Adoption {
+    /// No image row holds either hash, so the leaf becomes one
+    Adopt,
+    /// The image row exists and is brought up to date
+    Update,
+    /// Another image already holds the perceptual hash, so the leaf cannot get a row of its own
+    Skip,
+}
+
+/// Decide what to do with a logged leaf given the crypto hashes of the image rows that share
+/// its crypto or perceptual hash
+fn adoption(hash: &VeracityHash, stored: &[Vec<u8>]) -> Adoption {
+    if stored
+        .iter()
+        .any(|c_hash| c_hash.as_slice() == &hash.crypto_hash.as_ref()[..])
+    {
+        Adoption::Update
+    } else if stored.is_empty() {
+        Adoption::Adopt
+    } else {
+        Adoption::Skip
+    }
+}
+
+/// Bring the images table in line with leaves read from the log, returning how many leaves had
+/// no image row and were adopted
+pub(crate) async fn record_logged_leaves(
+    tx: &mut PgConnection,
+    perceptual_index: PerceptualIndex,
+    leaves: &[TrillianLogLeaf],
+) -> Result<usize, sqlx::Error> {
+    let mut adopted = 0;
+    for leaf in leaves {
+        // Tombstones take an image down rather than log one
+        if tombstone::is_tombstone(&leaf.leaf_value) {
+            continue;
+        }
+        let hash = match logged_hash(&leaf.leaf_value, &leaf.extra_data) {
+            Some(hash) => hash,
+            None => {
+                warn!("Leaf {} does not hold an image hash", leaf.leaf_index);
+                continue;
+            }
+        };
+        let details = LeafDetails::from(leaf);
+        let c_hash = &hash.crypto_hash.as_ref()[..];
+        let p_hash = &hash.perceptual_hash.as_ref()[..];
+        let stored = sqlx::query_scalar!(
+            "SELECT c_hash FROM images WHERE c_hash = $1 OR p_hash = $2",
+            c_hash,
+            p_hash,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        match adoption(&hash, &stored) {
+            Adoption::Update => {}
+            Adoption::Skip => {
+                // Rolling back would stall the audit on this leaf forever
+                warn!(
+                    "Leaf {} for {} shares its perceptual hash with another image, skipped",
+                    leaf.leaf_index, hash.crypto_hash
+                );
+                increment_counter!("veracity_logged_leaves_skipped_total");
+                continue;
+            }
+            Adoption::Adopt => {
+                let status = IntegrationStatus::Integrated.as_str();
+                let merkle_leaf_hash = details.merkle_leaf_hash.as_deref();
+                let inserted = match perceptual_index {
+                    PerceptualIndex::Scan => {
+                        sqlx::query!(
+                            "INSERT INTO images (c_hash, p_hash, status, leaf_index, \
+                            merkle_leaf_hash, queue_timestamp, integrate_timestamp) \
+                            VALUES ($1, $2, $3, $4, decode($5, 'hex'), $6, $7) \
+                            ON CONFLICT DO NOTHING",
+                            c_hash,
+                            p_hash,
+                            status,
+                            details.leaf_index,
+                            merkle_leaf_hash,
+                            details.queue_timestamp,
+                            details.integrate_timestamp,
+                        )
+                        .execute(&mut *tx)
+                        .await?
+                    }
+                    // p_vec only exists once the pgvector index is set up, so it is not checked
+                    // at compile time
+                    PerceptualIndex::PgVector => {
+                        sqlx::query(
+                            "INSERT INTO images (c_hash, p_hash, status, leaf_index, \
+                            merkle_leaf_hash, queue_timestamp, integrate_timestamp, p_vec) \
+                            VALUES ($1, $2, $3, $4, decode($5, 'hex'), $6, $7, \
+                            ('x' || encode($2::BYTEA, 'hex'))::bit(256)) ON CONFLICT DO NOTHING",
+                        )
+                        .bind(c_hash)
+                        .bind(p_hash)
+                        .bind(status)
+                        .bind(details.leaf_index)
+                        .bind(merkle_leaf_hash)
+                        .bind(details.queue_timestamp)
+                        .bind(details.integrate_timestamp)
+                        .execute(&mut *tx)
+                        .await?
+                    }
+                }
+                .rows_affected();
+                if inserted > 0 {
+                    warn!(
+                        "Leaf {} for {} had no image row",
+                        leaf.leaf_index, hash.crypto_hash
+                    );
+                    increment_counter!("veracity_orphan_leaves_adopted_total");
+                    adopted += 1;
+                }
+                continue;
+            }
+        }
+
+        // The outbox may not have recorded the leaf yet, or its update was lost
+        sqlx::query!(
+            "UPDATE images SET status = $2, leaf_index = $3, \
+            merkle_leaf_hash = decode($4, 'hex'), queue_timestamp = $5, integrate_timestamp = $6 \
+            WHERE c_hash = $1 AND status != $2",
+            c_hash,
+            IntegrationStatus::Integrated.as_str(),
+            details.leaf_index,
+            details.merkle_leaf_hash.as_deref(),
+            details.queue_timestamp,
+            details.integrate_timestamp,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(adopted)
+}
+
+/// Image hashes held by a leaf, which logs the crypto hash as its value and the perceptual hash
+/// at the start of its extra data, followed by any metadata logged with the image
+fn logged_hash(leaf_value: &[u8], extra_data: &[u8]) -> Option<VeracityHash> {
+    Some(VeracityHash {
+        crypto_hash: CryptographicHash::try_from(leaf_value.to_vec()).ok()?,
+        perceptual_hash: PerceptualHash::try_from(extra_data.get(..32)?.to_vec()).ok()?,
+    })
+}
+
+/// RFC 6962 Merkle leaf hash Trillian computes for a leaf holding `leaf_value`.
+/// Images are logged with their crypto hash as the leaf value.
+pub(crate) use veracity_verify::leaf_hash;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaf_hash_is_domain_separated() {
+        // RFC 6962 hash of the empty leaf
+        assert_eq!(
+            hex::encode(leaf_hash(&[])),
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d"
+        );
+    }
+
+    #[test]
+    fn only_image_leaves_are_adopted() {
+        let hash = logged_hash(&[1; 32], &[2; 32]).unwrap();
+        assert_eq!(hash.crypto_hash, &[1u8; 32][..]);
+        assert!(logged_hash(&[1; 31], &[2; 32]).is_none());
+        assert!(logged_hash(&[1; 32], &[]).is_none());
+        let mut with_metadata = vec![2; 32];
+        with_metadata.extend_from_slice(b"{}");
+        let hash = logged_hash(&[1; 32], &with_metadata).unwrap();
+        assert_eq!(hash.perceptual_hash.as_ref(), &[2u8; 32]);
+    }
+
+    #[test]
+    fn leaves_colliding_on_perceptual_hash_are_skipped() {
+        let hash = logged_hash(&[1; 32], &[2; 32]).unwrap();
+        assert_eq!(adoption(&hash, &[]), Adoption::Adopt);
+        assert_eq!(adoption(&hash, &[vec![1; 32]]), Adoption::Update);
+        // Another image was stored with the same perceptual hash
+        assert_eq!(adoption(&hash, &[vec![3; 32]]), Adoption::Skip);
+        assert_eq!(
+            adoption(&hash, &[vec![3; 32], vec![1; 32]]),
+            Adoption::Update
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, TimeZone, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::FromRow;
+
+use trillian::TrillianLogLeaf;
+
+use crate::attestation::AttestationVerdict;
+use crate::errors::LookupError;
+use crate::hash::cryptographic::CryptographicHash;
+use crate::hash::perceptual::PerceptualHash;
+use crate::hash::VeracityHash;
+use crate::metadata::UploadMetadata;
+
+/// Columns of an [`ImageRow`], for use in `SELECT` statements
+pub const IMAGE_RECORD_COLUMNS: &str =
+    "c_hash, p_hash, status, leaf_index, merkle_leaf_hash, queue_timestamp, \
+    integrate_timestamp, attestation_format, attested, attestation, withheld_at, \
+    withheld_reason, tombstone_leaf_hash, metadata";
+
+/// A stored image together with where its leaf sits in the Trillian log
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ImageRecord {
+    #[serde(flatten)]
+    pub hash: VeracityHash,
+    pub status: IntegrationStatus,
+    #[serde(flatten)]
+    pub leaf: LeafDetails,
+    /// Whether the upload came with an attestation from a genuine device and app
+    pub attested: bool,
+    /// Verified device attestation sent with the upload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<AttestationVerdict>,
+    /// Set once the image was taken down, its original is no longer served
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub withheld: Option<Withholding>,
+    /// Provenance context sent with the upload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<UploadMetadata>,
+}
+
+/// Why and when an image was taken down
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Withholding {
+    pub withheld_at: DateTime<Utc>,
+    pub reason: String,
+    /// RFC 6962 leaf hash of the tombstone logged for the takedown, as hex
+    pub tombstone_leaf_hash: String,
+}
+
+/// A stored image whose perceptual hash is near the one searched for
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SimilarImage {
+    #[serde(flatten)]
+    pub image: VeracityHash,
+    /// Hamming distance from the requested perceptual hash
+    pub distance: u32,
+}
+
+/// How far an image has made it into the Trillian log
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegrationStatus {
+    /// Stored, waiting for the outbox worker to queue it to Trillian
+    #[default]
+    Pending,
+    /// Queued to Trillian, not yet part of a signed tree
+    Queued,
+    /// Integrated into the tree, inclusion proofs are available
+    Integrated,
+}
+
+impl IntegrationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntegrationStatus::Pending => "pending",
+            IntegrationStatus::Queued => "queued",
+            IntegrationStatus::Integrated => "integrated",
+        }
+    }
+
+    /// Status after Trillian reported `leaf`
+    pub fn of_leaf(leaf: &LeafDetails) -> IntegrationStatus {
+        match leaf.integrate_timestamp {
+            Some(_) => IntegrationStatus::Integrated,
+            None => IntegrationStatus::Queued,
+        }
+    }
+}
+
+impl FromStr for IntegrationStatus {
+    type Err = LookupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(IntegrationStatus::Pending),
+            "queued" => Ok(IntegrationStatus::Queued),
+            "integrated" => Ok(IntegrationStatus::Integrated),
+            _ => Err(LookupError::InvalidRecord),
+        }
+    }
+}
+
+/// Leaf details reported by Trillian when the image was queued.
+/// Images stored before these were recorded have none of them.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct LeafDetails {
+    /// Position of the leaf in the log, only known once the leaf has been integrated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leaf_index: Option<i64>,
+    /// RFC 6962 leaf hash as hex, used to request inclusion proofs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merkle_leaf_hash: Option<String>,
+    /// When Trillian accepted the leaf into its queue
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_timestamp: Option<DateTime<Utc>>,
+    /// When Trillian integrated the leaf into the tree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrate_timestamp: Option<DateTime<Utc>>,
+}
+
+impl From<&TrillianLogLeaf> for LeafDetails {
+    fn from(leaf: &TrillianLogLeaf) -> Self {
+        let timestamp = |ts: &Option<prost_types::Timestamp>| {
+            ts.as_ref().and_then(|ts| {
+                Utc.timestamp_opt(ts.seconds, ts.nanos.try_into().ok()?)
+                    .single()
+            })
+        };
+        let integrate_timestamp = timestamp(&leaf.integrate_timestamp);
+        LeafDetails {
+            // Queued leaves report index 0 until they are integrated
+            leaf_index: integrate_timestamp.map(|_| leaf.leaf_index),
+            merkle_leaf_hash: (!leaf.merkle_leaf_hash.is_empty())
+                .then(|| hex::encode(&leaf.merkle_leaf_hash)),
+            queue_timestamp: timestamp(&leaf.queue_timestamp),
+            integrate_timestamp,
+        }
+    }
+}
+
+/// A row of the `images` table selected with [`IMAGE_RECORD_COLUMNS`]
+#[derive(Debug, FromRow)]
+pub struct ImageRow {
+    pub c_hash: Vec<u8>,
+    pub p_hash: Vec<u8>,
+    pub status: String,
+    pub leaf_index: Option<i64>,
+    pub merkle_leaf_hash: Option<Vec<u8>>,
+    pub queue_timestamp: Option<DateTime<Utc>>,
+    pub integrate_timestamp: Option<DateTime<Utc>>,
+    pub attestation_format: Option<String>,
+    pub attested: bool,
+    pub attestation: Option<Value>,
+    pub withheld_at: Option<DateTime<Utc>>,
+    pub withheld_reason: Option<String>,
+    pub tombstone_leaf_hash: Option<Vec<u8>>,
+    pub metadata: Option<Value>,
+}
+
+impl TryFrom<ImageRow> for ImageRecord {
+    type Error = LookupError;
+
+    fn try_from(row: ImageRow) -> Result<Self, Self::Error> {
+        let attestation = match row.attestation_format {
+            Some(format) => Some(AttestationVerdict {
+                format: format.parse().map_err(|_| LookupError::InvalidRecord)?,
+                attested: row.attested,
+                details: row.attestation.unwrap_or_default(),
+            }),
+            None => None,
+        };
+        let withheld = row.withheld_at.map(|withheld_at| Withholding {
+            withheld_at,
+            reason: row.withheld_reason.unwrap_or_default(),
+            tombstone_leaf_hash: row.tombstone_leaf_hash.map(hex::encode).unwrap_or_default(),
+        });
+        let metadata = row
+            .metadata
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|_| LookupError::InvalidRecord)?;
+        Ok(ImageRecord {
+            hash: VeracityHash {
+                crypto_hash: CryptographicHash::try_from(row.c_hash)
+                    .map_err(|_| LookupError::InvalidRecord)?,
+                perceptual_hash: PerceptualHash::try_from(row.p_hash)
+                    .map_err(|_| LookupError::InvalidRecord)?,
+            },
+            status: row.status.parse()?,
+            leaf: LeafDetails {
+                leaf_index: row.leaf_index,
+                merkle_leaf_hash: row.merkle_leaf_hash.map(hex::encode),
+                queue_timestamp: row.queue_timestamp,
+                integrate_timestamp: row.integrate_timestamp,
+            },
+            attested: row.attested,
+            attestation,
+            withheld,
+            metadata,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queued_leaf_has_no_index() {
+        let leaf = TrillianLogLeaf {
+            leaf_index: 0,
+            merkle_leaf_hash: vec![0xAB; 32],
+            queue_timestamp: Some(prost_types::Timestamp {
+                seconds: 1_687_262_055,
+                nanos: 500,
+            }),
+            ..TrillianLogLeaf::default()
+        };
+        let details = LeafDetails::from(&leaf);
+        assert_eq!(details.leaf_index, None);
+        assert_eq!(details.merkle_leaf_hash, Some("ab".repeat(32)));
+        assert_eq!(
+            details.queue_timestamp,
+            Some(Utc.timestamp_opt(1_687_262_055, 500).unwrap())
+        );
+        assert_eq!(details.integrate_timestamp, None);
+        assert_eq!(
+            IntegrationStatus::of_leaf(&details),
+            IntegrationStatus::Queued
+        );
+    }
+
+    #[test]
+    fn integrated_leaf_has_index() {
+        let leaf = TrillianLogLeaf {
+            leaf_index: 42,
+            integrate_timestamp: Some(prost_types::Timestamp {
+                seconds: 1_687_262_056,
+                nanos: 0,
+            }),
+            ..TrillianLogLeaf::default()
+        };
+        let details = LeafDetails::from(&leaf);
+        assert_eq!(details.leaf_index, Some(42));
+        assert_eq!(details.merkle_leaf_hash, None);
+        assert_eq!(
+            IntegrationStatus::of_leaf(&details),
+            IntegrationStatus::Integrated
+        );
+    }
+
+    #[test]
+    fn status_round_trips_through_strings() {
+        for status in [
+            IntegrationStatus::Pending,
+            IntegrationStatus::Queued,
+            IntegrationStatus::Integrated,
+        ] {
+            assert_eq!(
+                status.as_str().parse::<IntegrationStatus>().ok(),
+                Some(status)
+            );
+        }
+        assert!("lost".parse::<IntegrationStatus>().is_err());
+    }
+}
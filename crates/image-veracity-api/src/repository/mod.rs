@@ -0,0 +1,791 @@
+//! Storage of image records, behind a trait so handlers do not depend on the database.
+//!
+//! [`PostgresImageRepository`] keeps images in the `images` table of PostgreSQL or CockroachDB
+//! and is what the server runs on. [`MemoryImageRepository`] keeps them in process, for testing
+//! routes without a database. With the `sqlite` feature, `SqliteImageRepository` keeps them in
+//! a SQLite file for development.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Report};
+use futures::future::BoxFuture;
+use reqwest::Url;
+use sqlx::{FromRow, Postgres, QueryBuilder};
+use thiserror::Error;
+
+use crate::attestation::AttestationVerdict;
+use crate::errors::LookupError;
+use crate::extractors::Submitter;
+use crate::hash::cryptographic::CryptographicHash;
+use crate::hash::perceptual::PerceptualHash;
+use crate::hash::VeracityHash;
+use crate::metadata::UploadMetadata;
+use crate::outbox;
+use crate::record::{
+    ImageRecord, ImageRow, IntegrationStatus, LeafDetails, SimilarImage, Withholding,
+    IMAGE_RECORD_COLUMNS,
+};
+use crate::state::{ConnectionPool, PerceptualIndex};
+use crate::upload_token::{self, UploadClaims};
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+pub type SharedImageRepository = Arc<dyn ImageRepository>;
+
+/// Most recently stored images compared in process by similarity search without a perceptual
+/// index, so a search costs the same however many images are stored
+pub const SIMILAR_SCAN_LIMIT: i64 = 10_000;
+
+/// Work that has to succeed before a stored image is committed, such as keeping its original
+pub type BeforeCommit<'a> = BoxFuture<'a, eyre::Result<()>>;
+
+#[async_trait]
+pub trait ImageRepository: Send + Sync {
+    /// Short name of where images are kept, reported at startup
+    fn backend(&self) -> &'static str;
+
+    /// Store a new image along with its outbox entry, spending the upload token it came with.
+    /// `before_commit` runs once the image has claimed its hashes, and the image is not stored
+    /// if it fails.
+    async fn insert(
+        &self,
+        image: NewImage<'_>,
+        before_commit: Option<BeforeCommit<'_>>,
+    ) -> Result<ImageRecord, InsertError>;
+
+    /// The image with crypto hash `hash`, `None` if there is none
+    async fn get_by_crypto(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError>;
+
+    /// The image with perceptual hash `hash`, `None` if there is none
+    async fn get_by_perceptual(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError>;
+
+    /// Whether there is an image with crypto hash `hash`, without reading its record
+    async fn contains_crypto(&self, hash: &[u8; 32]) -> Result<bool, LookupError>;
+
+    /// Up to `limit` images in insertion order, starting after the `after` position. Only images
+    /// submitted with the API key `submitter` are listed when one is given.
+    async fn list(
+        &self,
+        submitter: Option<&str>,
+        after: Option<&ListPosition>,
+        limit: i64,
+    ) -> Result<Vec<ListedImage>, LookupError>;
+
+    /// Number of images submitted with the API key `submitter`
+    async fn count_submitted(&self, submitter: &str) -> Result<i64, LookupError>;
+
+    /// Up to `limit` images whose perceptual hashes are within `max_distance` of `target`,
+    /// closest first
+    async fn similar(
+        &self,
+        target: &PerceptualHash,
+        max_distance: u32,
+        limit: i64,
+    ) -> Result<Vec<SimilarImage>, LookupError>;
+
+    /// Mark the image with crypto hash `hash` as taken down, keeping its record. Returns `false`,
+    /// changing nothing, if there is no such image or it was already withheld.
+    async fn withhold(
+        &self,
+        hash: &[u8; 32],
+        withholding: &Withholding,
+    ) -> Result<bool, LookupError>;
+}
+
+/// Open the repository named by `url`, `sqlite:///path/to/images.db` or `sqlite::memory:`.
+/// Images are kept in the main database when no repository is configured.
+pub fn from_url(url: &str) -> eyre::Result<SharedImageRepository> {
+    let parsed =
+        Url::parse(url).map_err(|err| eyre!("invalid image repository URL {url}: {err}"))?;
+    match parsed.scheme() {
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Arc::new(sqlite::SqliteImageRepository::open(
+            parsed.path(),
+        )?)),
+        #[cfg(not(feature = "sqlite"))]
+        "sqlite" => Err(eyre!("image repository {url} needs the sqlite feature")),
+        other => Err(eyre!("unsupported image repository scheme {other}")),
+    }
+}
+
+/// An image to store, with what it was submitted with
+pub struct NewImage<'a> {
+    pub hash: &'a VeracityHash,
+    /// Verdict on the device attestation sent with the image
+    pub attestation: Option<&'a AttestationVerdict>,
+    /// Pre-signed upload token the image was sent with, spent when it is stored
+    pub upload_token: Option<&'a UploadClaims>,
+    /// Who Trillian quota for the leaf is charged to
+    pub submitter: Option<&'a Submitter>,
+    /// Provenance context sent with the image
+    pub metadata: Option<&'a UploadMetadata>,
+    /// Whether the metadata is logged in the leaf's extra data as well as stored
+    pub log_metadata: bool,
+}
+
+/// Position in the insertion order of images
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListPosition {
+    pub created_at: DateTime<Utc>,
+    pub crypto_hash: [u8; 32],
+}
+
+/// A stored image with when it was stored
+#[derive(Debug, Clone)]
+pub struct ListedImage {
+    pub record: ImageRecord,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ListedImage {
+    pub fn position(&self) -> ListPosition {
+        ListPosition {
+            created_at: self.created_at,
+            crypto_hash: *self.record.hash.crypto_hash.as_ref(),
+        }
+    }
+}
+
+/// Failure while storing an image
+#[derive(Debug, Error)]
+pub enum InsertError {
+    #[error("image already exists")]
+    Duplicate,
+    #[error("upload token has already been used")]
+    TokenSpent,
+    #[error("could not store image: {0}")]
+    Database(Report),
+    #[error("{0}")]
+    BeforeCommit(Report),
+}
+
+/// Images kept in the database
+#[derive(Clone)]
+pub struct PostgresImageRepository {
+    pool: ConnectionPool,
+    /// Serves lookups, listing and similarity search, the same as `pool` unless a replica is set
+    read_pool: ConnectionPool,
+    perceptual_index: PerceptualIndex,
+}
+
+impl PostgresImageRepository {
+    pub fn new(pool: ConnectionPool, perceptual_index: PerceptualIndex) -> Self {
+        PostgresImageRepository {
+            read_pool: pool.clone(),
+            pool,
+            perceptual_index,
+        }
+    }
+
+    /// Read from `read_pool`, a replica that may lag behind the primary, instead
+    pub fn with_read_pool(mut self, read_pool: ConnectionPool) -> Self {
+        self.read_pool = read_pool;
+        self
+    }
+}
+
+/// An image row with the time it was stored, for listing
+#[derive(FromRow)]
+struct ListedRow {
+    #[sqlx(flatten)]
+    image: ImageRow,
+    created_at: DateTime<Utc>,
+}
+
+#[async_trait]
+impl ImageRepository for PostgresImageRepository {
+    fn backend(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn insert(
+        &self,
+        image: NewImage<'_>,
+        before_commit: Option<BeforeCommit<'_>>,
+    ) -> Result<ImageRecord, InsertError> {
+        let database = |err: sqlx::Error| {
+            let duplicate = err
+                .as_database_error()
+                .is_some_and(|err| err.is_unique_violation());
+            if duplicate {
+                InsertError::Duplicate
+            } else {
+                InsertError::Database(err.into())
+            }
+        };
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|err| InsertError::Database(err.into()))?;
+
+        if let Some(claims) = image.upload_token {
+            upload_token::spend(&mut tx, claims)
+                .await
+                .map_err(|err| match database(err) {
+                    InsertError::Duplicate => InsertError::TokenSpent,
+                    err => err,
+                })?;
+        }
+
+        let c_hash = &image.hash.crypto_hash.as_ref()[..];
+        let p_hash = &image.hash.perceptual_hash.as_ref()[..];
+        let verdict = image.attestation;
+        let attestation_format = verdict.map(|verdict| verdict.format.as_str());
+        let attested = verdict.is_some_and(|verdict| verdict.attested);
+        let attestation = verdict.map(|verdict| verdict.details.clone());
+        // Webhooks belong to API keys, so only keyed submissions are attributed
+        let submitted_by = image.submitter.and_then(Submitter::api_key);
+        let metadata = image.metadata.map(sqlx::types::Json);
+        match self.perceptual_index {
+            PerceptualIndex::Scan => {
+                sqlx::query!(
+                    "INSERT INTO images \
+                    (c_hash, p_hash, attestation_format, attested, attestation, submitted_by, \
+                    metadata) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                    c_hash,
+                    p_hash,
+                    attestation_format,
+                    attested,
+                    attestation,
+                    submitted_by,
+                    metadata as _,
+                )
+                .execute(&mut *tx)
+                .await
+            }
+            // p_vec only exists once the pgvector index is set up, so it is not checked at
+            // compile time
+            PerceptualIndex::PgVector => {
+                sqlx::query(
+                    "INSERT INTO images \
+                    (c_hash, p_hash, attestation_format, attested, attestation, submitted_by, \
+                    metadata, p_vec) VALUES \
+                    ($1, $2, $3, $4, $5, $6, $7, ('x' || encode($2::BYTEA, 'hex'))::bit(256))",
+                )
+                .bind(c_hash)
+                .bind(p_hash)
+                .bind(attestation_format)
+                .bind(attested)
+                .bind(attestation)
+                .bind(submitted_by)
+                .bind(metadata)
+                .execute(&mut *tx)
+                .await
+            }
+        }
+        .map_err(database)?;
+        let logged_metadata = image.metadata.filter(|_| image.log_metadata);
+        outbox::enqueue(&mut tx, image.hash, image.submitter, logged_metadata)
+            .await
+            .map_err(database)?;
+
+        if let Some(before_commit) = before_commit {
+            before_commit.await.map_err(InsertError::BeforeCommit)?;
+        }
+        tx.commit()
+            .await
+            .map_err(|err| InsertError::Database(err.into()))?;
+        Ok(new_record(&image))
+    }
+
+    async fn get_by_crypto(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError> {
+        let row = sqlx::query_as!(
+            ImageRow,
+            "SELECT c_hash, p_hash, status, leaf_index, merkle_leaf_hash, queue_timestamp, \
+            integrate_timestamp, attestation_format, attested, attestation, withheld_at, \
+            withheld_reason, tombstone_leaf_hash, metadata \
+            FROM images WHERE c_hash = $1::BYTEA LIMIT 1",
+            &hash[..],
+        )
+        .fetch_optional(&self.read_pool)
+        .await?;
+        row.map(ImageRecord::try_from).transpose()
+    }
+
+    async fn get_by_perceptual(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError> {
+        let row = sqlx::query_as!(
+            ImageRow,
+            "SELECT c_hash, p_hash, status, leaf_index, merkle_leaf_hash, queue_timestamp, \
+            integrate_timestamp, attestation_format, attested, attestation, withheld_at, \
+            withheld_reason, tombstone_leaf_hash, metadata \
+            FROM images WHERE p_hash = $1::BYTEA LIMIT 1",
+            &hash[..],
+        )
+        .fetch_optional(&self.read_pool)
+        .await?;
+        row.map(ImageRecord::try_from).transpose()
+    }
+
+    async fn contains_crypto(&self, hash: &[u8; 32]) -> Result<bool, LookupError> {
+        Ok(sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM images WHERE c_hash = $1::BYTEA) AS "exists!""#,
+            &hash[..],
+        )
+        .fetch_one(&self.read_pool)
+        .await?)
+    }
+
+    async fn list(
+        &self,
+        submitter: Option<&str>,
+        after: Option<&ListPosition>,
+        limit: i64,
+    ) -> Result<Vec<ListedImage>, LookupError> {
+        let mut query = QueryBuilder::<Postgres>::new(format!(
+            "SELECT {IMAGE_RECORD_COLUMNS}, created_at FROM images"
+        ));
+        let mut separator = " WHERE ";
+        if let Some(submitter) = submitter {
+            query
+                .push(separator)
+                .push("submitted_by = ")
+                .push_bind(submitter);
+            separator = " AND ";
+        }
+        if let Some(after) = after {
+            query
+                .push(separator)
+                .push("(created_at, c_hash) > (")
+                .push_bind(after.created_at)
+                .push("::TIMESTAMPTZ, ")
+                .push_bind(after.crypto_hash.to_vec())
+                .push("::BYTEA)");
+        }
+        let rows = query
+            .push(" ORDER BY created_at, c_hash LIMIT ")
+            .push_bind(limit)
+            .build_query_as::<ListedRow>()
+            .fetch_all(&self.read_pool)
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(ListedImage {
+                    record: ImageRecord::try_from(row.image)?,
+                    created_at: row.created_at,
+                })
+            })
+            .collect()
+    }
+
+    async fn count_submitted(&self, submitter: &str) -> Result<i64, LookupError> {
+        Ok(sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM images WHERE submitted_by = $1"#,
+            submitter,
+        )
+        .fetch_one(&self.read_pool)
+        .await?)
+    }
+
+    async fn similar(
+        &self,
+        target: &PerceptualHash,
+        max_distance: u32,
+        limit: i64,
+    ) -> Result<Vec<SimilarImage>, LookupError> {
+        let rows: Vec<(Vec<u8>, Vec<u8>)> = match self.perceptual_index {
+            // Nearest neighbours come back ordered from the HNSW index; the distance cutoff is
+            // applied afterwards so the planner keeps using the index. p_vec only exists once
+            // the index is set up, so this query is not checked at compile time.
+            PerceptualIndex::PgVector => {
+                sqlx::query_as(
+                    "SELECT c_hash, p_hash FROM images \
+                    ORDER BY p_vec <~> ('x' || $1::TEXT)::bit(256) LIMIT $2",
+                )
+                .bind(target.to_hex())
+                .bind(limit)
+                .fetch_all(&self.read_pool)
+                .await?
+            }
+            PerceptualIndex::Scan => sqlx::query!(
+                "SELECT c_hash, p_hash FROM images \
+                ORDER BY created_at DESC, c_hash DESC LIMIT $1",
+                SIMILAR_SCAN_LIMIT,
+            )
+            .fetch_all(&self.read_pool)
+            .await?
+            .into_iter()
+            .map(|row| (row.c_hash, row.p_hash))
+            .collect(),
+        };
+
+        let images = rows.into_iter().filter_map(|(c_hash, p_hash)| {
+            Some(VeracityHash {
+                crypto_hash: CryptographicHash::try_from(c_hash).ok()?,
+                perceptual_hash: PerceptualHash::try_from(p_hash).ok()?,
+            })
+        });
+        Ok(closest(images, target, max_distance, limit))
+    }
+
+    async fn withhold(
+        &self,
+        hash: &[u8; 32],
+        withholding: &Withholding,
+    ) -> Result<bool, LookupError> {
+        let updated = sqlx::query!(
+            "UPDATE images SET withheld_at = $2, withheld_reason = $3, \
+            tombstone_leaf_hash = decode($4, 'hex') WHERE c_hash = $1 AND withheld_at IS NULL",
+            &hash[..],
+            withholding.withheld_at,
+            &withholding.reason,
+            &withholding.tombstone_leaf_hash,
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(updated > 0)
+    }
+}
+
+/// Images kept in process, lost when it exits
+#[derive(Debug, Default)]
+pub struct MemoryImageRepository {
+    images: Mutex<Vec<ListedImage>>,
+    /// API key each image was submitted with, by crypto hash
+    submitters: Mutex<HashMap<[u8; 32], String>>,
+    spent_tokens: Mutex<HashSet<String>>,
+}
+
+#[async_trait]
+impl ImageRepository for MemoryImageRepository {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn insert(
+        &self,
+        image: NewImage<'_>,
+        before_commit: Option<BeforeCommit<'_>>,
+    ) -> Result<ImageRecord, InsertError> {
+        let exists = |images: &[ListedImage]| {
+            images.iter().any(|stored| {
+                stored.record.hash.crypto_hash == image.hash.crypto_hash
+                    || stored.record.hash.perceptual_hash == image.hash.perceptual_hash
+            })
+        };
+        if exists(&self.images.lock().expect("images lock poisoned")) {
+            return Err(InsertError::Duplicate);
+        }
+        if let Some(claims) = image.upload_token {
+            let spent = self.spent_tokens.lock().expect("tokens lock poisoned");
+            if spent.contains(&claims.id) {
+                return Err(InsertError::TokenSpent);
+            }
+        }
+        if let Some(before_commit) = before_commit {
+            before_commit.await.map_err(InsertError::BeforeCommit)?;
+        }
+
+        // Check again in case the same image was stored while before_commit ran
+        let mut images = self.images.lock().expect("images lock poisoned");
+        if exists(&images) {
+            return Err(InsertError::Duplicate);
+        }
+        if let Some(claims) = image.upload_token {
+            let mut spent = self.spent_tokens.lock().expect("tokens lock poisoned");
+            if !spent.insert(claims.id.clone()) {
+                return Err(InsertError::TokenSpent);
+            }
+        }
+        let record = new_record(&image);
+        if let Some(submitter) = image.submitter.and_then(Submitter::api_key) {
+            self.submitters
+                .lock()
+                .expect("submitters lock poisoned")
+                .insert(*image.hash.crypto_hash.as_ref(), submitter.to_string());
+        }
+        images.push(ListedImage {
+            record: record.clone(),
+            created_at: Utc::now(),
+        });
+        Ok(record)
+    }
+
+    async fn get_by_crypto(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError> {
+        let images = self.images.lock().expect("images lock poisoned");
+        Ok(images
+            .iter()
+            .find(|image| image.record.hash.crypto_hash.as_ref() == hash)
+            .map(|image| image.record.clone()))
+    }
+
+    async fn get_by_perceptual(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError> {
+        let images = self.images.lock().expect("images lock poisoned");
+        Ok(images
+            .iter()
+            .find(|image| image.record.hash.perceptual_hash.as_ref() == hash)
+            .map(|image| image.record.clone()))
+    }
+
+    async fn contains_crypto(&self, hash: &[u8; 32]) -> Result<bool, LookupError> {
+        let images = self.images.lock().expect("images lock poisoned");
+        Ok(images
+            .iter()
+            .any(|image| image.record.hash.crypto_hash.as_ref() == hash))
+    }
+
+    async fn list(
+        &self,
+        submitter: Option<&str>,
+        after: Option<&ListPosition>,
+        limit: i64,
+    ) -> Result<Vec<ListedImage>, LookupError> {
+        let mut images = self.images.lock().expect("images lock poisoned").clone();
+        images.sort_by_key(|image| (image.created_at, *image.record.hash.crypto_hash.as_ref()));
+        let submitters = self.submitters.lock().expect("submitters lock poisoned");
+        Ok(images
+            .into_iter()
+            .filter(|image| {
+                submitter.map_or(true, |submitter| {
+                    submitters
+                        .get(image.record.hash.crypto_hash.as_ref())
+                        .is_some_and(|submitted_by| submitted_by == submitter)
+                })
+            })
+            .filter(|image| {
+                after.map_or(true, |after| {
+                    (image.created_at, *image.record.hash.crypto_hash.as_ref())
+                        > (after.created_at, after.crypto_hash)
+                })
+            })
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn count_submitted(&self, submitter: &str) -> Result<i64, LookupError> {
+        let submitters = self.submitters.lock().expect("submitters lock poisoned");
+        Ok(submitters
+            .values()
+            .filter(|submitted_by| *submitted_by == submitter)
+            .count() as i64)
+    }
+
+    async fn similar(
+        &self,
+        target: &PerceptualHash,
+        max_distance: u32,
+        limit: i64,
+    ) -> Result<Vec<SimilarImage>, LookupError> {
+        let images = self.images.lock().expect("images lock poisoned");
+        let hashes = images.iter().map(|image| image.record.hash.clone());
+        Ok(closest(hashes, target, max_distance, limit))
+    }
+
+    async fn withhold(
+        &self,
+        hash: &[u8; 32],
+        withholding: &Withholding,
+    ) -> Result<bool, LookupError> {
+        let mut images = self.images.lock().expect("images lock poisoned");
+        match images
+            .iter_mut()
+            .find(|image| image.record.hash.crypto_hash.as_ref() == hash)
+        {
+            Some(image) if image.record.withheld.is_none() => {
+                image.record.withheld = Some(withholding.clone());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Record of an image that was just stored, before it is queued to Trillian
+fn new_record(image: &NewImage<'_>) -> ImageRecord {
+    ImageRecord {
+        hash: image.hash.clone(),
+        status: IntegrationStatus::Pending,
+        leaf: LeafDetails::default(),
+        attested: image.attestation.is_some_and(|verdict| verdict.attested),
+        attestation: image.attestation.cloned(),
+        withheld: None,
+        metadata: image.metadata.cloned(),
+    }
+}
+
+/// Up to `limit` of the images within `max_distance` of `target`, closest first
+fn closest(
+    images: impl Iterator<Item = VeracityHash>,
+    target: &PerceptualHash,
+    max_distance: u32,
+    limit: i64,
+) -> Vec<SimilarImage> {
+    let mut similar: Vec<SimilarImage> = images
+        .filter_map(|image| {
+            let distance = image.perceptual_hash.hamming_distance(target);
+            (distance <= max_distance).then_some(SimilarImage { image, distance })
+        })
+        .collect();
+    similar.sort_by_key(|image| image.distance);
+    similar.truncate(limit.max(0) as usize);
+    similar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u8) -> VeracityHash {
+        VeracityHash {
+            crypto_hash: CryptographicHash::try_from(vec![n; 32]).unwrap(),
+            perceptual_hash: PerceptualHash::try_from(vec![n; 32]).unwrap(),
+        }
+    }
+
+    fn new_image(hash: &VeracityHash) -> NewImage<'_> {
+        NewImage {
+            hash,
+            attestation: None,
+            upload_token: None,
+            submitter: None,
+            metadata: None,
+            log_metadata: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_repository_stores_and_finds_images() {
+        let images = MemoryImageRepository::default();
+        let first = hash(1);
+        let record = images.insert(new_image(&first), None).await.unwrap();
+        assert_eq!(record.status, IntegrationStatus::Pending);
+
+        let found = images.get_by_crypto(&[1; 32]).await.unwrap().unwrap();
+        assert_eq!(found.hash.perceptual_hash, first.perceptual_hash);
+        let found = images.get_by_perceptual(&[1; 32]).await.unwrap().unwrap();
+        assert_eq!(found.hash.crypto_hash, first.crypto_hash);
+        assert!(images.get_by_crypto(&[2; 32]).await.unwrap().is_none());
+        assert!(images.contains_crypto(&[1; 32]).await.unwrap());
+        assert!(!images.contains_crypto(&[2; 32]).await.unwrap());
+
+        assert!(matches!(
+            images.insert(new_image(&first), None).await,
+            Err(InsertError::Duplicate)
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_before_commit_stores_nothing() {
+        let images = MemoryImageRepository::default();
+        let first = hash(1);
+        let failed = images
+            .insert(
+                new_image(&first),
+                Some(Box::pin(async { Err(eyre::eyre!("disk full")) })),
+            )
+            .await;
+        assert!(matches!(failed, Err(InsertError::BeforeCommit(_))));
+        assert!(images.get_by_crypto(&[1; 32]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn upload_tokens_are_spent_once() {
+        let images = MemoryImageRepository::default();
+        let claims = UploadClaims {
+            id: "token".to_string(),
+            expires_at: 0,
+            max_bytes: 1024,
+            tenant: None,
+        };
+        let (first, second) = (hash(1), hash(2));
+        let mut image = new_image(&first);
+        image.upload_token = Some(&claims);
+        images.insert(image, None).await.unwrap();
+        let mut image = new_image(&second);
+        image.upload_token = Some(&claims);
+        assert!(matches!(
+            images.insert(image, None).await,
+            Err(InsertError::TokenSpent)
+        ));
+    }
+
+    #[tokio::test]
+    async fn memory_repository_pages_in_insertion_order() {
+        let images = MemoryImageRepository::default();
+        for n in 1..=5 {
+            images.insert(new_image(&hash(n)), None).await.unwrap();
+        }
+        let first = images.list(None, None, 2).await.unwrap();
+        assert_eq!(first.len(), 2);
+        let rest = images
+            .list(None, Some(&first[1].position()), 10)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 3);
+        let mut all: Vec<_> = first
+            .iter()
+            .chain(&rest)
+            .map(|image| image.position())
+            .collect();
+        let listed = all.clone();
+        all.sort_by_key(|position| (position.created_at, position.crypto_hash));
+        assert_eq!(all, listed);
+    }
+
+    #[test]
+    fn unknown_repository_schemes_are_refused() {
+        assert!(from_url("mysql://localhost/images").is_err());
+        assert!(from_url("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn similar_images_are_closest_first() {
+        let images = MemoryImageRepository::default();
+        for n in [0b0000_0000, 0b0000_0001, 0b0000_0111, 0b1111_1111] {
+            images.insert(new_image(&hash(n)), None).await.unwrap();
+        }
+        let target = PerceptualHash::try_from(vec![0; 32]).unwrap();
+        let similar = images.similar(&target, 3 * 32, 10).await.unwrap();
+        let distances: Vec<u32> = similar.iter().map(|image| image.distance).collect();
+        assert_eq!(distances, vec![0, 32, 96]);
+        assert_eq!(images.similar(&target, 256, 2).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn memory_repository_lists_by_submitter() {
+        let images = MemoryImageRepository::default();
+        let keys = [
+            Submitter::ApiKey("first".to_string()),
+            Submitter::ApiKey("second".to_string()),
+        ];
+        for n in 1..=3 {
+            let hash = hash(n);
+            let mut image = new_image(&hash);
+            image.submitter = Some(&keys[(n % 2) as usize]);
+            images.insert(image, None).await.unwrap();
+        }
+
+        let listed = images.list(Some("second"), None, 10).await.unwrap();
+        let listed: Vec<_> = listed
+            .iter()
+            .map(|image| image.record.hash.crypto_hash.clone())
+            .collect();
+        assert_eq!(listed, vec![hash(1).crypto_hash, hash(3).crypto_hash]);
+        assert_eq!(images.count_submitted("second").await.unwrap(), 2);
+        assert_eq!(images.count_submitted("first").await.unwrap(), 1);
+        assert_eq!(images.count_submitted("third").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn images_are_withheld_once() {
+        let images = MemoryImageRepository::default();
+        images.insert(new_image(&hash(1)), None).await.unwrap();
+        let withholding = Withholding {
+            withheld_at: Utc::now(),
+            reason: "court order".to_string(),
+            tombstone_leaf_hash: "ab".repeat(32),
+        };
+        assert!(images.withhold(&[1; 32], &withholding).await.unwrap());
+        assert!(!images.withhold(&[1; 32], &withholding).await.unwrap());
+        assert!(!images.withhold(&[2; 32], &withholding).await.unwrap());
+
+        let found = images.get_by_crypto(&[1; 32]).await.unwrap().unwrap();
+        assert_eq!(found.withheld, Some(withholding));
+    }
+}
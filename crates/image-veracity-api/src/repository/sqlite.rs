@@ -0,0 +1,645 @@
+//! Images kept in a SQLite file, so the server can run without PostgreSQL or CockroachDB
+//! during development.
+//!
+//! The schema mirrors the `images` table of the main database, with timestamps stored as
+//! microseconds since the Unix epoch so they sort as they compare.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use eyre::{Report, Result};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::error;
+
+use crate::attestation::AttestationVerdict;
+use crate::errors::LookupError;
+use crate::extractors::Submitter;
+use crate::hash::cryptographic::CryptographicHash;
+use crate::hash::perceptual::PerceptualHash;
+use crate::hash::VeracityHash;
+use crate::record::{ImageRecord, LeafDetails, SimilarImage, Withholding, IMAGE_RECORD_COLUMNS};
+
+use super::{
+    closest, new_record, BeforeCommit, ImageRepository, InsertError, ListPosition, ListedImage,
+    NewImage, SIMILAR_SCAN_LIMIT,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS images (
+        c_hash BLOB NOT NULL PRIMARY KEY,
+        p_hash BLOB NOT NULL UNIQUE,
+        created_at INTEGER NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        leaf_index INTEGER,
+        merkle_leaf_hash BLOB,
+        queue_timestamp INTEGER,
+        integrate_timestamp INTEGER,
+        attestation_format TEXT,
+        attested INTEGER NOT NULL DEFAULT 0,
+        attestation TEXT,
+        submitted_by TEXT,
+        withheld_at INTEGER,
+        withheld_reason TEXT,
+        tombstone_leaf_hash BLOB,
+        metadata TEXT
+    );
+    CREATE INDEX IF NOT EXISTS images_created_at_index ON images (created_at, c_hash);
+    CREATE TABLE IF NOT EXISTS spent_upload_tokens (
+        token_id TEXT NOT NULL PRIMARY KEY,
+        expires_at INTEGER NOT NULL
+    );
+";
+
+/// Columns added to `images` since it was first created, with their types
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("withheld_at", "INTEGER"),
+    ("withheld_reason", "TEXT"),
+    ("tombstone_leaf_hash", "BLOB"),
+    ("metadata", "TEXT"),
+];
+
+/// Images kept in a SQLite database. Every statement goes through one connection, so writes
+/// are serialized, including while an insert waits on its `before_commit` work.
+#[derive(Clone)]
+pub struct SqliteImageRepository {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteImageRepository {
+    /// Open the database at `path`, or an in-memory one for `:memory:`, creating the tables if
+    /// they are missing
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        add_missing_columns(&conn)?;
+        Ok(SqliteImageRepository {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `f` on the connection on a blocking thread, handing the connection back with its
+    /// result so a transaction can continue across calls
+    async fn blocking<T, F>(
+        conn: OwnedMutexGuard<Connection>,
+        f: F,
+    ) -> Result<(OwnedMutexGuard<Connection>, T)>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> T + Send + 'static,
+    {
+        Ok(tokio::task::spawn_blocking(move || {
+            let result = f(&conn);
+            (conn, result)
+        })
+        .await?)
+    }
+
+    /// Run a read, or a write outside of a transaction, on the connection, logging failures
+    async fn read<T, F>(&self, f: F) -> Result<T, LookupError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<Result<T, LookupError>> + Send + 'static,
+    {
+        let conn = self.conn.clone().lock_owned().await;
+        match Self::blocking(conn, f).await {
+            Ok((_, Ok(result))) => result,
+            Ok((_, Err(err))) => {
+                error!("Error getting from database: {}", err);
+                Err(LookupError::Query)
+            }
+            Err(err) => {
+                error!("{}", err);
+                Err(LookupError::Connection)
+            }
+        }
+    }
+
+    async fn get_by(
+        &self,
+        column: &'static str,
+        hash: &[u8; 32],
+    ) -> Result<Option<ImageRecord>, LookupError> {
+        let hash = hash.to_vec();
+        self.read(move |conn| {
+            let statement =
+                format!("SELECT {IMAGE_RECORD_COLUMNS} FROM images WHERE {column} = ?1 LIMIT 1");
+            let record = conn
+                .prepare_cached(&statement)?
+                .query_row(params![hash], |row| Ok(record(row)))
+                .optional()?;
+            Ok(record.transpose())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ImageRepository for SqliteImageRepository {
+    fn backend(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn insert(
+        &self,
+        image: NewImage<'_>,
+        before_commit: Option<BeforeCommit<'_>>,
+    ) -> Result<ImageRecord, InsertError> {
+        let c_hash = image.hash.crypto_hash.as_ref().to_vec();
+        let p_hash = image.hash.perceptual_hash.as_ref().to_vec();
+        let verdict = image.attestation;
+        let format = verdict.map(|verdict| verdict.format.as_str().to_string());
+        let attested = verdict.is_some_and(|verdict| verdict.attested);
+        let details = verdict.map(|verdict| verdict.details.to_string());
+        let metadata = image
+            .metadata
+            .map(|metadata| serde_json::json!(metadata).to_string());
+        // Webhooks belong to API keys, so only keyed submissions are attributed
+        let submitted_by = image
+            .submitter
+            .and_then(Submitter::api_key)
+            .map(str::to_string);
+        let token = image
+            .upload_token
+            .map(|claims| (claims.id.clone(), claims.expires_at));
+        let created_at = Utc::now().timestamp_micros();
+
+        let conn = self.conn.clone().lock_owned().await;
+        let (conn, stored) = Self::blocking(conn, move |conn| {
+            // An insert dropped while it waited on before_commit leaves its transaction open
+            if !conn.is_autocommit() {
+                rollback(conn);
+            }
+            conn.execute_batch("BEGIN IMMEDIATE")
+                .map_err(|err| InsertError::Database(err.into()))?;
+            let stored = (|| -> Result<(), InsertError> {
+                if let Some((id, expires_at)) = token {
+                    conn.execute(
+                        "INSERT INTO spent_upload_tokens (token_id, expires_at) VALUES (?1, ?2)",
+                        params![id, expires_at],
+                    )
+                    .map_err(|err| match duplicate(err) {
+                        InsertError::Duplicate => InsertError::TokenSpent,
+                        err => err,
+                    })?;
+                }
+                conn.execute(
+                    "INSERT INTO images \
+                    (c_hash, p_hash, created_at, attestation_format, attested, attestation, \
+                    submitted_by, metadata) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        c_hash,
+                        p_hash,
+                        created_at,
+                        format,
+                        attested,
+                        details,
+                        submitted_by,
+                        metadata
+                    ],
+                )
+                .map_err(duplicate)?;
+                Ok(())
+            })();
+            if stored.is_err() {
+                rollback(conn);
+            }
+            stored
+        })
+        .await
+        .map_err(InsertError::Database)?;
+        stored?;
+
+        let committed = match before_commit {
+            Some(before_commit) => before_commit.await.map_err(InsertError::BeforeCommit),
+            None => Ok(()),
+        };
+        let (_, finished) = Self::blocking(conn, move |conn| match committed {
+            Ok(()) => conn
+                .execute_batch("COMMIT")
+                .map_err(|err| InsertError::Database(err.into())),
+            Err(err) => {
+                rollback(conn);
+                Err(err)
+            }
+        })
+        .await
+        .map_err(InsertError::Database)?;
+        finished?;
+        Ok(new_record(&image))
+    }
+
+    async fn get_by_crypto(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError> {
+        self.get_by("c_hash", hash).await
+    }
+
+    async fn get_by_perceptual(&self, hash: &[u8; 32]) -> Result<Option<ImageRecord>, LookupError> {
+        self.get_by("p_hash", hash).await
+    }
+
+    async fn contains_crypto(&self, hash: &[u8; 32]) -> Result<bool, LookupError> {
+        let hash = hash.to_vec();
+        self.read(move |conn| {
+            let exists = conn
+                .prepare_cached("SELECT EXISTS (SELECT 1 FROM images WHERE c_hash = ?1)")?
+                .query_row(params![hash], |row| row.get(0))?;
+            Ok(Ok(exists))
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        submitter: Option<&str>,
+        after: Option<&ListPosition>,
+        limit: i64,
+    ) -> Result<Vec<ListedImage>, LookupError> {
+        let submitter = submitter.map(str::to_string);
+        let after = after.map(|after| {
+            (
+                after.created_at.timestamp_micros(),
+                after.crypto_hash.to_vec(),
+            )
+        });
+        self.read(move |conn| {
+            let listed = |row: &Row<'_>| {
+                Ok(record(row).and_then(|record| {
+                    Ok(ListedImage {
+                        record,
+                        created_at: timestamp(row.get("created_at").map_err(invalid)?)?,
+                    })
+                }))
+            };
+            let images = match after {
+                None => conn
+                    .prepare_cached(&format!(
+                        "SELECT {IMAGE_RECORD_COLUMNS}, created_at FROM images \
+                        WHERE (?1 IS NULL OR submitted_by = ?1) \
+                        ORDER BY created_at, c_hash LIMIT ?2"
+                    ))?
+                    .query_map(params![submitter, limit], listed)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?,
+                Some((created_at, c_hash)) => conn
+                    .prepare_cached(&format!(
+                        "SELECT {IMAGE_RECORD_COLUMNS}, created_at FROM images \
+                        WHERE (?1 IS NULL OR submitted_by = ?1) \
+                        AND (created_at, c_hash) > (?2, ?3) \
+                        ORDER BY created_at, c_hash LIMIT ?4"
+                    ))?
+                    .query_map(params![submitter, created_at, c_hash, limit], listed)?
+                    .collect::<rusqlite::Result<Vec<_>>>()?,
+            };
+            Ok(images.into_iter().collect())
+        })
+        .await
+    }
+
+    async fn count_submitted(&self, submitter: &str) -> Result<i64, LookupError> {
+        let submitter = submitter.to_string();
+        self.read(move |conn| {
+            let count = conn
+                .prepare_cached("SELECT count(*) FROM images WHERE submitted_by = ?1")?
+                .query_row(params![submitter], |row| row.get(0))?;
+            Ok(Ok(count))
+        })
+        .await
+    }
+
+    async fn similar(
+        &self,
+        target: &PerceptualHash,
+        max_distance: u32,
+        limit: i64,
+    ) -> Result<Vec<SimilarImage>, LookupError> {
+        let target = target.clone();
+        self.read(move |conn| {
+            let rows = conn
+                .prepare_cached(
+                    "SELECT c_hash, p_hash FROM images \
+                    ORDER BY created_at DESC, c_hash DESC LIMIT ?1",
+                )?
+                .query_map(params![SIMILAR_SCAN_LIMIT], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(Vec<u8>, Vec<u8>)>>>()?;
+            let images = rows.into_iter().filter_map(|(c_hash, p_hash)| {
+                Some(VeracityHash {
+                    crypto_hash: CryptographicHash::try_from(c_hash).ok()?,
+                    perceptual_hash: PerceptualHash::try_from(p_hash).ok()?,
+                })
+            });
+            Ok(Ok(closest(images, &target, max_distance, limit)))
+        })
+        .await
+    }
+
+    async fn withhold(
+        &self,
+        hash: &[u8; 32],
+        withholding: &Withholding,
+    ) -> Result<bool, LookupError> {
+        let hash = hash.to_vec();
+        let withheld_at = withholding.withheld_at.timestamp_micros();
+        let reason = withholding.reason.clone();
+        let Ok(tombstone_leaf_hash) = hex::decode(&withholding.tombstone_leaf_hash) else {
+            return Err(LookupError::InvalidRecord);
+        };
+        self.read(move |conn| {
+            let updated = conn.execute(
+                "UPDATE images SET withheld_at = ?2, withheld_reason = ?3, \
+                tombstone_leaf_hash = ?4 WHERE c_hash = ?1 AND withheld_at IS NULL",
+                params![hash, withheld_at, reason, tombstone_leaf_hash],
+            )?;
+            Ok(Ok(updated > 0))
+        })
+        .await
+    }
+}
+
+/// Add the columns of [`ADDED_COLUMNS`] a database created by an earlier version lacks
+fn add_missing_columns(conn: &Connection) -> rusqlite::Result<()> {
+    for (column, column_type) in ADDED_COLUMNS {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pragma_table_info('images') WHERE name = ?1)",
+            params![column],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            conn.execute_batch(&format!(
+                "ALTER TABLE images ADD COLUMN {column} {column_type}"
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Read a row selected with [`IMAGE_RECORD_COLUMNS`]
+fn record(row: &Row<'_>) -> Result<ImageRecord, LookupError> {
+    let attested = row.get("attested").map_err(invalid)?;
+    let attestation = match row
+        .get::<_, Option<String>>("attestation_format")
+        .map_err(invalid)?
+    {
+        Some(format) => Some(AttestationVerdict {
+            format: format.parse().map_err(|_| LookupError::InvalidRecord)?,
+            attested,
+            details: match row
+                .get::<_, Option<String>>("attestation")
+                .map_err(invalid)?
+            {
+                Some(details) => {
+                    serde_json::from_str(&details).map_err(|_| LookupError::InvalidRecord)?
+                }
+                None => Default::default(),
+            },
+        }),
+        None => None,
+    };
+    let timestamp_column = |column| {
+        row.get::<_, Option<i64>>(column)
+            .map_err(invalid)?
+            .map(timestamp)
+            .transpose()
+    };
+    Ok(ImageRecord {
+        hash: VeracityHash {
+            crypto_hash: CryptographicHash::try_from(
+                row.get::<_, Vec<u8>>("c_hash").map_err(invalid)?,
+            )
+            .map_err(|_| LookupError::InvalidRecord)?,
+            perceptual_hash: PerceptualHash::try_from(
+                row.get::<_, Vec<u8>>("p_hash").map_err(invalid)?,
+            )
+            .map_err(|_| LookupError::InvalidRecord)?,
+        },
+        status: row.get::<_, String>("status").map_err(invalid)?.parse()?,
+        leaf: LeafDetails {
+            leaf_index: row.get("leaf_index").map_err(invalid)?,
+            merkle_leaf_hash: row
+                .get::<_, Option<Vec<u8>>>("merkle_leaf_hash")
+                .map_err(invalid)?
+                .map(hex::encode),
+            queue_timestamp: timestamp_column("queue_timestamp")?,
+            integrate_timestamp: timestamp_column("integrate_timestamp")?,
+        },
+        attested,
+        attestation,
+        withheld: match timestamp_column("withheld_at")? {
+            Some(withheld_at) => Some(Withholding {
+                withheld_at,
+                reason: row
+                    .get::<_, Option<String>>("withheld_reason")
+                    .map_err(invalid)?
+                    .unwrap_or_default(),
+                tombstone_leaf_hash: row
+                    .get::<_, Option<Vec<u8>>>("tombstone_leaf_hash")
+                    .map_err(invalid)?
+                    .map(hex::encode)
+                    .unwrap_or_default(),
+            }),
+            None => None,
+        },
+        metadata: match row.get::<_, Option<String>>("metadata").map_err(invalid)? {
+            Some(metadata) => {
+                Some(serde_json::from_str(&metadata).map_err(|_| LookupError::InvalidRecord)?)
+            }
+            None => None,
+        },
+    })
+}
+
+fn invalid(_: rusqlite::Error) -> LookupError {
+    LookupError::InvalidRecord
+}
+
+/// Time stored as microseconds since the Unix epoch
+fn timestamp(micros: i64) -> Result<DateTime<Utc>, LookupError> {
+    Utc.timestamp_opt(
+        micros.div_euclid(1_000_000),
+        (micros.rem_euclid(1_000_000) * 1_000) as u32,
+    )
+    .single()
+    .ok_or(LookupError::InvalidRecord)
+}
+
+fn duplicate(err: rusqlite::Error) -> InsertError {
+    match err.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => InsertError::Duplicate,
+        _ => InsertError::Database(Report::from(err)),
+    }
+}
+
+fn rollback(conn: &Connection) {
+    if let Err(err) = conn.execute_batch("ROLLBACK") {
+        error!("Could not roll back image insert: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata::UploadMetadata;
+    use crate::upload_token::UploadClaims;
+
+    use super::*;
+
+    fn hash(n: u8) -> VeracityHash {
+        VeracityHash {
+            crypto_hash: CryptographicHash::try_from(vec![n; 32]).unwrap(),
+            perceptual_hash: PerceptualHash::try_from(vec![n; 32]).unwrap(),
+        }
+    }
+
+    fn new_image(hash: &VeracityHash) -> NewImage<'_> {
+        NewImage {
+            hash,
+            attestation: None,
+            upload_token: None,
+            submitter: None,
+            metadata: None,
+            log_metadata: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_and_finds_images() {
+        let images = SqliteImageRepository::open(":memory:").unwrap();
+        let first = hash(1);
+        images.insert(new_image(&first), None).await.unwrap();
+
+        let found = images.get_by_crypto(&[1; 32]).await.unwrap().unwrap();
+        assert_eq!(found.hash.perceptual_hash, first.perceptual_hash);
+        let found = images.get_by_perceptual(&[1; 32]).await.unwrap().unwrap();
+        assert_eq!(found.hash.crypto_hash, first.crypto_hash);
+        assert!(images.get_by_crypto(&[2; 32]).await.unwrap().is_none());
+        assert!(images.contains_crypto(&[1; 32]).await.unwrap());
+        assert!(!images.contains_crypto(&[2; 32]).await.unwrap());
+
+        assert!(matches!(
+            images.insert(new_image(&first), None).await,
+            Err(InsertError::Duplicate)
+        ));
+    }
+
+    #[tokio::test]
+    async fn metadata_is_kept_with_the_image() {
+        let images = SqliteImageRepository::open(":memory:").unwrap();
+        let first = hash(1);
+        let metadata = UploadMetadata {
+            caption: Some("Flooding on Main Street".to_string()),
+            ..UploadMetadata::default()
+        };
+        let mut image = new_image(&first);
+        image.metadata = Some(&metadata);
+        images.insert(image, None).await.unwrap();
+
+        let found = images.get_by_crypto(&[1; 32]).await.unwrap().unwrap();
+        assert_eq!(found.metadata, Some(metadata));
+    }
+
+    #[tokio::test]
+    async fn failed_before_commit_rolls_back() {
+        let images = SqliteImageRepository::open(":memory:").unwrap();
+        let first = hash(1);
+        let claims = UploadClaims {
+            id: "token".to_string(),
+            expires_at: 0,
+            max_bytes: 1024,
+            tenant: None,
+        };
+        let mut image = new_image(&first);
+        image.upload_token = Some(&claims);
+        let failed = images
+            .insert(
+                image,
+                Some(Box::pin(async { Err(eyre::eyre!("disk full")) })),
+            )
+            .await;
+        assert!(matches!(failed, Err(InsertError::BeforeCommit(_))));
+        assert!(images.get_by_crypto(&[1; 32]).await.unwrap().is_none());
+
+        // The token was not spent either, so the upload can be retried
+        let mut image = new_image(&first);
+        image.upload_token = Some(&claims);
+        images.insert(image, None).await.unwrap();
+        let second = hash(2);
+        let mut image = new_image(&second);
+        image.upload_token = Some(&claims);
+        assert!(matches!(
+            images.insert(image, None).await,
+            Err(InsertError::TokenSpent)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pages_in_insertion_order() {
+        let images = SqliteImageRepository::open(":memory:").unwrap();
+        for n in 1..=5 {
+            images.insert(new_image(&hash(n)), None).await.unwrap();
+        }
+        let first = images.list(None, None, 2).await.unwrap();
+        assert_eq!(first.len(), 2);
+        let rest = images
+            .list(None, Some(&first[1].position()), 10)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 3);
+        let listed: Vec<_> = first
+            .iter()
+            .chain(&rest)
+            .map(|image| image.record.hash.crypto_hash.clone())
+            .collect();
+        let stored: Vec<_> = (1..=5).map(|n| hash(n).crypto_hash).collect();
+        assert_eq!(listed, stored);
+    }
+
+    #[tokio::test]
+    async fn finds_similar_images() {
+        let images = SqliteImageRepository::open(":memory:").unwrap();
+        for n in [0b0000_0000, 0b0000_0001, 0b1111_1111] {
+            images.insert(new_image(&hash(n)), None).await.unwrap();
+        }
+        let target = PerceptualHash::try_from(vec![0; 32]).unwrap();
+        let similar = images.similar(&target, 32, 10).await.unwrap();
+        let distances: Vec<u32> = similar.iter().map(|image| image.distance).collect();
+        assert_eq!(distances, vec![0, 32]);
+    }
+
+    #[tokio::test]
+    async fn lists_by_submitter() {
+        let images = SqliteImageRepository::open(":memory:").unwrap();
+        let key = Submitter::ApiKey("key".to_string());
+        for n in 1..=3 {
+            let hash = hash(n);
+            let mut image = new_image(&hash);
+            image.submitter = (n != 2).then_some(&key);
+            images.insert(image, None).await.unwrap();
+        }
+        let listed = images.list(Some("key"), None, 10).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        let rest = images
+            .list(Some("key"), Some(&listed[0].position()), 10)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].record.hash.crypto_hash, hash(3).crypto_hash);
+        assert_eq!(images.count_submitted("key").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn withheld_images_keep_their_record() {
+        let images = SqliteImageRepository::open(":memory:").unwrap();
+        images.insert(new_image(&hash(1)), None).await.unwrap();
+        let withholding = Withholding {
+            withheld_at: timestamp(Utc::now().timestamp_micros()).unwrap(),
+            reason: "court order".to_string(),
+            tombstone_leaf_hash: "ab".repeat(32),
+        };
+        assert!(images.withhold(&[1; 32], &withholding).await.unwrap());
+        assert!(!images.withhold(&[1; 32], &withholding).await.unwrap());
+
+        let found = images.get_by_crypto(&[1; 32]).await.unwrap().unwrap();
+        assert_eq!(found.withheld, Some(withholding));
+    }
+}
@@ -0,0 +1,518 @@
+use std::sync::Arc;
+
+use aide::axum::routing::{delete_with, get_with, post_with};
+use aide::axum::{ApiRouter, IntoApiResponse};
+use aide::transform::TransformOperation;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use hex::FromHex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::api_key::{self, ApiKeyRecord, Role, Scope};
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, Json};
+use crate::hash::cryptographic::CryptographicHash;
+use crate::hash::VeracityHash;
+use crate::jobs::{self, JobKind, JobProgress};
+use crate::startup::StartupSummary;
+use crate::state::{AppState, PerceptualIndex};
+
+const DEFAULT_SHARD_SIZE: i64 = 100_000;
+const DEFAULT_PARALLELISM: i64 = 4;
+const MAX_PARALLELISM: i64 = 16;
+const JOB_LIST_LIMIT: i64 = 100;
+const CLUSTER_LIMIT: i64 = 100;
+
+pub fn admin_routes(state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route_with("/config", get_with(get_config, get_config_docs), |p| {
+            p.security_requirement("ApiKey")
+        })
+        .api_route_with(
+            "/keys",
+            post_with(create_key, create_key_docs).get_with(list_keys, list_keys_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with("/keys/:id", delete_with(revoke_key, revoke_key_docs), |p| {
+            p.security_requirement("ApiKey")
+        })
+        .api_route_with("/jobs", get_with(list_jobs, list_jobs_docs), |p| {
+            p.security_requirement("ApiKey")
+        })
+        .api_route_with(
+            "/jobs/backfill",
+            post_with(start_backfill, start_backfill_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/jobs/rehash",
+            post_with(start_rehash, start_rehash_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/jobs/cluster",
+            post_with(start_cluster, start_cluster_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/jobs/rewrap",
+            post_with(start_rewrap, start_rewrap_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with("/jobs/:id", get_with(get_job, get_job_docs), |p| {
+            p.security_requirement("ApiKey")
+        })
+        .api_route_with(
+            "/jobs/:id/resume",
+            post_with(resume_job, resume_job_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/clusters/:id",
+            get_with(get_cluster, get_cluster_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .with_state(state)
+}
+
+async fn get_config(
+    _: Authorized<scope::Audit>,
+    Extension(summary): Extension<Arc<StartupSummary>>,
+) -> impl IntoApiResponse {
+    Json(summary).into_response()
+}
+
+fn get_config_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Configuration and backend versions resolved at startup, secrets redacted")
+        .response_with::<200, Json<StartupSummary>, _>(|res| res.description("Startup summary"))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CreateKeyRequest {
+    /// Who or what the key is for
+    name: String,
+    role: Role,
+    /// Narrow the key to some of its role's scopes, defaults to all of them
+    scopes: Option<Vec<Scope>>,
+    /// Tenant whose tree and schema the key uses, the default ones if not given
+    tenant: Option<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct CreatedKey {
+    #[serde(flatten)]
+    pub record: ApiKeyRecord,
+    /// The key itself, shown only once
+    pub key: String,
+}
+
+async fn create_key(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+    Json(request): Json<CreateKeyRequest>,
+) -> impl IntoApiResponse {
+    let scopes = request
+        .scopes
+        .unwrap_or_else(|| request.role.scopes().to_vec());
+    if scopes.is_empty() {
+        return AppError::new("a key needs at least one scope")
+            .with_status(StatusCode::BAD_REQUEST)
+            .into_response();
+    }
+    if !request.role.allows(&scopes) {
+        return AppError::new(&format!(
+            "the {} role does not allow the requested scopes",
+            request.role.as_str()
+        ))
+        .with_status(StatusCode::BAD_REQUEST)
+        .into_response();
+    }
+    if let Some(tenant) = &request.tenant {
+        if state.for_tenant(tenant).is_none() {
+            return AppError::new(&format!("there is no tenant {}", tenant))
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    }
+    let tenant = request.tenant.as_deref();
+    match api_key::create(&state.db_pool, &request.name, request.role, &scopes, tenant).await {
+        Ok((record, key)) => {
+            info!("Created API key {} for {}", record.id, record.name);
+            let mut res = Json(CreatedKey { record, key }).into_response();
+            *res.status_mut() = StatusCode::CREATED;
+            res
+        }
+        Err(err) => {
+            error!("Could not create API key: {}", err);
+            key_db_error().into_response()
+        }
+    }
+}
+
+fn create_key_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Create an API key for a role. Uploaders may upload and read images, auditors may read \
+        images, configuration and jobs, and admins may do everything.",
+    )
+    .response_with::<201, Json<CreatedKey>, _>(|res| res.description("Key created"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("no scopes given, scopes outside the role or an unknown tenant")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available")
+            .example(key_db_error())
+    })
+}
+
+async fn list_keys(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+) -> impl IntoApiResponse {
+    match api_key::list(&state.db_pool).await {
+        Ok(keys) => Json(keys).into_response(),
+        Err(err) => {
+            error!("Could not list API keys: {}", err);
+            key_db_error().into_response()
+        }
+    }
+}
+
+fn list_keys_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List API keys, without their secrets")
+        .response_with::<200, Json<Vec<ApiKeyRecord>>, _>(|res| res.description("Keys"))
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("service not available")
+                .example(key_db_error())
+        })
+}
+
+async fn revoke_key(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+    Path(id): Path<Uuid>,
+) -> impl IntoApiResponse {
+    match api_key::revoke(&state.db_pool, id).await {
+        Ok(true) => {
+            info!("Revoked API key {}", id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!("Could not revoke API key {}: {}", id, err);
+            key_db_error().into_response()
+        }
+    }
+}
+
+fn revoke_key_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Revoke an API key")
+        .response_with::<204, (), _>(|res| res.description("Key revoked"))
+        .response_with::<404, (), _>(|res| res.description("no active key with this id"))
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("service not available")
+                .example(key_db_error())
+        })
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct JobRequest {
+    /// First leaf index to work on, defaults to 0
+    start_index: Option<i64>,
+    /// Leaf index to stop before, defaults to the current tree size
+    end_index: Option<i64>,
+    /// Leaf indexes per checkpointed range, defaults to 100,000
+    shard_size: Option<i64>,
+    /// Most ranges worked on at once, defaults to 4 and capped at 16
+    parallelism: Option<i64>,
+}
+
+async fn start_backfill(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+    Json(request): Json<JobRequest>,
+) -> impl IntoApiResponse {
+    start_job(&state, JobKind::TrillianBackfill, request).await
+}
+
+async fn start_rehash(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+    Json(request): Json<JobRequest>,
+) -> impl IntoApiResponse {
+    if state.blob_store.is_none() {
+        return not_enabled("original images are not kept").into_response();
+    }
+    start_job(&state, JobKind::Rehash, request).await
+}
+
+async fn start_cluster(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+    Json(request): Json<JobRequest>,
+) -> impl IntoApiResponse {
+    if state.perceptual_index != PerceptualIndex::PgVector || state.near_duplicate_distance == 0 {
+        return not_enabled("clustering needs pgvector and a near-duplicate distance")
+            .into_response();
+    }
+    start_job(&state, JobKind::Cluster, request).await
+}
+
+async fn start_rewrap(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+    Json(request): Json<JobRequest>,
+) -> impl IntoApiResponse {
+    if !state
+        .blob_store
+        .as_ref()
+        .is_some_and(|blob_store| blob_store.encrypts())
+    {
+        return not_enabled("original images are not encrypted").into_response();
+    }
+    start_job(&state, JobKind::Rewrap, request).await
+}
+
+/// Create a job of `kind` over the requested leaf indexes
+async fn start_job(state: &AppState, kind: JobKind, request: JobRequest) -> Response {
+    let end = match request.end_index {
+        Some(end) => end,
+        None => match state
+            .trillian
+            .clone()
+            .get_tree_size(&state.trillian_tree)
+            .await
+        {
+            Ok(size) => size,
+            Err(err) => {
+                error!("Could not get tree size: {}", err);
+                return AppError::new("Could not get tree size")
+                    .with_status(StatusCode::SERVICE_UNAVAILABLE)
+                    .with_code(ErrorCode::LogUnavailable)
+                    .into_response();
+            }
+        },
+    };
+    let start = request.start_index.unwrap_or(0).max(0);
+    if end <= start {
+        return AppError::new("end_index must be greater than start_index")
+            .with_status(StatusCode::BAD_REQUEST)
+            .into_response();
+    }
+
+    let created = jobs::create(
+        state,
+        kind,
+        start,
+        end,
+        request.shard_size.unwrap_or(DEFAULT_SHARD_SIZE).max(1),
+        request
+            .parallelism
+            .unwrap_or(DEFAULT_PARALLELISM)
+            .clamp(1, MAX_PARALLELISM),
+    )
+    .await;
+    match created {
+        Ok(id) => job_response(state, id, StatusCode::ACCEPTED).await,
+        Err(err) => {
+            error!("Could not create {} job: {}", kind.as_str(), err);
+            db_error().into_response()
+        }
+    }
+}
+
+fn start_backfill_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Start a resumable job that checks Trillian leaves against the database \
+        and adds any image missing from it",
+    )
+    .response_with::<202, Json<JobProgress>, _>(|res| res.description("Job started"))
+    .response_with::<400, Json<AppError>, _>(|res| res.description("empty leaf range"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+fn start_rehash_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Start a resumable job that hashes the kept originals of integrated images again \
+        and updates perceptual hashes that changed",
+    )
+    .response_with::<202, Json<JobProgress>, _>(|res| res.description("Job started"))
+    .response_with::<400, Json<AppError>, _>(|res| res.description("empty leaf range"))
+    .response_with::<501, Json<AppError>, _>(|res| res.description("original images are not kept"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+fn start_cluster_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Start a resumable job that groups integrated images with their near-duplicates, \
+        within the near-duplicate distance",
+    )
+    .response_with::<202, Json<JobProgress>, _>(|res| res.description("Job started"))
+    .response_with::<400, Json<AppError>, _>(|res| res.description("empty leaf range"))
+    .response_with::<501, Json<AppError>, _>(|res| {
+        res.description("pgvector or the near-duplicate distance is not configured")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+fn start_rewrap_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Start a resumable job that moves the kept originals of integrated images under the \
+        current blob encryption key, encrypting any stored in plaintext",
+    )
+    .response_with::<202, Json<JobProgress>, _>(|res| res.description("Job started"))
+    .response_with::<400, Json<AppError>, _>(|res| res.description("empty leaf range"))
+    .response_with::<501, Json<AppError>, _>(|res| {
+        res.description("original images are not encrypted")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+async fn get_cluster(
+    State(state): State<AppState>,
+    _: Authorized<scope::Audit>,
+    Path(id): Path<String>,
+) -> impl IntoApiResponse {
+    let hash = match CryptographicHash::from_hex(&id) {
+        Ok(hash) => hash,
+        Err(err) => {
+            return AppError::new("Invalid id")
+                .with_details(json!(err.to_string()))
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+    match jobs::cluster(&state, &hash, CLUSTER_LIMIT).await {
+        Ok(members) if members.is_empty() => StatusCode::NOT_FOUND.into_response(),
+        Ok(members) => Json(members).into_response(),
+        Err(err) => {
+            error!("Could not get the cluster of {}: {}", hash, err);
+            db_error().into_response()
+        }
+    }
+}
+
+fn get_cluster_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "List up to 100 images in the near-duplicate cluster of an image, \
+        including the image, in log order",
+    )
+    .response_with::<200, Json<Vec<VeracityHash>>, _>(|res| res.description("Cluster members"))
+    .response_with::<400, Json<AppError>, _>(|res| res.description("invalid id"))
+    .response_with::<404, (), _>(|res| res.description("image not found or not clustered"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+async fn list_jobs(
+    State(state): State<AppState>,
+    _: Authorized<scope::Audit>,
+) -> impl IntoApiResponse {
+    match jobs::list(&state, JOB_LIST_LIMIT).await {
+        Ok(jobs) => Json(jobs).into_response(),
+        Err(err) => {
+            error!("Could not list jobs: {}", err);
+            db_error().into_response()
+        }
+    }
+}
+
+fn list_jobs_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List maintenance jobs and their progress, most recent first")
+        .response_with::<200, Json<Vec<JobProgress>>, _>(|res| res.description("Jobs"))
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("service not available").example(db_error())
+        })
+}
+
+async fn get_job(
+    State(state): State<AppState>,
+    _: Authorized<scope::Audit>,
+    Path(id): Path<Uuid>,
+) -> impl IntoApiResponse {
+    job_response(&state, id, StatusCode::OK).await
+}
+
+fn get_job_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Get the progress of a maintenance job")
+        .response_with::<200, Json<JobProgress>, _>(|res| res.description("Job progress"))
+        .response_with::<404, (), _>(|res| res.description("job not found"))
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("service not available").example(db_error())
+        })
+}
+
+async fn resume_job(
+    State(state): State<AppState>,
+    _: Authorized<scope::Admin>,
+    Path(id): Path<Uuid>,
+) -> impl IntoApiResponse {
+    match jobs::resume(&state, id).await {
+        Ok(true) => job_response(&state, id, StatusCode::ACCEPTED).await,
+        Ok(false) => AppError::new("only failed jobs can be resumed")
+            .with_status(StatusCode::CONFLICT)
+            .into_response(),
+        Err(err) => {
+            error!("Could not resume job {}: {}", id, err);
+            db_error().into_response()
+        }
+    }
+}
+
+fn resume_job_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Continue a failed maintenance job from its last checkpoints")
+        .response_with::<202, Json<JobProgress>, _>(|res| res.description("Job resumed"))
+        .response_with::<409, Json<AppError>, _>(|res| {
+            res.description("job is not failed or does not exist")
+        })
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("service not available").example(db_error())
+        })
+}
+
+async fn job_response(state: &AppState, id: Uuid, status: StatusCode) -> Response {
+    match jobs::progress(state, id).await {
+        Ok(Some(progress)) => {
+            let mut res = Json(progress).into_response();
+            *res.status_mut() = status;
+            res
+        }
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!("Could not get job {}: {}", id, err);
+            db_error().into_response()
+        }
+    }
+}
+
+fn not_enabled(message: &str) -> AppError {
+    AppError::new(message)
+        .with_status(StatusCode::NOT_IMPLEMENTED)
+        .with_code(ErrorCode::NotEnabled)
+}
+
+fn db_error() -> AppError {
+    AppError::new("Could not get job details")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
+}
+
+fn key_db_error() -> AppError {
+    AppError::new("Could not get API key details")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
+}
@@ -0,0 +1,53 @@
+use axum::extract::State;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::{error, warn};
+
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::AUTH_KEY_HEADER;
+use crate::state::AppState;
+
+/// Identify the API key a request carries and attach it to the request for [`Authorized`].
+/// Requests without a key pass through so public routes keep working; a key that is sent but
+/// unknown is rejected outright.
+///
+/// [`Authorized`]: crate::extractors::Authorized
+pub async fn authenticate<B>(
+    State(state): State<AppState>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.api_keys.is_enabled() {
+        return next.run(req).await;
+    }
+    let key = match req.headers().get(AUTH_KEY_HEADER) {
+        Some(key) => match key.to_str() {
+            Ok(key) => key.to_owned(),
+            Err(_) => return unauthorized().into_response(),
+        },
+        None => return next.run(req).await,
+    };
+
+    match state.api_keys.authenticate(&state.db_pool, &key).await {
+        Ok(Some(identity)) => {
+            req.extensions_mut().insert(identity);
+            next.run(req).await
+        }
+        Ok(None) => {
+            warn!("Rejected unknown API key");
+            unauthorized().into_response()
+        }
+        Err(err) => {
+            error!("Could not check API key: {}", err);
+            AppError::new("Could not check API key")
+                .with_status(StatusCode::SERVICE_UNAVAILABLE)
+                .with_code(ErrorCode::DatabaseUnavailable)
+                .into_response()
+        }
+    }
+}
+
+pub(crate) fn unauthorized() -> AppError {
+    AppError::new("missing or invalid API key").with_status(StatusCode::UNAUTHORIZED)
+}
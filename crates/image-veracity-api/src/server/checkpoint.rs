@@ -0,0 +1,149 @@
+use aide::axum::IntoApiResponse;
+use aide::transform::TransformOperation;
+use axum::extract::State;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_qs::axum::QsQuery;
+use tracing::error;
+
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::Json;
+use crate::state::AppState;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CosignedParams {
+    /// Fewest witness cosignatures the checkpoint must carry, defaults to 1
+    witnesses: Option<i64>,
+}
+
+/// The latest log root as a signed note, for witnesses and monitors
+pub(super) async fn get_checkpoint(State(state): State<AppState>) -> impl IntoApiResponse {
+    let signer = match &state.checkpoint_signer {
+        Some(signer) => signer,
+        None => {
+            return AppError::new("checkpoints are not enabled")
+                .with_status(StatusCode::NOT_FOUND)
+                .with_code(ErrorCode::NotEnabled)
+                .into_response();
+        }
+    };
+    let root = match state
+        .trillian
+        .clone()
+        .get_latest_root(&state.trillian_tree)
+        .await
+    {
+        Ok(root) => root,
+        Err(err) => {
+            error!("Could not get latest root for checkpoint: {}", err);
+            return trillian_error().into_response();
+        }
+    };
+
+    note(signer.checkpoint(root.tree_size, &root.root_hash))
+}
+
+pub(super) fn get_checkpoint_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Get the latest log root as a checkpoint in the transparency-dev signed note format: \
+        the origin, the tree size, and the base64 root hash on separate lines, then a blank \
+        line and an Ed25519 signature line under the server's own key. Standard witnesses and \
+        monitors can follow the log from it; the verifier key is logged at startup.",
+    )
+    .response_with::<200, String, _>(|res| res.description("signed checkpoint note"))
+    .response_with::<404, Json<AppError>, _>(|res| res.description("checkpoints are not enabled"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("the log is unreachable")
+            .example(trillian_error())
+    })
+}
+
+/// The latest checkpoint witnesses have cosigned, with their cosignature lines
+pub(super) async fn get_cosigned_checkpoint(
+    State(state): State<AppState>,
+    QsQuery(qs): QsQuery<CosignedParams>,
+) -> impl IntoApiResponse {
+    let signer = match &state.checkpoint_signer {
+        Some(signer) => signer,
+        None => {
+            return AppError::new("checkpoints are not enabled")
+                .with_status(StatusCode::NOT_FOUND)
+                .with_code(ErrorCode::NotEnabled)
+                .into_response();
+        }
+    };
+    let required = qs.witnesses.unwrap_or(1).max(1);
+    let row = match sqlx::query!(
+        r#"SELECT tree_size, root_hash,
+        array_agg(signature ORDER BY witness) AS "signatures!"
+        FROM checkpoint_cosignatures WHERE tree_id = $1
+        GROUP BY tree_size, root_hash HAVING count(*) >= $2
+        ORDER BY tree_size DESC LIMIT 1"#,
+        state.trillian_tree,
+        required,
+    )
+    .fetch_optional(state.read_pool())
+    .await
+    {
+        Ok(Some(row)) => row,
+        Ok(None) => {
+            return AppError::new("No checkpoint has enough witness cosignatures")
+                .with_status(StatusCode::NOT_FOUND)
+                .into_response();
+        }
+        Err(err) => {
+            error!("Could not read checkpoint cosignatures: {}", err);
+            return db_error().into_response();
+        }
+    };
+
+    let mut checkpoint = signer.checkpoint(row.tree_size, &row.root_hash);
+    for signature in row.signatures {
+        checkpoint.push_str(&signature);
+        checkpoint.push('\n');
+    }
+    note(checkpoint)
+}
+
+pub(super) fn get_cosigned_checkpoint_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Get the newest checkpoint cosigned by at least `witnesses` of the configured \
+        witnesses, with their cosignature lines after the log's own signature. Clients that \
+        require k-of-n witnesses verify k of these lines before trusting the root; the \
+        checkpoint may be older than the one at `/checkpoint` until witnesses catch up.",
+    )
+    .response_with::<200, String, _>(|res| res.description("cosigned checkpoint note"))
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("checkpoints are not enabled, or none has enough cosignatures")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+/// Signed note response, which must always be fetched fresh
+fn note(note: String) -> Response {
+    let mut res = note.into_response();
+    let headers = res.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    res
+}
+
+fn db_error() -> AppError {
+    AppError::new("Could not read checkpoint cosignatures")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
+}
+
+fn trillian_error() -> AppError {
+    AppError::new("Could not get the latest log root")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::LogUnavailable)
+}
@@ -0,0 +1,213 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::response::sse::Event;
+use futures::Stream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::warn;
+
+use crate::record::{ImageRecord, IntegrationStatus};
+use crate::server::images::{find_image, ImageStatus};
+use crate::state::{AppState, ImageKey};
+
+/// Status changes buffered for slow subscribers, who re-read the image if they fall behind
+const CHANNEL_CAPACITY: usize = 1024;
+/// How often open streams re-read their image, catching changes made by other instances
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Announces images whose integration status changed in this process, so event streams can
+/// update without waiting for their next poll
+#[derive(Clone)]
+pub struct StatusEvents(broadcast::Sender<[u8; 32]>);
+
+impl Default for StatusEvents {
+    fn default() -> Self {
+        StatusEvents(broadcast::channel(CHANNEL_CAPACITY).0)
+    }
+}
+
+impl StatusEvents {
+    /// Wake the streams watching the image with crypto hash `c_hash`
+    pub fn publish(&self, c_hash: &[u8]) {
+        if let Ok(hash) = c_hash.try_into() {
+            // Nobody may be listening
+            let _ = self.0.send(hash);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<[u8; 32]> {
+        self.0.subscribe()
+    }
+}
+
+/// Steps of the upload pipeline, reported in order as the image reaches them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Received,
+    Hashed,
+    Queued,
+    Integrated,
+}
+
+impl Stage {
+    const ALL: [Stage; 4] = [
+        Stage::Received,
+        Stage::Hashed,
+        Stage::Queued,
+        Stage::Integrated,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Received => "received",
+            Stage::Hashed => "hashed",
+            Stage::Queued => "queued",
+            Stage::Integrated => "integrated",
+        }
+    }
+
+    /// Latest stage of an image with `status`. Stored images have been received and hashed.
+    fn reached(status: IntegrationStatus) -> Stage {
+        match status {
+            IntegrationStatus::Pending => Stage::Hashed,
+            IntegrationStatus::Queued => Stage::Queued,
+            IntegrationStatus::Integrated => Stage::Integrated,
+        }
+    }
+
+    /// Stage named by an event ID, as sent back in `Last-Event-ID` on reconnect
+    pub fn from_event_id(id: &str) -> Option<Stage> {
+        Stage::ALL.into_iter().find(|stage| stage.as_str() == id)
+    }
+}
+
+/// Follows one image from its current stage until it is integrated
+pub struct Watch {
+    state: AppState,
+    hash: [u8; 32],
+    updates: broadcast::Receiver<[u8; 32]>,
+    recheck: Interval,
+    record: ImageRecord,
+    sent: Option<Stage>,
+}
+
+impl Watch {
+    /// Watch `record`, the image with crypto hash `hash`, skipping stages up to `sent`.
+    /// Subscribe to `updates` before reading `record` so no change falls between the two.
+    pub fn new(
+        state: AppState,
+        hash: [u8; 32],
+        updates: broadcast::Receiver<[u8; 32]>,
+        record: ImageRecord,
+        sent: Option<Stage>,
+    ) -> Self {
+        let mut recheck =
+            tokio::time::interval_at(Instant::now() + RECHECK_INTERVAL, RECHECK_INTERVAL);
+        recheck.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Watch {
+            state,
+            hash,
+            updates,
+            recheck,
+            record,
+            sent,
+        }
+    }
+
+    /// One event per stage, each carrying the image's status when it was sent. Ends once the
+    /// image is integrated.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Event, Infallible>> {
+        futures::stream::unfold(self, |mut watch| async move {
+            loop {
+                if let Some(stage) = watch.next_stage() {
+                    watch.sent = Some(stage);
+                    return Some((Ok(watch.event(stage)), watch));
+                }
+                if watch.sent == Some(Stage::Integrated) || !watch.refresh().await {
+                    return None;
+                }
+            }
+        })
+    }
+
+    fn next_stage(&self) -> Option<Stage> {
+        let reached = Stage::reached(self.record.status);
+        Stage::ALL
+            .into_iter()
+            .find(|stage| Some(*stage) > self.sent && *stage <= reached)
+    }
+
+    fn event(&self, stage: Stage) -> Event {
+        Event::default()
+            .id(stage.as_str())
+            .event(stage.as_str())
+            .json_data(ImageStatus {
+                status: self.record.status,
+                leaf: self.record.leaf.clone(),
+            })
+            .expect("statuses serialize to JSON")
+    }
+
+    /// Wait for a change to be announced or the next recheck, then re-read the image.
+    /// `false` if it no longer exists.
+    async fn refresh(&mut self) -> bool {
+        tokio::select! {
+            _ = self.recheck.tick() => {}
+            _ = changed(&mut self.updates, &self.hash) => {}
+        }
+        match find_image(&self.state, ImageKey::CryptoHash(self.hash)).await {
+            Ok(Some(record)) => {
+                self.record = record;
+                true
+            }
+            Ok(None) => false,
+            Err(err) => {
+                // Try again on the next recheck
+                warn!("Could not refresh image for event stream: {}", err);
+                true
+            }
+        }
+    }
+}
+
+/// Resolves once `hash` is announced, or when announcements were missed and it may have been
+async fn changed(updates: &mut broadcast::Receiver<[u8; 32]>, hash: &[u8; 32]) {
+    loop {
+        match updates.recv().await {
+            Ok(changed) if changed == *hash => return,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(_)) => return,
+            Err(RecvError::Closed) => return futures::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_follow_the_integration_status() {
+        assert_eq!(Stage::reached(IntegrationStatus::Pending), Stage::Hashed);
+        assert_eq!(
+            Stage::reached(IntegrationStatus::Integrated),
+            Stage::Integrated
+        );
+        assert!(Stage::Received < Stage::Hashed && Stage::Queued < Stage::Integrated);
+        assert_eq!(Stage::from_event_id("queued"), Some(Stage::Queued));
+        assert_eq!(Stage::from_event_id("pending"), None);
+    }
+
+    #[tokio::test]
+    async fn changes_wake_only_their_own_watchers() {
+        let events = StatusEvents::default();
+        let mut updates = events.subscribe();
+        events.publish(&[1; 32]);
+        events.publish(&[2; 32]);
+        // Not a crypto hash
+        events.publish(&[3; 31]);
+        changed(&mut updates, &[2; 32]).await;
+        assert!(updates.try_recv().is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::io;
+
+use aide::axum::IntoApiResponse;
+use aide::transform::TransformOperation;
+use axum::body::StreamBody;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use eyre::Report;
+use futures::TryStreamExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_qs::axum::QsQuery;
+use tracing::{debug, error};
+
+use trillian::TrillianLogLeaf;
+
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, Json};
+use crate::record::LeafDetails;
+use crate::state::AppState;
+use crate::tombstone;
+
+const DEFAULT_EXPORT_COUNT: i64 = 1000;
+const MAX_EXPORT_COUNT: i64 = 100_000;
+/// Leaves read from Trillian per request while streaming
+const EXPORT_PAGE_SIZE: i64 = 256;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExportParams {
+    /// Index of the first leaf, defaults to 0
+    start: Option<i64>,
+    /// Most leaves to export, defaults to 1000 and capped at 100000
+    count: Option<i64>,
+}
+
+/// One log leaf, as a line of the export
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ExportedLeaf {
+    /// Leaf value as hex, the crypto hash of an image
+    pub crypto_hash: String,
+    /// Leaf extra data as hex, the perceptual hash of an image
+    pub perceptual_hash: String,
+    /// Metadata logged in the extra data after the perceptual hash, when it was uploaded with
+    /// the image and the server logs metadata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    /// Signed note logged when the image was taken down, only on tombstone leaves, whose hashes
+    /// are those of the withheld image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tombstone: Option<String>,
+    #[serde(flatten)]
+    pub leaf: LeafDetails,
+}
+
+impl From<&TrillianLogLeaf> for ExportedLeaf {
+    fn from(leaf: &TrillianLogLeaf) -> Self {
+        let tombstone = tombstone::is_tombstone(&leaf.leaf_value)
+            .then(|| String::from_utf8_lossy(&leaf.leaf_value).into_owned());
+        // Tombstones carry both hashes of the withheld image as extra data
+        let (crypto_hash, perceptual_hash, metadata) = match tombstone {
+            Some(_) => {
+                let (crypto_hash, perceptual_hash) =
+                    leaf.extra_data.split_at(leaf.extra_data.len().min(32));
+                (crypto_hash, perceptual_hash, None)
+            }
+            None => {
+                let (perceptual_hash, metadata) =
+                    leaf.extra_data.split_at(leaf.extra_data.len().min(32));
+                let metadata = (!metadata.is_empty())
+                    .then(|| serde_json::from_slice(metadata).ok())
+                    .flatten();
+                (&leaf.leaf_value[..], perceptual_hash, metadata)
+            }
+        };
+        ExportedLeaf {
+            crypto_hash: hex::encode(crypto_hash),
+            perceptual_hash: hex::encode(perceptual_hash),
+            metadata,
+            tombstone,
+            leaf: LeafDetails::from(leaf),
+        }
+    }
+}
+
+/// Integrated leaves as newline-delimited JSON, read from Trillian a page at a time
+pub(super) async fn get_export(
+    State(state): State<AppState>,
+    _: Authorized<scope::Read>,
+    QsQuery(qs): QsQuery<ExportParams>,
+) -> impl IntoApiResponse {
+    debug!("export hit with query parameters {:?}", qs);
+
+    let start = qs.start.unwrap_or(0);
+    let count = qs.count.unwrap_or(DEFAULT_EXPORT_COUNT);
+    if start < 0 || count < 1 {
+        return AppError::new("start must not be negative and count must be positive")
+            .with_status(StatusCode::BAD_REQUEST)
+            .into_response();
+    }
+    let tree_size = match state
+        .trillian
+        .clone()
+        .get_tree_size(&state.trillian_tree)
+        .await
+    {
+        Ok(tree_size) => tree_size,
+        Err(err) => {
+            error!("Could not get tree size for export: {}", err);
+            return trillian_error().into_response();
+        }
+    };
+    let end = tree_size.min(start.saturating_add(count.min(MAX_EXPORT_COUNT)));
+
+    let pages = futures::stream::try_unfold((state, start), move |(state, next)| async move {
+        if next >= end {
+            return Ok(None);
+        }
+        let leaves = state
+            .trillian
+            .clone()
+            .get_leaves_by_range(&state.trillian_tree, next, EXPORT_PAGE_SIZE.min(end - next))
+            .await?;
+        if leaves.is_empty() {
+            return Ok(None);
+        }
+        let mut page = Vec::new();
+        for leaf in &leaves {
+            serde_json::to_writer(&mut page, &ExportedLeaf::from(leaf))?;
+            page.push(b'\n');
+        }
+        Ok::<_, Report>(Some((page, (state, next + leaves.len() as i64))))
+    });
+    // The status is already sent, so a failure can only cut the stream short
+    let pages = pages.map_err(|err| {
+        error!("Export stopped early: {}", err);
+        io::Error::new(io::ErrorKind::Other, err.to_string())
+    });
+
+    let mut res = StreamBody::new(pages).into_response();
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    res
+}
+
+pub(super) fn get_export_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Stream integrated log leaves as newline-delimited JSON, one object per leaf with its \
+        crypto and perceptual hashes, leaf index, Merkle leaf hash, and timestamps. Mirrors and \
+        researchers can replicate the log by paging through it with `start` and `count`; the \
+        export ends at the current tree size.",
+    )
+    .response_with::<200, Json<ExportedLeaf>, _>(|res| {
+        res.description("one JSON object per line, in leaf index order")
+    })
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid range").example(
+            AppError::new("start must not be negative and count must be positive")
+                .with_status(StatusCode::BAD_REQUEST),
+        )
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("the log is unreachable")
+            .example(trillian_error())
+    })
+}
+
+fn trillian_error() -> AppError {
+    AppError::new("Could not read the log")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::LogUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_export_as_hash_pairs() {
+        let leaf = TrillianLogLeaf {
+            leaf_value: vec![0xab; 32],
+            extra_data: vec![0xcd; 32],
+            merkle_leaf_hash: vec![0xef; 32],
+            leaf_index: 7,
+            integrate_timestamp: Some(prost_types::Timestamp {
+                seconds: 1_700_000_000,
+                nanos: 0,
+            }),
+            ..TrillianLogLeaf::default()
+        };
+        let line = serde_json::to_value(ExportedLeaf::from(&leaf)).unwrap();
+        assert_eq!(line["crypto_hash"], "ab".repeat(32));
+        assert_eq!(line["perceptual_hash"], "cd".repeat(32));
+        assert_eq!(line["merkle_leaf_hash"], "ef".repeat(32));
+        assert_eq!(line["leaf_index"], 7);
+        assert!(line.get("queue_timestamp").is_none());
+        assert!(line.get("tombstone").is_none());
+        assert!(line.get("metadata").is_none());
+    }
+
+    #[test]
+    fn logged_metadata_is_exported_after_the_perceptual_hash() {
+        let mut extra_data = vec![0xcd; 32];
+        extra_data.extend_from_slice(br#"{"caption":"Flooding on Main Street"}"#);
+        let leaf = TrillianLogLeaf {
+            leaf_value: vec![0xab; 32],
+            extra_data,
+            ..TrillianLogLeaf::default()
+        };
+        let line = serde_json::to_value(ExportedLeaf::from(&leaf)).unwrap();
+        assert_eq!(line["perceptual_hash"], "cd".repeat(32));
+        assert_eq!(line["metadata"]["caption"], "Flooding on Main Street");
+    }
+
+    #[test]
+    fn tombstones_export_with_the_withheld_hashes() {
+        let mut extra_data = vec![0xab; 32];
+        extra_data.extend_from_slice(&[0xcd; 32]);
+        let leaf = TrillianLogLeaf {
+            leaf_value: b"image-veracity tombstone v1\nabab\n".to_vec(),
+            extra_data,
+            ..TrillianLogLeaf::default()
+        };
+        let line = serde_json::to_value(ExportedLeaf::from(&leaf)).unwrap();
+        assert_eq!(line["crypto_hash"], "ab".repeat(32));
+        assert_eq!(line["perceptual_hash"], "cd".repeat(32));
+        assert_eq!(line["tombstone"], "image-veracity tombstone v1\nabab\n");
+    }
+}
@@ -0,0 +1,452 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use metrics::increment_counter;
+use tokio::sync::OwnedSemaphorePermit;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{debug, error, info, warn};
+
+use crate::api_key::{ApiKeyIdentity, Scope};
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{Submitter, AUTH_KEY_HEADER};
+use crate::protobuf::veracity::get_image_request::Hash;
+use crate::protobuf::veracity::submit_image_request::Part;
+use crate::protobuf::veracity::veracity_server::{Veracity, VeracityServer};
+use crate::protobuf::veracity::{
+    GetImageRequest, GetProofRequest, GetProofResponse, ImageRecord, SubmitImageRequest,
+    Withholding,
+};
+use crate::record::{self, IntegrationStatus};
+use crate::server::auth::unauthorized;
+use crate::server::hash_file;
+use crate::server::images::find_image;
+use crate::server::load_shed::{UploadSlots, SHED_RETRY_AFTER_SECONDS};
+use crate::server::routes::{store_image, uploads_paused, MAX_UPLOAD_SIZE};
+use crate::state::{AppState, ImageKey};
+
+/// Serve the gRPC API on `addr` until `shutdown` completes. It shares `state`, and so the
+/// database, Trillian client, API keys, and rate limits, with the HTTP server.
+pub async fn serve(
+    addr: SocketAddr,
+    state: AppState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    info!("gRPC listening on {}", addr);
+    Server::builder()
+        .add_service(VeracityServer::new(VeracityService { state }))
+        .serve_with_shutdown(addr, shutdown)
+        .await?;
+    Ok(())
+}
+
+struct VeracityService {
+    state: AppState,
+}
+
+impl VeracityService {
+    /// Authenticate the API key in the `x-auth-key` metadata entry and require `scope`, the
+    /// gRPC counterpart of [`authenticate`] and [`Authorized`]
+    ///
+    /// [`authenticate`]: crate::server::auth::authenticate
+    /// [`Authorized`]: crate::extractors::Authorized
+    async fn authorize(
+        &self,
+        metadata: &MetadataMap,
+        scope: Scope,
+    ) -> Result<Option<ApiKeyIdentity>, Status> {
+        if !self.state.api_keys.is_enabled() {
+            return Ok(None);
+        }
+        let key = metadata
+            .get(AUTH_KEY_HEADER)
+            .and_then(|key| key.to_str().ok())
+            .ok_or_else(|| status(unauthorized()))?;
+
+        match self
+            .state
+            .api_keys
+            .authenticate(&self.state.db_pool, key)
+            .await
+        {
+            Ok(Some(identity)) if identity.has_scope(scope) => Ok(Some(identity)),
+            Ok(Some(_)) => Err(Status::permission_denied(format!(
+                "API key lacks the {scope} scope"
+            ))),
+            Ok(None) => {
+                warn!("Rejected unknown API key");
+                Err(status(unauthorized()))
+            }
+            Err(err) => {
+                error!("Could not check API key: {}", err);
+                Err(Status::unavailable("Could not check API key"))
+            }
+        }
+    }
+
+    /// State for the tenant of `identity`, the gRPC counterpart of [`TenantState`]
+    ///
+    /// [`TenantState`]: crate::extractors::TenantState
+    fn tenant_state(&self, identity: Option<&ApiKeyIdentity>) -> Result<AppState, Status> {
+        match identity.and_then(|identity| identity.tenant.as_deref()) {
+            None => Ok(self.state.clone()),
+            Some(tenant) => self.state.for_tenant(tenant).ok_or_else(|| {
+                Status::permission_denied(format!("API key belongs to unknown tenant {tenant}"))
+            }),
+        }
+    }
+
+    /// Take an upload from the submitter's rate limit, see [`limit_uploads`]
+    ///
+    /// [`limit_uploads`]: crate::server::rate_limit::limit_uploads
+    fn limit_upload(&self, submitter: &Submitter) -> Result<(), Status> {
+        let wait = match self
+            .state
+            .rate_limiter
+            .check(submitter.clone(), Instant::now())
+        {
+            Ok(()) => return Ok(()),
+            Err(wait) => wait,
+        };
+        warn!("Rate limited {:?}", submitter);
+        increment_counter!("veracity_rate_limited_total");
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            "retry-after",
+            MetadataValue::from(wait.as_secs_f64().ceil() as u64),
+        );
+        Err(Status::with_metadata(
+            Code::ResourceExhausted,
+            "too many uploads, slow down",
+            metadata,
+        ))
+    }
+
+    /// Hold one of the upload slots shared with the HTTP routes until the permit is dropped,
+    /// see [`shed_uploads`]
+    ///
+    /// [`shed_uploads`]: crate::server::load_shed::shed_uploads
+    fn upload_slot(slots: &UploadSlots) -> Result<Option<OwnedSemaphorePermit>, Status> {
+        if !slots.is_enabled() {
+            return Ok(None);
+        }
+        if let Some(slot) = slots.try_acquire() {
+            return Ok(Some(slot));
+        }
+        warn!(
+            "Shed a gRPC upload, {} are already in progress",
+            slots.max()
+        );
+        increment_counter!("veracity_uploads_shed_total");
+        let mut metadata = MetadataMap::new();
+        metadata.insert("retry-after", MetadataValue::from(SHED_RETRY_AFTER_SECONDS));
+        Err(Status::with_metadata(
+            Code::ResourceExhausted,
+            "too many uploads in progress, try again shortly",
+            metadata,
+        ))
+    }
+
+    async fn lookup(state: &AppState, key: ImageKey) -> Result<record::ImageRecord, Status> {
+        match find_image(state, key).await {
+            Ok(Some(image)) => Ok(image),
+            Ok(None) => Err(Status::not_found("image not found")),
+            Err(_) => Err(Status::unavailable("Could not get image details")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Veracity for VeracityService {
+    async fn submit_image(
+        &self,
+        request: Request<Streaming<SubmitImageRequest>>,
+    ) -> Result<Response<ImageRecord>, Status> {
+        let identity = self.authorize(request.metadata(), Scope::Upload).await?;
+        let state = self.tenant_state(identity.as_ref())?;
+        let submitter = match &identity {
+            Some(identity) => Some(Submitter::from_identity(identity)),
+            None => request.remote_addr().map(|addr| Submitter::Ip(addr.ip())),
+        };
+        if let Some(submitter) = &submitter {
+            self.limit_upload(submitter)?;
+        }
+        if !state.availability.trillian_available() {
+            return Err(status(uploads_paused()));
+        }
+        // Buffered like HTTP uploads, so held to the same ceiling while the stream is read
+        let _slot = Self::upload_slot(&state.upload_slots)?;
+
+        let mut stream = request.into_inner();
+        let mut attestation = None;
+        let mut buffer = Vec::new();
+        while let Some(message) = stream.message().await? {
+            match message.part {
+                Some(Part::Attestation(_)) if !buffer.is_empty() => {
+                    return Err(Status::invalid_argument(
+                        "the attestation has to come before the image",
+                    ));
+                }
+                Some(Part::Attestation(sent)) => attestation = Some(sent),
+                Some(Part::Chunk(chunk)) => {
+                    if buffer.len() + chunk.len() > MAX_UPLOAD_SIZE {
+                        return Err(status(
+                            AppError::new(&format!(
+                                "file too large, the limit is {MAX_UPLOAD_SIZE} bytes"
+                            ))
+                            .with_status(StatusCode::PAYLOAD_TOO_LARGE),
+                        ));
+                    }
+                    buffer.extend_from_slice(&chunk);
+                }
+                None => {}
+            }
+        }
+        if buffer.is_empty() {
+            return Err(Status::invalid_argument("no image chunks were sent"));
+        }
+        state.image_types.check(None, &buffer).map_err(status)?;
+
+        let file = hash_file(&state.hash_pool, buffer)
+            .await
+            .map_err(|err| match err.code {
+                ErrorCode::Overloaded => status(err),
+                _ => Status::invalid_argument(format!("Could not hash image: {}", err.error)),
+            })?;
+        let (attestation_format, attestation) = match attestation {
+            Some(sent) => (Some(sent.format), Some(sent.attestation)),
+            None => (None, None),
+        };
+        let image = store_image(
+            &state,
+            file,
+            attestation_format,
+            attestation,
+            None,
+            None,
+            submitter.as_ref(),
+        )
+        .await
+        .map_err(status)?;
+        Ok(Response::new(image.record.into()))
+    }
+
+    async fn get_image(
+        &self,
+        request: Request<GetImageRequest>,
+    ) -> Result<Response<ImageRecord>, Status> {
+        let identity = self.authorize(request.metadata(), Scope::Read).await?;
+        let state = self.tenant_state(identity.as_ref())?;
+        let key = match request.into_inner().hash {
+            Some(Hash::CryptoHash(hash)) => ImageKey::CryptoHash(hash_bytes(&hash)?),
+            Some(Hash::PerceptualHash(hash)) => ImageKey::PerceptualHash(hash_bytes(&hash)?),
+            None => return Err(Status::invalid_argument("a hash is required")),
+        };
+        let image = Self::lookup(&state, key).await?;
+        debug!("retrieved {}", image.hash.crypto_hash);
+        Ok(Response::new(image.into()))
+    }
+
+    async fn get_proof(
+        &self,
+        request: Request<GetProofRequest>,
+    ) -> Result<Response<GetProofResponse>, Status> {
+        let identity = self.authorize(request.metadata(), Scope::Read).await?;
+        let state = self.tenant_state(identity.as_ref())?;
+        let crypto_hash = hash_bytes(&request.into_inner().crypto_hash)?;
+        let image = Self::lookup(&state, ImageKey::CryptoHash(crypto_hash)).await?;
+        if image.status != IntegrationStatus::Integrated {
+            return Err(Status::failed_precondition(format!(
+                "image is {}, proofs are available once it is integrated",
+                image.status.as_str()
+            )));
+        }
+
+        let mut trillian = state.trillian.clone();
+        let proof = async {
+            let tree_size = trillian.get_tree_size(&state.trillian_tree).await?;
+            let proofs = trillian
+                .get_inclusion_proof_by_hash(
+                    &state.trillian_tree,
+                    &image.hash.merkle_leaf_hash(),
+                    tree_size,
+                )
+                .await?;
+            Ok::<_, eyre::Report>((tree_size, proofs.into_iter().next()))
+        }
+        .await;
+        match proof {
+            Ok((tree_size, Some(proof))) => Ok(Response::new(GetProofResponse {
+                leaf_index: proof.leaf_index,
+                tree_size,
+                hashes: proof.hashes,
+            })),
+            Ok((_, None)) => Err(Status::not_found("the log has no proof for this image")),
+            Err(err) => {
+                error!("Could not get inclusion proof: {}", err);
+                Err(Status::unavailable("Could not get inclusion proof"))
+            }
+        }
+    }
+}
+
+impl From<record::ImageRecord> for ImageRecord {
+    fn from(image: record::ImageRecord) -> Self {
+        ImageRecord {
+            crypto_hash: image.hash.crypto_hash.as_ref().to_vec(),
+            perceptual_hash: image.hash.perceptual_hash.as_ref().to_vec(),
+            status: image.status.as_str().to_string(),
+            leaf_index: image.leaf.leaf_index,
+            merkle_leaf_hash: image
+                .leaf
+                .merkle_leaf_hash
+                .and_then(|hash| hex::decode(hash).ok())
+                .unwrap_or_default(),
+            queue_timestamp: image.leaf.queue_timestamp.map(timestamp),
+            integrate_timestamp: image.leaf.integrate_timestamp.map(timestamp),
+            attested: image.attested,
+            withheld: image.withheld.map(Withholding::from),
+        }
+    }
+}
+
+impl From<record::Withholding> for Withholding {
+    fn from(withholding: record::Withholding) -> Self {
+        Withholding {
+            withheld_at: Some(timestamp(withholding.withheld_at)),
+            reason: withholding.reason,
+            tombstone_leaf_hash: hex::decode(withholding.tombstone_leaf_hash).unwrap_or_default(),
+        }
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn hash_bytes(hash: &[u8]) -> Result<[u8; 32], Status> {
+    hash.try_into()
+        .map_err(|_| Status::invalid_argument("hashes are 32 bytes"))
+}
+
+/// The gRPC status closest to the HTTP status of `err`
+fn status(err: AppError) -> Status {
+    let code = match err.status {
+        StatusCode::BAD_REQUEST
+        | StatusCode::UNSUPPORTED_MEDIA_TYPE
+        | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::AlreadyExists,
+        StatusCode::PAYLOAD_TOO_LARGE => Code::OutOfRange,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    Status::new(code, err.error)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::hash::VeracityHash;
+    use crate::record::LeafDetails;
+
+    use super::*;
+
+    #[test]
+    fn errors_map_to_grpc_codes() {
+        let conflict = status(
+            AppError::new("image already exists in database").with_status(StatusCode::CONFLICT),
+        );
+        assert_eq!(conflict.code(), Code::AlreadyExists);
+        assert_eq!(conflict.message(), "image already exists in database");
+        assert_eq!(status(uploads_paused()).code(), Code::Unavailable);
+        assert_eq!(status(unauthorized()).code(), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn uploads_over_the_ceiling_are_refused() {
+        let slots = UploadSlots::new(1);
+        let held = VeracityService::upload_slot(&slots).unwrap();
+        assert!(held.is_some());
+        let refused = VeracityService::upload_slot(&slots).unwrap_err();
+        assert_eq!(refused.code(), Code::ResourceExhausted);
+        assert_eq!(refused.metadata().get("retry-after").unwrap(), "1");
+        drop(held);
+        assert!(VeracityService::upload_slot(&slots).unwrap().is_some());
+        assert!(VeracityService::upload_slot(&UploadSlots::new(0))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn records_convert_to_messages() {
+        let integrated = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let image: ImageRecord = record::ImageRecord {
+            hash: VeracityHash::default(),
+            status: IntegrationStatus::Integrated,
+            leaf: LeafDetails {
+                leaf_index: Some(7),
+                merkle_leaf_hash: Some("00ff".to_string()),
+                queue_timestamp: None,
+                integrate_timestamp: Some(integrated),
+            },
+            attested: false,
+            attestation: None,
+            withheld: None,
+            metadata: None,
+        }
+        .into();
+        assert_eq!(image.status, "integrated");
+        assert_eq!(image.leaf_index, Some(7));
+        assert_eq!(image.merkle_leaf_hash, vec![0x00, 0xff]);
+        assert_eq!(image.crypto_hash.len(), 32);
+        assert_eq!(image.queue_timestamp, None);
+        assert_eq!(
+            image.integrate_timestamp.map(|ts| ts.seconds),
+            Some(integrated.timestamp())
+        );
+        assert_eq!(image.withheld, None);
+    }
+
+    #[test]
+    fn withheld_records_carry_their_takedown() {
+        let withheld_at = Utc.with_ymd_and_hms(2024, 3, 1, 9, 30, 0).unwrap();
+        let image: ImageRecord = record::ImageRecord {
+            withheld: Some(record::Withholding {
+                withheld_at,
+                reason: "court order".to_string(),
+                tombstone_leaf_hash: "ab".repeat(32),
+            }),
+            ..Default::default()
+        }
+        .into();
+        let withheld = image.withheld.unwrap();
+        assert_eq!(
+            withheld.withheld_at.map(|ts| ts.seconds),
+            Some(withheld_at.timestamp())
+        );
+        assert_eq!(withheld.reason, "court order");
+        assert_eq!(withheld.tombstone_leaf_hash, vec![0xab; 32]);
+    }
+
+    #[test]
+    fn hashes_must_be_32_bytes() {
+        assert!(hash_bytes(&[0; 32]).is_ok());
+        assert_eq!(
+            hash_bytes(&[0; 31]).unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use axum::http::StatusCode;
+use eyre::{bail, Result};
+use image::ImageFormat;
+use serde_json::json;
+
+use crate::errors::AppError;
+
+/// Image types uploads may have, checked against the magic bytes of every upload before it is
+/// decoded. JPEG and PNG, the types images can be hashed from, unless narrowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedImageTypes(Vec<ImageFormat>);
+
+impl Default for AllowedImageTypes {
+    fn default() -> Self {
+        AllowedImageTypes(vec![ImageFormat::Jpeg, ImageFormat::Png])
+    }
+}
+
+impl AllowedImageTypes {
+    /// Read a comma separated list of media types, such as `image/jpeg,image/png`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut formats = vec![];
+        for media_type in spec
+            .split(',')
+            .map(|media_type| media_type.trim().to_ascii_lowercase())
+            .filter(|media_type| !media_type.is_empty())
+        {
+            match ImageFormat::from_mime_type(&media_type) {
+                Some(format) if media_type_of(format).is_some() => {
+                    if !formats.contains(&format) {
+                        formats.push(format);
+                    }
+                }
+                _ => bail!("{media_type} cannot be allowed, only image/jpeg and image/png"),
+            }
+        }
+        if formats.is_empty() {
+            bail!("at least one image type has to be allowed");
+        }
+        Ok(AllowedImageTypes(formats))
+    }
+
+    /// Media types allowed, in the order they were given
+    pub fn media_types(&self) -> Vec<&'static str> {
+        self.0
+            .iter()
+            .filter_map(|format| media_type_of(*format))
+            .collect()
+    }
+
+    /// Check that `body` starts with the magic bytes of an allowed type, and of the type
+    /// `content_type` declares. A missing or `application/octet-stream` content type leaves the
+    /// type to the sniffed bytes. Rejections are 415 errors detailing the declared, detected
+    /// and allowed types.
+    pub fn check(&self, content_type: Option<&str>, body: &[u8]) -> Result<ImageFormat, AppError> {
+        let declared = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|essence| essence.trim().to_ascii_lowercase());
+        let sniffed = image::guess_format(body).ok();
+        let unsupported = |message: &str| {
+            AppError::new(message)
+                .with_status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                .with_details(json!({
+                    "declared": declared,
+                    "detected": sniffed.and_then(media_type_of),
+                    "allowed": self.media_types(),
+                }))
+        };
+
+        let Some(sniffed) = sniffed else {
+            return Err(unsupported("body is not a recognized image"));
+        };
+        if !self.0.contains(&sniffed) {
+            return Err(unsupported(&format!(
+                "{sniffed:?} images are not accepted, only {}",
+                self.media_types().join(", ")
+            )));
+        }
+        match declared.as_deref() {
+            None | Some("application/octet-stream") => Ok(sniffed),
+            Some(essence) if essence.starts_with("image/") => {
+                match ImageFormat::from_mime_type(essence) {
+                    Some(format) if format == sniffed => Ok(sniffed),
+                    _ => Err(unsupported(&format!(
+                        "content type is {essence} but the body is a {sniffed:?} image"
+                    ))),
+                }
+            }
+            Some(essence) => Err(unsupported(&format!(
+                "content type must be an image type, not {essence}"
+            ))),
+        }
+    }
+}
+
+/// Media type of the formats images can be hashed from
+fn media_type_of(format: ImageFormat) -> Option<&'static str> {
+    match format {
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Png => Some("image/png"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn raw_uploads_must_be_declared_images() {
+        let allowed = AllowedImageTypes::default();
+        assert_eq!(allowed.check(None, PNG).unwrap(), ImageFormat::Png);
+        assert_eq!(
+            allowed.check(Some("image/png"), PNG).unwrap(),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            allowed
+                .check(Some("application/octet-stream"), PNG)
+                .unwrap(),
+            ImageFormat::Png
+        );
+        for content_type in [Some("image/jpeg"), Some("text/plain")] {
+            assert_eq!(
+                allowed.check(content_type, PNG).unwrap_err().status,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            );
+        }
+        assert!(allowed.check(Some("image/png"), b"not an image").is_err());
+        assert!(allowed.check(None, b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn mismatches_are_detailed() {
+        let allowed = AllowedImageTypes::parse("image/jpeg").unwrap();
+        let err = allowed.check(Some("image/png"), PNG).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            err.error_details,
+            Some(json!({
+                "declared": "image/png",
+                "detected": "image/png",
+                "allowed": ["image/jpeg"],
+            }))
+        );
+    }
+
+    #[test]
+    fn only_hashable_types_can_be_allowed() {
+        assert_eq!(
+            AllowedImageTypes::parse(" image/png, IMAGE/JPEG,image/png").unwrap(),
+            AllowedImageTypes(vec![ImageFormat::Png, ImageFormat::Jpeg])
+        );
+        assert!(AllowedImageTypes::parse("image/gif").is_err());
+        assert!(AllowedImageTypes::parse("text/plain").is_err());
+        assert!(AllowedImageTypes::parse("").is_err());
+    }
+}
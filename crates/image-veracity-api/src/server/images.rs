@@ -1,9 +1,14 @@
-use aide::axum::routing::get_with;
+use aide::axum::routing::{get_with, post_with};
 use aide::axum::{ApiRouter, IntoApiResponse};
 use aide::transform::TransformOperation;
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware;
+use axum::response::sse::{KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, SecondsFormat, Utc};
 use hex::FromHex;
 use schemars::JsonSchema;
 use serde::{de, Deserialize, Deserializer, Serialize};
@@ -11,27 +16,313 @@ use serde_json::json;
 use serde_qs::axum::QsQuery;
 use std::fmt;
 use std::str::FromStr;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-use crate::errors::AppError;
-use crate::extractors::Json;
+use crate::api_key::{ApiKeyIdentity, Scope};
+use crate::attestation::AttestationVerdict;
+use crate::errors::{AppError, ErrorCode, LookupError};
+use crate::extractors::{
+    scope, Authorized, Format, Json, SubmittedBy, Submitter, TenantState, AUTH_KEY_HEADER,
+};
+use crate::fetch::FetchError;
 use crate::hash::cryptographic::CryptographicHash;
 use crate::hash::perceptual::PerceptualHash;
 use crate::hash::VeracityHash;
-use crate::state::AppState;
+use crate::metadata::UploadMetadata;
+use crate::public_id::PublicIds;
+use crate::record::{ImageRecord, IntegrationStatus, LeafDetails, SimilarImage};
+use crate::repository::ListPosition;
+use crate::server::events::{Stage, Watch};
+use crate::server::hash_file;
+use crate::server::load_shed;
+use crate::server::negotiate::response_format;
+use crate::server::originals;
+use crate::server::rate_limit;
+use crate::server::routes::{store_image, uploads_paused, StoredImageOutput, MAX_UPLOAD_SIZE};
+use crate::server::takedown;
+use crate::state::{AppState, ImageKey};
 
 pub fn image_routes(state: AppState) -> ApiRouter {
     ApiRouter::new()
-        .api_route("/", get_with(get_image_by_params, get_image_by_params_docs))
-        .api_route("/:id", get_with(get_image, get_image_docs))
+        .api_route_with(
+            "/",
+            get_with(get_image_by_params, get_image_by_params_docs)
+                .put_with(put_image, put_image_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with("/fetch", post_with(fetch_image, fetch_image_docs), |p| {
+            p.security_requirement("ApiKey")
+        })
+        .api_route_with(
+            "/similar",
+            get_with(get_similar_images, get_similar_images_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with("/count", get_with(count_images, count_images_docs), |p| {
+            p.security_requirement("ApiKey")
+        })
+        .api_route_with(
+            "/:id",
+            get_with(get_image, get_image_docs)
+                .head_with(head_image, head_image_docs)
+                .delete_with(takedown::withhold_image, takedown::withhold_image_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id/exists",
+            get_with(image_exists, image_exists_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id/status",
+            get_with(get_image_status, get_image_status_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id/proof",
+            get_with(get_image_proof, get_image_proof_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id/events",
+            get_with(get_image_events, get_image_events_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id/original",
+            get_with(originals::get_original, originals::get_original_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id/thumbnail",
+            get_with(originals::get_thumbnail, originals::get_thumbnail_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            load_shed::shed_uploads,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_uploads,
+        ))
         .with_state(state)
 }
 
+/// Header carrying the attestation format for raw body uploads
+const ATTESTATION_FORMAT_HEADER: &str = "X-Attestation-Format";
+/// Header carrying the attestation for raw body uploads
+const ATTESTATION_HEADER: &str = "X-Attestation";
+
+/// Store an image sent as the raw request body rather than in a multipart form
+async fn put_image(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Upload>,
+    SubmittedBy(submitter): SubmittedBy,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoApiResponse {
+    if !state.availability.trillian_available() {
+        return uploads_paused().into_response();
+    }
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if let Err(err) = state.image_types.check(content_type, &body) {
+        return err.into_response();
+    }
+
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    hash_and_store(
+        &state,
+        body.to_vec(),
+        header(ATTESTATION_FORMAT_HEADER),
+        header(ATTESTATION_HEADER),
+        submitter.as_ref(),
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct FetchRequest {
+    /// `http` or `https` URL of a JPEG or PNG image on an allowlisted host
+    url: String,
+}
+
+/// Download an image from a URL and store it like an upload
+async fn fetch_image(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Upload>,
+    SubmittedBy(submitter): SubmittedBy,
+    Json(request): Json<FetchRequest>,
+) -> impl IntoApiResponse {
+    let fetcher = match &state.url_fetcher {
+        Some(fetcher) => fetcher,
+        None => {
+            return AppError::new("fetching images by URL is not enabled")
+                .with_status(StatusCode::NOT_FOUND)
+                .with_code(ErrorCode::NotEnabled)
+                .into_response();
+        }
+    };
+    if !state.availability.trillian_available() {
+        return uploads_paused().into_response();
+    }
+
+    let body = match fetcher.fetch(&request.url).await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Could not fetch {}: {}", request.url, err);
+            return fetch_error(err).into_response();
+        }
+    };
+    if let Err(err) = state.image_types.check(None, &body) {
+        return err.into_response();
+    }
+    debug!("fetched {} bytes from {}", body.len(), request.url);
+    hash_and_store(&state, body, None, None, submitter.as_ref()).await
+}
+
+fn fetch_error(err: FetchError) -> AppError {
+    let status = match err {
+        FetchError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+        FetchError::HostNotAllowed(_) | FetchError::NonPublicAddress(_) => StatusCode::FORBIDDEN,
+        FetchError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        FetchError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        FetchError::TooManyRedirects | FetchError::Status(_) | FetchError::Request(_) => {
+            StatusCode::BAD_GATEWAY
+        }
+    };
+    AppError::new(&err.to_string()).with_status(status)
+}
+
+fn fetch_image_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Download a JPEG or PNG image from a URL and store it. Only hosts on the server's \
+        allowlist that resolve to public addresses are fetched; redirects are followed up to \
+        three times, each checked the same way.",
+    )
+    .response_with::<201, Json<StoredImageOutput>, _>(|res| res.description("stored image"))
+    .response_with::<200, Json<StoredImageOutput>, _>(|res| {
+        res.description("image was already stored, when duplicates are answered with it")
+    })
+    .response_with::<400, Json<AppError>, _>(|res| res.description("invalid URL or image"))
+    .response_with::<401, Json<AppError>, _>(|res| res.description("missing or invalid API key"))
+    .response_with::<403, Json<AppError>, _>(|res| {
+        res.description("host is not allowlisted or not public")
+    })
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("fetching by URL is not enabled")
+    })
+    .response_with::<409, Json<AppError>, _>(|res| {
+        res.description("image already stored, which is in the error details")
+    })
+    .response_with::<413, Json<AppError>, _>(|res| {
+        res.description("image larger than the upload limit")
+    })
+    .response_with::<415, Json<AppError>, _>(|res| res.description("not a supported image"))
+    .response_with::<429, Json<AppError>, _>(|res| {
+        res.description("upload rate limit exceeded, retry after the Retry-After delay")
+    })
+    .response_with::<502, Json<AppError>, _>(|res| {
+        res.description("the remote server failed or could not be reached")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("downstream dependency unavailable, or the log is unreachable")
+    })
+    .response_with::<504, Json<AppError>, _>(|res| res.description("the download timed out"))
+}
+
+/// Hash an image held in memory and store it, responding with the stored record
+async fn hash_and_store(
+    state: &AppState,
+    body: Vec<u8>,
+    attestation_format: Option<String>,
+    attestation: Option<String>,
+    submitter: Option<&Submitter>,
+) -> Response {
+    let file = match hash_file(&state.hash_pool, body).await {
+        Ok(file) => file,
+        Err(err) => {
+            return AppError::new("Could not hash image")
+                .with_details(json!(err))
+                .with_status(StatusCode::BAD_REQUEST)
+                .with_code(ErrorCode::HashFailed)
+                .into_response();
+        }
+    };
+    match store_image(
+        state,
+        file,
+        attestation_format,
+        attestation,
+        None,
+        None,
+        submitter,
+    )
+    .await
+    {
+        Ok(stored) => (stored.status(), Json(stored)).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+fn put_image_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Store an image sent as the raw request body, for clients that cannot easily build a \
+        multipart form, e.g. `curl -T photo.jpg -H 'Content-Type: image/jpeg' .../images`. \
+        The image type is sniffed from its first bytes and has to match the `Content-Type`. \
+        A device attestation may be sent in the `X-Attestation-Format` and `X-Attestation` \
+        headers.",
+    )
+    .response_with::<201, Json<StoredImageOutput>, _>(|res| res.description("stored image"))
+    .response_with::<200, Json<StoredImageOutput>, _>(|res| {
+        res.description("image was already stored, when duplicates are answered with it")
+    })
+    .response_with::<400, Json<AppError>, _>(|res| res.description("could not hash image"))
+    .response_with::<401, Json<AppError>, _>(|res| res.description("missing or invalid API key"))
+    .response_with::<409, Json<AppError>, _>(|res| {
+        res.description("image already stored, which is in the error details")
+    })
+    .response_with::<413, (), _>(|res| res.description("image larger than the upload limit"))
+    .response_with::<415, Json<AppError>, _>(|res| {
+        res.description("body is not a supported image, or not the declared type")
+    })
+    .response_with::<422, Json<AppError>, _>(|res| {
+        res.description("attestation could not be verified")
+    })
+    .response_with::<429, Json<AppError>, _>(|res| {
+        res.description("upload rate limit exceeded, retry after the Retry-After delay")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("downstream dependency unavailable, or the log is unreachable")
+    })
+}
+
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 500;
+
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct Params {
     #[serde(default, deserialize_with = "empty_string_as_none")]
     /// Get image by perceptual hash
     p: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    /// Page size when listing images, defaults to 50 and capped at 500
+    limit: Option<i64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    /// Opaque cursor from a previous listing page
+    cursor: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    /// Only list images submitted with this API key, by key ID. Keys without the audit scope
+    /// can only list their own.
+    submitter: Option<String>,
 }
 
 /// Serde deserialization decorator to map empty Strings to None,
@@ -49,15 +340,29 @@ where
 }
 
 async fn get_image_by_params(
-    State(AppState { db_pool, .. }): State<AppState>,
+    TenantState(state): TenantState,
+    Authorized(identity, _): Authorized<scope::Read>,
+    headers: HeaderMap,
     QsQuery(qs): QsQuery<Params>,
 ) -> impl IntoApiResponse {
     debug!("images hit with query parameters {:?}", qs);
 
-    if qs.p.is_none() {
-        return StatusCode::NOT_FOUND.into_response();
+    if let Some(submitter) = &qs.submitter {
+        if let Err(err) = check_submitter(identity.as_ref(), submitter) {
+            return err.into_response();
+        }
     }
-    let p = qs.p.unwrap();
+    let p = match (qs.p, &qs.submitter) {
+        (Some(_), Some(_)) => {
+            return AppError::new("submitter filters listings, it cannot be combined with p")
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+        (Some(p), None) => p,
+        (None, submitter) => {
+            return list_images(&state, submitter.as_deref(), qs.limit, qs.cursor).await
+        }
+    };
 
     // TODO remove legacy support
     let (_, p) = if p.starts_with("0x") {
@@ -66,15 +371,6 @@ async fn get_image_by_params(
         ("", p.as_str())
     };
 
-    let pool = db_pool.clone();
-    let conn = match pool.get().await {
-        Ok(conn) => conn,
-        Err(err) => {
-            error!("{}", err);
-            return db_error().into_response();
-        }
-    };
-    // create the accounts and get the IDs
     let p_hash_hex: [u8; 32] = match <[u8; 32]>::from_hex(p) {
         Ok(x) => x,
         Err(err) => {
@@ -85,116 +381,740 @@ async fn get_image_by_params(
         }
     };
 
-    let image_vec: (Vec<u8>, Vec<u8>) = match conn
-        .query(
-            "SELECT c_hash, p_hash FROM images WHERE p_hash = $1::BYTEA LIMIT 1",
-            &[&&p_hash_hex[..]],
-        )
-        .await
+    match find_image(&state, ImageKey::PerceptualHash(p_hash_hex)).await {
+        Ok(Some(image)) => {
+            debug!("retrieved {}", image.hash.crypto_hash);
+            conditional(&headers, image, "record", |image| {
+                Json(image).into_response()
+            })
+        }
+        Ok(None) => {
+            debug!("No records found for {}", &p);
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(_) => db_error().into_response(),
+    }
+}
+
+/// List stored images in insertion order, one page at a time, only those submitted with the API
+/// key `submitter` when given
+async fn list_images(
+    state: &AppState,
+    submitter: Option<&str>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+) -> Response {
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let cursor = match cursor
+        .as_deref()
+        .map(|cursor| ListCursor::decode(cursor, &state.public_ids))
     {
-        Ok(result) => match &result[..] {
-            [row_hashes] => (row_hashes.get(0), row_hashes.get(1)),
-            _ => {
-                debug!("No records found for {}", &p);
-                return StatusCode::NOT_FOUND.into_response();
-            }
-        },
-        Err(err) => {
-            error!("Error getting from database: {}", err);
-            return db_error().into_response();
+        None => None,
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => {
+            return AppError::new("Invalid cursor")
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response();
         }
     };
 
-    let image = VeracityHash {
-        crypto_hash: CryptographicHash::try_from(image_vec.0).unwrap(),
-        perceptual_hash: PerceptualHash::try_from(image_vec.1).unwrap(),
+    // Fetch one extra image to find out whether there is a next page
+    let after = cursor.map(|cursor| ListPosition {
+        created_at: cursor.created_at,
+        crypto_hash: cursor.crypto_hash,
+    });
+    let mut records = match state
+        .images
+        .list(submitter, after.as_ref(), limit + 1)
+        .await
+    {
+        Ok(records) => records,
+        Err(_) => return db_error().into_response(),
+    };
+
+    let next_cursor = if records.len() > limit as usize {
+        records.truncate(limit as usize);
+        records.last().map(|image| {
+            let position = image.position();
+            ListCursor {
+                created_at: position.created_at,
+                crypto_hash: position.crypto_hash,
+            }
+            .encode(&state.public_ids)
+        })
+    } else {
+        None
     };
-    debug!("retrieved {}", image.crypto_hash);
-    Json(image).into_response()
+
+    let public_ids = &state.public_ids;
+    let images: Vec<ImageListItem> = records
+        .into_iter()
+        .map(|image| ImageListItem {
+            id: public_ids.id(&image.record.hash.crypto_hash),
+            image: public_ids.reveals_hashes().then_some(image.record),
+            created_at: image.created_at,
+        })
+        .collect();
+
+    debug!("listed {} images", images.len());
+    Json(ImagePage {
+        images,
+        next_cursor,
+    })
+    .into_response()
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ImageListItem {
+    /// Public ID, the crypto hash unless opaque IDs are enabled
+    pub id: String,
+    /// Stored hashes and leaf details, omitted when opaque IDs are enabled
+    #[serde(flatten)]
+    pub image: Option<ImageRecord>,
+    /// When the image was stored
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ImagePage {
+    pub images: Vec<ImageListItem>,
+    /// Pass as `cursor` to get the next page, absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Either a single image looked up by perceptual hash or a page of the listing
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ImageQueryOutput {
+    Image(ImageRecordOutput),
+    Page(ImagePage),
+}
+
+impl From<VeracityHash> for ImageQueryOutput {
+    fn from(value: VeracityHash) -> Self {
+        ImageQueryOutput::Image(value.into())
+    }
+}
+
+/// Position of the last image of a listing page.
+/// Encoded as base64 so clients treat it as opaque, and encrypted when opaque IDs are enabled.
+#[derive(Debug, PartialEq)]
+struct ListCursor {
+    created_at: DateTime<Utc>,
+    crypto_hash: [u8; 32],
+}
+
+impl ListCursor {
+    fn encode(&self, public_ids: &PublicIds) -> String {
+        public_ids.seal_cursor(&format!(
+            "{}|{}",
+            self.created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            hex::encode(self.crypto_hash)
+        ))
+    }
+
+    fn decode(cursor: &str, public_ids: &PublicIds) -> Option<ListCursor> {
+        let decoded = public_ids.open_cursor(cursor)?;
+        let (created_at, crypto_hash) = decoded.split_once('|')?;
+        Some(ListCursor {
+            created_at: DateTime::parse_from_rfc3339(created_at)
+                .ok()?
+                .with_timezone(&Utc),
+            crypto_hash: <[u8; 32]>::from_hex(crypto_hash).ok()?,
+        })
+    }
 }
 
 fn get_image_by_params_docs(op: TransformOperation) -> TransformOperation {
-    op.description("Get image by query parameter")
-        .response_with::<200, Json<VeracityHashOutput>, _>(|res| {
-            res.example(VeracityHash {
-                perceptual_hash: PerceptualHash::from_hex(
-                    "9cfde03dc4198467ad671d171c071c5b1ff81bf919d9181838f8f890f807ff01",
-                )
-                .unwrap(),
-                crypto_hash: CryptographicHash::from_b64(
-                    "oY1OmtqoZ32_nUVGgKzmAAdn6Bo0ndvr-YhnDRYju4U",
-                )
+    op.description(
+        "Get image by perceptual hash, or list images in insertion order when no hash is given. \
+        Listings can be narrowed to the images one API key submitted with `submitter`.",
+    )
+    .response_with::<200, Json<ImageQueryOutput>, _>(|res| {
+        res.example(VeracityHash {
+            perceptual_hash: PerceptualHash::from_hex(
+                "9cfde03dc4198467ad671d171c071c5b1ff81bf919d9181838f8f890f807ff01",
+            )
+            .unwrap(),
+            crypto_hash: CryptographicHash::from_b64("oY1OmtqoZ32_nUVGgKzmAAdn6Bo0ndvr-YhnDRYju4U")
                 .unwrap(),
-            })
+        })
+    })
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid Id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<304, (), _>(|res| res.description("not modified since the ETag sent"))
+    .response_with::<403, Json<AppError>, _>(|res| {
+        res.description("listing another key's images needs the audit scope")
+    })
+    .response_with::<404, (), _>(|res| res.description("image not found"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CountParams {
+    /// API key ID to count the images of. Keys without the audit scope can only count their own.
+    submitter: String,
+}
+
+/// How many images an API key has submitted
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SubmitterCount {
+    pub submitter: String,
+    pub count: i64,
+}
+
+async fn count_images(
+    TenantState(state): TenantState,
+    Authorized(identity, _): Authorized<scope::Read>,
+    QsQuery(qs): QsQuery<CountParams>,
+) -> impl IntoApiResponse {
+    if let Err(err) = check_submitter(identity.as_ref(), &qs.submitter) {
+        return err.into_response();
+    }
+    match state.images.count_submitted(&qs.submitter).await {
+        Ok(count) => Json(SubmitterCount {
+            submitter: qs.submitter,
+            count,
+        })
+        .into_response(),
+        Err(_) => db_error().into_response(),
+    }
+}
+
+fn count_images_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Count the images submitted with an API key, so the owner of a key can audit what it \
+        has logged. List them with `GET /images?submitter=`.",
+    )
+    .response_with::<200, Json<SubmitterCount>, _>(|res| res.description("image count"))
+    .response_with::<403, Json<AppError>, _>(|res| {
+        res.description("counting another key's images needs the audit scope")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+/// Check that `identity` may see what the API key `submitter` stored. Keys may see their own
+/// submissions, keys with the audit scope anyone's, and anyone may when API keys are not enabled.
+fn check_submitter(identity: Option<&ApiKeyIdentity>, submitter: &str) -> Result<(), AppError> {
+    match identity {
+        Some(identity)
+            if !identity.has_scope(Scope::Audit)
+                && Submitter::from_identity(identity).api_key() != Some(submitter) =>
+        {
+            Err(
+                AppError::new("only keys with the audit scope can see what other keys submitted")
+                    .with_status(StatusCode::FORBIDDEN),
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Default maximum Hamming distance for similarity search, out of 256 bits
+const DEFAULT_SIMILAR_DISTANCE: u32 = 10;
+const DEFAULT_SIMILAR_LIMIT: i64 = 10;
+const MAX_SIMILAR_LIMIT: i64 = 100;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SimilarParams {
+    /// Perceptual hash to search near
+    p: String,
+    /// Maximum Hamming distance between perceptual hashes, defaults to 10
+    distance: Option<u32>,
+    /// Maximum number of results, defaults to 10 and capped at 100
+    limit: Option<i64>,
+}
+
+async fn get_similar_images(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    QsQuery(qs): QsQuery<SimilarParams>,
+) -> impl IntoApiResponse {
+    debug!("similar images hit with query parameters {:?}", qs);
+
+    let target = match PerceptualHash::from_hex(&qs.p) {
+        Ok(x) => x,
+        Err(err) => {
+            return AppError::new("Invalid perceptual hash")
+                .with_details(json!(err.to_string()))
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    };
+    let max_distance = qs.distance.unwrap_or(DEFAULT_SIMILAR_DISTANCE);
+    let limit = qs
+        .limit
+        .unwrap_or(DEFAULT_SIMILAR_LIMIT)
+        .clamp(1, MAX_SIMILAR_LIMIT);
+
+    match find_similar(&state, &target, max_distance, limit).await {
+        Ok(similar) => {
+            debug!("found {} similar images", similar.len());
+            Json(similar).into_response()
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Up to `limit` stored images whose perceptual hashes are within `max_distance` of `target`,
+/// closest first
+async fn find_similar(
+    state: &AppState,
+    target: &PerceptualHash,
+    max_distance: u32,
+    limit: i64,
+) -> Result<Vec<SimilarImage>, AppError> {
+    state
+        .images
+        .similar(target, max_distance, limit)
+        .await
+        .map_err(|_| db_error())
+}
+
+/// Other stored images within `max_distance` of a newly stored `image`, such as re-encoded
+/// copies. Lookup failures are logged and reported as no matches, so they never fail an upload.
+pub(crate) async fn near_duplicates(
+    state: &AppState,
+    image: &VeracityHash,
+    max_distance: u32,
+) -> Vec<SimilarImage> {
+    // The image finds itself at distance 0
+    let similar = find_similar(
+        state,
+        &image.perceptual_hash,
+        max_distance,
+        DEFAULT_SIMILAR_LIMIT + 1,
+    )
+    .await;
+    match similar {
+        Ok(mut similar) => {
+            similar.retain(|other| other.image.crypto_hash != image.crypto_hash);
+            similar.truncate(DEFAULT_SIMILAR_LIMIT as usize);
+            similar
+        }
+        Err(err) => {
+            warn!("Could not check for near-duplicates: {}", err.error);
+            vec![]
+        }
+    }
+}
+
+fn get_similar_images_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Find images with perceptual hashes within a Hamming distance")
+        .response_with::<200, Json<Vec<SimilarImage>>, _>(|res| {
+            res.description(
+                "similar images, closest first, among the latest 10,000 without pgvector",
+            )
         })
         .response_with::<400, Json<AppError>, _>(|res| {
-            res.description("invalid request")
-                .example(AppError::new("Invalid Id").with_status(StatusCode::BAD_REQUEST))
+            res.description("invalid request").example(
+                AppError::new("Invalid perceptual hash").with_status(StatusCode::BAD_REQUEST),
+            )
         })
-        .response_with::<404, (), _>(|res| res.description("image not found"))
         .response_with::<503, Json<AppError>, _>(|res| {
             res.description("service not available").example(db_error())
         })
 }
 
 async fn get_image(
-    State(AppState { db_pool, .. }): State<AppState>,
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
     Path(id): Path<String>,
+    headers: HeaderMap,
 ) -> impl IntoApiResponse {
-    let pool = db_pool.clone();
-    let conn = match pool.get().await {
-        Ok(conn) => conn,
-        Err(err) => {
-            error!("{}", err);
-            return db_error().into_response();
+    let id_hex: [u8; 32] = match <[u8; 32]>::from_hex(&id) {
+        Ok(x) => x,
+        Err(err) => return invalid_id(err).into_response(),
+    };
+
+    match find_image(&state, ImageKey::CryptoHash(id_hex)).await {
+        Ok(Some(image)) => {
+            debug!("retrieved {}", image.hash.crypto_hash);
+            conditional(&headers, image, "record", |image| {
+                Json(image).into_response()
+            })
+        }
+        Ok(None) => {
+            debug!("No records found for {}", &id);
+            StatusCode::NOT_FOUND.into_response()
         }
+        Err(_) => db_error().into_response(),
+    }
+}
+
+/// `GET /images/:id` without the body, answered from an existence check rather than the record
+async fn head_image(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    Path(id): Path<String>,
+) -> impl IntoApiResponse {
+    match contains_image(&state, &id).await {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+fn head_image_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Check whether an image is stored, without a body")
+        .response_with::<200, (), _>(|res| res.description("image found"))
+        .response_with::<400, (), _>(|res| res.description("invalid id"))
+        .response_with::<404, (), _>(|res| res.description("image not found"))
+        .response_with::<503, (), _>(|res| res.description("service not available"))
+}
+
+/// Whether an image is stored, for dedupe checks that only need a yes or no
+async fn image_exists(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    Path(id): Path<String>,
+) -> impl IntoApiResponse {
+    match contains_image(&state, &id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+fn image_exists_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Check whether an image is stored, by crypto hash. Cheaper than getting its details, \
+        and answered without a body either way.",
+    )
+    .response_with::<204, (), _>(|res| res.description("image found"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<404, (), _>(|res| res.description("image not found"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+/// Whether the image with crypto hash `id` is stored. A cached lookup answers it when there is
+/// one; otherwise the repository is asked, without reading or caching the record.
+async fn contains_image(state: &AppState, id: &str) -> Result<bool, AppError> {
+    let id_hex: [u8; 32] = <[u8; 32]>::from_hex(id).map_err(invalid_id)?;
+    if let Some(cached) = state.image_cache.get(&ImageKey::CryptoHash(id_hex)).await {
+        return Ok(cached.is_some());
+    }
+    state
+        .images
+        .contains_crypto(&id_hex)
+        .await
+        .map_err(|_| db_error())
+}
+
+/// Where an image is in the Trillian log
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ImageStatus {
+    pub status: IntegrationStatus,
+    #[serde(flatten)]
+    pub leaf: LeafDetails,
+}
+
+async fn get_image_status(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoApiResponse {
+    let id_hex: [u8; 32] = match <[u8; 32]>::from_hex(&id) {
+        Ok(x) => x,
+        Err(err) => return invalid_id(err).into_response(),
     };
 
+    match find_image(&state, ImageKey::CryptoHash(id_hex)).await {
+        Ok(Some(image)) => conditional(&headers, image, "status", |image| {
+            Json(ImageStatus {
+                status: image.status,
+                leaf: image.leaf,
+            })
+            .into_response()
+        }),
+        Ok(None) => {
+            debug!("No records found for {}", &id);
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(_) => db_error().into_response(),
+    }
+}
+
+fn get_image_status_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Get whether an image is still waiting to be queued to Trillian, queued, \
+        or integrated into the tree",
+    )
+    .response_with::<200, Json<ImageStatus>, _>(|res| res.description("integration status"))
+    .response_with::<304, (), _>(|res| res.description("not modified since the ETag sent"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<404, (), _>(|res| res.description("image not found"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ProofParams {
+    /// Size of the tree to prove inclusion in, defaults to the latest
+    tree_size: Option<i64>,
+}
+
+/// Audit path proving an image is a leaf of the log
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct InclusionProof {
+    /// Position of the leaf in the log
+    pub leaf_index: i64,
+    /// Size of the tree the proof is for
+    pub tree_size: i64,
+    /// Audit path as hex, from the leaf up to the root
+    pub hashes: Vec<String>,
+}
+
+async fn get_image_proof(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    Path(id): Path<String>,
+    QsQuery(qs): QsQuery<ProofParams>,
+) -> impl IntoApiResponse {
     let id_hex: [u8; 32] = match <[u8; 32]>::from_hex(&id) {
         Ok(x) => x,
-        Err(err) => {
-            return AppError::new("Invalid id")
-                .with_details(json!(err.to_string()))
-                .with_status(StatusCode::BAD_REQUEST)
-                .into_response();
+        Err(err) => return invalid_id(err).into_response(),
+    };
+    if qs.tree_size.map_or(false, |tree_size| tree_size < 1) {
+        return AppError::new("tree_size must be positive")
+            .with_status(StatusCode::BAD_REQUEST)
+            .into_response();
+    }
+
+    let image = match find_image(&state, ImageKey::CryptoHash(id_hex)).await {
+        Ok(Some(image)) => image,
+        Ok(None) => {
+            debug!("No records found for {}", &id);
+            return StatusCode::NOT_FOUND.into_response();
         }
+        Err(_) => return db_error().into_response(),
     };
+    if image.status != IntegrationStatus::Integrated {
+        return AppError::new(&format!(
+            "image is {}, proofs are available once it is integrated",
+            image.status.as_str()
+        ))
+        .with_status(StatusCode::CONFLICT)
+        .into_response();
+    }
 
-    let image_vec: (Vec<u8>, Vec<u8>) = match conn
-        .query(
-            "SELECT c_hash, p_hash FROM images WHERE c_hash = $1::BYTEA LIMIT 1",
-            &[&&id_hex[..]],
-        )
-        .await
-    {
-        Ok(result) => match &result[..] {
-            [row_hashes] => (row_hashes.get(0), row_hashes.get(1)),
-            _ => {
-                debug!("No records found for {}", &id);
-                return StatusCode::NOT_FOUND.into_response();
-            }
-        },
+    let mut trillian = state.trillian.clone();
+    let proof = async {
+        let tree_size = match qs.tree_size {
+            Some(tree_size) => tree_size,
+            None => trillian.get_tree_size(&state.trillian_tree).await?,
+        };
+        let proofs = trillian
+            .get_inclusion_proof_by_hash(
+                &state.trillian_tree,
+                &image.hash.merkle_leaf_hash(),
+                tree_size,
+            )
+            .await?;
+        Ok::<_, eyre::Report>((tree_size, proofs.into_iter().next()))
+    }
+    .await;
+    match proof {
+        Ok((tree_size, Some(proof))) => Json(InclusionProof {
+            leaf_index: proof.leaf_index,
+            tree_size,
+            hashes: proof.hashes.iter().map(hex::encode).collect(),
+        })
+        .into_response(),
+        Ok((_, None)) => AppError::new("the log has no proof for this image at that tree size")
+            .with_status(StatusCode::NOT_FOUND)
+            .into_response(),
         Err(err) => {
-            error!("Error getting from database: {}", err);
-            return db_error().into_response();
+            error!("Could not get inclusion proof: {}", err);
+            proof_error().into_response()
         }
+    }
+}
+
+fn get_image_proof_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Get the inclusion proof of an integrated image, by crypto hash, in the latest tree or \
+        the tree of `tree_size` leaves. Check it against the root hash of a checkpoint of the \
+        same size, e.g. with `veracity_verify::verify_inclusion` and the RFC 6962 leaf hash of \
+        the crypto hash.",
+    )
+    .response_with::<200, Json<InclusionProof>, _>(|res| res.description("inclusion proof"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("image not found, or not in a tree of that size")
+    })
+    .response_with::<409, Json<AppError>, _>(|res| res.description("image not integrated yet"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available")
+            .example(proof_error())
+    })
+}
+
+fn proof_error() -> AppError {
+    AppError::new("Could not get inclusion proof")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::LogUnavailable)
+}
+
+/// Stream the image's progress through the pipeline as server-sent events
+async fn get_image_events(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoApiResponse {
+    let id_hex: [u8; 32] = match <[u8; 32]>::from_hex(&id) {
+        Ok(x) => x,
+        Err(err) => return invalid_id(err).into_response(),
     };
+    // A reconnecting client has already seen the stages up to the last event ID
+    let sent = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(Stage::from_event_id);
+
+    let updates = state.status_events.subscribe();
+    match find_image(&state, ImageKey::CryptoHash(id_hex)).await {
+        Ok(Some(image)) => Sse::new(Watch::new(state, id_hex, updates, image, sent).into_stream())
+            .keep_alive(KeepAlive::default())
+            .into_response(),
+        Ok(None) => {
+            debug!("No records found for {}", &id);
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(_) => db_error().into_response(),
+    }
+}
 
-    let image = VeracityHash {
-        crypto_hash: CryptographicHash::try_from(image_vec.0).unwrap(),
-        perceptual_hash: PerceptualHash::try_from(image_vec.1).unwrap(),
+fn get_image_events_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Follow an image through the pipeline as a `text/event-stream`. One event is sent for \
+        each stage the image has reached, `received`, `hashed`, `queued` and `integrated`, \
+        with its integration status as data. The stream ends once the image is integrated.",
+    )
+    .response_with::<200, (), _>(|res| res.description("event stream"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<404, (), _>(|res| res.description("image not found"))
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+fn invalid_id(err: hex::FromHexError) -> AppError {
+    AppError::new("Invalid id")
+        .with_details(json!(err.to_string()))
+        .with_status(StatusCode::BAD_REQUEST)
+}
+
+/// Look up a single image by one of its hashes.
+/// Recent results are served from the image cache, and identical concurrent lookups share a
+/// single repository query.
+pub(crate) async fn find_image(
+    state: &AppState,
+    key: ImageKey,
+) -> Result<Option<ImageRecord>, LookupError> {
+    if let Some(cached) = state.image_cache.get(&key).await {
+        return Ok(cached);
+    }
+    let generation = state.image_cache.generation(&key).await;
+    let images = state.images.clone();
+    let lookup = key.clone();
+    let found = state
+        .image_lookups
+        .run(key.clone(), move || async move {
+            match &lookup {
+                ImageKey::CryptoHash(hash) => images.get_by_crypto(hash).await,
+                ImageKey::PerceptualHash(hash) => images.get_by_perceptual(hash).await,
+            }
+        })
+        .await?;
+    state
+        .image_cache
+        .insert(key, found.clone(), generation)
+        .await;
+    Ok(found)
+}
+
+/// Cache-Control of image records. Only clients may keep them, revalidating on every use, since
+/// even an integrated record changes when its image is withheld and a takedown must show at once.
+const RECORD_CACHE_CONTROL: &str = "private, no-cache";
+
+/// Strong entity tag of the `variant` of an image record sent in `format`. The crypto hash names
+/// the image, and the stage its record has reached tells versions of the record apart, since its
+/// status and leaf details change until it is integrated and it may be withheld after.
+fn record_etag(image: &ImageRecord, variant: &str, format: Format) -> String {
+    let stage = match image.withheld {
+        Some(_) => "withheld",
+        None => image.status.as_str(),
     };
-    debug!("retrieved {}", image.crypto_hash);
-    Json(image).into_response()
+    let encoding = format.content_type().trim_start_matches("application/");
+    format!(
+        "\"{variant}-{}-{stage}-{encoding}\"",
+        image.hash.crypto_hash.to_hex()
+    )
+}
+
+/// Answer a lookup of `image` with `respond`, or `304 Not Modified` without encoding a body when
+/// the request's `If-None-Match` names the record's current entity tag, with caching headers
+/// either way. Responses differ by API key as well as by `Accept`, as keys may belong to
+/// different tenants.
+fn conditional(
+    headers: &HeaderMap,
+    image: ImageRecord,
+    variant: &str,
+    respond: impl FnOnce(ImageRecord) -> Response,
+) -> Response {
+    let tag = record_etag(&image, variant, response_format());
+    let mut res = if originals::etag_matches(headers, &tag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        respond(image)
+    };
+    let res_headers = res.headers_mut();
+    res_headers.insert(
+        ETAG,
+        HeaderValue::from_str(&tag).expect("hex is a header value"),
+    );
+    res_headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static(RECORD_CACHE_CONTROL),
+    );
+    res_headers.append(VARY, HeaderValue::from_static(AUTH_KEY_HEADER));
+    res
 }
 
 fn db_error() -> AppError {
-    AppError::new("Could not get image details").with_status(StatusCode::SERVICE_UNAVAILABLE)
+    AppError::new("Could not get image details")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
 }
 
 fn get_image_docs(op: TransformOperation) -> TransformOperation {
     op.description("Get image details")
-        .response_with::<200, Json<VeracityHashOutput>, _>(|res| {
+        .response_with::<200, Json<ImageRecordOutput>, _>(|res| {
             res.example(VeracityHash {
                 perceptual_hash: PerceptualHash::from_hex(
                     "9cfde03dc4198467ad671d171c071c5b1ff81bf919d9181838f8f890f807ff01",
@@ -206,6 +1126,7 @@ fn get_image_docs(op: TransformOperation) -> TransformOperation {
                 .unwrap(),
             })
         })
+        .response_with::<304, (), _>(|res| res.description("not modified since the ETag sent"))
         .response_with::<400, Json<AppError>, _>(|res| {
             res.description("invalid request")
                 .example(AppError::new("Invalid Id").with_status(StatusCode::BAD_REQUEST))
@@ -230,3 +1151,155 @@ impl From<VeracityHash> for VeracityHashOutput {
         }
     }
 }
+
+/// Documented shape of a serialized [`ImageRecord`]
+#[derive(Default, Serialize, JsonSchema)]
+pub struct ImageRecordOutput {
+    #[serde(flatten)]
+    pub hash: VeracityHashOutput,
+    pub status: IntegrationStatus,
+    #[serde(flatten)]
+    pub leaf: LeafDetails,
+    pub attested: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<AttestationVerdict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<UploadMetadata>,
+}
+
+impl From<VeracityHash> for ImageRecordOutput {
+    fn from(value: VeracityHash) -> Self {
+        ImageRecordOutput {
+            hash: value.into(),
+            ..ImageRecordOutput::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::IF_NONE_MATCH;
+    use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+    use chrono::TimeZone;
+
+    use crate::record::Withholding;
+
+    use super::*;
+
+    #[test]
+    fn only_auditors_see_other_keys_submissions() {
+        let id = uuid::Uuid::new_v4();
+        let mut identity = ApiKeyIdentity {
+            id: Some(id),
+            name: "uploader".to_string(),
+            role: None,
+            scopes: vec![Scope::Read, Scope::Upload],
+            tenant: None,
+        };
+        assert!(check_submitter(Some(&identity), &id.to_string()).is_ok());
+        let err = check_submitter(Some(&identity), "someone else").unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        identity.scopes.push(Scope::Audit);
+        assert!(check_submitter(Some(&identity), "someone else").is_ok());
+        assert!(check_submitter(None, "someone else").is_ok());
+    }
+
+    #[test]
+    fn list_cursor_round_trip() {
+        let cursor = ListCursor {
+            created_at: Utc.with_ymd_and_hms(2023, 6, 20, 11, 54, 15).unwrap()
+                + chrono::Duration::microseconds(123_456),
+            crypto_hash: [0xAB; 32],
+        };
+        for public_ids in [PublicIds::CryptoHash, PublicIds::hmac(b"secret")] {
+            let encoded = cursor.encode(&public_ids);
+            assert_eq!(
+                ListCursor::decode(&encoded, &public_ids).as_ref(),
+                Some(&cursor)
+            );
+        }
+    }
+
+    #[test]
+    fn list_cursor_rejects_garbage() {
+        let public_ids = PublicIds::default();
+        assert_eq!(ListCursor::decode("not a cursor", &public_ids), None);
+        assert_eq!(
+            ListCursor::decode(
+                &BASE64_URL_SAFE_NO_PAD.encode("2023-06-20T11:54:15Z|abc"),
+                &public_ids
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn record_etags_change_with_the_record() {
+        let mut image = ImageRecord::default();
+        let pending = record_etag(&image, "record", Format::Json);
+        assert_eq!(
+            pending,
+            format!("\"record-{}-pending-json\"", "00".repeat(32))
+        );
+        assert_ne!(record_etag(&image, "status", Format::Json), pending);
+        assert_ne!(record_etag(&image, "record", Format::Cbor), pending);
+
+        image.status = IntegrationStatus::Integrated;
+        let integrated = record_etag(&image, "record", Format::Json);
+        assert_ne!(integrated, pending);
+        image.withheld = Some(Withholding {
+            withheld_at: Utc::now(),
+            reason: "court order".to_string(),
+            tombstone_leaf_hash: "ab".repeat(32),
+        });
+        assert_ne!(record_etag(&image, "record", Format::Json), integrated);
+    }
+
+    #[test]
+    fn matching_lookups_are_not_modified() {
+        let image = ImageRecord::default();
+        let tag = record_etag(&image, "record", Format::Json);
+        let mut headers = HeaderMap::new();
+        let res = conditional(&headers, image.clone(), "record", |image| {
+            Json(image).into_response()
+        });
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ETAG], tag.as_str());
+        assert_eq!(res.headers()[CACHE_CONTROL], RECORD_CACHE_CONTROL);
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&tag).unwrap());
+        let res = conditional(&headers, image, "record", |_| {
+            panic!("not modified lookups are not encoded")
+        });
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[ETAG], tag.as_str());
+    }
+
+    #[test]
+    fn withheld_records_are_not_served_stale() {
+        let mut image = ImageRecord {
+            status: IntegrationStatus::Integrated,
+            ..ImageRecord::default()
+        };
+        let mut headers = HeaderMap::new();
+        let res = conditional(&headers, image.clone(), "record", |image| {
+            Json(image).into_response()
+        });
+        assert_eq!(res.headers()[CACHE_CONTROL], "private, no-cache");
+        headers.insert(IF_NONE_MATCH, res.headers()[ETAG].clone());
+
+        // A client revalidating its copy after the takedown gets the withheld record
+        image.withheld = Some(Withholding {
+            withheld_at: Utc::now(),
+            reason: "court order".to_string(),
+            tombstone_leaf_hash: "ab".repeat(32),
+        });
+        let res = conditional(&headers, image, "record", |image| {
+            Json(image).into_response()
+        });
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers()[ETAG], headers[IF_NONE_MATCH]);
+        assert_eq!(res.headers()[CACHE_CONTROL], "private, no-cache");
+    }
+}
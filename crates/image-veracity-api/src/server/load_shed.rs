@@ -0,0 +1,118 @@
+//! Load shedding for uploads.
+//!
+//! Uploads are buffered in memory, up to [`MAX_UPLOAD_SIZE`] each, so a burst of them can exhaust
+//! memory long before the CPU is busy. Only so many are let through at once, and those over the
+//! ceiling are refused straight away with `503 Service Unavailable` rather than queued, like
+//! tower's `ConcurrencyLimit` behind `LoadShed` but shared by every upload route.
+//!
+//! [`MAX_UPLOAD_SIZE`]: crate::server::routes::MAX_UPLOAD_SIZE
+
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use metrics::increment_counter;
+use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::errors::{AppError, ErrorCode};
+use crate::state::AppState;
+
+/// Uploads let through at once unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 32;
+/// Seconds shed clients are asked to wait before trying again
+pub(crate) const SHED_RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Slots for the uploads in progress, or no ceiling at all
+#[derive(Clone, Debug)]
+pub struct UploadSlots {
+    max: usize,
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl Default for UploadSlots {
+    fn default() -> Self {
+        UploadSlots::new(DEFAULT_MAX_CONCURRENT_UPLOADS)
+    }
+}
+
+impl UploadSlots {
+    /// Let `max` uploads through at once, or any number when 0
+    pub fn new(max: usize) -> Self {
+        UploadSlots {
+            max,
+            slots: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.slots.is_some()
+    }
+
+    /// Uploads let through at once, 0 for any number
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// A slot held until the permit is dropped, or `None` when every slot is taken
+    pub(crate) fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.slots.clone()?.try_acquire_owned().ok()
+    }
+}
+
+/// Refuse uploads with `503 Service Unavailable` and a `Retry-After` while the ceiling is
+/// reached. `POST`, `PUT` and `PATCH` requests take a slot for as long as they are handled, so
+/// upload forms and lookups on the same routes are never shed.
+pub async fn shed_uploads<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.upload_slots.is_enabled()
+        || !matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH)
+    {
+        return next.run(req).await;
+    }
+    let Some(_slot) = state.upload_slots.try_acquire() else {
+        warn!(
+            "Shed an upload, {} are already in progress",
+            state.upload_slots.max
+        );
+        increment_counter!("veracity_uploads_shed_total");
+        let mut res = AppError::new("too many uploads in progress, try again shortly")
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)
+            .with_code(ErrorCode::Overloaded)
+            .with_details(json!({ "retry_after_seconds": SHED_RETRY_AFTER_SECONDS }))
+            .into_response();
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(SHED_RETRY_AFTER_SECONDS));
+        return res;
+    };
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_freed_when_uploads_finish() {
+        let slots = UploadSlots::new(2);
+        let first = slots.try_acquire();
+        let second = slots.try_acquire();
+        assert!(first.is_some() && second.is_some());
+        assert!(slots.try_acquire().is_none());
+        drop(first);
+        assert!(slots.try_acquire().is_some());
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        assert!(!UploadSlots::new(0).is_enabled());
+        assert!(UploadSlots::default().is_enabled());
+    }
+}
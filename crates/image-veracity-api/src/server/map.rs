@@ -0,0 +1,164 @@
+use aide::axum::routing::get_with;
+use aide::axum::{ApiRouter, IntoApiResponse};
+use aide::transform::TransformOperation;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use hex::FromHex;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::json;
+use tracing::error;
+
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, Json};
+use crate::map::MapRoot;
+use crate::state::AppState;
+
+pub fn map_routes(state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route("/root", get_with(get_map_root, get_map_root_docs))
+        .api_route_with(
+            "/:phash/proof",
+            get_with(get_map_proof, get_map_proof_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .with_state(state)
+}
+
+/// Latest root of the map from perceptual hashes to crypto hashes
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct MapRootOutput {
+    /// Revision of the map, one more for each batch of images added
+    pub revision: u64,
+    /// Root hash as hex
+    pub root_hash: String,
+    /// Trillian tree the map's node hashes commit to
+    pub tree_id: i64,
+}
+
+/// Proof that a perceptual hash is in the map with its crypto hash, or that it is absent
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct MapProofOutput {
+    /// Revision of the map the proof is for
+    pub revision: u64,
+    /// Root hash of that revision as hex
+    pub root_hash: String,
+    /// Trillian tree the map's node hashes commit to
+    pub tree_id: i64,
+    /// Crypto hash of the image with the perceptual hash as hex, null if there is none
+    pub crypto_hash: Option<String>,
+    /// Hashes of the siblings on the key's path as hex, from the leaf level up to just below
+    /// the root, null for empty subtrees
+    pub siblings: Vec<Option<String>>,
+}
+
+impl MapRootOutput {
+    fn new(root: MapRoot, tree_id: i64) -> Self {
+        MapRootOutput {
+            revision: root.revision,
+            root_hash: hex::encode(root.root_hash),
+            tree_id,
+        }
+    }
+}
+
+async fn get_map_root(State(state): State<AppState>) -> impl IntoApiResponse {
+    match &state.veracity_map {
+        Some(map) => Json(MapRootOutput::new(map.root(), state.trillian_tree)).into_response(),
+        None => not_enabled().into_response(),
+    }
+}
+
+fn get_map_root_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Get the latest root of the verifiable map from perceptual hashes to crypto hashes. The \
+        map is a sparse Merkle tree hashed like a Trillian map, with the CONIKS hasher and the \
+        log's tree ID. Each image is a leaf keyed by the SHA-256 of its perceptual hash, whose \
+        value is its crypto hash.",
+    )
+    .response_with::<200, Json<MapRootOutput>, _>(|res| res.description("map root"))
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("the map is not enabled")
+            .example(not_enabled())
+    })
+}
+
+async fn get_map_proof(
+    State(state): State<AppState>,
+    _: Authorized<scope::Read>,
+    Path(phash): Path<String>,
+) -> impl IntoApiResponse {
+    let map = match &state.veracity_map {
+        Some(map) => map.clone(),
+        None => return not_enabled().into_response(),
+    };
+    let p_hash = match <[u8; 32]>::from_hex(&phash) {
+        Ok(x) => x,
+        Err(err) => {
+            return AppError::new("Invalid perceptual hash")
+                .with_details(json!(err.to_string()))
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+
+    // Proofs are read from memory, but hold the map's lock while they are built
+    match tokio::task::spawn_blocking(move || map.prove(&p_hash)).await {
+        Ok(Ok(proof)) => Json(MapProofOutput {
+            revision: proof.root.revision,
+            root_hash: hex::encode(proof.root.root_hash),
+            tree_id: state.trillian_tree,
+            crypto_hash: proof.crypto_hash.map(hex::encode),
+            siblings: proof
+                .proof
+                .siblings
+                .iter()
+                .map(|sibling| sibling.map(hex::encode))
+                .collect(),
+        })
+        .into_response(),
+        Ok(Err(err)) => {
+            error!("Could not prove {} in the veracity map: {}", phash, err);
+            proof_error().into_response()
+        }
+        Err(err) => {
+            error!("Veracity map proof panicked: {}", err);
+            proof_error().into_response()
+        }
+    }
+}
+
+fn get_map_proof_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Prove whether a perceptual hash, as hex, is in the latest revision of the map. A \
+        known hash comes with the crypto hash of its image, and the proof leads from the CONIKS \
+        leaf hash of that crypto hash to the root. An unknown hash has a null `crypto_hash`, \
+        and the proof leads from the empty subtree holding its key, proving it is absent.",
+    )
+    .response_with::<200, Json<MapProofOutput>, _>(|res| {
+        res.description("inclusion or non-inclusion proof")
+    })
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid perceptual hash").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("the map is not enabled")
+            .example(not_enabled())
+    })
+    .response_with::<500, Json<AppError>, _>(|res| {
+        res.description("the proof could not be built")
+            .example(proof_error())
+    })
+}
+
+fn not_enabled() -> AppError {
+    AppError::new("the veracity map is not enabled")
+        .with_status(StatusCode::NOT_FOUND)
+        .with_code(ErrorCode::NotEnabled)
+}
+
+fn proof_error() -> AppError {
+    AppError::new("Could not build the map proof").with_status(StatusCode::INTERNAL_SERVER_ERROR)
+}
@@ -1,20 +1,54 @@
 use std::io;
 
 use axum::body::Bytes;
+use axum::http::StatusCode;
 use axum::BoxError;
 use futures::{Stream, TryStreamExt};
+use metrics::increment_counter;
+use ring::digest::{digest, SHA256};
 use serde_json::json;
 use tokio::io::AsyncReadExt;
 use tokio_util::io::StreamReader;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-use crate::errors::AppError;
+use crate::errors::{AppError, ErrorCode};
 use crate::hash::{hash_image, HashError, VeracityHash};
+use crate::hash_pool::{HashPool, Overloaded};
 
+mod admin;
+pub mod auth;
+mod checkpoint;
+pub mod events;
+mod export;
+pub mod grpc;
+pub mod image_types;
 mod images;
+pub mod load_shed;
+mod map;
+pub mod negotiate;
+mod originals;
+pub mod rate_limit;
+pub mod request_id;
+pub mod retry;
 pub mod routes;
+mod takedown;
+pub mod tls;
+mod tus;
+mod uploads;
+#[cfg(feature = "test-vectors")]
+mod vectors;
+mod webhooks;
 
-async fn stream_to_file<S, E>(path: &str, stream: S) -> Result<VeracityHash, AppError>
+/// An uploaded file read into memory and hashed
+pub(crate) struct HashedFile {
+    pub hash: VeracityHash,
+    /// SHA-256 of the raw bytes, which device attestations are bound to
+    pub digest: [u8; 32],
+    pub bytes: Vec<u8>,
+}
+
+/// Read an uploaded file into memory, up to `max_bytes` long
+async fn read_upload<S, E>(path: &str, stream: S, max_bytes: usize) -> Result<Vec<u8>, AppError>
 where
     S: Stream<Item = Result<Bytes, E>>,
     E: Into<BoxError>,
@@ -32,8 +66,13 @@ where
 
         futures::pin_mut!(body_reader);
 
+        // Read one byte past the limit to tell a file of exactly max_bytes from a larger one
         let mut buffer = Vec::new();
-        match body_reader.read_to_end(&mut buffer).await {
+        match body_reader
+            .take(max_bytes as u64 + 1)
+            .read_to_end(&mut buffer)
+            .await
+        {
             Ok(_) => debug!("read multipart buffer"),
             Err(err) => {
                 error!("could not read buffer: {}", err.to_string());
@@ -42,43 +81,64 @@ where
             }
         }
 
-        match parallel_hash(buffer).await {
-            Ok(hash) => {
-                debug!("created hash {:?}", hash);
-                Ok(hash)
-            }
-            Err(err) => {
-                error!("error while hashing {}", err.to_string());
-                Err(AppError::new(&err.to_string()))
-            }
+        if buffer.len() > max_bytes {
+            return Err(AppError::new("file too large")
+                .with_status(StatusCode::PAYLOAD_TOO_LARGE)
+                .with_details(json!({ "max_bytes": max_bytes })));
         }
+
+        Ok(buffer)
     }
     .await
 }
 
-async fn parallel_hash(buffer: Vec<u8>) -> Result<VeracityHash, HashError> {
-    let (send, recv) = tokio::sync::oneshot::channel();
-
-    // Spawn a task on rayon.
-    rayon::spawn(move || {
-        match hash_image(&buffer) {
-            Ok(veracity) => {
-                debug!(
-                    "image phash {} chash {}",
-                    veracity.perceptual_hash, veracity.crypto_hash
-                );
-                // Send the result back to Tokio.
-                let _ = send.send(Ok(veracity));
-            }
-            Err(err) => {
-                error!("{}", err);
-                let _ = send.send(Err(err));
-            }
+/// Hash a file read into memory on the hashing pool, failing with a 503 when its queue is full
+async fn hash_file(pool: &HashPool, buffer: Vec<u8>) -> Result<HashedFile, AppError> {
+    let file_digest: [u8; 32] = digest(&SHA256, &buffer)
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are 32 bytes");
+
+    let hashed = parallel_hash(pool, buffer).await.map_err(|err| {
+        warn!("Refused to hash an upload: {}", err);
+        increment_counter!("veracity_hash_queue_full_total");
+        AppError::from(err)
+    })?;
+    match hashed {
+        (bytes, Ok(hash)) => {
+            debug!("created hash {:?}", hash);
+            Ok(HashedFile {
+                hash,
+                digest: file_digest,
+                bytes,
+            })
         }
-    });
+        (_, Err(err)) => {
+            error!("error while hashing {}", err.to_string());
+            Err(AppError::new(&err.to_string()).with_code(ErrorCode::HashFailed))
+        }
+    }
+}
 
-    // Wait for the rayon task.
-    recv.await.expect("Panic in rayon::spawn")
+/// Hash on the hashing pool, handing the buffer back alongside the result
+async fn parallel_hash(
+    pool: &HashPool,
+    buffer: Vec<u8>,
+) -> Result<(Vec<u8>, Result<VeracityHash, HashError>), Overloaded> {
+    pool.run(move || match hash_image(&buffer) {
+        Ok(veracity) => {
+            debug!(
+                "image phash {} chash {}",
+                veracity.perceptual_hash, veracity.crypto_hash
+            );
+            (buffer, Ok(veracity))
+        }
+        Err(err) => {
+            error!("{}", err);
+            (buffer, Err(err))
+        }
+    })
+    .await
 }
 
 fn path_is_valid(path: &str) -> bool {
@@ -0,0 +1,98 @@
+use axum::http::header::VARY;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::extractors::Format;
+
+tokio::task_local! {
+    static RESPONSE_FORMAT: Format;
+}
+
+/// Format responses to the request being handled on this task are encoded in, JSON outside of
+/// [`negotiate_format`]
+pub fn response_format() -> Format {
+    RESPONSE_FORMAT
+        .try_with(|format| *format)
+        .unwrap_or_default()
+}
+
+/// Encode [`Json`] responses, errors included, in the format the request's `Accept` header
+/// prefers out of JSON, CBOR, and MessagePack.
+///
+/// [`Json`]: crate::extractors::Json
+pub async fn negotiate_format<B>(req: Request<B>, next: Next<B>) -> Response {
+    let format = Format::from_accept(req.headers());
+    let mut res = RESPONSE_FORMAT.scope(format, next.run(req)).await;
+    res.headers_mut()
+        .append(VARY, HeaderValue::from_static("accept"));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{ACCEPT, CONTENT_TYPE};
+    use axum::http::HeaderMap;
+    use axum::response::IntoResponse;
+
+    use crate::errors::AppError;
+    use crate::extractors::Json;
+    use crate::hash::VeracityHash;
+
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn accept_picks_the_preferred_format() {
+        assert_eq!(Format::from_accept(&HeaderMap::new()), Format::Json);
+        assert_eq!(Format::from_accept(&accept("text/html")), Format::Json);
+        assert_eq!(
+            Format::from_accept(&accept("application/cbor")),
+            Format::Cbor
+        );
+        assert_eq!(
+            Format::from_accept(&accept(
+                "application/json;q=0.5, application/x-msgpack;q=0.9, */*;q=0.1"
+            )),
+            Format::MessagePack
+        );
+        assert_eq!(
+            Format::from_accept(&accept("application/json, application/cbor")),
+            Format::Json
+        );
+    }
+
+    #[test]
+    fn binary_formats_send_hashes_as_bytes() {
+        let hash = VeracityHash::default();
+        // A two byte header and the 32 bytes of the hash, against 66 bytes of quoted hex
+        for format in [Format::Cbor, Format::MessagePack] {
+            assert_eq!(format.serialize(&hash.crypto_hash).unwrap().len(), 34);
+        }
+        let cbor = Format::Cbor.serialize(&hash).unwrap();
+        let msgpack = Format::MessagePack.serialize(&hash).unwrap();
+        let decoded: VeracityHash = Format::Cbor.deserialize(&cbor).unwrap();
+        assert_eq!(decoded.crypto_hash, hash.crypto_hash);
+        let decoded: VeracityHash = Format::MessagePack.deserialize(&msgpack).unwrap();
+        assert_eq!(decoded.perceptual_hash, hash.perceptual_hash);
+    }
+
+    #[tokio::test]
+    async fn responses_use_the_negotiated_format() {
+        let res = RESPONSE_FORMAT
+            .scope(Format::Cbor, async {
+                AppError::new("failed").into_response()
+            })
+            .await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/cbor");
+        assert_eq!(
+            Json("plain").into_response().headers()[CONTENT_TYPE],
+            "application/json"
+        );
+    }
+}
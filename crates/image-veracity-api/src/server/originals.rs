@@ -0,0 +1,320 @@
+use std::io::Cursor;
+
+use aide::axum::IntoApiResponse;
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use hex::FromHex;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use serde_json::json;
+use tracing::{debug, error, warn};
+
+use crate::blob::SharedBlobStore;
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, Json, TenantState, AUTH_KEY_HEADER};
+use crate::hash::cryptographic::CryptographicHash;
+use crate::state::AppState;
+
+/// Longest side of generated thumbnails, in pixels
+const THUMBNAIL_SIZE: u32 = 256;
+const THUMBNAIL_QUALITY: u8 = 80;
+/// Cache-Control of originals and thumbnails. They need an API key, so only clients may keep
+/// them, and revalidate on every use so a taken down image is not served from a cache.
+const BLOB_CACHE_CONTROL: &str = "private, no-cache";
+
+/// The original file of an image, exactly as it was uploaded
+pub(super) async fn get_original(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoApiResponse {
+    let (blob_store, key) = match target(&state, &id).await {
+        Ok(target) => target,
+        Err(err) => return err.into_response(),
+    };
+    match blob_store.get(&key).await {
+        Ok(Some(original)) => {
+            let content_type = image::guess_format(&original)
+                .map(|format| format.to_mime_type())
+                .unwrap_or("application/octet-stream");
+            cached(&headers, &key, "original", content_type, original)
+        }
+        Ok(None) => {
+            debug!("No original kept for {}", key);
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(err) => {
+            error!("Could not read original {}: {}", key, err);
+            blob_error().into_response()
+        }
+    }
+}
+
+pub(super) fn get_original_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Download the file that was uploaded for an image, byte for byte, so auditors can see \
+        what was logged rather than just its hashes. Only available when the server keeps \
+        originals.",
+    )
+    .response_with::<200, (), _>(|res| res.description("the original JPEG or PNG file"))
+    .response_with::<304, (), _>(|res| res.description("the cached copy is current"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("originals are not kept, or none was kept for this image")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available")
+            .example(blob_error())
+    })
+}
+
+/// A small JPEG preview of an image, generated from its original on first request
+pub(super) async fn get_thumbnail(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Read>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoApiResponse {
+    let (blob_store, key) = match target(&state, &id).await {
+        Ok(target) => target,
+        Err(err) => return err.into_response(),
+    };
+    match sqlx::query_scalar!(
+        "SELECT data FROM image_thumbnails WHERE c_hash = $1",
+        &key.as_ref()[..],
+    )
+    .fetch_optional(&state.db_pool)
+    .await
+    {
+        Ok(Some(data)) => return cached(&headers, &key, "thumbnail", "image/jpeg", data),
+        Ok(None) => {}
+        // Render it again rather than fail
+        Err(err) => warn!("Could not read cached thumbnail {}: {}", key, err),
+    }
+
+    let original = match blob_store.get(&key).await {
+        Ok(Some(original)) => original,
+        Ok(None) => {
+            debug!("No original kept for {}", key);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(err) => {
+            error!("Could not read original {}: {}", key, err);
+            return blob_error().into_response();
+        }
+    };
+    // Decoded on the hashing pool to keep it off the async workers
+    let thumbnail = match state
+        .hash_pool
+        .run(move || render_thumbnail(&original))
+        .await
+    {
+        Ok(Ok(thumbnail)) => thumbnail,
+        Ok(Err(err)) => {
+            error!("Could not render thumbnail of {}: {}", key, err);
+            return AppError::new("Could not render thumbnail")
+                .with_status(StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response();
+        }
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    // Concurrent requests render the same bytes, so the first to finish wins
+    if let Err(err) = sqlx::query!(
+        "INSERT INTO image_thumbnails (c_hash, data) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        &key.as_ref()[..],
+        &thumbnail,
+    )
+    .execute(&state.db_pool)
+    .await
+    {
+        warn!("Could not cache thumbnail {}: {}", key, err);
+    }
+    cached(&headers, &key, "thumbnail", "image/jpeg", thumbnail)
+}
+
+pub(super) fn get_thumbnail_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Get a JPEG preview of an image, at most 256 pixels on its longest side. Thumbnails are \
+        generated from the original on first request and cached. Only available when the \
+        server keeps originals.",
+    )
+    .response_with::<200, (), _>(|res| res.description("JPEG thumbnail"))
+    .response_with::<304, (), _>(|res| res.description("the cached copy is current"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid request")
+            .example(AppError::new("Invalid id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("originals are not kept, or none was kept for this image")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available")
+            .example(blob_error())
+    })
+}
+
+/// The blob store and the key named by `id`, when originals are kept and the image is one of
+/// the tenant's and not withheld. Tenants share the blob store, so its keys alone do not say
+/// whose an original is.
+async fn target(
+    state: &AppState,
+    id: &str,
+) -> Result<(SharedBlobStore, CryptographicHash), AppError> {
+    let blob_store = state.blob_store.clone().ok_or_else(|| {
+        AppError::new("original images are not kept")
+            .with_status(StatusCode::NOT_FOUND)
+            .with_code(ErrorCode::NotEnabled)
+    })?;
+    let id_hex = <[u8; 32]>::from_hex(id).map_err(|err| {
+        AppError::new("Invalid id")
+            .with_details(json!(err.to_string()))
+            .with_status(StatusCode::BAD_REQUEST)
+    })?;
+    match state.images.get_by_crypto(&id_hex).await {
+        Ok(Some(image)) if image.withheld.is_none() => Ok((blob_store, image.hash.crypto_hash)),
+        Ok(_) => {
+            debug!("No original of {} is served to this tenant", id);
+            Err(AppError::new("image not found").with_status(StatusCode::NOT_FOUND))
+        }
+        Err(_) => Err(AppError::new("Could not get image details")
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)
+            .with_code(ErrorCode::DatabaseUnavailable)),
+    }
+}
+
+/// Entity tag of a `variant` of an image, which only changes with its hash
+fn etag(key: &CryptographicHash, variant: &str) -> String {
+    format!("\"{variant}-{}\"", key.to_hex())
+}
+
+fn not_modified(headers: &HeaderMap, key: &CryptographicHash, variant: &str) -> bool {
+    etag_matches(headers, &etag(key, variant))
+}
+
+/// Whether the request's `If-None-Match` names `tag`, compared weakly as it asks for, or is `*`
+pub(super) fn etag_matches(headers: &HeaderMap, tag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .map(|candidate| candidate.trim().trim_start_matches("W/"))
+                .any(|candidate| candidate == tag || candidate == "*")
+        })
+}
+
+/// Answer with `body`, or `304 Not Modified` when the request's `If-None-Match` names it, with
+/// caching headers either way. Only bytes that are still kept are answered, so a client
+/// revalidating a taken down image is not told its copy is current.
+fn cached(
+    headers: &HeaderMap,
+    key: &CryptographicHash,
+    variant: &str,
+    content_type: &'static str,
+    body: Vec<u8>,
+) -> Response {
+    let mut res = if not_modified(headers, key, variant) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut res = body.into_response();
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res
+    };
+    let res_headers = res.headers_mut();
+    res_headers.insert(CACHE_CONTROL, HeaderValue::from_static(BLOB_CACHE_CONTROL));
+    res_headers.insert(
+        ETAG,
+        HeaderValue::from_str(&etag(key, variant)).expect("hex is a header value"),
+    );
+    res_headers.append(VARY, HeaderValue::from_static(AUTH_KEY_HEADER));
+    res
+}
+
+/// JPEG of `original` scaled to fit within the thumbnail size, keeping its aspect ratio.
+/// Images already small enough keep their size.
+fn render_thumbnail(original: &[u8]) -> image::ImageResult<Vec<u8>> {
+    let image = image::load_from_memory(original)?;
+    let image = if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+        image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+    } else {
+        image
+    };
+    // JPEG has no alpha channel
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+    let mut thumbnail = Vec::new();
+    image.write_to(
+        &mut Cursor::new(&mut thumbnail),
+        ImageOutputFormat::Jpeg(THUMBNAIL_QUALITY),
+    )?;
+    Ok(thumbnail)
+}
+
+fn blob_error() -> AppError {
+    AppError::new("Could not get original image")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::StorageUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnails_fit_within_the_limit() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(image::RgbaImage::new(600, 300))
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+
+        let thumbnail = render_thumbnail(&png).unwrap();
+        assert_eq!(image::guess_format(&thumbnail).unwrap(), ImageFormat::Jpeg);
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+    }
+
+    #[test]
+    fn matching_etags_are_not_modified() {
+        let key = CryptographicHash::try_from(vec![0xab; 32]).unwrap();
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified(&headers, &key, "original"));
+        headers.insert(
+            IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", W/{}", etag(&key, "original"))).unwrap(),
+        );
+        assert!(not_modified(&headers, &key, "original"));
+        assert!(!not_modified(&headers, &key, "thumbnail"));
+    }
+
+    #[test]
+    fn originals_are_not_kept_by_shared_caches() {
+        let key = CryptographicHash::try_from(vec![0xab; 32]).unwrap();
+        let res = cached(
+            &HeaderMap::new(),
+            &key,
+            "original",
+            "image/png",
+            b"png".to_vec(),
+        );
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_CONTROL], BLOB_CACHE_CONTROL);
+        assert_eq!(res.headers()[VARY], AUTH_KEY_HEADER);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            IF_NONE_MATCH,
+            HeaderValue::from_str(&etag(&key, "original")).unwrap(),
+        );
+        let res = cached(&headers, &key, "original", "image/png", b"png".to_vec());
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[CACHE_CONTROL], BLOB_CACHE_CONTROL);
+        assert_eq!(res.headers()[ETAG], etag(&key, "original"));
+    }
+}
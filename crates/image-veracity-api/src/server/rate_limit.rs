@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use metrics::increment_counter;
+use serde_json::json;
+use tracing::warn;
+
+use crate::errors::AppError;
+use crate::extractors::Submitter;
+use crate::state::AppState;
+
+/// Idle buckets are dropped once this many clients are tracked
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A token bucket allowance: up to `burst` requests at once, refilled at `per_minute`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Quota {
+    /// Allow `per_minute` requests a minute, all of which may arrive at once
+    pub fn per_minute(per_minute: u32) -> Self {
+        Quota {
+            per_minute,
+            burst: per_minute,
+        }
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits uploads per API key, or per client IP for requests without a key.
+/// Either limit can be left unset to let those requests through unchecked.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    per_ip: Option<Quota>,
+    per_key: Option<Quota>,
+    buckets: Arc<Mutex<HashMap<Submitter, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: Option<Quota>, per_key: Option<Quota>) -> Self {
+        RateLimiter {
+            per_ip,
+            per_key,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Whether any limit is configured
+    pub fn is_enabled(&self) -> bool {
+        self.per_ip.is_some() || self.per_key.is_some()
+    }
+
+    /// Take a token for `client`, returning how long to wait if none is left
+    pub(crate) fn check(&self, client: Submitter, now: Instant) -> Result<(), Duration> {
+        let quota = match &client {
+            Submitter::Ip(_) => self.per_ip,
+            Submitter::ApiKey(_) => self.per_key,
+        };
+        let quota = match quota {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        let mut buckets = self.buckets.lock().expect("rate limit buckets poisoned");
+        if buckets.len() >= PRUNE_THRESHOLD {
+            self.prune(&mut buckets, now);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: f64::from(quota.burst),
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * quota.refill_per_second()).min(f64::from(quota.burst));
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        if quota.per_minute == 0 {
+            return Err(Duration::from_secs(60));
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / quota.refill_per_second(),
+        ))
+    }
+
+    /// Forget buckets that have refilled completely, they behave the same as new ones
+    fn prune(&self, buckets: &mut HashMap<Submitter, Bucket>, now: Instant) {
+        buckets.retain(|client, bucket| {
+            let quota = match client {
+                Submitter::Ip(_) => self.per_ip,
+                Submitter::ApiKey(_) => self.per_key,
+            };
+            quota.is_some_and(|quota| {
+                let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * quota.refill_per_second() < f64::from(quota.burst)
+            })
+        });
+    }
+}
+
+/// Reject uploads from clients that are over their quota with `429 Too Many Requests`.
+/// Only `POST` and `PUT` requests are counted, so upload forms, lookups, and the chunks of
+/// resumable uploads on the same routes stay free.
+pub async fn limit_uploads<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.rate_limiter.is_enabled() || !matches!(*req.method(), Method::POST | Method::PUT) {
+        return next.run(req).await;
+    }
+    let client = match Submitter::from_extensions(req.extensions()) {
+        Some(client) => client,
+        None => return next.run(req).await,
+    };
+
+    match state.rate_limiter.check(client.clone(), Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            warn!("Rate limited {:?}", client);
+            increment_counter!("veracity_rate_limited_total");
+            let retry_after = wait.as_secs_f64().ceil() as u64;
+            let mut res = AppError::new("too many uploads, slow down")
+                .with_status(StatusCode::TOO_MANY_REQUESTS)
+                .with_details(json!({ "retry_after_seconds": retry_after }))
+                .into_response();
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn ip(last: u8) -> Submitter {
+        Submitter::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)))
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limiter = RateLimiter::new(
+            Some(Quota {
+                per_minute: 60,
+                burst: 2,
+            }),
+            None,
+        );
+        let start = Instant::now();
+        assert_eq!(limiter.check(ip(1), start), Ok(()));
+        assert_eq!(limiter.check(ip(1), start), Ok(()));
+        assert_eq!(limiter.check(ip(1), start), Err(Duration::from_secs(1)));
+        // Other clients have their own bucket
+        assert_eq!(limiter.check(ip(2), start), Ok(()));
+        assert_eq!(limiter.check(ip(1), start + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn unset_quota_is_unlimited() {
+        let limiter = RateLimiter::new(None, Some(Quota::per_minute(1)));
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.check(ip(1), now), Ok(()));
+        }
+        let key = Submitter::ApiKey("uploader".to_string());
+        assert_eq!(limiter.check(key.clone(), now), Ok(()));
+        assert!(limiter.check(key, now).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+
+/// Header a request ID is read from and echoed back in
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+tokio::task_local! {
+    static REQUEST_ID: Uuid;
+}
+
+/// ID of the request being handled on this task, if any
+pub fn current() -> Option<Uuid> {
+    REQUEST_ID.try_with(|id| *id).ok()
+}
+
+/// Give every request an ID, taken from its `X-Request-Id` header when that holds a UUID.
+/// The ID is recorded on the request's tracing span, used as the `error_id` of any [`AppError`]
+/// created while handling it, and returned in the response's `X-Request-Id` header.
+///
+/// [`AppError`]: crate::errors::AppError
+pub async fn request_id<B>(req: Request<B>, next: Next<B>) -> Response {
+    let id = from_headers(req.headers()).unwrap_or_else(Uuid::new_v4);
+    let span = info_span!("request", request_id = %id, method = %req.method(), uri = %req.uri());
+    let mut res = REQUEST_ID.scope(id, next.run(req).instrument(span)).await;
+    res.headers_mut().insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(&id.to_string()).expect("UUIDs are valid header values"),
+    );
+    res
+}
+
+fn from_headers(headers: &HeaderMap) -> Option<Uuid> {
+    headers
+        .get(REQUEST_ID_HEADER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::AppError;
+
+    use super::*;
+
+    #[test]
+    fn only_uuids_are_propagated() {
+        let id = Uuid::new_v4();
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, id.to_string().parse().unwrap());
+        assert_eq!(from_headers(&headers), Some(id));
+
+        headers.insert(REQUEST_ID_HEADER, "not-a-uuid".parse().unwrap());
+        assert_eq!(from_headers(&headers), None);
+        assert_eq!(from_headers(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn errors_carry_the_request_id() {
+        let id = Uuid::new_v4();
+        let error = REQUEST_ID
+            .scope(id, async { AppError::new("failed") })
+            .await;
+        assert_eq!(error.error_id, id);
+        assert_ne!(AppError::new("failed").error_id, id);
+    }
+}
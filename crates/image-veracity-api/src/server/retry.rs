@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::errors::is_retryable;
+use crate::state::AppState;
+
+const BASE_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Tracks consecutive transient failures to tell clients how long to back off.
+/// Each failure in a row doubles the suggested delay, any other response resets it.
+#[derive(Clone, Default)]
+pub struct Backoff {
+    consecutive_failures: Arc<AtomicU32>,
+}
+
+impl Backoff {
+    /// Record a response and return the delay to suggest if it was a transient failure
+    pub fn record(&self, retryable: bool) -> Option<Duration> {
+        if !retryable {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            return None;
+        }
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        Some(
+            BASE_RETRY_AFTER
+                .saturating_mul(1u32 << failures.min(16))
+                .min(MAX_RETRY_AFTER),
+        )
+    }
+}
+
+/// Add a `Retry-After` header to transient failures that do not already carry one
+pub async fn retry_after<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut res = next.run(req).await;
+    if let Some(delay) = state.backoff.record(is_retryable(res.status())) {
+        res.headers_mut()
+            .entry(RETRY_AFTER)
+            .or_insert_with(|| HeaderValue::from(delay.as_secs()));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_until_reset() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.record(true), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.record(true), Some(Duration::from_secs(2)));
+        assert_eq!(backoff.record(true), Some(Duration::from_secs(4)));
+        assert_eq!(backoff.record(false), None);
+        assert_eq!(backoff.record(true), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let backoff = Backoff::default();
+        for _ in 0..40 {
+            backoff.record(true);
+        }
+        assert_eq!(backoff.record(true), Some(MAX_RETRY_AFTER));
+    }
+}
@@ -1,29 +1,150 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
 use aide::axum::routing::get_with;
 use aide::{
     axum::{routing::post_with, ApiRouter, IntoApiResponse},
     transform::TransformOperation,
 };
 use axum::extract::{DefaultBodyLimit, Multipart, State};
-use axum::http::StatusCode;
-use axum::response::{Html, IntoResponse};
-use eyre::Result;
+use axum::http::{HeaderValue, Request, StatusCode, Uri};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use chrono::Utc;
 use hex::FromHex;
+use schemars::JsonSchema;
+use serde::Serialize;
 use serde_json::json;
 use tracing::log::debug;
 use tracing::{error, warn};
 
-use trillian::TrillianLogLeaf;
-
-use crate::errors::AppError;
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, SubmittedBy, Submitter, TenantState};
 use crate::hash::{cryptographic::CryptographicHash, perceptual::PerceptualHash, VeracityHash};
-use crate::server::images;
-use crate::state::TrillianState;
+use crate::metadata::UploadMetadata;
+use crate::record::{ImageRecord, SimilarImage};
+use crate::repository::{BeforeCommit, InsertError, NewImage};
+use crate::server::images::ImageRecordOutput;
+use crate::server::HashedFile;
+use crate::server::{
+    admin, checkpoint, export, images, load_shed, map, rate_limit, uploads, webhooks,
+};
+use crate::state::{DuplicateUploads, ImageKey};
+use crate::upload_token::UploadClaims;
 use crate::{extractors::Json, server, state::AppState};
 
-const MAX_UPLOAD_SIZE: usize = 1024 * 1024 * 20;
+/// Largest image accepted, however it is uploaded
+pub const MAX_UPLOAD_SIZE: usize = 1024 * 1024 * 20;
+/// Longest a readiness check waits on a single dependency
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Header naming the API version that answered a request
+pub const API_VERSION_HEADER: &str = "x-api-version";
+
+/// A version of the API, served under its own path prefix. A new version gets a variant here and
+/// its own router, and is served alongside the older ones until they are retired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+}
+
+impl ApiVersion {
+    /// Every version served
+    pub const ALL: [ApiVersion; 1] = [ApiVersion::V1];
+    /// Version unversioned paths are redirected to
+    pub const LATEST: ApiVersion = ApiVersion::V1;
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "v1",
+        }
+    }
 
+    /// Path the version's routes are nested under
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/v1",
+        }
+    }
+
+    fn routes(self, state: AppState) -> ApiRouter {
+        match self {
+            ApiVersion::V1 => v1_routes(state),
+        }
+    }
+}
+
+/// Every API version under its prefix, with the probes left unversioned for orchestrators. Any
+/// other path is redirected to the latest version.
 pub fn server_routes(state: AppState) -> ApiRouter {
-    app(&state).nest_api_service("/images", images::image_routes(state))
+    let mut router = ApiRouter::new()
+        .api_route("/livez", get_with(livez, livez_docs))
+        .api_route("/readyz", get_with(readyz, readyz_docs))
+        .with_state(state.clone());
+    for version in ApiVersion::ALL {
+        router = router.nest_api_service(
+            version.prefix(),
+            version
+                .routes(state.clone())
+                .layer(middleware::from_fn_with_state(version, stamp_version)),
+        );
+    }
+    router.fallback(redirect_to_latest)
+}
+
+fn v1_routes(state: AppState) -> ApiRouter {
+    let router = app(&state)
+        .nest_api_service("/images", images::image_routes(state.clone()))
+        .nest_api_service("/map", map::map_routes(state.clone()))
+        .nest_api_service("/admin", admin::admin_routes(state.clone()))
+        .nest_api_service("/uploads", uploads::upload_routes(state.clone()))
+        .nest_api_service("/webhooks", webhooks::webhook_routes(state.clone()));
+    with_test_vectors(router, state)
+}
+
+async fn stamp_version<B>(
+    State(version): State<ApiVersion>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut res = next.run(req).await;
+    res.headers_mut().insert(
+        API_VERSION_HEADER,
+        HeaderValue::from_static(version.as_str()),
+    );
+    res
+}
+
+/// Permanently redirect an unversioned path to the same path under the latest version. Paths
+/// already under a version were not found there, so they are not redirected again.
+async fn redirect_to_latest(uri: Uri) -> Response {
+    let path = uri.path();
+    let versioned = ApiVersion::ALL.iter().any(|version| {
+        path.strip_prefix(version.prefix())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    });
+    if versioned {
+        return AppError::new("route not found")
+            .with_status(StatusCode::NOT_FOUND)
+            .into_response();
+    }
+    let mut location = format!("{}{}", ApiVersion::LATEST.prefix(), path);
+    if let Some(query) = uri.query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Redirect::permanent(&location).into_response()
+}
+
+/// Serve canonical verifier test vectors when built with the `test-vectors` feature
+#[cfg(feature = "test-vectors")]
+fn with_test_vectors(router: ApiRouter, state: AppState) -> ApiRouter {
+    router.nest_api_service("/testdata", server::vectors::vector_routes(state))
+}
+
+#[cfg(not(feature = "test-vectors"))]
+fn with_test_vectors(router: ApiRouter, _state: AppState) -> ApiRouter {
+    router
 }
 
 fn app(state: &AppState) -> ApiRouter {
@@ -32,31 +153,128 @@ fn app(state: &AppState) -> ApiRouter {
             "/",
             post_with(accept_form, accept_form_docs).get_with(show_form, show_form_docs),
         )
-        .api_route("/healthcheck", get_with(healthcheck, healthcheck_docs))
+        .api_route(
+            "/checkpoint",
+            get_with(checkpoint::get_checkpoint, checkpoint::get_checkpoint_docs),
+        )
+        .api_route(
+            "/checkpoint/cosigned",
+            get_with(
+                checkpoint::get_cosigned_checkpoint,
+                checkpoint::get_cosigned_checkpoint_docs,
+            ),
+        )
+        .api_route(
+            "/export",
+            get_with(export::get_export, export::get_export_docs),
+        )
         .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            load_shed::shed_uploads,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_uploads,
+        ))
         .with_state(state.clone())
 }
 
-async fn healthcheck(State(AppState { db_pool, .. }): State<AppState>) -> impl IntoApiResponse {
-    let pool = db_pool.clone();
-    let conn = match pool.get().await {
-        Ok(conn) => conn,
-        Err(err) => {
-            error!("{}", err);
-            return db_error().into_response();
-        }
-    };
+async fn livez() -> impl IntoApiResponse {
+    (StatusCode::OK, "alive")
+}
+
+fn livez_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Liveness probe, succeeds while the process is serving requests")
+        .response_with::<200, (), _>(|res| res.description("Application is running"))
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Readiness {
+    /// Whether the database is healthy, lookups are served without Trillian
+    pub ready: bool,
+    /// Whether uploads are refused because Trillian is unhealthy
+    pub degraded: bool,
+    pub database: DependencyStatus,
+    pub trillian: DependencyStatus,
+}
 
-    match conn.query("SELECT 1", &[]).await {
-        Ok(_) => (StatusCode::OK, "healthy").into_response(),
-        Err(_) => db_error().into_response(),
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct DependencyStatus {
+    pub healthy: bool,
+    /// How long the check took
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DependencyStatus {
+    async fn check<F>(name: &str, check: F) -> Self
+    where
+        F: Future<Output = eyre::Result<()>>,
+    {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check).await {
+            Ok(result) => result,
+            Err(_) => Err(eyre::eyre!("timed out")),
+        };
+        let latency_ms = start.elapsed().as_millis() as u64;
+        if let Err(err) = &result {
+            error!("Readiness check for {} failed: {}", name, err);
+        }
+        DependencyStatus {
+            healthy: result.is_ok(),
+            latency_ms,
+            error: result.err().map(|err| err.to_string()),
+        }
     }
 }
 
-fn healthcheck_docs(op: TransformOperation) -> TransformOperation {
-    op.description("Healthcheck")
-        .response_with::<200, (), _>(|res| res.description("Application is healthy"))
-        .response_with::<503, (), _>(|res| res.description("Application is unhealthy"))
+async fn readyz(State(state): State<AppState>) -> impl IntoApiResponse {
+    let database = DependencyStatus::check("database", async {
+        sqlx::query!("SELECT 1 AS ready")
+            .fetch_one(&state.db_pool)
+            .await?;
+        Ok::<_, eyre::Report>(())
+    });
+    let trillian = DependencyStatus::check("trillian", async {
+        state
+            .trillian
+            .clone()
+            .get_tree(&state.trillian_tree)
+            .await?;
+        Ok::<_, eyre::Report>(())
+    });
+    let (database, trillian) = tokio::join!(database, trillian);
+
+    let ready = database.healthy;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            degraded: !trillian.healthy,
+            database,
+            trillian,
+        }),
+    )
+        .into_response()
+}
+
+fn readyz_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Readiness probe, succeeds once the database answers a query. Trillian is checked by \
+        fetching the configured tree; while it is unreachable the service is degraded and \
+        refuses uploads but still serves lookups.",
+    )
+    .response_with::<200, Json<Readiness>, _>(|res| {
+        res.description("Application can serve traffic")
+    })
+    .response_with::<503, Json<Readiness>, _>(|res| res.description("the database is unavailable"))
 }
 
 async fn show_form() -> Html<&'static str> {
@@ -68,7 +286,7 @@ async fn show_form() -> Html<&'static str> {
                 <title>Image Upload</title>
             </head>
             <body>
-                <form action="/" method="post" enctype="multipart/form-data">
+                <form action="/v1/" method="post" enctype="multipart/form-data">
                     <div>
                         <label>
                             Image File:
@@ -91,119 +309,381 @@ fn show_form_docs(op: TransformOperation) -> TransformOperation {
         .response_with::<200, (), _>(|res| res.description("Form upload HTML"))
 }
 
+/// The image an upload stored, or the one already stored with its hash when duplicates are
+/// answered with it, see [`DuplicateUploads`]
+#[derive(Debug, Serialize)]
+pub struct StoredImage {
+    #[serde(flatten)]
+    pub record: ImageRecord,
+    pub already_existed: bool,
+}
+
+impl StoredImage {
+    /// 201 Created for a new image, 200 OK for one stored before
+    pub fn status(&self) -> StatusCode {
+        if self.already_existed {
+            StatusCode::OK
+        } else {
+            StatusCode::CREATED
+        }
+    }
+}
+
+/// Documented shape of a serialized [`StoredImage`]
+#[derive(Default, Serialize, JsonSchema)]
+pub struct StoredImageOutput {
+    #[serde(flatten)]
+    pub record: ImageRecordOutput,
+    /// Whether the image was stored before this upload, only ever true when the server answers
+    /// duplicate uploads with the stored image
+    pub already_existed: bool,
+}
+
+/// A stored image, with stored images that look like it
+#[derive(Debug, Serialize)]
+pub struct UploadedImage {
+    #[serde(flatten)]
+    pub record: ImageRecord,
+    pub already_existed: bool,
+    pub similar_images: Vec<SimilarImage>,
+}
+
+/// Documented shape of a serialized [`UploadedImage`]
+#[derive(Default, Serialize, JsonSchema)]
+pub struct UploadedImageOutput {
+    #[serde(flatten)]
+    pub record: ImageRecordOutput,
+    /// Whether the image was stored before this upload, only ever true when the server answers
+    /// duplicate uploads with the stored image
+    pub already_existed: bool,
+    /// Stored images within the server's near-duplicate Hamming distance, such as re-encoded
+    /// copies, closest first
+    pub similar_images: Vec<SimilarImage>,
+}
+
 async fn accept_form(
-    State(AppState {
-        trillian,
-        trillian_tree,
-        db_pool,
-        ..
-    }): State<AppState>,
-    mut multipart: Multipart,
+    TenantState(state): TenantState,
+    _: Authorized<scope::Upload>,
+    SubmittedBy(submitter): SubmittedBy,
+    multipart: Multipart,
 ) -> impl IntoApiResponse {
+    let stored =
+        match store_upload(&state, multipart, MAX_UPLOAD_SIZE, None, submitter.as_ref()).await {
+            Ok(stored) => stored,
+            Err(err) => return err.into_response(),
+        };
+    let similar_images = match state.near_duplicate_distance {
+        0 => vec![],
+        distance => images::near_duplicates(&state, &stored.record.hash, distance).await,
+    };
+    (
+        stored.status(),
+        Json(UploadedImage {
+            record: stored.record,
+            already_existed: stored.already_existed,
+            similar_images,
+        }),
+    )
+        .into_response()
+}
+
+/// Name of the multipart field the image is sent in
+pub(crate) const IMAGE_FIELD: &str = "image";
+/// Name of the multipart field optional [`UploadMetadata`] is sent in, as JSON
+const METADATA_FIELD: &str = "metadata";
+
+/// What is wrong with one field of a multipart upload
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct FieldProblem {
+    /// Name of the field, none for a part sent without one
+    field: Option<String>,
+    problem: String,
+}
+
+impl FieldProblem {
+    fn new(field: Option<&str>, problem: &str) -> Self {
+        FieldProblem {
+            field: field.map(str::to_owned),
+            problem: problem.to_owned(),
+        }
+    }
+}
+
+/// Hash and store the file in the `image` field of `multipart`, up to `max_bytes` long.
+/// A pre-signed upload token is spent in the same transaction that stores the image.
+/// A device attestation is read from the `attestation_format` and `attestation` text fields,
+/// and provenance context from the `metadata` JSON field. Trillian quota for the leaf is
+/// charged to `submitter`. A form with a missing, empty or repeated image, invalid metadata, or
+/// any other field is rejected with every problem listed in the error details before the image
+/// is hashed.
+pub(crate) async fn store_upload(
+    state: &AppState,
+    mut multipart: Multipart,
+    max_bytes: usize,
+    upload_token: Option<&UploadClaims>,
+    submitter: Option<&Submitter>,
+) -> Result<StoredImage, AppError> {
+    if !state.availability.trillian_available() {
+        return Err(uploads_paused());
+    }
+    let mut attestation_format = None;
+    let mut attestation = None;
+    let mut metadata = None;
+    let mut image = None;
+    let mut problems = vec![];
     while let Some(field) = match multipart.next_field().await {
         Ok(x) => x,
         Err(err) => {
             error!("{}", err);
-            return AppError::new(&err.to_string())
-                .with_status(StatusCode::BAD_REQUEST)
-                .into_response();
+            return Err(AppError::new(&err.to_string()).with_status(StatusCode::BAD_REQUEST));
         }
     } {
-        let file_name = if let Some(file_name) = field.file_name() {
-            file_name.to_owned()
-        } else {
-            continue;
-        };
-
-        let hash = match server::stream_to_file(&file_name, field).await {
-            Ok(x) => x,
-            Err(err) => {
-                return AppError::new("Could not hash image")
-                    .with_details(json!(err))
-                    .with_status(StatusCode::BAD_REQUEST)
-                    .into_response();
+        let name = field.name().map(str::to_owned);
+        match name.as_deref() {
+            Some(IMAGE_FIELD) => {
+                let Some(file_name) = field.file_name().map(str::to_owned) else {
+                    problems.push(FieldProblem::new(name.as_deref(), "must be a file"));
+                    continue;
+                };
+                if image.is_some() {
+                    problems.push(FieldProblem::new(
+                        name.as_deref(),
+                        "only one image can be sent",
+                    ));
+                    continue;
+                }
+                let content_type = field.content_type().map(str::to_owned);
+                let bytes = server::read_upload(&file_name, field, max_bytes).await?;
+                if bytes.is_empty() {
+                    problems.push(FieldProblem::new(name.as_deref(), "file is empty"));
+                    continue;
+                }
+                image = Some((bytes, content_type));
             }
-        };
-
-        let (hash, _leaf) = match add_hash_to_tree(trillian, &trillian_tree, hash).await {
-            Ok(x) => x,
-            Err(err) => {
-                error!("{}", err);
-                return AppError::new("Could not add image to Trillian")
-                    .with_status(StatusCode::SERVICE_UNAVAILABLE)
-                    .into_response();
+            Some(field_name @ ("attestation_format" | "attestation")) => {
+                let value = if field_name == "attestation_format" {
+                    &mut attestation_format
+                } else {
+                    &mut attestation
+                };
+                if value.is_some() {
+                    problems.push(FieldProblem::new(name.as_deref(), "sent more than once"));
+                    continue;
+                }
+                *value = match field.text().await {
+                    Ok(text) => Some(text),
+                    Err(err) => {
+                        return Err(
+                            AppError::new(&err.to_string()).with_status(StatusCode::BAD_REQUEST)
+                        );
+                    }
+                };
             }
-        };
-
-        // Add leaf to DB
-        let pool = db_pool.clone();
-        let conn = match pool.get().await {
-            Ok(conn) => conn,
-            Err(err) => {
-                error!("{}", err);
-                return db_error().into_response();
+            Some(METADATA_FIELD) => {
+                if metadata.is_some() {
+                    problems.push(FieldProblem::new(name.as_deref(), "sent more than once"));
+                    continue;
+                }
+                let json = match field.bytes().await {
+                    Ok(json) => json,
+                    Err(err) => {
+                        return Err(
+                            AppError::new(&err.to_string()).with_status(StatusCode::BAD_REQUEST)
+                        );
+                    }
+                };
+                match UploadMetadata::parse(&json) {
+                    Ok(parsed) => metadata = Some(parsed),
+                    Err(err) => problems.push(FieldProblem::new(name.as_deref(), &err.to_string())),
+                }
             }
-        };
+            Some(_) if field.file_name().is_some() => problems.push(FieldProblem::new(
+                name.as_deref(),
+                "unexpected file, the image goes in the image field",
+            )),
+            Some(_) => problems.push(FieldProblem::new(name.as_deref(), "unexpected field")),
+            None => problems.push(FieldProblem::new(None, "field has no name")),
+        }
+    }
+    let image_problem = problems
+        .iter()
+        .any(|problem| problem.field.as_deref() == Some(IMAGE_FIELD));
+    if image.is_none() && !image_problem {
+        problems.push(FieldProblem::new(
+            Some(IMAGE_FIELD),
+            "missing, send the image as a file in this field",
+        ));
+    }
+    let Some((bytes, content_type)) = image.filter(|_| problems.is_empty()) else {
+        return Err(AppError::new("invalid multipart form")
+            .with_status(StatusCode::BAD_REQUEST)
+            .with_details(json!({ "fields": problems })));
+    };
 
-        // create the accounts and get the IDs
-        match conn
-            .query(
-                "INSERT INTO images (c_hash, p_hash) VALUES ($1, $2)",
-                &[
-                    &hash.crypto_hash.as_ref().to_vec(),
-                    &hash.perceptual_hash.as_ref().to_vec(),
-                ],
-            )
-            .await
-        {
-            Ok(_) => {}
-            Err(err) => {
-                warn!("Could not add to database: {}", err.to_string());
-                return if err.to_string().contains("duplicate") {
-                    AppError::new("image already exists in database")
-                        .with_status(StatusCode::CONFLICT)
-                        .into_response()
-                } else {
-                    db_error().into_response()
-                };
+    state.image_types.check(content_type.as_deref(), &bytes)?;
+    let file = match server::hash_file(&state.hash_pool, bytes).await {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(AppError::new("Could not hash image")
+                .with_details(json!(err))
+                .with_status(StatusCode::BAD_REQUEST)
+                .with_code(ErrorCode::HashFailed));
+        }
+    };
+
+    store_image(
+        state,
+        file,
+        attestation_format,
+        attestation,
+        metadata,
+        upload_token,
+        submitter,
+    )
+    .await
+}
+
+/// Verify the attestation sent with an image, if any, then store the image, its metadata and
+/// its outbox entry, keeping the original bytes when a blob store is configured. Shared by
+/// every upload route; the outbox worker queues the leaf to Trillian and fills in the leaf
+/// details afterwards. Uploads of an image that is already stored are answered as configured by
+/// [`DuplicateUploads`].
+pub(crate) async fn store_image(
+    state: &AppState,
+    file: HashedFile,
+    attestation_format: Option<String>,
+    attestation: Option<String>,
+    metadata: Option<UploadMetadata>,
+    upload_token: Option<&UploadClaims>,
+    submitter: Option<&Submitter>,
+) -> Result<StoredImage, AppError> {
+    let verdict = match (attestation_format, attestation) {
+        (None, None) => None,
+        (Some(format), Some(attestation)) => {
+            let verdict = format.parse().and_then(|format| {
+                state
+                    .attestations
+                    .verify(format, &attestation, &file.digest, Utc::now())
+            });
+            match verdict {
+                Ok(verdict) => Some(verdict),
+                Err(err) => {
+                    warn!("Rejected attestation: {}", err);
+                    return Err(AppError::new(&err.to_string())
+                        .with_status(StatusCode::UNPROCESSABLE_ENTITY));
+                }
             }
-        };
+        }
+        _ => {
+            return Err(
+                AppError::new("attestation_format and attestation must be sent together")
+                    .with_status(StatusCode::BAD_REQUEST),
+            );
+        }
+    };
 
-        debug!(
-            "added c_hash {} p_hash {}",
-            &hash.crypto_hash, &hash.perceptual_hash
-        );
-        let mut res = Json(hash).into_response();
-        *res.status_mut() = StatusCode::CREATED;
-        return res;
-    }
-    AppError::new("no multipart fields found")
-        .with_status(StatusCode::BAD_REQUEST)
-        .into_response()
+    let HashedFile { hash, bytes, .. } = file;
+    // Kept once the insert has claimed the crypto hash, which covers pixels rather than file
+    // bytes, so a re-encoded duplicate never replaces the original that was logged. Committing
+    // afterwards means every stored image has its original.
+    let keep_original: Option<BeforeCommit> = state.blob_store.as_ref().map(|blob_store| {
+        let crypto_hash = &hash.crypto_hash;
+        Box::pin(async move {
+            blob_store.put(crypto_hash, bytes).await.map_err(|err| {
+                error!("Could not store original {}: {}", crypto_hash, err);
+                err
+            })
+        }) as BeforeCommit
+    });
+    let image = NewImage {
+        hash: &hash,
+        attestation: verdict.as_ref(),
+        upload_token,
+        submitter,
+        metadata: metadata.as_ref(),
+        log_metadata: state.log_metadata,
+    };
+    let record = match state.images.insert(image, keep_original).await {
+        Ok(record) => record,
+        Err(err) => {
+            warn!("Could not add to database: {}", err);
+            return match err {
+                InsertError::Duplicate => duplicate(state, &hash).await,
+                InsertError::TokenSpent => Err(AppError::new("upload token has already been used")
+                    .with_status(StatusCode::FORBIDDEN)
+                    .with_code(ErrorCode::UploadTokenInvalid)),
+                InsertError::BeforeCommit(_) => {
+                    Err(AppError::new("Could not store original image")
+                        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+                        .with_code(ErrorCode::StorageUnavailable))
+                }
+                InsertError::Database(_) => Err(db_error()),
+            };
+        }
+    };
+
+    state
+        .image_cache
+        .invalidate(hash.crypto_hash.as_ref(), hash.perceptual_hash.as_ref())
+        .await;
+    debug!(
+        "added c_hash {} p_hash {}",
+        &hash.crypto_hash, &hash.perceptual_hash
+    );
+    Ok(StoredImage {
+        record,
+        already_existed: false,
+    })
 }
 
-async fn add_hash_to_tree(
-    mut trillian: TrillianState,
-    trillian_tree: &i64,
-    hash: VeracityHash,
-) -> Result<(VeracityHash, TrillianLogLeaf)> {
-    match trillian
-        .add_leaf(
-            trillian_tree,
-            hash.crypto_hash.as_ref(),
-            hash.perceptual_hash.as_ref(),
-        )
-        .await
-    {
-        Ok(leaf) => Ok((hash, leaf)),
-        Err(err) => Err(err),
+/// Answer an upload of an image that is already stored. The stored image, looked up by crypto
+/// hash or, for a different image with the same perceptual hash, by perceptual hash, goes in
+/// the details of a 409. When configured, an image with the same crypto hash is returned as if
+/// it had just been stored instead.
+async fn duplicate(state: &AppState, hash: &VeracityHash) -> Result<StoredImage, AppError> {
+    let conflict = AppError::new("image already exists in database")
+        .with_status(StatusCode::CONFLICT)
+        .with_code(ErrorCode::Duplicate);
+    let lookups = [
+        ImageKey::CryptoHash(*hash.crypto_hash.as_ref()),
+        ImageKey::PerceptualHash(*hash.perceptual_hash.as_ref()),
+    ];
+    for key in lookups {
+        let same_image = matches!(key, ImageKey::CryptoHash(_));
+        match images::find_image(state, key).await {
+            Ok(Some(record))
+                if same_image && state.duplicate_uploads == DuplicateUploads::Existing =>
+            {
+                return Ok(StoredImage {
+                    record,
+                    already_existed: true,
+                });
+            }
+            Ok(Some(record)) => return Err(conflict.with_details(json!({ "existing": record }))),
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Could not look up the stored duplicate: {}", err);
+                break;
+            }
+        }
     }
+    Err(conflict)
 }
 
 fn accept_form_docs(op: TransformOperation) -> TransformOperation {
-    op.description("Return a veracity hash")
-        .response_with::<201, Json<VeracityHash>, _>(|res| {
-            res.example(VeracityHash {
+    op.description(
+        "Store an image sent as a file in the `image` field and return its veracity hash. \
+        Leaf details appear on later lookups once the image has been queued to Trillian. \
+        A device attestation bound to the SHA-256 of the file may be sent in the \
+        `attestation_format` and `attestation` fields, and provenance context such as a \
+        caption, source URL and capture claims as JSON in the `metadata` field. \
+        Stored images with nearby perceptual hashes, such as re-encoded variants, are listed in \
+        `similar_images`.",
+    )
+    .security_requirement("ApiKey")
+    .response_with::<201, Json<UploadedImageOutput>, _>(|res| {
+        res.example(UploadedImageOutput {
+            record: VeracityHash {
                 perceptual_hash: PerceptualHash::from_hex(
                     "9cfde03dc4198467ad671d171c071c5b1ff81bf919d9181838f8f890f807ff01",
                 )
@@ -212,20 +692,64 @@ fn accept_form_docs(op: TransformOperation) -> TransformOperation {
                     "oY1OmtqoZ32_nUVGgKzmAAdn6Bo0ndvr-YhnDRYju4U",
                 )
                 .unwrap(),
-            })
-        })
-        .response_with::<400, Json<AppError>, _>(|res| {
-            res.description("could not process request")
-                .example(AppError::new("Could not hash image").with_status(StatusCode::BAD_REQUEST))
-        })
-        .response_with::<503, Json<AppError>, _>(|res| {
-            res.description("downstream dependency unavailable")
-                .example(db_error())
+            }
+            .into(),
+            already_existed: false,
+            similar_images: vec![],
         })
+    })
+    .response_with::<200, Json<UploadedImageOutput>, _>(|res| {
+        res.description("image was already stored, when duplicates are answered with it")
+    })
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description(
+            "could not process request, or the form is invalid, with each field's problem in \
+            the error details",
+        )
+        .example(
+            AppError::new("invalid multipart form")
+                .with_status(StatusCode::BAD_REQUEST)
+                .with_details(json!({
+                    "fields": [
+                        FieldProblem::new(Some(IMAGE_FIELD), "file is empty"),
+                        FieldProblem::new(Some("caption"), "unexpected field"),
+                    ]
+                })),
+        )
+    })
+    .response_with::<401, Json<AppError>, _>(|res| res.description("missing or invalid API key"))
+    .response_with::<409, Json<AppError>, _>(|res| {
+        res.description("image already stored, which is in the error details")
+    })
+    .response_with::<415, Json<AppError>, _>(|res| {
+        res.description("the file is not an allowed image type, or not the type its part declares")
+    })
+    .response_with::<422, Json<AppError>, _>(|res| {
+        res.description("attestation could not be verified")
+            .example(
+                AppError::new("attestation is not bound to this image")
+                    .with_status(StatusCode::UNPROCESSABLE_ENTITY),
+            )
+    })
+    .response_with::<429, Json<AppError>, _>(|res| {
+        res.description("upload rate limit exceeded, retry after the Retry-After delay")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("downstream dependency unavailable, or the log is unreachable")
+            .example(db_error())
+    })
+}
+
+pub(crate) fn uploads_paused() -> AppError {
+    AppError::new("uploads are paused while the log is unreachable")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::LogUnavailable)
 }
 
 fn db_error() -> AppError {
-    AppError::new("Could add image").with_status(StatusCode::SERVICE_UNAVAILABLE)
+    AppError::new("Could add image")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
 }
 
 #[cfg(test)]
@@ -235,12 +759,24 @@ mod tests {
     use aide::openapi::OpenApi;
     use async_trait::async_trait;
     use axum::{body::Body, http::Request};
+    use eyre::Result;
     use hyper::Method;
     use mockall::mock;
 
-    use trillian::client::TrillianClientApiMethods;
-    use trillian::{TrillianLogLeaf, TrillianTree};
+    use trillian::client::{
+        EntryAndProof, LogRoot, QueuedLeaf, SignedRoot, TreeUpdate, TrillianClientApiMethods,
+    };
+    use trillian::{
+        TrillianChargeTo, TrillianLogLeaf, TrillianProof, TrillianTree, TrillianTreeType,
+    };
+
+    use std::sync::Arc;
 
+    use crate::api_key::ApiKeys;
+    use crate::blob::{FilesystemStore, SharedBlobStore};
+    use crate::extractors::AUTH_KEY_HEADER;
+    use crate::repository::{MemoryImageRepository, SharedImageRepository};
+    use crate::server::auth;
     use crate::state::AppStateBuilder;
 
     use super::*;
@@ -258,20 +794,131 @@ mod tests {
 
     #[async_trait]
     impl TrillianClientApiMethods for MockTrillianClient {
+        fn set_timeout(&mut self, _timeout: Option<Duration>) {}
         async fn add_leaf(
             &mut self,
             _id: &i64,
             _data: &[u8],
             _extra_data: &[u8],
-        ) -> Result<TrillianLogLeaf> {
-            Ok(self.get_leaf())
+            _identity_hash: Option<&[u8]>,
+            _charge_to: Option<TrillianChargeTo>,
+        ) -> Result<QueuedLeaf> {
+            Ok(QueuedLeaf {
+                leaf: self.get_leaf(),
+                already_existed: false,
+            })
         }
-        async fn create_tree(&mut self, _name: &str, _description: &str) -> Result<TrillianTree> {
+        async fn add_leaves(
+            &mut self,
+            _id: &i64,
+            leaves: Vec<(Vec<u8>, Vec<u8>)>,
+            _charge_to: Option<TrillianChargeTo>,
+        ) -> Vec<Result<QueuedLeaf>> {
+            leaves
+                .iter()
+                .map(|_| {
+                    Ok(QueuedLeaf {
+                        leaf: self.get_leaf(),
+                        already_existed: false,
+                    })
+                })
+                .collect()
+        }
+        async fn add_sequenced_leaves(
+            &mut self,
+            _id: &i64,
+            leaves: Vec<TrillianLogLeaf>,
+            _charge_to: Option<TrillianChargeTo>,
+        ) -> Result<Vec<Result<QueuedLeaf>>> {
+            Ok(leaves
+                .into_iter()
+                .map(|leaf| {
+                    Ok(QueuedLeaf {
+                        leaf,
+                        already_existed: false,
+                    })
+                })
+                .collect())
+        }
+        async fn create_tree(
+            &mut self,
+            _name: &str,
+            _description: &str,
+            _tree_type: TrillianTreeType,
+        ) -> Result<TrillianTree> {
             Ok(self.get_tree())
         }
         async fn list_trees(&mut self) -> Result<Vec<TrillianTree>> {
             Ok(vec![self.get_tree()])
         }
+        async fn get_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+            Ok(TrillianTree::default())
+        }
+        async fn update_tree(&mut self, _id: &i64, _update: TreeUpdate) -> Result<TrillianTree> {
+            Ok(self.get_tree())
+        }
+        async fn freeze_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+            Ok(self.get_tree())
+        }
+        async fn delete_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+            Ok(self.get_tree())
+        }
+        async fn undelete_tree(&mut self, _id: &i64) -> Result<TrillianTree> {
+            Ok(self.get_tree())
+        }
+        async fn get_tree_size(&mut self, _id: &i64) -> Result<i64> {
+            Ok(0)
+        }
+        async fn get_latest_root(&mut self, _id: &i64) -> Result<LogRoot> {
+            Ok(LogRoot::default())
+        }
+        async fn get_latest_signed_log_root(
+            &mut self,
+            _id: &i64,
+            _first_tree_size: Option<i64>,
+        ) -> Result<SignedRoot> {
+            Ok(SignedRoot::default())
+        }
+        async fn get_inclusion_proof_by_hash(
+            &mut self,
+            _id: &i64,
+            _leaf_hash: &[u8],
+            _tree_size: i64,
+        ) -> Result<Vec<TrillianProof>> {
+            Ok(vec![])
+        }
+        async fn get_inclusion_proof(
+            &mut self,
+            _id: &i64,
+            _leaf_index: i64,
+            _tree_size: i64,
+        ) -> Result<TrillianProof> {
+            Ok(TrillianProof::default())
+        }
+        async fn get_entry_and_proof(
+            &mut self,
+            _id: &i64,
+            _leaf_index: i64,
+            _tree_size: i64,
+        ) -> Result<EntryAndProof> {
+            Ok(EntryAndProof::default())
+        }
+        async fn get_consistency_proof(
+            &mut self,
+            _id: &i64,
+            _first_tree_size: i64,
+            _second_tree_size: i64,
+        ) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![])
+        }
+        async fn get_leaves_by_range(
+            &mut self,
+            _id: &i64,
+            _start_index: i64,
+            _count: i64,
+        ) -> Result<Vec<TrillianLogLeaf>> {
+            Ok(vec![self.get_leaf()])
+        }
     }
 
     impl Clone for MockTrillianClient {
@@ -281,16 +928,20 @@ mod tests {
     }
 
     async fn mock_state() -> AppState {
+        mock_state_with(|_| {}).await
+    }
+
+    async fn mock_state_with(configure: impl FnOnce(&mut AppStateBuilder)) -> AppState {
         // TODO mock this as well
         let database_url = "postgresql://root@localhost:26257/veracity?sslmode=disable";
-        AppStateBuilder::default()
+        let mut builder = AppStateBuilder::default();
+        builder
             .trillian(Box::from(MockTrillianClient::new()))
             .trillian_host("http://localhost:8090".to_string())
             .trillian_tree(0)
-            .create_postgres_client(database_url)
-            .build()
-            .await
-            .unwrap()
+            .create_postgres_client(database_url);
+        configure(&mut builder);
+        builder.build().await.unwrap()
     }
 
     #[tokio::test]
@@ -303,7 +954,7 @@ mod tests {
             .request(
                 Request::builder()
                     .method(Method::GET)
-                    .uri(format!("http://{}/", addr))
+                    .uri(format!("http://{}/v1/", addr))
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -314,6 +965,26 @@ mod tests {
         assert!(!body.is_empty());
     }
 
+    #[tokio::test]
+    async fn export_rejects_negative_ranges() {
+        let addr = start_test_server().await;
+
+        let client = hyper::Client::new();
+
+        let response = client
+            .request(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("http://{}/v1/export?start=-1", addr))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
     #[tokio::test]
     async fn does_not_exist() {
         let addr = start_test_server().await;
@@ -324,7 +995,7 @@ mod tests {
             .request(
                 Request::builder()
                     .method(Method::GET)
-                    .uri(format!("http://{}/does-not-exist", addr))
+                    .uri(format!("http://{}/v1/does-not-exist", addr))
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -334,17 +1005,390 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
+    #[tokio::test]
+    async fn unversioned_paths_redirect_to_the_latest_version() {
+        let addr = start_test_server().await;
+
+        let client = hyper::Client::new();
+
+        let response = client
+            .request(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("http://{}/export?start=0", addr))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()["location"], "/v1/export?start=0");
+    }
+
+    #[tokio::test]
+    async fn responses_name_the_api_version() {
+        let addr = start_test_server().await;
+
+        let client = hyper::Client::new();
+
+        let response = client
+            .request(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("http://{}/v1/export?start=-1", addr))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()[API_VERSION_HEADER], "v1");
+    }
+
+    #[tokio::test]
+    async fn livez_needs_no_dependencies() {
+        let addr = start_test_server().await;
+
+        let client = hyper::Client::new();
+
+        let response = client
+            .request(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("http://{}/livez", addr))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn malformed_forms_list_each_problem() {
+        let addr = start_test_server().await;
+
+        let client = hyper::Client::new();
+
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"photo.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\r\n\
+            not really a photo\r\n\
+            --b\r\n\
+            Content-Disposition: form-data; name=\"caption\"\r\n\r\n\
+            a photo\r\n\
+            --b--\r\n";
+        let response = client
+            .request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("http://{}/v1/", addr))
+                    .header("content-type", "multipart/form-data; boundary=b")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json["error_details"]["fields"],
+            json!([
+                {
+                    "field": "file",
+                    "problem": "unexpected file, the image goes in the image field",
+                },
+                { "field": "caption", "problem": "unexpected field" },
+                {
+                    "field": "image",
+                    "problem": "missing, send the image as a file in this field",
+                },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_answered_with_the_stored_image() {
+        let (images, hash) = seeded_images().await;
+        let lookalike = VeracityHash {
+            crypto_hash: CryptographicHash::try_from(vec![2; 32]).unwrap(),
+            perceptual_hash: PerceptualHash::try_from(vec![1; 32]).unwrap(),
+        };
+
+        let state = mock_state_with(|builder| {
+            builder.images(Some(images.clone()));
+        })
+        .await;
+        let conflict = duplicate(&state, &hash).await.unwrap_err();
+        assert_eq!(conflict.status, StatusCode::CONFLICT);
+        assert_eq!(conflict.code, ErrorCode::Duplicate);
+        let details = conflict.error_details.unwrap();
+        assert_eq!(
+            details["existing"]["crypto_hash"],
+            hash.crypto_hash.to_hex()
+        );
+
+        let state = mock_state_with(|builder| {
+            builder
+                .images(Some(images))
+                .duplicate_uploads(DuplicateUploads::Existing);
+        })
+        .await;
+        let existing = duplicate(&state, &hash).await.unwrap();
+        assert!(existing.already_existed);
+        assert_eq!(existing.status(), StatusCode::OK);
+        // Only the same image is returned in place of a new one
+        let conflict = duplicate(&state, &lookalike).await.unwrap_err();
+        assert_eq!(conflict.status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn images_are_read_through_the_repository() {
+        let addr = start_test_server_with(seeded_state().await).await;
+
+        let client = hyper::Client::new();
+        for (p_hash, status) in [([1; 32], StatusCode::OK), ([2; 32], StatusCode::NOT_FOUND)] {
+            let response = client
+                .request(
+                    Request::builder()
+                        .method(Method::GET)
+                        .uri(format!(
+                            "http://{}/v1/images?p={}",
+                            addr,
+                            hex::encode(p_hash)
+                        ))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn existence_checks_have_no_body() {
+        let addr = start_test_server_with(seeded_state().await).await;
+
+        let client = hyper::Client::new();
+        let known = hex::encode([1; 32]);
+        let unknown = hex::encode([2; 32]);
+        for (method, path, status) in [
+            (Method::HEAD, known.clone(), StatusCode::OK),
+            (Method::HEAD, unknown.clone(), StatusCode::NOT_FOUND),
+            (
+                Method::GET,
+                format!("{known}/exists"),
+                StatusCode::NO_CONTENT,
+            ),
+            (
+                Method::GET,
+                format!("{unknown}/exists"),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Method::GET,
+                "nothex/exists".to_string(),
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            let response = client
+                .request(
+                    Request::builder()
+                        .method(method.clone())
+                        .uri(format!("http://{}/v1/images/{}", addr, path))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{method} {path}");
+            if status != StatusCode::BAD_REQUEST {
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                assert!(body.is_empty(), "{method} {path}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_routes_are_closed_without_api_keys() {
+        let addr = start_test_server().await;
+
+        let client = hyper::Client::new();
+        for (method, path) in [
+            (Method::GET, "admin/jobs".to_string()),
+            (Method::GET, "admin/config".to_string()),
+            (Method::DELETE, format!("images/{}", hex::encode([1; 32]))),
+        ] {
+            let response = client
+                .request(
+                    Request::builder()
+                        .method(method.clone())
+                        .uri(format!("http://{}/v1/{}", addr, path))
+                        .header("content-type", "application/json")
+                        .body(Body::from(r#"{"reason":"court order"}"#))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{method} {path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn takedowns_need_a_signing_key() {
+        let state = mock_state_with(|builder| {
+            builder.api_keys(ApiKeys::new("admin"));
+        })
+        .await;
+        let addr = start_test_server_with(state).await;
+
+        let client = hyper::Client::new();
+
+        let response = client
+            .request(
+                Request::builder()
+                    .method(Method::DELETE)
+                    .uri(format!(
+                        "http://{}/v1/images/{}",
+                        addr,
+                        hex::encode([1; 32])
+                    ))
+                    .header("content-type", "application/json")
+                    .header(AUTH_KEY_HEADER, "admin")
+                    .body(Body::from(r#"{"reason":"court order"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn originals_are_only_served_to_tenants_holding_them() {
+        let (seeded, hash) = seeded_images().await;
+        let root = std::env::temp_dir().join(format!("blob-store-{}", uuid::Uuid::new_v4()));
+        let store = FilesystemStore::new(&root).await.unwrap();
+        store
+            .put(&hash.crypto_hash, b"original".to_vec())
+            .await
+            .unwrap();
+        let blob_store: SharedBlobStore = Arc::new(store);
+        let other_tenant: SharedImageRepository = Arc::new(MemoryImageRepository::default());
+
+        let client = hyper::Client::new();
+        for (images, status) in [
+            (seeded, StatusCode::OK),
+            (other_tenant, StatusCode::NOT_FOUND),
+        ] {
+            let state = mock_state_with(|builder| {
+                builder
+                    .api_keys(ApiKeys::new("admin"))
+                    .images(Some(images))
+                    .blob_store(Some(blob_store.clone()));
+            })
+            .await;
+            let addr = start_test_server_with(state).await;
+            let response = client
+                .request(
+                    Request::builder()
+                        .method(Method::GET)
+                        .uri(format!(
+                            "http://{}/v1/images/{}/original",
+                            addr,
+                            hash.crypto_hash.to_hex()
+                        ))
+                        .header(AUTH_KEY_HEADER, "admin")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn tenants_get_their_own_tree() {
+        let tenants = crate::tenant::parse_tenants("acme=7,globex=8:globex_images").unwrap();
+        let state = mock_state_with(|builder| {
+            builder.tenants(tenants);
+        })
+        .await;
+
+        let acme = state.for_tenant("acme").unwrap();
+        assert_eq!(acme.trillian_tree, 7);
+        assert!(acme.for_tenant("globex").is_none());
+        assert!(state.for_tenant("initech").is_none());
+        assert_eq!(state.tenant_states().len(), 2);
+        assert_eq!(state.trillian_tree, 0);
+    }
+
+    #[test]
+    fn uploads_always_list_similar_images() {
+        let uploaded = UploadedImage {
+            record: ImageRecord::default(),
+            already_existed: false,
+            similar_images: vec![],
+        };
+        let json = serde_json::to_value(&uploaded).unwrap();
+        assert!(json["crypto_hash"].is_string());
+        assert_eq!(json["status"], "pending");
+        assert_eq!(json["similar_images"], json!([]));
+    }
+
+    /// A repository holding one image, with crypto and perceptual hashes of all ones
+    async fn seeded_images() -> (SharedImageRepository, VeracityHash) {
+        let images: SharedImageRepository = Arc::new(MemoryImageRepository::default());
+        let hash = VeracityHash {
+            crypto_hash: CryptographicHash::try_from(vec![1; 32]).unwrap(),
+            perceptual_hash: PerceptualHash::try_from(vec![1; 32]).unwrap(),
+        };
+        let image = NewImage {
+            hash: &hash,
+            attestation: None,
+            upload_token: None,
+            submitter: None,
+            metadata: None,
+            log_metadata: false,
+        };
+        images.insert(image, None).await.unwrap();
+        (images, hash)
+    }
+
+    /// A mock state whose repository is [`seeded_images`]
+    async fn seeded_state() -> AppState {
+        let (images, _) = seeded_images().await;
+        mock_state_with(|builder| {
+            builder.images(Some(images));
+        })
+        .await
+    }
+
     async fn start_test_server() -> SocketAddr {
+        start_test_server_with(mock_state().await).await
+    }
+
+    /// Serve the routes for `state`, identifying API keys as the server does
+    async fn start_test_server_with(state: AppState) -> SocketAddr {
         let listener = TcpListener::bind("0.0.0.0:0".parse::<SocketAddr>().unwrap()).unwrap();
         let addr = listener.local_addr().unwrap();
 
-        let state = mock_state().await;
-
         tokio::spawn(async move {
             let mut api = OpenApi::default();
             axum::Server::from_tcp(listener)
                 .unwrap()
-                .serve(app(&state).finish_api(&mut api).into_make_service())
+                .serve(
+                    server_routes(state.clone())
+                        .finish_api(&mut api)
+                        .layer(middleware::from_fn_with_state(state, auth::authenticate))
+                        .into_make_service(),
+                )
                 .await
                 .unwrap();
         });
@@ -0,0 +1,270 @@
+use aide::axum::IntoApiResponse;
+use aide::transform::TransformOperation;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use chrono::Utc;
+use hex::FromHex;
+use metrics::increment_counter;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{debug, error, info};
+
+use crate::errors::{AppError, ErrorCode, LookupError};
+use crate::extractors::{scope, Authorized, Json, TenantState};
+use crate::hash::cryptographic::CryptographicHash;
+use crate::reconcile::leaf_hash;
+use crate::record::{LeafDetails, Withholding};
+use crate::repository::SharedImageRepository;
+use crate::state::AppState;
+use crate::tombstone;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct TakedownRequest {
+    /// Why the image is withheld, such as the legal request being honored. It is logged in the
+    /// tombstone for anyone auditing the log to read.
+    reason: String,
+}
+
+/// Withhold an image: stop serving its original and log a signed tombstone for it. The image
+/// record and its leaf are kept, so the log stays verifiable.
+pub(super) async fn withhold_image(
+    State(root): State<AppState>,
+    TenantState(state): TenantState,
+    _: Authorized<scope::Admin>,
+    Path(id): Path<String>,
+    Json(request): Json<TakedownRequest>,
+) -> impl IntoApiResponse {
+    let id_hex: [u8; 32] = match <[u8; 32]>::from_hex(&id) {
+        Ok(x) => x,
+        Err(err) => {
+            return AppError::new("Invalid id")
+                .with_details(json!(err.to_string()))
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+    if request.reason.trim().is_empty() {
+        return AppError::new("a takedown needs a reason")
+            .with_status(StatusCode::BAD_REQUEST)
+            .into_response();
+    }
+    let Some(signer) = &state.checkpoint_signer else {
+        return not_enabled().into_response();
+    };
+
+    let image = match state.images.get_by_crypto(&id_hex).await {
+        Ok(Some(image)) => image,
+        Ok(None) => {
+            debug!("No records found for {}", &id);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(_) => return db_error().into_response(),
+    };
+    let crypto_hash = &image.hash.crypto_hash;
+
+    // Taking down an image again only finishes removing its original
+    let withholding = match image.withheld {
+        Some(withholding) => withholding,
+        None => {
+            let withheld_at = Utc::now();
+            let note = tombstone::sign(signer, &image.hash, withheld_at, &request.reason);
+            let identity_hash = tombstone::identity_hash(crypto_hash.as_ref());
+            let mut trillian = state.trillian.clone();
+            let queued = match trillian
+                .add_leaf(
+                    &state.trillian_tree,
+                    note.as_bytes(),
+                    &tombstone::extra_data(&image.hash),
+                    Some(identity_hash.as_slice()),
+                    None,
+                )
+                .await
+            {
+                Ok(queued) => queued,
+                Err(err) => {
+                    error!("Could not log tombstone for {}: {}", crypto_hash, err);
+                    return AppError::new("Could not log tombstone")
+                        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+                        .with_code(ErrorCode::LogUnavailable)
+                        .into_response();
+                }
+            };
+            let withholding = Withholding {
+                withheld_at,
+                reason: request.reason,
+                // Trillian reports the tombstone logged first if this one was deduplicated
+                tombstone_leaf_hash: LeafDetails::from(&queued.leaf)
+                    .merkle_leaf_hash
+                    .unwrap_or_else(|| hex::encode(leaf_hash(note.as_bytes()))),
+            };
+            match state.images.withhold(&id_hex, &withholding).await {
+                Ok(true) => {
+                    info!("Withheld {}: {}", crypto_hash, withholding.reason);
+                    increment_counter!("veracity_images_withheld_total");
+                }
+                Ok(false) => {
+                    return AppError::new("image was taken down concurrently, try again")
+                        .with_status(StatusCode::CONFLICT)
+                        .into_response();
+                }
+                Err(_) => return db_error().into_response(),
+            }
+            state
+                .image_cache
+                .invalidate(crypto_hash.as_ref(), image.hash.perceptual_hash.as_ref())
+                .await;
+            withholding
+        }
+    };
+
+    if let Some(blob_store) = &state.blob_store {
+        if let Err(err) = sqlx::query!(
+            "DELETE FROM image_thumbnails WHERE c_hash = $1",
+            &crypto_hash.as_ref()[..],
+        )
+        .execute(&state.db_pool)
+        .await
+        {
+            error!("Could not delete thumbnail {}: {}", crypto_hash, err);
+            return removal_error().into_response();
+        }
+        // Tenants share the blob store, so the original stays while another tenant serves it
+        let repositories = std::iter::once(root.clone())
+            .chain(root.tenant_states())
+            .map(|tenant| tenant.images);
+        match served_by_any(repositories, crypto_hash).await {
+            Ok(true) => info!(
+                "Keeping original {} still served to another tenant",
+                crypto_hash
+            ),
+            Ok(false) => {
+                if let Err(err) = blob_store.delete(crypto_hash).await {
+                    error!("Could not delete original {}: {}", crypto_hash, err);
+                    return removal_error().into_response();
+                }
+            }
+            Err(err) => {
+                error!(
+                    "Could not check whether {} is still served: {}",
+                    crypto_hash, err
+                );
+                return removal_error().into_response();
+            }
+        }
+    }
+    Json(withholding).into_response()
+}
+
+pub(super) fn withhold_image_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Take an image down to honor a legal removal request. Its original and thumbnail are \
+        no longer served and are deleted, unless another tenant still serves the original, and \
+        a tombstone note with the reason, signed with the checkpoint key, is appended to the \
+        log. The image record and its leaf are kept, marked \
+        withheld, since the log is append-only. Taking an image down again retries removing its \
+        original without logging another tombstone.",
+    )
+    .response_with::<200, Json<Withholding>, _>(|res| res.description("image withheld"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("invalid id, or no reason given")
+            .example(AppError::new("Invalid id").with_status(StatusCode::BAD_REQUEST))
+    })
+    .response_with::<401, Json<AppError>, _>(|res| res.description("missing or invalid API key"))
+    .response_with::<403, Json<AppError>, _>(|res| res.description("the key is not an admin key"))
+    .response_with::<404, (), _>(|res| res.description("image not found"))
+    .response_with::<409, Json<AppError>, _>(|res| {
+        res.description("the image was taken down concurrently")
+    })
+    .response_with::<501, Json<AppError>, _>(|res| {
+        res.description("takedowns are not enabled")
+            .example(not_enabled())
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("the database, log or blob store is unavailable")
+            .example(removal_error())
+    })
+}
+
+/// Whether any of `repositories` holds the image with crypto hash `key` without withholding it
+async fn served_by_any(
+    repositories: impl IntoIterator<Item = SharedImageRepository>,
+    key: &CryptographicHash,
+) -> Result<bool, LookupError> {
+    let mut id = [0; 32];
+    id.copy_from_slice(key.as_ref());
+    for images in repositories {
+        if let Some(image) = images.get_by_crypto(&id).await? {
+            if image.withheld.is_none() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn not_enabled() -> AppError {
+    AppError::new("takedowns need a checkpoint signing key to sign tombstones")
+        .with_status(StatusCode::NOT_IMPLEMENTED)
+}
+
+fn db_error() -> AppError {
+    AppError::new("Could not get image details")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
+}
+
+fn removal_error() -> AppError {
+    AppError::new("the image is withheld but its original could not be deleted, try again")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::StorageUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::hash::perceptual::PerceptualHash;
+    use crate::hash::VeracityHash;
+    use crate::repository::{MemoryImageRepository, NewImage};
+
+    async fn holding(hash: &VeracityHash) -> SharedImageRepository {
+        let images: SharedImageRepository = Arc::new(MemoryImageRepository::default());
+        let image = NewImage {
+            hash,
+            attestation: None,
+            upload_token: None,
+            submitter: None,
+            metadata: None,
+            log_metadata: false,
+        };
+        images.insert(image, None).await.unwrap();
+        images
+    }
+
+    #[tokio::test]
+    async fn originals_outlive_takedowns_by_one_tenant() {
+        let hash = VeracityHash {
+            crypto_hash: CryptographicHash::try_from(vec![1; 32]).unwrap(),
+            perceptual_hash: PerceptualHash::try_from(vec![1; 32]).unwrap(),
+        };
+        let acme = holding(&hash).await;
+        let globex = holding(&hash).await;
+        let initech: SharedImageRepository = Arc::new(MemoryImageRepository::default());
+        let withholding = Withholding {
+            withheld_at: Utc::now(),
+            reason: "court order".to_string(),
+            tombstone_leaf_hash: "00".repeat(32),
+        };
+        assert!(globex.withhold(&[1; 32], &withholding).await.unwrap());
+
+        let tenants = [acme.clone(), globex.clone(), initech.clone()];
+        assert!(served_by_any(tenants.clone(), &hash.crypto_hash)
+            .await
+            .unwrap());
+        assert!(acme.withhold(&[1; 32], &withholding).await.unwrap());
+        assert!(!served_by_any(tenants, &hash.crypto_hash).await.unwrap());
+    }
+}
@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
+use axum::extract::Host;
+use axum::http::Uri;
+use axum::response::Redirect;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use clap::ValueEnum;
+use eyre::{eyre, Result, WrapErr};
+use rustls::version::{TLS12, TLS13};
+use rustls::{Certificate, PrivateKey, ServerConfig, SupportedProtocolVersion};
+use rustls_pemfile::Item;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+
+/// How often the certificate and key are read again, to pick up renewals
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long open connections get to finish after a shutdown signal
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Oldest TLS version clients may negotiate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TlsVersion {
+    #[default]
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+/// Serve HTTPS directly from a PEM certificate chain and private key.
+///
+/// The files are read again every hour, so certificates renewed in place by an ACME client such
+/// as certbot are picked up without a restart.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub min_version: TlsVersion,
+}
+
+impl TlsSettings {
+    fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        let versions: &[&'static SupportedProtocolVersion] = match self.min_version {
+            TlsVersion::Tls12 => &[&TLS13, &TLS12],
+            TlsVersion::Tls13 => &[&TLS13],
+        };
+        let mut config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)?
+            .with_no_client_auth()
+            .with_single_cert(
+                read_certs(&self.cert_path)?,
+                read_private_key(&self.key_path)?,
+            )?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+/// Serve `app` over HTTPS until `shutdown` completes
+pub async fn serve(
+    addr: SocketAddr,
+    settings: TlsSettings,
+    app: IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let config = RustlsConfig::from_config(settings.server_config()?);
+    tokio::spawn(reload(settings, config.clone()));
+
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        shutdown_handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+    });
+
+    axum_server::bind_rustls(addr, config)
+        .handle(handle)
+        .serve(app)
+        .await?;
+    Ok(())
+}
+
+/// Redirect every plain HTTP request on `addr` to the same URL over HTTPS on `https_port`
+pub async fn redirect_http(addr: SocketAddr, https_port: u16) {
+    let redirect = Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        Redirect::permanent(&https_url(&host, https_port, &uri))
+    });
+    info!("Redirecting HTTP on {} to HTTPS", addr);
+    if let Err(err) = axum::Server::bind(&addr)
+        .serve(redirect.into_make_service())
+        .await
+    {
+        error!("HTTP redirect server stopped: {}", err);
+    }
+}
+
+async fn reload(settings: TlsSettings, config: RustlsConfig) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately, and the files were only just read
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match settings.server_config() {
+            Ok(server_config) => config.reload_from_config(server_config),
+            Err(err) => error!(
+                "Could not reload TLS certificate, keeping the old one: {}",
+                err
+            ),
+        }
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path)
+            .wrap_err_with(|| format!("could not open certificate {}", path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(eyre!("no certificates in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path)
+            .wrap_err_with(|| format!("could not open private key {}", path.display()))?,
+    );
+    rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| eyre!("no private key in {}", path.display()))
+}
+
+/// `uri` on `host` over HTTPS, dropping any port from `host`
+fn https_url(host: &str, https_port: u16, uri: &Uri) -> String {
+    let hostname = match host.rsplit_once(':') {
+        // A colon inside brackets is part of an IPv6 address rather than a port separator
+        Some((hostname, port)) if !port.ends_with(']') => hostname,
+        _ => host,
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    if https_port == 443 {
+        format!("https://{hostname}{path}")
+    } else {
+        format!("https://{hostname}:{https_port}{path}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_keep_the_path() {
+        let uri: Uri = "/images?p=abc".parse().unwrap();
+        assert_eq!(
+            https_url("veracity.example:80", 443, &uri),
+            "https://veracity.example/images?p=abc"
+        );
+        assert_eq!(
+            https_url("veracity.example", 8443, &"/".parse().unwrap()),
+            "https://veracity.example:8443/"
+        );
+        assert_eq!(
+            https_url("[::1]:8080", 443, &uri),
+            "https://[::1]/images?p=abc"
+        );
+        assert_eq!(https_url("[::1]", 443, &uri), "https://[::1]/images?p=abc");
+    }
+}
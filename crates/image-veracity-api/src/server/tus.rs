@@ -0,0 +1,582 @@
+//! Resumable uploads over the [tus 1.0.0](https://tus.io/protocols/resumable-upload) protocol,
+//! with the creation, expiration and termination extensions.
+//!
+//! Chunks are stored in the database as they arrive, so an interrupted `PATCH` keeps every byte
+//! that was received and any instance can take the next one. Once the last byte is in, the
+//! chunks are assembled and stored like any other upload.
+
+use std::collections::HashMap;
+
+use aide::axum::routing::{delete_with, head_with, options_with, patch_with, post_with};
+use aide::axum::{ApiRouter, IntoApiResponse};
+use aide::transform::TransformOperation;
+use axum::extract::{BodyStream, Path};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use serde_json::json;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, Json, SubmittedBy, Submitter, TenantState};
+use crate::server::hash_file;
+use crate::server::load_shed;
+use crate::server::rate_limit;
+use crate::server::routes::{store_image, uploads_paused, ApiVersion, MAX_UPLOAD_SIZE};
+use crate::state::AppState;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_RESUMABLE: &str = "Tus-Resumable";
+const TUS_VERSION_HEADER: &str = "Tus-Version";
+const TUS_EXTENSION: &str = "Tus-Extension";
+const TUS_MAX_SIZE: &str = "Tus-Max-Size";
+const UPLOAD_LENGTH: &str = "Upload-Length";
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const UPLOAD_METADATA: &str = "Upload-Metadata";
+const UPLOAD_EXPIRES: &str = "Upload-Expires";
+/// Headers naming the hashes of the stored image on the `PATCH` that completes an upload
+const CRYPTO_HASH_HEADER: &str = "X-Veracity-Crypto-Hash";
+const PERCEPTUAL_HASH_HEADER: &str = "X-Veracity-Perceptual-Hash";
+const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// How long an upload may take from creation to its last byte
+const UPLOAD_TTL_HOURS: i64 = 24;
+/// Bytes stored per database row, larger requests are split
+const CHUNK_ROW_SIZE: usize = 1024 * 1024;
+
+pub fn tus_routes(state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route_with(
+            "/",
+            options_with(discover, discover_docs).post_with(create_upload, create_upload_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id",
+            head_with(get_offset, get_offset_docs)
+                .patch_with(append_chunk, append_chunk_docs)
+                .delete_with(terminate, terminate_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            load_shed::shed_uploads,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_uploads,
+        ))
+        .layer(middleware::from_fn(tus_resumable))
+        .with_state(state)
+}
+
+/// Refuse requests for another protocol version and mark every response with ours
+async fn tus_resumable<B>(req: Request<B>, next: Next<B>) -> Response {
+    let supported = req.method() == Method::OPTIONS
+        || req
+            .headers()
+            .get(TUS_RESUMABLE)
+            .is_some_and(|version| version == TUS_VERSION);
+    let mut res = if supported {
+        next.run(req).await
+    } else {
+        let mut res = AppError::new("unsupported tus version")
+            .with_status(StatusCode::PRECONDITION_FAILED)
+            .into_response();
+        res.headers_mut()
+            .insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+        res
+    };
+    res.headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    res
+}
+
+/// An upload still receiving chunks
+struct Upload {
+    id: Uuid,
+    length: i64,
+    offset: i64,
+    attestation_format: Option<String>,
+    attestation: Option<String>,
+    expires_at: DateTime<Utc>,
+}
+
+async fn discover() -> impl IntoApiResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (TUS_VERSION_HEADER, TUS_VERSION.to_string()),
+            (TUS_EXTENSION, "creation,expiration,termination".to_string()),
+            (TUS_MAX_SIZE, MAX_UPLOAD_SIZE.to_string()),
+        ],
+    )
+        .into_response()
+}
+
+fn discover_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Advertise the supported tus version, extensions and largest upload")
+        .response_with::<204, (), _>(|res| res.description("tus capabilities in headers"))
+}
+
+async fn create_upload(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Upload>,
+    SubmittedBy(submitter): SubmittedBy,
+    headers: HeaderMap,
+) -> impl IntoApiResponse {
+    if !state.availability.trillian_available() {
+        return uploads_paused().into_response();
+    }
+    let length = match header(&headers, UPLOAD_LENGTH).and_then(|length| length.parse().ok()) {
+        Some(length) if length >= 0 => length,
+        _ => return AppError::new("Upload-Length must be a byte count").into_response(),
+    };
+    if length > MAX_UPLOAD_SIZE as i64 {
+        return AppError::new("file too large")
+            .with_status(StatusCode::PAYLOAD_TOO_LARGE)
+            .with_details(json!({ "max_bytes": MAX_UPLOAD_SIZE }))
+            .into_response();
+    }
+    let mut metadata = match header(&headers, UPLOAD_METADATA).map(parse_metadata) {
+        Some(Ok(metadata)) => metadata,
+        Some(Err(err)) => return err.into_response(),
+        None => HashMap::new(),
+    };
+
+    // Expired uploads are cleared out as new ones arrive
+    if let Err(err) = sqlx::query!("DELETE FROM resumable_uploads WHERE expires_at < now()")
+        .execute(&state.db_pool)
+        .await
+    {
+        warn!("Could not delete expired uploads: {}", err);
+    }
+    let id = Uuid::new_v4();
+    let expires_at = Utc::now() + Duration::hours(UPLOAD_TTL_HOURS);
+    if let Err(err) = sqlx::query!(
+        "INSERT INTO resumable_uploads \
+        (id, owner, upload_length, attestation_format, attestation, expires_at) \
+        VALUES ($1, $2, $3, $4, $5, $6)",
+        id,
+        submitter.as_ref().and_then(Submitter::api_key),
+        length,
+        metadata.remove("attestation_format"),
+        metadata.remove("attestation"),
+        expires_at,
+    )
+    .execute(&state.db_pool)
+    .await
+    {
+        error!("Could not create upload: {}", err);
+        return db_error().into_response();
+    }
+
+    debug!("created resumable upload {} of {} bytes", id, length);
+    let mut res = StatusCode::CREATED.into_response();
+    res.headers_mut().insert(
+        LOCATION,
+        HeaderValue::from_str(&format!(
+            "{}/uploads/resumable/{id}",
+            ApiVersion::V1.prefix()
+        ))
+        .expect("valid header value"),
+    );
+    res.headers_mut()
+        .insert(UPLOAD_EXPIRES, http_date(expires_at));
+    res
+}
+
+fn create_upload_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Start a resumable upload of `Upload-Length` bytes, returning its URL in `Location`. \
+        A device attestation may be sent as the `attestation_format` and `attestation` keys of \
+        `Upload-Metadata`. Uploads expire after 24 hours.",
+    )
+    .response_with::<201, (), _>(|res| res.description("Upload created"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("missing Upload-Length or malformed Upload-Metadata")
+    })
+    .response_with::<412, Json<AppError>, _>(|res| res.description("unsupported tus version"))
+    .response_with::<413, Json<AppError>, _>(|res| res.description("upload is too large"))
+    .response_with::<429, Json<AppError>, _>(|res| {
+        res.description("upload rate limit exceeded, retry after the Retry-After delay")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("uploads are paused while the log is unreachable")
+    })
+}
+
+async fn get_offset(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Upload>,
+    SubmittedBy(submitter): SubmittedBy,
+    Path(id): Path<Uuid>,
+) -> impl IntoApiResponse {
+    let upload = match find_upload(&state, id, submitter.as_ref()).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => return err.into_response(),
+    };
+    let mut res = progress(&upload, StatusCode::OK);
+    res.headers_mut()
+        .insert(UPLOAD_LENGTH, HeaderValue::from(upload.length));
+    res.headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res
+}
+
+fn get_offset_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Get how many bytes of an upload have been received, in `Upload-Offset`")
+        .response_with::<200, (), _>(|res| res.description("Upload progress in headers"))
+        .response_with::<404, (), _>(|res| res.description("no such upload, or it expired"))
+}
+
+async fn append_chunk(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Upload>,
+    SubmittedBy(submitter): SubmittedBy,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> impl IntoApiResponse {
+    if header(&headers, CONTENT_TYPE.as_str()) != Some(CHUNK_CONTENT_TYPE) {
+        return AppError::new(&format!("chunks must be sent as {CHUNK_CONTENT_TYPE}"))
+            .with_status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .with_code(ErrorCode::InvalidRequest)
+            .into_response();
+    }
+    let offset: i64 = match header(&headers, UPLOAD_OFFSET).and_then(|offset| offset.parse().ok()) {
+        Some(offset) => offset,
+        None => return AppError::new("Upload-Offset must be a byte count").into_response(),
+    };
+    let mut upload = match find_upload(&state, id, submitter.as_ref()).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => return err.into_response(),
+    };
+    if offset != upload.offset {
+        return offset_conflict(upload.offset).into_response();
+    }
+
+    if let Err(err) = append(&state, &mut upload, body).await {
+        return err.into_response();
+    }
+    if upload.offset < upload.length {
+        return progress(&upload, StatusCode::NO_CONTENT);
+    }
+    finish(&state, &upload, submitter.as_ref()).await
+}
+
+fn append_chunk_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Append bytes to an upload at `Upload-Offset`. Bytes received before a dropped \
+        connection are kept, ask for the offset to resume from with `HEAD`. The request that \
+        completes the upload stores the image and names it in the `X-Veracity-Crypto-Hash` and \
+        `X-Veracity-Perceptual-Hash` headers; if storing fails with a retryable error, send an \
+        empty `PATCH` at the final offset to try again.",
+    )
+    .response_with::<204, (), _>(|res| res.description("Chunk stored"))
+    .response_with::<404, (), _>(|res| res.description("no such upload, or it expired"))
+    .response_with::<409, Json<AppError>, _>(|res| {
+        res.description("Upload-Offset does not match the bytes received")
+            .example(offset_conflict(0))
+    })
+    .response_with::<413, Json<AppError>, _>(|res| {
+        res.description("chunk runs past the Upload-Length")
+    })
+    .response_with::<415, Json<AppError>, _>(|res| {
+        res.description("chunk is not sent as application/offset+octet-stream")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available").example(db_error())
+    })
+}
+
+async fn terminate(
+    TenantState(state): TenantState,
+    _: Authorized<scope::Upload>,
+    SubmittedBy(submitter): SubmittedBy,
+    Path(id): Path<Uuid>,
+) -> impl IntoApiResponse {
+    match find_upload(&state, id, submitter.as_ref()).await {
+        Ok(Some(upload)) => match delete_upload(&state, upload.id).await {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(err) => err.into_response(),
+        },
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+fn terminate_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Abandon an upload and discard the bytes received so far")
+        .response_with::<204, (), _>(|res| res.description("Upload deleted"))
+        .response_with::<404, (), _>(|res| res.description("no such upload, or it expired"))
+}
+
+/// Look up an unexpired upload. Uploads created with an API key are only visible to that key,
+/// others are reachable by anyone holding their unguessable URL.
+async fn find_upload(
+    state: &AppState,
+    id: Uuid,
+    submitter: Option<&Submitter>,
+) -> Result<Option<Upload>, AppError> {
+    let row = sqlx::query!(
+        "SELECT id, upload_length, upload_offset, attestation_format, attestation, \
+        expires_at FROM resumable_uploads \
+        WHERE id = $1 AND expires_at > now() AND (owner IS NULL OR owner = $2)",
+        id,
+        submitter.and_then(Submitter::api_key),
+    )
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|err| {
+        error!("Could not get upload {}: {}", id, err);
+        db_error()
+    })?;
+    Ok(row.map(|row| Upload {
+        id: row.id,
+        length: row.upload_length,
+        offset: row.upload_offset,
+        attestation_format: row.attestation_format,
+        attestation: row.attestation,
+        expires_at: row.expires_at,
+    }))
+}
+
+/// Store the request body at the end of `upload`, committing a row at a time so a dropped
+/// connection keeps what arrived
+async fn append(
+    state: &AppState,
+    upload: &mut Upload,
+    mut body: BodyStream,
+) -> Result<(), AppError> {
+    let mut buffer = Vec::with_capacity(CHUNK_ROW_SIZE);
+    while let Some(bytes) = body.next().await {
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                debug!("upload {} interrupted: {}", upload.id, err);
+                break;
+            }
+        };
+        if upload.offset + (buffer.len() + bytes.len()) as i64 > upload.length {
+            write_chunk(state, upload, &buffer).await?;
+            return Err(AppError::new("chunk runs past the Upload-Length")
+                .with_status(StatusCode::PAYLOAD_TOO_LARGE)
+                .with_details(json!({ "upload_length": upload.length })));
+        }
+        buffer.extend_from_slice(&bytes);
+        if buffer.len() >= CHUNK_ROW_SIZE {
+            write_chunk(state, upload, &buffer).await?;
+            buffer.clear();
+        }
+    }
+    write_chunk(state, upload, &buffer).await
+}
+
+/// Store `data` at the upload's offset and advance it, failing if another request got there
+/// first
+async fn write_chunk(state: &AppState, upload: &mut Upload, data: &[u8]) -> Result<(), AppError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let written = async {
+        let mut tx = state.db_pool.begin().await?;
+        let advanced = sqlx::query!(
+            "UPDATE resumable_uploads SET upload_offset = upload_offset + $3 \
+            WHERE id = $1 AND upload_offset = $2",
+            upload.id,
+            upload.offset,
+            data.len() as i64,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if advanced == 0 {
+            return Ok(false);
+        }
+        sqlx::query!(
+            "INSERT INTO resumable_upload_chunks (upload_id, chunk_offset, data) \
+            VALUES ($1, $2, $3)",
+            upload.id,
+            upload.offset,
+            data,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(true)
+    }
+    .await;
+    match written {
+        Ok(true) => {
+            upload.offset += data.len() as i64;
+            Ok(())
+        }
+        Ok(false) => Err(AppError::new("upload was written to by another request")
+            .with_status(StatusCode::CONFLICT)),
+        Err(err) => {
+            error!("Could not store chunk of upload {}: {}", upload.id, err);
+            Err(db_error())
+        }
+    }
+}
+
+/// Assemble a complete upload and store the image. The upload is kept after retryable failures
+/// so an empty `PATCH` can try again.
+async fn finish(state: &AppState, upload: &Upload, submitter: Option<&Submitter>) -> Response {
+    if !state.availability.trillian_available() {
+        return uploads_paused().into_response();
+    }
+    let stored = async {
+        let buffer = assemble(state, upload).await?;
+        state.image_types.check(None, &buffer)?;
+        let file = hash_file(&state.hash_pool, buffer).await?;
+        store_image(
+            state,
+            file,
+            upload.attestation_format.clone(),
+            upload.attestation.clone(),
+            None,
+            None,
+            submitter,
+        )
+        .await
+    }
+    .await;
+    if !stored.as_ref().is_err_and(|err| err.retryable) {
+        if let Err(err) = delete_upload(state, upload.id).await {
+            // Left to expire
+            warn!("Could not delete finished upload {}: {}", upload.id, err);
+        }
+    }
+
+    let record = match stored {
+        Ok(stored) => stored.record,
+        Err(err) => return err.into_response(),
+    };
+    let mut res = progress(upload, StatusCode::NO_CONTENT);
+    let headers = res.headers_mut();
+    headers.insert(
+        CRYPTO_HASH_HEADER,
+        HeaderValue::from_str(&record.hash.crypto_hash.to_hex()).expect("hex is a header value"),
+    );
+    headers.insert(
+        PERCEPTUAL_HASH_HEADER,
+        HeaderValue::from_str(&record.hash.perceptual_hash.to_hex())
+            .expect("hex is a header value"),
+    );
+    res
+}
+
+async fn assemble(state: &AppState, upload: &Upload) -> Result<Vec<u8>, AppError> {
+    let chunks = sqlx::query_scalar!(
+        "SELECT data FROM resumable_upload_chunks WHERE upload_id = $1 ORDER BY chunk_offset",
+        upload.id,
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .map_err(|err| {
+        error!("Could not read chunks of upload {}: {}", upload.id, err);
+        db_error()
+    })?;
+    let mut buffer = Vec::with_capacity(upload.length as usize);
+    for chunk in &chunks {
+        buffer.extend_from_slice(chunk);
+    }
+    Ok(buffer)
+}
+
+async fn delete_upload(state: &AppState, id: Uuid) -> Result<(), AppError> {
+    sqlx::query!("DELETE FROM resumable_uploads WHERE id = $1", id)
+        .execute(&state.db_pool)
+        .await
+        .map_err(|err| {
+            error!("Could not delete upload {}: {}", id, err);
+            db_error()
+        })?;
+    Ok(())
+}
+
+/// Response reporting the upload's offset and expiry
+fn progress(upload: &Upload, status: StatusCode) -> Response {
+    let mut res = status.into_response();
+    res.headers_mut()
+        .insert(UPLOAD_OFFSET, HeaderValue::from(upload.offset));
+    res.headers_mut()
+        .insert(UPLOAD_EXPIRES, http_date(upload.expires_at));
+    res
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Decode `Upload-Metadata`: comma-separated keys, each followed by a space and its base64
+/// value unless it has none
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, AppError> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = BASE64_STANDARD
+                .decode(value.trim())
+                .ok()
+                .and_then(|value| String::from_utf8(value).ok())
+                .ok_or_else(|| {
+                    AppError::new("Upload-Metadata values must be base64 encoded UTF-8")
+                        .with_details(json!({ "key": key }))
+                })?;
+            Ok((key.to_string(), value))
+        })
+        .collect()
+}
+
+fn http_date(time: DateTime<Utc>) -> HeaderValue {
+    HeaderValue::from_str(&time.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+        .expect("dates are header values")
+}
+
+fn offset_conflict(offset: i64) -> AppError {
+    AppError::new("Upload-Offset does not match the bytes received")
+        .with_status(StatusCode::CONFLICT)
+        .with_details(json!({ "upload_offset": offset }))
+}
+
+fn db_error() -> AppError {
+    AppError::new("Could not get upload details")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_values_are_base64() {
+        let metadata =
+            parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential")
+                .unwrap();
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["is_confidential"], "");
+        assert!(parse_metadata("attestation not-base64!").is_err());
+        assert!(parse_metadata("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn expiry_uses_http_dates() {
+        let time = DateTime::parse_from_rfc3339("1994-11-06T08:49:37Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
@@ -0,0 +1,197 @@
+use aide::axum::routing::post_with;
+use aide::axum::{ApiRouter, IntoApiResponse};
+use aide::transform::TransformOperation;
+use axum::extract::{DefaultBodyLimit, Multipart, Path, State};
+use axum::http::StatusCode;
+use axum::middleware;
+use axum::response::IntoResponse;
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, Json, SubmittedBy};
+use crate::server::routes::{store_upload, ApiVersion, StoredImageOutput, MAX_UPLOAD_SIZE};
+use crate::server::{load_shed, rate_limit, tus};
+use crate::state::AppState;
+
+/// Default lifetime of a pre-signed upload token
+const DEFAULT_TOKEN_TTL_SECONDS: i64 = 15 * 60;
+const MAX_TOKEN_TTL_SECONDS: i64 = 24 * 60 * 60;
+
+pub fn upload_routes(state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route("/tokens", post_with(mint_token, mint_token_docs))
+        .api_route(
+            "/:token",
+            post_with(upload_with_token, upload_with_token_docs),
+        )
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            load_shed::shed_uploads,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_uploads,
+        ))
+        // Added after the layers above, the tus routes bring their own
+        .nest_api_service("/resumable", tus::tus_routes(state.clone()))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct MintTokenRequest {
+    /// Seconds until the token expires, defaults to 15 minutes and capped at 24 hours
+    ttl_seconds: Option<i64>,
+    /// Largest image the token accepts in bytes, defaults to and capped at the upload limit
+    max_bytes: Option<usize>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct UploadToken {
+    pub token: String,
+    /// Path to POST a single multipart image to, no credentials needed
+    pub upload_path: String,
+    pub expires_at: DateTime<Utc>,
+    pub max_bytes: usize,
+}
+
+async fn mint_token(
+    State(state): State<AppState>,
+    Authorized(identity, _): Authorized<scope::Upload>,
+    Json(request): Json<MintTokenRequest>,
+) -> impl IntoApiResponse {
+    let upload_tokens = match &state.upload_tokens {
+        Some(upload_tokens) => upload_tokens,
+        None => return not_enabled().into_response(),
+    };
+
+    let ttl = request
+        .ttl_seconds
+        .unwrap_or(DEFAULT_TOKEN_TTL_SECONDS)
+        .clamp(1, MAX_TOKEN_TTL_SECONDS);
+    let max_bytes = request
+        .max_bytes
+        .unwrap_or(MAX_UPLOAD_SIZE)
+        .clamp(1, MAX_UPLOAD_SIZE);
+
+    let tenant = identity.and_then(|identity| identity.tenant);
+    let (token, claims) = upload_tokens.mint(Duration::seconds(ttl), max_bytes, tenant);
+    debug!(
+        "minted upload token {} expiring at {}",
+        claims.id, claims.expires_at
+    );
+    let mut res = Json(UploadToken {
+        upload_path: format!("{}/uploads/{token}", ApiVersion::V1.prefix()),
+        token,
+        expires_at: claims.expires_at(),
+        max_bytes: claims.max_bytes,
+    })
+    .into_response();
+    *res.status_mut() = StatusCode::CREATED;
+    res
+}
+
+fn mint_token_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Mint a short-lived token that lets a device without credentials upload a single image",
+    )
+    .security_requirement("ApiKey")
+    .response_with::<201, Json<UploadToken>, _>(|res| res.description("Pre-signed upload token"))
+    .response_with::<401, Json<AppError>, _>(|res| res.description("missing or invalid API key"))
+    .response_with::<403, Json<AppError>, _>(|res| {
+        res.description("API key lacks the upload scope")
+    })
+    .response_with::<404, Json<AppError>, _>(|res| {
+        res.description("pre-signed uploads are not enabled")
+            .example(not_enabled())
+    })
+}
+
+async fn upload_with_token(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    SubmittedBy(submitter): SubmittedBy,
+    multipart: Multipart,
+) -> impl IntoApiResponse {
+    let upload_tokens = match &state.upload_tokens {
+        Some(upload_tokens) => upload_tokens,
+        None => return not_enabled().into_response(),
+    };
+    let claims = match upload_tokens.verify(&token, Utc::now()) {
+        Ok(claims) => claims,
+        Err(err) => {
+            warn!("Rejected upload token: {}", err);
+            return AppError::new(&err.to_string())
+                .with_status(StatusCode::FORBIDDEN)
+                .with_code(ErrorCode::UploadTokenInvalid)
+                .into_response();
+        }
+    };
+    let state = match &claims.tenant {
+        Some(tenant) => match state.for_tenant(tenant) {
+            Some(state) => state,
+            None => {
+                return AppError::new(&format!(
+                    "upload token belongs to unknown tenant {}",
+                    tenant
+                ))
+                .with_status(StatusCode::FORBIDDEN)
+                .into_response()
+            }
+        },
+        None => state,
+    };
+
+    match store_upload(
+        &state,
+        multipart,
+        claims.max_bytes,
+        Some(&claims),
+        submitter.as_ref(),
+    )
+    .await
+    {
+        Ok(stored) => (stored.status(), Json(stored)).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+fn upload_with_token_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Store an image using a pre-signed upload token")
+        .response_with::<201, Json<StoredImageOutput>, _>(|res| res.description("Image stored"))
+        .response_with::<200, Json<StoredImageOutput>, _>(|res| {
+            res.description("image was already stored, when duplicates are answered with it")
+        })
+        .response_with::<403, Json<AppError>, _>(|res| {
+            res.description("token is invalid, expired or already used")
+                .example(
+                    AppError::new("upload token has expired")
+                        .with_status(StatusCode::FORBIDDEN)
+                        .with_code(ErrorCode::UploadTokenInvalid),
+                )
+        })
+        .response_with::<409, Json<AppError>, _>(|res| {
+            res.description("image already exists in database, which is in the error details")
+        })
+        .response_with::<413, Json<AppError>, _>(|res| {
+            res.description("image is larger than the token allows")
+        })
+        .response_with::<422, Json<AppError>, _>(|res| {
+            res.description("attestation could not be verified")
+        })
+        .response_with::<429, Json<AppError>, _>(|res| {
+            res.description("upload rate limit exceeded, retry after the Retry-After delay")
+        })
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("uploads are paused while the log is unreachable")
+        })
+}
+
+fn not_enabled() -> AppError {
+    AppError::new("pre-signed uploads are not enabled")
+        .with_status(StatusCode::NOT_FOUND)
+        .with_code(ErrorCode::NotEnabled)
+}
@@ -0,0 +1,288 @@
+//! Canonical test vectors for third-party verifier implementations.
+//!
+//! Everything served here is computed locally and deterministically from a fixed set of sample
+//! images, using the same leaf encoding and RFC 6962 hashing as this deployment's Trillian tree.
+//! Independent implementations can fetch the vectors and check their leaf encoding, inclusion
+//! proof, consistency proof, and log root parsing against them without touching real data.
+//! The example log root is not signed, as no signing key takes part in generating the vectors.
+use aide::axum::routing::get_with;
+use aide::axum::{ApiRouter, IntoApiResponse};
+use aide::transform::TransformOperation;
+use axum::extract::State;
+use ring::digest::{digest, SHA256};
+use schemars::JsonSchema;
+use serde::Serialize;
+use veracity_verify::{consistency_proof, inclusion_proof, root_hash, LogRootV1};
+
+use crate::extractors::Json;
+use crate::hash::cryptographic::CryptographicHash;
+use crate::hash::perceptual::PerceptualHash;
+use crate::hash::VeracityHash;
+use crate::state::AppState;
+
+/// Number of sample leaves; deliberately not a power of two so proofs exercise unbalanced subtrees
+const SAMPLE_LEAF_COUNT: usize = 7;
+/// Fixed timestamp for the example log root so the serialized bytes never change
+const SAMPLE_TIMESTAMP_NANOS: u64 = 1_687_276_455_000_000_000;
+
+pub fn vector_routes(state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route("/vectors", get_with(get_vectors, get_vectors_docs))
+        .with_state(state)
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TestVectors {
+    /// Parameters of this deployment the vectors were generated for
+    pub parameters: VectorParameters,
+    /// Sample leaves in log order
+    pub leaves: Vec<LeafVector>,
+    /// Root hash of a tree containing all sample leaves, hex encoded
+    pub root_hash: String,
+    /// Inclusion proofs for every sample leaf against the full tree
+    pub inclusion_proofs: Vec<InclusionProofVector>,
+    /// Consistency proofs from every smaller tree size to the full tree
+    pub consistency_proofs: Vec<ConsistencyProofVector>,
+    /// Example log root for the full tree, unsigned
+    pub log_root: LogRootVector,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VectorParameters {
+    /// Trillian tree ID of this deployment
+    pub tree_id: i64,
+    /// Merkle tree hash strategy
+    pub hash_strategy: String,
+    /// Which hash is stored as the Trillian leaf value
+    pub leaf_value: String,
+    /// Which hash is stored as the Trillian leaf extra data
+    pub extra_data: String,
+    /// Log root TLS-serialization version
+    pub log_root_version: u16,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LeafVector {
+    pub leaf_index: u64,
+    pub crypto_hash: CryptographicHash,
+    pub perceptual_hash: PerceptualHash,
+    /// Hex encoded Trillian leaf value
+    pub leaf_value: String,
+    /// Hex encoded Trillian leaf extra data
+    pub extra_data: String,
+    /// Hex encoded RFC 6962 leaf hash, SHA-256(0x00 || leaf_value)
+    pub merkle_leaf_hash: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct InclusionProofVector {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    /// Hex encoded audit path, ordered from the leaf upwards
+    pub hashes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ConsistencyProofVector {
+    pub first_tree_size: u64,
+    pub second_tree_size: u64,
+    /// Hex encoded root hash of the first tree
+    pub first_root_hash: String,
+    /// Hex encoded consistency proof hashes
+    pub hashes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LogRootVector {
+    pub tree_size: u64,
+    /// Hex encoded root hash
+    pub root_hash: String,
+    pub timestamp_nanos: u64,
+    pub revision: u64,
+    /// Hex encoded TLS-serialized LogRootV1, laid out as in `SignedLogRoot.log_root` but with no
+    /// signature over it
+    pub log_root: String,
+}
+
+async fn get_vectors(
+    State(AppState { trillian_tree, .. }): State<AppState>,
+) -> impl IntoApiResponse {
+    Json(test_vectors(trillian_tree))
+}
+
+fn get_vectors_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Canonical test vectors for independent verifier implementations")
+        .response_with::<200, Json<TestVectors>, _>(|res| {
+            res.description(
+                "Leaf encodings, proofs, and an unsigned log root for a fixed sample tree",
+            )
+        })
+}
+
+fn test_vectors(tree_id: i64) -> TestVectors {
+    let leaves: Vec<LeafVector> = (0..SAMPLE_LEAF_COUNT).map(sample_leaf).collect();
+    let leaf_hashes: Vec<Vec<u8>> = leaves
+        .iter()
+        .map(|leaf| veracity_hash(leaf).merkle_leaf_hash().to_vec())
+        .collect();
+    let tree_size = leaf_hashes.len() as u64;
+    let root = root_hash(&leaf_hashes);
+
+    let inclusion_proofs = (0..leaf_hashes.len())
+        .map(|index| InclusionProofVector {
+            leaf_index: index as u64,
+            tree_size,
+            hashes: encode_all(&inclusion_proof(index, &leaf_hashes)),
+        })
+        .collect();
+
+    let consistency_proofs = (1..leaf_hashes.len())
+        .map(|first| ConsistencyProofVector {
+            first_tree_size: first as u64,
+            second_tree_size: tree_size,
+            first_root_hash: hex::encode(root_hash(&leaf_hashes[..first])),
+            hashes: encode_all(&consistency_proof(first, &leaf_hashes)),
+        })
+        .collect();
+
+    let log_root = LogRootV1 {
+        tree_size,
+        root_hash: root.clone(),
+        timestamp_nanos: SAMPLE_TIMESTAMP_NANOS,
+        revision: tree_size,
+        metadata: vec![],
+    };
+    TestVectors {
+        parameters: VectorParameters {
+            tree_id,
+            hash_strategy: "RFC6962_SHA256".to_string(),
+            leaf_value: "crypto_hash".to_string(),
+            extra_data: "perceptual_hash".to_string(),
+            log_root_version: 1,
+        },
+        leaves,
+        root_hash: hex::encode(&root),
+        inclusion_proofs,
+        consistency_proofs,
+        log_root: LogRootVector {
+            tree_size,
+            root_hash: hex::encode(&root),
+            timestamp_nanos: SAMPLE_TIMESTAMP_NANOS,
+            revision: tree_size,
+            log_root: hex::encode(
+                log_root
+                    .serialize()
+                    .expect("a SHA-256 root fits a log root"),
+            ),
+        },
+    }
+}
+
+/// Create a deterministic sample leaf, encoded exactly as the upload handler queues it
+fn sample_leaf(index: usize) -> LeafVector {
+    let crypto = digest(
+        &SHA256,
+        format!("veracity test vector crypto {index}").as_bytes(),
+    );
+    let perceptual = digest(
+        &SHA256,
+        format!("veracity test vector perceptual {index}").as_bytes(),
+    );
+    let hash = VeracityHash {
+        crypto_hash: CryptographicHash::try_from(crypto).expect("SHA-256 digest is 32 bytes"),
+        perceptual_hash: PerceptualHash::try_from(perceptual.as_ref().to_vec())
+            .expect("SHA-256 digest is 32 bytes"),
+    };
+
+    LeafVector {
+        leaf_index: index as u64,
+        leaf_value: hash.crypto_hash.to_hex(),
+        extra_data: hash.perceptual_hash.to_hex(),
+        merkle_leaf_hash: hex::encode(hash.merkle_leaf_hash()),
+        crypto_hash: hash.crypto_hash,
+        perceptual_hash: hash.perceptual_hash,
+    }
+}
+
+fn veracity_hash(leaf: &LeafVector) -> VeracityHash {
+    VeracityHash {
+        crypto_hash: leaf.crypto_hash.clone(),
+        perceptual_hash: leaf.perceptual_hash.clone(),
+    }
+}
+
+fn encode_all(hashes: &[Vec<u8>]) -> Vec<String> {
+    hashes.iter().map(hex::encode).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use veracity_verify::{verify_consistency, verify_inclusion};
+
+    use super::*;
+
+    #[test]
+    fn leaf_hashes_are_the_logged_leaf_hashes() {
+        let vectors = test_vectors(0);
+        for leaf in &vectors.leaves {
+            assert_eq!(
+                leaf.merkle_leaf_hash,
+                hex::encode(veracity_verify::leaf_hash(
+                    &hex::decode(&leaf.leaf_value).unwrap()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn proofs_verify() {
+        let vectors = test_vectors(0);
+        let root = hex::decode(&vectors.root_hash).unwrap();
+        let decode_all = |hashes: &[String]| -> Vec<Vec<u8>> {
+            hashes
+                .iter()
+                .map(|hash| hex::decode(hash).unwrap())
+                .collect()
+        };
+
+        for (leaf, proof) in vectors.leaves.iter().zip(&vectors.inclusion_proofs) {
+            let leaf_hash = hex::decode(&leaf.merkle_leaf_hash).unwrap();
+            assert_eq!(
+                verify_inclusion(
+                    proof.leaf_index,
+                    proof.tree_size,
+                    &leaf_hash,
+                    &decode_all(&proof.hashes),
+                    &root
+                ),
+                Ok(()),
+                "leaf {}",
+                proof.leaf_index
+            );
+        }
+        for proof in &vectors.consistency_proofs {
+            assert_eq!(
+                verify_consistency(
+                    proof.first_tree_size,
+                    proof.second_tree_size,
+                    &hex::decode(&proof.first_root_hash).unwrap(),
+                    &root,
+                    &decode_all(&proof.hashes)
+                ),
+                Ok(()),
+                "{} -> {}",
+                proof.first_tree_size,
+                proof.second_tree_size
+            );
+        }
+    }
+
+    #[test]
+    fn log_root_parses() {
+        let vectors = test_vectors(0);
+        let log_root = LogRootV1::parse(&hex::decode(&vectors.log_root.log_root).unwrap()).unwrap();
+        assert_eq!(log_root.tree_size, 7);
+        assert_eq!(hex::encode(log_root.root_hash), vectors.root_hash);
+        assert_eq!(log_root.timestamp_nanos, SAMPLE_TIMESTAMP_NANOS);
+    }
+}
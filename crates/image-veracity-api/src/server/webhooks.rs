@@ -0,0 +1,182 @@
+use aide::axum::routing::{delete_with, post_with};
+use aide::axum::{ApiRouter, IntoApiResponse};
+use aide::transform::TransformOperation;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::api_key::ApiKeyIdentity;
+use crate::errors::{AppError, ErrorCode};
+use crate::extractors::{scope, Authorized, Json, Submitter, TenantState};
+use crate::fetch::FetchError;
+use crate::state::AppState;
+use crate::webhooks::{self, WebhookRecord, MAX_WEBHOOKS_PER_KEY};
+
+pub fn webhook_routes(state: AppState) -> ApiRouter {
+    ApiRouter::new()
+        .api_route_with(
+            "/",
+            post_with(create_webhook, create_webhook_docs)
+                .get_with(list_webhooks, list_webhooks_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .api_route_with(
+            "/:id",
+            delete_with(delete_webhook, delete_webhook_docs),
+            |p| p.security_requirement("ApiKey"),
+        )
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CreateWebhookRequest {
+    /// `http` or `https` URL on a public host to POST events to
+    url: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub record: WebhookRecord,
+    /// Key for checking the `X-Veracity-Signature` of deliveries, shown only once
+    pub secret: String,
+}
+
+async fn create_webhook(
+    TenantState(state): TenantState,
+    Authorized(identity, _): Authorized<scope::Upload>,
+    Json(request): Json<CreateWebhookRequest>,
+) -> impl IntoApiResponse {
+    let owner = match owner(identity.as_ref()) {
+        Ok(owner) => owner,
+        Err(err) => return err.into_response(),
+    };
+    match webhooks::create(&state.db_pool, &owner, &request.url).await {
+        Ok(Some((record, secret))) => {
+            info!("Registered webhook {} for {}", record.id, owner);
+            let mut res = Json(CreatedWebhook { record, secret }).into_response();
+            *res.status_mut() = StatusCode::CREATED;
+            res
+        }
+        Ok(None) => AppError::new("too many webhooks for this API key")
+            .with_status(StatusCode::CONFLICT)
+            .with_details(json!({ "max_webhooks": MAX_WEBHOOKS_PER_KEY }))
+            .into_response(),
+        Err(err) => match err.downcast_ref::<FetchError>() {
+            Some(err) => {
+                warn!("Rejected webhook URL {}: {}", request.url, err);
+                AppError::new(&err.to_string())
+                    .with_status(StatusCode::BAD_REQUEST)
+                    .into_response()
+            }
+            None => {
+                error!("Could not register webhook: {}", err);
+                webhook_db_error().into_response()
+            }
+        },
+    }
+}
+
+fn create_webhook_docs(op: TransformOperation) -> TransformOperation {
+    op.description(
+        "Register a URL to be sent a signed `image.integrated` event, with the leaf index and \
+        latest root, whenever an image uploaded with this API key is integrated into the tree. \
+        When the log monitor is enabled, a `log.inconsistent` event is also sent if the log \
+        stops being consistent with a root seen before. \
+        Each delivery is signed with `X-Veracity-Signature: sha256=<hex>`, the HMAC-SHA256 of \
+        `{X-Veracity-Timestamp}.{body}` under the returned secret. Failed deliveries are retried \
+        with backoff for a few hours.",
+    )
+    .response_with::<201, Json<CreatedWebhook>, _>(|res| res.description("Webhook registered"))
+    .response_with::<400, Json<AppError>, _>(|res| {
+        res.description("the URL is invalid or does not resolve to a public address")
+    })
+    .response_with::<409, Json<AppError>, _>(|res| {
+        res.description("the API key already has the most webhooks allowed")
+    })
+    .response_with::<503, Json<AppError>, _>(|res| {
+        res.description("service not available")
+            .example(webhook_db_error())
+    })
+}
+
+async fn list_webhooks(
+    TenantState(state): TenantState,
+    Authorized(identity, _): Authorized<scope::Upload>,
+) -> impl IntoApiResponse {
+    let owner = match owner(identity.as_ref()) {
+        Ok(owner) => owner,
+        Err(err) => return err.into_response(),
+    };
+    match webhooks::list(&state.db_pool, &owner).await {
+        Ok(records) => Json(records).into_response(),
+        Err(err) => {
+            error!("Could not list webhooks: {}", err);
+            webhook_db_error().into_response()
+        }
+    }
+}
+
+fn list_webhooks_docs(op: TransformOperation) -> TransformOperation {
+    op.description("List the webhooks registered with this API key, without their secrets")
+        .response_with::<200, Json<Vec<WebhookRecord>>, _>(|res| res.description("Webhooks"))
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("service not available")
+                .example(webhook_db_error())
+        })
+}
+
+async fn delete_webhook(
+    TenantState(state): TenantState,
+    Authorized(identity, _): Authorized<scope::Upload>,
+    Path(id): Path<Uuid>,
+) -> impl IntoApiResponse {
+    let owner = match owner(identity.as_ref()) {
+        Ok(owner) => owner,
+        Err(err) => return err.into_response(),
+    };
+    match webhooks::delete(&state.db_pool, &owner, id).await {
+        Ok(true) => {
+            info!("Deleted webhook {}", id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!("Could not delete webhook {}: {}", id, err);
+            webhook_db_error().into_response()
+        }
+    }
+}
+
+fn delete_webhook_docs(op: TransformOperation) -> TransformOperation {
+    op.description("Delete a webhook, dropping any deliveries still pending")
+        .response_with::<204, (), _>(|res| res.description("Webhook deleted"))
+        .response_with::<404, (), _>(|res| {
+            res.description("no webhook with this id for this API key")
+        })
+        .response_with::<503, Json<AppError>, _>(|res| {
+            res.description("service not available")
+                .example(webhook_db_error())
+        })
+}
+
+/// Webhooks are owned by the API key that registers them, so they need keys to be enabled
+fn owner(identity: Option<&ApiKeyIdentity>) -> Result<String, AppError> {
+    match identity.map(Submitter::from_identity) {
+        Some(Submitter::ApiKey(key)) => Ok(key),
+        _ => Err(AppError::new("webhooks require API keys to be enabled")
+            .with_status(StatusCode::NOT_FOUND)
+            .with_code(ErrorCode::NotEnabled)),
+    }
+}
+
+fn webhook_db_error() -> AppError {
+    AppError::new("Could not get webhook details")
+        .with_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_code(ErrorCode::DatabaseUnavailable)
+}
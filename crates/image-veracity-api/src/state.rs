@@ -1,19 +1,114 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use bb8::Pool;
-use bb8_postgres::PostgresConnectionManager;
-use eyre::{Error, Report, Result};
-use openssl::error::ErrorStack;
-use openssl::ssl::{SslConnector, SslMethod};
-use postgres_openssl::MakeTlsConnector;
-use tokio_postgres::Config;
-use tracing::{debug, error, instrument};
+use eyre::{eyre, Error, Report, Result};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use tracing::{debug, error, instrument, warn};
 
-use trillian::client::{TrillianClient, TrillianClientApiMethods};
+use trillian::client::{TlsOptions, TrillianClient, TrillianClientApiMethods};
 
-pub type ConnectionPool = Pool<PostgresConnectionManager<MakeTlsConnector>>;
+use crate::api_key::ApiKeys;
+use crate::attestation::Attestations;
+use crate::availability::Availability;
+use crate::blob::SharedBlobStore;
+use crate::cache::ImageCache;
+use crate::checkpoint::NoteSigner;
+use crate::coalesce::Coalescer;
+use crate::errors::LookupError;
+use crate::fetch::UrlFetcher;
+use crate::hash_pool::HashPool;
+use crate::map::VeracityMap;
+use crate::public_id::PublicIds;
+use crate::record::ImageRecord;
+use crate::repository::{PostgresImageRepository, SharedImageRepository};
+use crate::server::events::StatusEvents;
+use crate::server::image_types::AllowedImageTypes;
+use crate::server::load_shed::UploadSlots;
+use crate::server::rate_limit::RateLimiter;
+use crate::server::retry::Backoff;
+use crate::tenant::TenantConfig;
+use crate::upload_token::UploadTokens;
+use crate::witness::Witness;
+
+pub type ConnectionPool = sqlx::PgPool;
 pub type TrillianState = Box<dyn TrillianClientApiMethods + Send + Sync>;
+pub type ImageLookups = Coalescer<ImageKey, Result<Option<ImageRecord>, LookupError>>;
+
+/// Key of a single-image lookup, used to coalesce identical concurrent lookups
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum ImageKey {
+    CryptoHash([u8; 32]),
+    PerceptualHash([u8; 32]),
+}
+
+/// A customer with its own Trillian tree and database schema, see [`crate::tenant`]
+#[derive(Clone)]
+pub struct Tenant {
+    pub tree_id: i64,
+    pub schema: String,
+    db_pool: ConnectionPool,
+    db_read_pool: Option<ConnectionPool>,
+    images: SharedImageRepository,
+    image_lookups: ImageLookups,
+}
+
+/// Delay before the second attempt to reach the database on startup, doubled after each failure
+const BASE_CONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How long a single startup attempt waits for a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hamming distance within which uploads report near-duplicates unless configured otherwise
+pub const DEFAULT_NEAR_DUPLICATE_DISTANCE: u32 = 10;
+
+/// How perceptual hashes are indexed for similarity search
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PerceptualIndex {
+    /// Compare against every stored perceptual hash in process
+    #[default]
+    Scan,
+    /// Store perceptual hashes in a pgvector `bit(256)` column and use its HNSW index
+    PgVector,
+}
+
+impl FromStr for PerceptualIndex {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "scan" => Ok(PerceptualIndex::Scan),
+            "pgvector" => Ok(PerceptualIndex::PgVector),
+            other => Err(Error::msg(format!("unknown perceptual index {other}"))),
+        }
+    }
+}
+
+/// How uploads of an image that is already stored are answered
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateUploads {
+    /// 409 Conflict, with the stored image in the error details
+    #[default]
+    Conflict,
+    /// 200 OK with the stored image, marked `already_existed`
+    Existing,
+}
+
+impl FromStr for DuplicateUploads {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "conflict" => Ok(DuplicateUploads::Conflict),
+            "existing" => Ok(DuplicateUploads::Existing),
+            other => Err(Error::msg(format!(
+                "unknown duplicate upload answer {other}"
+            ))),
+        }
+    }
+}
 
 #[allow(dead_code)]
 #[derive(Builder, Clone)]
@@ -26,10 +121,167 @@ pub struct AppState {
 
     trillian_host: String,
 
+    /// TLS settings for an `https://` Trillian host
+    #[builder(default)]
+    trillian_tls: TlsOptions,
+
+    #[builder(default)]
+    pub perceptual_index: PerceptualIndex,
+
+    /// Image types uploads are accepted in
+    #[builder(default)]
+    pub image_types: AllowedImageTypes,
+
+    #[builder(default)]
+    pub duplicate_uploads: DuplicateUploads,
+
+    /// Pool uploads are hashed on, apart from the global rayon pool
+    #[builder(default)]
+    pub hash_pool: HashPool,
+
+    /// Whether metadata uploaded with an image is logged in its leaf's extra data, rather than
+    /// only stored
+    #[builder(default)]
+    pub log_metadata: bool,
+
+    /// Hamming distance within which `POST /` reports similar stored images, 0 to skip the check
+    #[builder(default = "DEFAULT_NEAR_DUPLICATE_DISTANCE")]
+    pub near_duplicate_distance: u32,
+
+    #[builder(default)]
+    pub public_ids: PublicIds,
+
+    /// Pre-signed uploads are disabled when unset
+    #[builder(default)]
+    pub upload_tokens: Option<UploadTokens>,
+
+    /// Original images are not kept when unset
+    #[builder(default)]
+    pub blob_store: Option<SharedBlobStore>,
+
+    /// Signed checkpoints are not served when unset
+    #[builder(default)]
+    pub checkpoint_signer: Option<NoteSigner>,
+
+    /// Witnesses checkpoints are submitted to for cosigning, needs a checkpoint signer
+    #[builder(default)]
+    pub witnesses: Vec<Witness>,
+
+    /// How often the log monitor checks the latest root, the monitor is off when unset
+    #[builder(default)]
+    pub log_monitor_interval: Option<Duration>,
+
+    /// Map from perceptual hashes to crypto hashes served with proofs, not kept when unset
+    #[builder(default)]
+    pub veracity_map: Option<VeracityMap>,
+
+    /// Fetching images by URL is disabled when unset
+    #[builder(default)]
+    pub url_fetcher: Option<UrlFetcher>,
+
+    /// API keys are not required when unset
+    #[builder(default)]
+    pub api_keys: ApiKeys,
+
+    /// Device attestation formats accepted with uploads, none by default
+    #[builder(default)]
+    pub attestations: Attestations,
+
+    /// Uploads are not rate limited when no quota is set
+    #[builder(default)]
+    pub rate_limiter: RateLimiter,
+
+    /// Ceiling on uploads in progress, over which they are shed
+    #[builder(default)]
+    pub upload_slots: UploadSlots,
+
+    /// Uploads are refused while Trillian is unreachable
+    #[builder(default)]
+    pub availability: Availability,
+
+    /// Delay suggested to clients after transient failures
+    #[builder(default)]
+    pub backoff: Backoff,
+
+    /// Wakes image event streams when this process changes an image's status
+    #[builder(default)]
+    pub status_events: StatusEvents,
+
+    #[builder(default = "Coalescer::new(\"image\")")]
+    pub image_lookups: ImageLookups,
+
+    /// Single-image lookups are not cached when unset
+    #[builder(default)]
+    pub image_cache: ImageCache,
+
+    /// Stored images, kept in the database unless another repository is set
+    #[builder(setter(custom))]
+    pub images: SharedImageRepository,
+
     #[builder(setter(custom))]
     pub db_pool: ConnectionPool,
+    /// Times to try reaching the database before `build` gives up, 0 to connect on first use
+    #[builder(default)]
+    db_connect_attempts: u32,
+    /// Connection string of the primary, parsed when the state is built
     #[builder(setter(custom))]
-    db_config: Config,
+    db_url: String,
+    /// Replica serving GET and search queries, the primary serves them when unset
+    #[builder(setter(custom), default)]
+    db_read_pool: Option<ConnectionPool>,
+    #[builder(setter(custom), default)]
+    db_read_url: Option<String>,
+
+    /// Tenants by name, whose keys use their own tree and schema instead of the default ones
+    #[builder(setter(custom), default)]
+    tenants: Arc<BTreeMap<String, Tenant>>,
+    #[builder(setter(custom), default)]
+    tenant_configs: BTreeMap<String, TenantConfig>,
+}
+
+impl AppState {
+    /// Pool for queries that only read, and can see data a little behind the primary
+    pub fn read_pool(&self) -> &ConnectionPool {
+        self.db_read_pool.as_ref().unwrap_or(&self.db_pool)
+    }
+
+    /// Whether reads go to a replica rather than the primary
+    pub fn has_read_replica(&self) -> bool {
+        self.db_read_pool.is_some()
+    }
+
+    /// Configured tenants by name
+    pub fn tenants(&self) -> impl Iterator<Item = (&str, &Tenant)> {
+        self.tenants
+            .iter()
+            .map(|(name, tenant)| (name.as_str(), tenant))
+    }
+
+    /// State for serving the tenant `name`, which logs to its tree and keeps images in its
+    /// schema. `None` if there is no such tenant.
+    pub fn for_tenant(&self, name: &str) -> Option<AppState> {
+        let tenant = self.tenants.get(name)?;
+        Some(AppState {
+            trillian_tree: tenant.tree_id,
+            db_pool: tenant.db_pool.clone(),
+            db_read_pool: tenant.db_read_pool.clone(),
+            images: tenant.images.clone(),
+            image_lookups: tenant.image_lookups.clone(),
+            // Cached lookups are keyed by hash alone, so tenants do without the cache
+            image_cache: ImageCache::default(),
+            tenants: Arc::default(),
+            tenant_configs: BTreeMap::new(),
+            ..self.clone()
+        })
+    }
+
+    /// State for serving each tenant, to run background work on every tree
+    pub fn tenant_states(&self) -> Vec<AppState> {
+        self.tenants
+            .keys()
+            .filter_map(|name| self.for_tenant(name))
+            .collect()
+    }
 }
 
 impl AppStateBuilder {
@@ -42,47 +294,101 @@ impl AppStateBuilder {
 
     #[instrument(skip(self))]
     pub fn create_postgres_client(&mut self, host: &str) -> &mut Self {
-        let mut config = Config::from_str(host).expect("valid db url");
-        config.application_name("image-veracity-api");
-        if let Ok(pwd) = env::var("DATABASE_PASSWORD") {
-            debug!("Setting DB password from environment variable");
-            config.password(pwd);
-        }
-        self.db_config = Some(config);
+        self.db_url = Some(host.to_string());
         self
     }
 
-    fn ssl_config() -> Result<MakeTlsConnector, ErrorStack> {
-        let mut builder = SslConnector::builder(SslMethod::tls())?;
-        if let Ok(root_cert_path) = env::var("DATABASE_ROOT_CERT_PATH") {
-            debug!("Setting CA to path {}", root_cert_path);
-            builder.set_ca_file(root_cert_path)?;
-        }
-        Ok(MakeTlsConnector::new(builder.build()))
+    /// Send GET and search queries to the replica at `host`, when set
+    #[instrument(skip(self))]
+    pub fn create_postgres_read_client(&mut self, host: Option<&str>) -> &mut Self {
+        self.db_read_url = Some(host.map(str::to_string));
+        self
+    }
+
+    /// Serve the tenants in `tenants`, each from its own tree and schema
+    pub fn tenants(&mut self, tenants: BTreeMap<String, TenantConfig>) -> &mut Self {
+        self.tenant_configs = Some(tenants);
+        self
+    }
+
+    /// Keep images in `images` instead of the database, when set
+    pub fn images(&mut self, images: Option<SharedImageRepository>) -> &mut Self {
+        self.images = images;
+        self
     }
 
     #[instrument(skip(self))]
     pub async fn build(&mut self) -> Result<AppState> {
-        let connector = match AppStateBuilder::ssl_config() {
-            Ok(x) => x,
-            Err(err) => return Err(Report::from(err)),
-        };
-
-        let config = match self.db_config.as_ref() {
+        let config = match self.db_url.as_deref() {
             None => return Err(Error::msg("expected database configuration")),
-            Some(x) => x.clone(),
+            Some(url) => connect_options(url)?,
         };
+        let read_config = self
+            .db_read_url
+            .clone()
+            .flatten()
+            .as_deref()
+            .map(connect_options)
+            .transpose()?;
 
-        // set up connection pool
-        let pg_mgr = PostgresConnectionManager::new(config, connector);
-        let pool = match Pool::builder().max_size(15).build(pg_mgr).await {
-            Ok(pool) => pool,
-            Err(e) => {
-                error!("{}", e);
-                panic!("connection pool error: {e:?}")
-            }
-        };
+        // set up connection pool; connections are opened on first use, so the server starts
+        // even while the database is down
+        let pool = pool_options().connect_lazy_with(config.clone());
         debug!("Created DB connection pool");
+        let read_pool = read_config
+            .clone()
+            .map(|config| pool_options().connect_lazy_with(config));
+        if read_pool.is_some() {
+            debug!("Created DB read replica connection pool");
+        }
+        let perceptual_index = self.perceptual_index.unwrap_or_default();
+        let tenants = self
+            .tenant_configs
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, tenant)| {
+                let in_schema = |config: PgConnectOptions| {
+                    pool_options()
+                        .connect_lazy_with(config.options([("search_path", &tenant.schema)]))
+                };
+                let db_pool = in_schema(config.clone());
+                let db_read_pool = read_config.clone().map(in_schema);
+                let mut images = PostgresImageRepository::new(db_pool.clone(), perceptual_index);
+                if let Some(read_pool) = &db_read_pool {
+                    images = images.with_read_pool(read_pool.clone());
+                }
+                debug!("Created DB connection pool for tenant {}", name);
+                let tenant = Tenant {
+                    tree_id: tenant.tree_id,
+                    schema: tenant.schema,
+                    db_pool,
+                    db_read_pool,
+                    images: Arc::new(images),
+                    image_lookups: Coalescer::new("image"),
+                };
+                (name, tenant)
+            })
+            .collect();
+        self.tenants = Some(Arc::new(tenants));
+        if self.images.is_none() {
+            let mut images = PostgresImageRepository::new(pool.clone(), perceptual_index);
+            if let Some(read_pool) = &read_pool {
+                images = images.with_read_pool(read_pool.clone());
+            }
+            self.images = Some(Arc::new(images));
+        }
+        self.db_read_pool = Some(read_pool);
+        let attempts = self.db_connect_attempts.unwrap_or_default();
+        if attempts > 0 {
+            if let Err(err) = wait_for_database(&pool, attempts).await {
+                // Image routes keep working without the database when images are kept elsewhere
+                match &self.images {
+                    Some(images) if images.backend() != "postgres" => error!("{}", err),
+                    _ => return Err(err),
+                }
+            }
+        }
         self.db_pool = Some(pool);
 
         // When we need to make out client
@@ -92,12 +398,11 @@ impl AppStateBuilder {
                 .replace("".to_string())
                 .expect("Trillian host address was supplied");
 
-            let trillian = TrillianClient::new(host)
-                .await
-                .expect("created trillian client")
-                .build();
-
-            debug!("Connected Trillian client");
+            // The client connects on first use and reconnects after failures, so the server
+            // starts even while Trillian is down
+            let tls = self.trillian_tls.clone().unwrap_or_default();
+            let trillian = TrillianClient::new(host, &tls)?.build();
+            debug!("Created Trillian client");
             self.trillian = Some(Box::from(trillian));
         }
 
@@ -108,3 +413,77 @@ impl AppStateBuilder {
         }
     }
 }
+
+/// Connection settings for `host`, with the password and CA certificate from the environment
+fn connect_options(host: &str) -> Result<PgConnectOptions> {
+    let mut config = PgConnectOptions::from_str(host)
+        .map_err(|err| eyre!("invalid database URL: {}", err))?
+        .application_name("image-veracity-api");
+    if let Ok(pwd) = env::var("DATABASE_PASSWORD") {
+        debug!("Setting DB password from environment variable");
+        config = config.password(&pwd);
+    }
+    if let Ok(root_cert_path) = env::var("DATABASE_ROOT_CERT_PATH") {
+        debug!("Setting CA to path {}", root_cert_path);
+        config = config.ssl_root_cert(root_cert_path);
+    }
+    Ok(config)
+}
+
+fn pool_options() -> PgPoolOptions {
+    PgPoolOptions::new().max_connections(15)
+}
+
+/// Try to reach the database up to `attempts` times, backing off between tries so startup
+/// outlasts a failover instead of exiting straight away
+async fn wait_for_database(pool: &ConnectionPool, attempts: u32) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let err = match tokio::time::timeout(CONNECT_TIMEOUT, pool.acquire()).await {
+            Ok(Ok(_)) => {
+                debug!("Connected to the database");
+                return Ok(());
+            }
+            Ok(Err(err)) => Report::from(err),
+            Err(_) => eyre!("timed out after {:?}", CONNECT_TIMEOUT),
+        };
+        if attempt >= attempts {
+            return Err(eyre!(
+                "could not connect to the database after {attempts} attempts: {err}"
+            ));
+        }
+        let delay = connect_delay(attempt);
+        warn!(
+            attempt,
+            attempts, "Could not connect to the database, retrying in {:?}: {}", delay, err
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Delay after the `attempt`th failed try to reach the database
+fn connect_delay(attempt: u32) -> Duration {
+    BASE_CONNECT_DELAY
+        .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+        .min(MAX_CONNECT_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_delay_doubles_up_to_a_cap() {
+        assert_eq!(connect_delay(1), Duration::from_secs(1));
+        assert_eq!(connect_delay(2), Duration::from_secs(2));
+        assert_eq!(connect_delay(3), Duration::from_secs(4));
+        assert_eq!(connect_delay(40), MAX_CONNECT_DELAY);
+    }
+
+    #[test]
+    fn invalid_database_urls_are_errors() {
+        assert!(connect_options("postgresql://root@localhost:26257/veracity").is_ok());
+        assert!(connect_options("not a database url").is_err());
+    }
+}
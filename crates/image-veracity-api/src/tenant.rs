@@ -0,0 +1,91 @@
+//! Customers sharing one deployment, each with its own Trillian tree and database schema.
+//!
+//! API keys may belong to a tenant. Uploads and lookups made with such a key go to the tenant's
+//! tree and to the tables in its schema, so tenants never see each other's images or leaves.
+//! Keys without a tenant use the default tree and schema, as does everything when no tenants
+//! are configured.
+
+use std::collections::BTreeMap;
+
+use eyre::{bail, eyre, Result};
+
+/// Where the images of a tenant are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantConfig {
+    /// Trillian tree the tenant's images are logged to
+    pub tree_id: i64,
+    /// Database schema holding the tenant's tables, named after the tenant unless configured
+    pub schema: String,
+}
+
+/// Read tenants from a comma separated list of `name=tree_id` or `name=tree_id:schema`
+pub fn parse_tenants(spec: &str) -> Result<BTreeMap<String, TenantConfig>> {
+    let mut tenants = BTreeMap::new();
+    for entry in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (name, target) = entry
+            .split_once('=')
+            .ok_or_else(|| eyre!("tenant {entry} is not name=tree_id"))?;
+        let (tree_id, schema) = match target.split_once(':') {
+            Some((tree_id, schema)) => (tree_id, schema),
+            None => (target, name),
+        };
+        let tree_id = tree_id
+            .parse()
+            .map_err(|err| eyre!("invalid tree ID for tenant {name}: {err}"))?;
+        check_identifier("tenant name", name)?;
+        // Schemas are named in statements, so only plain identifiers are accepted
+        check_identifier("schema", schema)?;
+        let config = TenantConfig {
+            tree_id,
+            schema: schema.to_string(),
+        };
+        if tenants.insert(name.to_string(), config).is_some() {
+            bail!("tenant {name} is configured twice");
+        }
+    }
+    Ok(tenants)
+}
+
+fn check_identifier(what: &str, name: &str) -> Result<()> {
+    let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        bail!("{what} {name:?} must be lowercase letters, digits and underscores");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tenants() {
+        let tenants = parse_tenants("acme=1, globex=2:globex_images,").unwrap();
+        assert_eq!(
+            tenants["acme"],
+            TenantConfig {
+                tree_id: 1,
+                schema: "acme".to_string()
+            }
+        );
+        assert_eq!(tenants["globex"].tree_id, 2);
+        assert_eq!(tenants["globex"].schema, "globex_images");
+        assert!(parse_tenants("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_tenants() {
+        assert!(parse_tenants("acme").is_err());
+        assert!(parse_tenants("acme=tree").is_err());
+        assert!(parse_tenants("acme=1,acme=2").is_err());
+        assert!(parse_tenants("acme=1:public; DROP TABLE images").is_err());
+        assert!(parse_tenants("Acme=1").is_err());
+    }
+}
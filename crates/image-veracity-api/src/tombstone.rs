@@ -0,0 +1,110 @@
+//! Tombstones logged when an image is taken down.
+//!
+//! The log is append-only, so a takedown cannot remove the leaf of an image. Instead a note
+//! recording the takedown, signed with the checkpoint key, is logged as a leaf of its own, with
+//! the hashes of the withheld image as extra data like the leaf of the image itself.
+//! Auditors can then tell which images were withheld, when and why, while every leaf logged
+//! before stays verifiable.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use ring::digest::{digest, SHA256};
+
+use crate::checkpoint::NoteSigner;
+use crate::hash::VeracityHash;
+
+/// First line of every tombstone note
+const TOMBSTONE_HEADER: &str = "image-veracity tombstone v1";
+
+/// Text of the tombstone withholding `image`, the part covered by the signature
+pub fn body(image: &VeracityHash, withheld_at: DateTime<Utc>, reason: &str) -> String {
+    format!(
+        "{TOMBSTONE_HEADER}\n{}\n{}\n{}\n{}\n",
+        image.crypto_hash.to_hex(),
+        image.perceptual_hash.to_hex(),
+        withheld_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        one_line(reason)
+    )
+}
+
+/// Signed tombstone withholding `image`, to log as the leaf value
+pub fn sign(
+    signer: &NoteSigner,
+    image: &VeracityHash,
+    withheld_at: DateTime<Utc>,
+    reason: &str,
+) -> String {
+    signer.sign(&body(image, withheld_at, reason))
+}
+
+/// Extra data logged with the tombstone of `image`, its crypto then perceptual hash
+pub fn extra_data(image: &VeracityHash) -> Vec<u8> {
+    let mut extra_data = image.crypto_hash.as_ref().to_vec();
+    extra_data.extend_from_slice(image.perceptual_hash.as_ref());
+    extra_data
+}
+
+/// Identity Trillian deduplicates the tombstone of an image by, so an image is only ever
+/// tombstoned once. It differs from the identity of the image leaf, its crypto hash.
+pub fn identity_hash(crypto_hash: &[u8]) -> Vec<u8> {
+    let mut identity = format!("{TOMBSTONE_HEADER}\n").into_bytes();
+    identity.extend_from_slice(crypto_hash);
+    digest(&SHA256, &identity).as_ref().to_vec()
+}
+
+/// Whether a log leaf holds a tombstone rather than an image
+pub fn is_tombstone(leaf_value: &[u8]) -> bool {
+    leaf_value
+        .strip_prefix(TOMBSTONE_HEADER.as_bytes())
+        .is_some_and(|rest| rest.starts_with(b"\n"))
+}
+
+/// `reason` with runs of whitespace and control characters collapsed to single spaces, since
+/// notes are read line by line
+fn one_line(reason: &str) -> String {
+    reason
+        .split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::hash::cryptographic::CryptographicHash;
+    use crate::hash::perceptual::PerceptualHash;
+
+    fn image() -> VeracityHash {
+        VeracityHash {
+            crypto_hash: CryptographicHash::try_from(vec![1; 32]).unwrap(),
+            perceptual_hash: PerceptualHash::try_from(vec![2; 32]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn reasons_are_kept_to_one_line() {
+        let withheld_at = Utc.with_ymd_and_hms(2023, 9, 1, 12, 0, 0).unwrap();
+        let body = body(&image(), withheld_at, "court order\n\n#42\u{0}\t(EU)");
+        assert_eq!(
+            body,
+            format!(
+                "image-veracity tombstone v1\n{}\n{}\n2023-09-01T12:00:00Z\ncourt order #42 (EU)\n",
+                "01".repeat(32),
+                "02".repeat(32)
+            )
+        );
+    }
+
+    #[test]
+    fn tombstones_are_told_apart_from_images() {
+        let withheld_at = Utc.with_ymd_and_hms(2023, 9, 1, 12, 0, 0).unwrap();
+        assert!(is_tombstone(
+            body(&image(), withheld_at, "legal").as_bytes()
+        ));
+        assert!(!is_tombstone(&[1; 32]));
+        assert!(!is_tombstone(b"image-veracity tombstone v10\n"));
+        assert_ne!(identity_hash(&[1; 32]), vec![1; 32]);
+    }
+}
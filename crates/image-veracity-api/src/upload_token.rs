@@ -0,0 +1,167 @@
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use thiserror::Error;
+
+/// Issues and verifies pre-signed upload tokens.
+///
+/// A token lets a device without credentials of its own submit a single image before it expires.
+/// It is an HMAC-signed set of [`UploadClaims`], so verifying one needs no database lookup; only
+/// spending it does.
+#[derive(Clone)]
+pub struct UploadTokens {
+    key: hmac::Key,
+}
+
+/// What a pre-signed upload token allows
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadClaims {
+    /// Random token ID, recorded when the token is spent so it cannot be used twice
+    pub id: String,
+    /// Unix timestamp after which the token is rejected
+    pub expires_at: i64,
+    /// Largest image the token accepts, in bytes
+    pub max_bytes: usize,
+    /// Tenant of the key that minted the token, whose tree and schema the upload goes to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum UploadTokenError {
+    #[error("upload token is malformed")]
+    Malformed,
+    #[error("upload token signature is invalid")]
+    InvalidSignature,
+    #[error("upload token has expired")]
+    Expired,
+}
+
+impl UploadTokens {
+    /// Tokens signed with `secret`
+    pub fn new(secret: &[u8]) -> Self {
+        UploadTokens {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    /// Create and sign claims for a token valid for `ttl`
+    pub fn mint(
+        &self,
+        ttl: Duration,
+        max_bytes: usize,
+        tenant: Option<String>,
+    ) -> (String, UploadClaims) {
+        let mut id = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut id)
+            .expect("system random source available");
+        let claims = UploadClaims {
+            id: hex::encode(id),
+            expires_at: (Utc::now() + ttl).timestamp(),
+            max_bytes,
+            tenant,
+        };
+        (self.sign(&claims), claims)
+    }
+
+    /// Encode claims as `<claims>.<signature>`, both base64url
+    pub fn sign(&self, claims: &UploadClaims) -> String {
+        let claims = BASE64_URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(claims).expect("claims serialize to JSON"));
+        let signature = hmac::sign(&self.key, claims.as_bytes());
+        format!("{claims}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Check a token's signature and expiry at `now`
+    pub fn verify(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<UploadClaims, UploadTokenError> {
+        let (claims, signature) = token.split_once('.').ok_or(UploadTokenError::Malformed)?;
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| UploadTokenError::Malformed)?;
+        hmac::verify(&self.key, claims.as_bytes(), &signature)
+            .map_err(|_| UploadTokenError::InvalidSignature)?;
+
+        let claims: UploadClaims = BASE64_URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or(UploadTokenError::Malformed)?;
+        if claims.expires_at <= now.timestamp() {
+            return Err(UploadTokenError::Expired);
+        }
+        Ok(claims)
+    }
+}
+
+impl UploadClaims {
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.expires_at, 0)
+            .single()
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+}
+
+/// Mark the token as spent. Fails with a unique violation if it already was.
+/// Call inside the transaction that stores the image so a failed upload does not spend it.
+pub async fn spend(tx: &mut PgConnection, claims: &UploadClaims) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO spent_upload_tokens (token_id, expires_at) VALUES ($1, $2)",
+        &claims.id,
+        claims.expires_at(),
+    )
+    .execute(tx)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minted_tokens_verify() {
+        let tokens = UploadTokens::new(b"secret");
+        let (token, claims) = tokens.mint(Duration::minutes(5), 1024, None);
+
+        assert_eq!(tokens.verify(&token, Utc::now()), Ok(claims.clone()));
+        assert_eq!(
+            tokens.verify(&token, claims.expires_at()),
+            Err(UploadTokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let tokens = UploadTokens::new(b"secret");
+        let (token, mut claims) = tokens.mint(Duration::minutes(5), 1024, None);
+
+        let other = UploadTokens::new(b"other secret");
+        assert_eq!(
+            other.verify(&token, Utc::now()),
+            Err(UploadTokenError::InvalidSignature)
+        );
+
+        // Raise the size limit but keep the original signature
+        claims.max_bytes = usize::MAX;
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = tokens.sign(&claims);
+        let (forged_claims, _) = forged.split_once('.').unwrap();
+        assert_eq!(
+            tokens.verify(&format!("{forged_claims}.{signature}"), Utc::now()),
+            Err(UploadTokenError::InvalidSignature)
+        );
+
+        assert_eq!(
+            tokens.verify("no signature", Utc::now()),
+            Err(UploadTokenError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,365 @@
+//! Webhook notifications for API key holders.
+//!
+//! Keys register URLs that are sent a signed `image.integrated` event once an image they
+//! submitted is integrated into the tree, and a `log.inconsistent` alert if the log monitor
+//! catches the log contradicting itself. Events are written to a delivery table in the
+//! transaction that records the integration and posted by [`run`], retrying failures with the
+//! outbox's backoff. Webhook hosts must resolve to public addresses, checked on every attempt.
+
+use std::time::Duration;
+
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use metrics::increment_counter;
+use reqwest::Url;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use schemars::JsonSchema;
+use serde::Serialize;
+use sqlx::PgConnection;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, instrument, warn};
+use uuid::Uuid;
+
+use crate::fetch::{self, FetchError};
+use crate::hash::VeracityHash;
+use crate::outbox::retry_delay;
+use crate::record::LeafDetails;
+use crate::state::{AppState, ConnectionPool};
+
+/// Header holding the Unix time a delivery was signed at
+pub const TIMESTAMP_HEADER: &str = "X-Veracity-Timestamp";
+/// Header holding `sha256=` and the hex HMAC of `{timestamp}.{body}` under the webhook secret
+pub const SIGNATURE_HEADER: &str = "X-Veracity-Signature";
+/// Header holding the delivery ID, the same across retries of one event
+pub const DELIVERY_HEADER: &str = "X-Veracity-Delivery";
+
+/// Most webhooks a single API key may register
+pub const MAX_WEBHOOKS_PER_KEY: i64 = 10;
+/// Prefix of generated secrets, so leaked secrets are easy to recognise
+const SECRET_PREFIX: &str = "ivwh_";
+
+/// How often the worker looks for deliveries that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Most deliveries claimed per poll
+const BATCH_SIZE: i64 = 32;
+/// How long a claimed delivery is hidden from other workers
+const CLAIM_LEASE_SECONDS: i64 = 120;
+/// Attempts after which a delivery is dropped, about three hours after the first
+const MAX_ATTEMPTS: i64 = 24;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A registered webhook, without its secret
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct WebhookRecord {
+    pub id: Uuid,
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body posted to webhooks when an image is integrated into the tree
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct IntegrationEvent {
+    /// Always `image.integrated`
+    pub event: String,
+    #[serde(flatten)]
+    pub hash: VeracityHash,
+    pub leaf_index: i64,
+    /// RFC 6962 leaf hash as hex
+    pub merkle_leaf_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrate_timestamp: Option<DateTime<Utc>>,
+    /// Size of the tree the leaf was found in
+    pub tree_size: i64,
+    /// Root hash of that tree as hex, which the leaf's inclusion proof leads to
+    pub root_hash: String,
+}
+
+impl IntegrationEvent {
+    pub fn new(hash: VeracityHash, leaf: &LeafDetails, tree_size: i64, root_hash: &[u8]) -> Self {
+        IntegrationEvent {
+            event: "image.integrated".to_string(),
+            hash,
+            leaf_index: leaf.leaf_index.unwrap_or_default(),
+            merkle_leaf_hash: leaf.merkle_leaf_hash.clone().unwrap_or_default(),
+            integrate_timestamp: leaf.integrate_timestamp,
+            tree_size,
+            root_hash: hex::encode(root_hash),
+        }
+    }
+}
+
+/// Body posted to every webhook when the log monitor finds the log inconsistent with a root it
+/// saw before, which means the log has been rewritten or is showing different views
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct InconsistencyEvent {
+    /// Always `log.inconsistent`
+    pub event: String,
+    pub tree_id: i64,
+    /// Size of the root seen before
+    pub previous_tree_size: i64,
+    /// Root hash seen before as hex
+    pub previous_root_hash: String,
+    /// Size of the root that does not extend it
+    pub tree_size: i64,
+    /// Root hash that does not extend it as hex
+    pub root_hash: String,
+    /// What the check found
+    pub reason: String,
+}
+
+/// Check `url` can receive deliveries and register it for `owner`, returning the record and
+/// the signing secret, which is shown only once. `None` if `owner` is at the webhook limit.
+pub async fn create(
+    pool: &ConnectionPool,
+    owner: &str,
+    url: &str,
+) -> Result<Option<(WebhookRecord, String)>> {
+    let url = Url::parse(url).map_err(|err| FetchError::InvalidUrl(err.to_string()))?;
+    fetch::resolve_public(&url).await?;
+
+    let secret = generate_secret();
+    let record = sqlx::query_as!(
+        WebhookRecord,
+        "INSERT INTO webhooks (id, owner, url, secret) \
+        SELECT $1::UUID, $2::TEXT, $3::TEXT, $4::TEXT \
+        WHERE (SELECT count(*) FROM webhooks WHERE owner = $2) < $5 \
+        RETURNING id, url, created_at",
+        Uuid::new_v4(),
+        owner,
+        url.as_str(),
+        &secret,
+        MAX_WEBHOOKS_PER_KEY,
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(record.map(|record| (record, secret)))
+}
+
+/// Webhooks registered by `owner`, newest first
+pub async fn list(pool: &ConnectionPool, owner: &str) -> Result<Vec<WebhookRecord>> {
+    Ok(sqlx::query_as!(
+        WebhookRecord,
+        "SELECT id, url, created_at FROM webhooks WHERE owner = $1 ORDER BY created_at DESC",
+        owner,
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Remove a webhook and its pending deliveries, `false` if `owner` has no webhook with this ID
+pub async fn delete(pool: &ConnectionPool, owner: &str, id: Uuid) -> Result<bool> {
+    let deleted = sqlx::query!(
+        "DELETE FROM webhooks WHERE id = $1 AND owner = $2",
+        id,
+        owner,
+    )
+    .execute(pool)
+    .await?;
+    Ok(deleted.rows_affected() > 0)
+}
+
+/// Queue `event` for every webhook of `owner`. Call inside the transaction that records the
+/// integration so the event is sent exactly when it is stored.
+pub async fn enqueue(
+    tx: &mut PgConnection,
+    owner: &str,
+    event: &IntegrationEvent,
+) -> Result<u64, sqlx::Error> {
+    let payload = serde_json::to_value(event).expect("events serialize to JSON");
+    sqlx::query!(
+        "INSERT INTO webhook_deliveries (webhook_id, payload) \
+        SELECT id, $2::JSONB FROM webhooks WHERE owner = $1",
+        owner,
+        payload,
+    )
+    .execute(tx)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// Queue `event` for every registered webhook, whoever owns it
+pub async fn broadcast(
+    tx: &mut PgConnection,
+    event: &InconsistencyEvent,
+) -> Result<u64, sqlx::Error> {
+    let payload = serde_json::to_value(event).expect("events serialize to JSON");
+    sqlx::query!(
+        "INSERT INTO webhook_deliveries (webhook_id, payload) SELECT id, $1::JSONB FROM webhooks",
+        payload,
+    )
+    .execute(tx)
+    .await
+    .map(|result| result.rows_affected())
+}
+
+/// Post due deliveries until the process exits
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match deliver_due(&state.db_pool).await {
+            Ok(0) => {}
+            Ok(delivered) => debug!("Delivered {} webhook events", delivered),
+            Err(err) => error!("Webhook worker: {}", err),
+        }
+    }
+}
+
+/// Claim due deliveries and post them, dropping those that succeed or run out of attempts
+#[instrument(skip_all)]
+async fn deliver_due(pool: &ConnectionPool) -> Result<usize> {
+    let rows = sqlx::query!(
+        "WITH claimed AS (\
+            UPDATE webhook_deliveries \
+            SET next_attempt_at = now() + $2::INT8 * INTERVAL '1 second' \
+            WHERE id IN (\
+                SELECT id FROM webhook_deliveries WHERE next_attempt_at <= now() \
+                ORDER BY next_attempt_at LIMIT $1\
+            ) \
+            RETURNING id, webhook_id, payload, attempts\
+        ) \
+        SELECT claimed.id, claimed.payload, claimed.attempts, webhooks.url, webhooks.secret \
+        FROM claimed JOIN webhooks ON webhooks.id = claimed.webhook_id",
+        BATCH_SIZE,
+        CLAIM_LEASE_SECONDS,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut delivered = 0;
+    for row in rows {
+        let id = row.id;
+        let attempts = row.attempts;
+        let url = row.url;
+        let secret = row.secret;
+
+        let body = serde_json::to_vec(&row.payload)?;
+        match post(&url, &secret, id, body).await {
+            Ok(()) => {
+                delete_delivery(pool, id).await?;
+                increment_counter!("veracity_webhooks_delivered_total");
+                delivered += 1;
+            }
+            Err(err) if attempts + 1 >= MAX_ATTEMPTS => {
+                warn!(
+                    "Giving up on webhook delivery {} to {} after {} attempts: {}",
+                    id,
+                    url,
+                    attempts + 1,
+                    err
+                );
+                increment_counter!("veracity_webhooks_dropped_total");
+                delete_delivery(pool, id).await?;
+            }
+            Err(err) => {
+                let delay = retry_delay(attempts);
+                debug!(
+                    "Webhook delivery {} to {} failed (attempt {}), retrying in {}s: {}",
+                    id,
+                    url,
+                    attempts + 1,
+                    delay,
+                    err
+                );
+                increment_counter!("veracity_webhooks_failures_total");
+                sqlx::query!(
+                    "UPDATE webhook_deliveries \
+                    SET attempts = attempts + 1, \
+                    next_attempt_at = now() + $2::INT8 * INTERVAL '1 second', \
+                    last_error = $3 \
+                    WHERE id = $1",
+                    id,
+                    delay,
+                    err.to_string(),
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+    Ok(delivered)
+}
+
+async fn delete_delivery(pool: &ConnectionPool, id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM webhook_deliveries WHERE id = $1", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Post a signed body to `url`, which must answer with a 2xx status
+async fn post(url: &str, secret: &str, delivery: Uuid, body: Vec<u8>) -> Result<(), FetchError> {
+    let url = Url::parse(url).map_err(|err| FetchError::InvalidUrl(err.to_string()))?;
+    let addr = fetch::resolve_public(&url).await?;
+    let timestamp = Utc::now().timestamp();
+    let res = fetch::pinned_client(&url, addr)?
+        .post(url)
+        .timeout(DELIVERY_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, sign(secret, timestamp, &body))
+        .header(DELIVERY_HEADER, delivery.to_string())
+        .body(body)
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(FetchError::Status(res.status().as_u16()));
+    }
+    Ok(())
+}
+
+/// Signature header value for `body` sent at `timestamp`. Receivers recompute it with their
+/// secret, compare in constant time, and reject stale timestamps to stop replays.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut context = hmac::Context::with_key(&key);
+    context.update(timestamp.to_string().as_bytes());
+    context.update(b".");
+    context.update(body);
+    format!("sha256={}", hex::encode(context.sign().as_ref()))
+}
+
+fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .expect("system random source available");
+    format!("{SECRET_PREFIX}{}", BASE64_URL_SAFE_NO_PAD.encode(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_cover_the_timestamp_and_body() {
+        let signature = sign("secret", 1_700_000_000, b"{}");
+        // HMAC-SHA256 of "1700000000.{}" under "secret"
+        assert_eq!(
+            signature,
+            "sha256=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+        assert_ne!(signature, sign("secret", 1_700_000_001, b"{}"));
+        assert_ne!(signature, sign("other", 1_700_000_000, b"{}"));
+    }
+
+    #[test]
+    fn events_carry_the_leaf_and_root() {
+        let leaf = LeafDetails {
+            leaf_index: Some(41),
+            merkle_leaf_hash: Some("ab".repeat(32)),
+            ..LeafDetails::default()
+        };
+        let event = IntegrationEvent::new(VeracityHash::default(), &leaf, 42, &[0xcd; 32]);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "image.integrated");
+        assert_eq!(json["leaf_index"], 41);
+        assert_eq!(json["tree_size"], 42);
+        assert_eq!(json["root_hash"], "cd".repeat(32));
+        assert!(json["crypto_hash"].is_string());
+        assert!(json.get("integrate_timestamp").is_none());
+    }
+}
@@ -0,0 +1,273 @@
+//! Cosignatures from transparency log witnesses.
+//!
+//! A witness checks that the log only ever grows and countersigns its checkpoints, so a client
+//! that requires cosignatures from several witnesses knows everyone is shown the same log.
+//! [`run`] submits the latest checkpoint to every configured witness with the C2SP
+//! `tlog-witness` protocol, proving it consistent with the last size that witness cosigned, and
+//! keeps the cosignatures that verify so they can be served alongside the checkpoint.
+
+use std::time::Duration;
+
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use eyre::{bail, eyre, Result};
+use metrics::increment_counter;
+use reqwest::{StatusCode, Url};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, instrument, warn};
+
+use crate::checkpoint::{Checkpoint, NoteSigner, NoteVerifier};
+use crate::state::AppState;
+
+/// How often the latest checkpoint is offered to witnesses
+const SUBMIT_INTERVAL: Duration = Duration::from_secs(60);
+const SUBMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A witness the log submits checkpoints to
+#[derive(Debug, Clone)]
+pub struct Witness {
+    pub verifier: NoteVerifier,
+    /// Submission prefix, `add-checkpoint` is requested under it
+    pub url: Url,
+}
+
+impl Witness {
+    pub fn name(&self) -> &str {
+        self.verifier.name()
+    }
+
+    /// Parse a comma-separated list of `<verifier key> <submission URL>` pairs
+    pub fn parse_list(list: &str) -> Result<Vec<Witness>> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (key, url) = entry
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| eyre!("witness {entry:?} is not `<verifier key> <URL>`"))?;
+                let mut url =
+                    Url::parse(url.trim()).map_err(|err| eyre!("invalid witness URL: {err}"))?;
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Ok(Witness {
+                    verifier: NoteVerifier::from_verifier_key(key)?,
+                    url,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Submit the latest checkpoint to every witness until the process exits. Does nothing unless
+/// checkpoints are signed and witnesses are configured.
+pub async fn run(state: AppState) {
+    let signer = match &state.checkpoint_signer {
+        Some(signer) if !state.witnesses.is_empty() => signer.clone(),
+        _ => return,
+    };
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(SUBMIT_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(err) = submit_latest(&state, &signer, &client).await {
+            error!("Witness submission: {}", err);
+        }
+    }
+}
+
+/// Offer the latest checkpoint to each witness that has not cosigned it yet
+#[instrument(skip_all)]
+async fn submit_latest(
+    state: &AppState,
+    signer: &NoteSigner,
+    client: &reqwest::Client,
+) -> Result<()> {
+    let root = state
+        .trillian
+        .clone()
+        .get_latest_root(&state.trillian_tree)
+        .await?;
+    let checkpoint = Checkpoint {
+        origin: signer.name().to_string(),
+        tree_size: root.tree_size,
+        root_hash: root.root_hash,
+    };
+    for witness in &state.witnesses {
+        match cosign(state, signer, client, witness, &checkpoint).await {
+            Ok(true) => {
+                debug!(
+                    "Witness {} cosigned tree size {}",
+                    witness.name(),
+                    checkpoint.tree_size
+                );
+                increment_counter!(
+                    "veracity_witness_cosignatures_total",
+                    "witness" => witness.name().to_string()
+                );
+            }
+            Ok(false) => {}
+            Err(err) => {
+                warn!(
+                    "Witness {} did not cosign tree size {}: {}",
+                    witness.name(),
+                    checkpoint.tree_size,
+                    err
+                );
+                increment_counter!(
+                    "veracity_witness_failures_total",
+                    "witness" => witness.name().to_string()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get and store `witness`'s cosignature of `checkpoint`, false if it was already stored
+async fn cosign(
+    state: &AppState,
+    signer: &NoteSigner,
+    client: &reqwest::Client,
+    witness: &Witness,
+    checkpoint: &Checkpoint,
+) -> Result<bool> {
+    let existing = sqlx::query!(
+        "SELECT 1 AS found FROM checkpoint_cosignatures \
+        WHERE tree_id = $1 AND tree_size = $2 AND witness = $3",
+        state.trillian_tree,
+        checkpoint.tree_size,
+        witness.name(),
+    )
+    .fetch_optional(&state.db_pool)
+    .await?;
+    if existing.is_some() {
+        return Ok(false);
+    }
+    let mut old_size = sqlx::query_scalar!(
+        "SELECT tree_size FROM witness_sizes WHERE tree_id = $1 AND witness = $2",
+        state.trillian_tree,
+        witness.name(),
+    )
+    .fetch_optional(&state.db_pool)
+    .await?
+    .unwrap_or(0);
+
+    let body = checkpoint.body();
+    let note = signer.sign(&body);
+    let url = witness.url.join("add-checkpoint")?;
+    // A witness that lost track of the log says which size it has, so retry once from there
+    let mut retried = false;
+    let cosignatures = loop {
+        if old_size > checkpoint.tree_size {
+            bail!(
+                "witness has seen size {old_size}, larger than {}",
+                checkpoint.tree_size
+            );
+        }
+        let proof = if old_size == 0 || old_size == checkpoint.tree_size {
+            vec![]
+        } else {
+            state
+                .trillian
+                .clone()
+                .get_consistency_proof(&state.trillian_tree, old_size, checkpoint.tree_size)
+                .await?
+        };
+        let res = client
+            .post(url.clone())
+            .timeout(SUBMIT_TIMEOUT)
+            .body(add_checkpoint_request(old_size, &proof, &note))
+            .send()
+            .await?;
+        let status = res.status();
+        let text = res.text().await?;
+        match status {
+            StatusCode::OK => break text,
+            StatusCode::CONFLICT if !retried => {
+                old_size = text
+                    .trim()
+                    .parse()
+                    .map_err(|_| eyre!("witness sent a conflict without its size"))?;
+                retried = true;
+            }
+            status => bail!("witness answered {status}: {}", text.trim()),
+        }
+    };
+
+    let line = cosignatures
+        .lines()
+        .find(|line| witness.verifier.verify(&body, line))
+        .ok_or_else(|| eyre!("witness sent no valid cosignature"))?;
+    sqlx::query!(
+        "INSERT INTO checkpoint_cosignatures (tree_id, tree_size, root_hash, witness, signature) \
+        VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+        state.trillian_tree,
+        checkpoint.tree_size,
+        &checkpoint.root_hash,
+        witness.name(),
+        line,
+    )
+    .execute(&state.db_pool)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO witness_sizes (tree_id, witness, tree_size) VALUES ($1, $2, $3) \
+        ON CONFLICT (tree_id, witness) DO UPDATE SET tree_size = excluded.tree_size",
+        state.trillian_tree,
+        witness.name(),
+        checkpoint.tree_size,
+    )
+    .execute(&state.db_pool)
+    .await?;
+    Ok(true)
+}
+
+/// Body of an `add-checkpoint` request: the size the witness last saw, a consistency proof
+/// from it, a blank line, and the signed checkpoint
+fn add_checkpoint_request(old_size: i64, proof: &[Vec<u8>], note: &str) -> String {
+    let mut body = format!("old {old_size}\n");
+    for hash in proof {
+        body.push_str(&BASE64_STANDARD.encode(hash));
+        body.push('\n');
+    }
+    body.push('\n');
+    body.push_str(note);
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERIFIER_KEY: &str = "PeterNeumann+c74f20a3+ARpc2QcUPDhMQegwxbzhKqiBfsVkmqq/LDE4izWy10TW";
+
+    #[test]
+    fn witness_lists_pair_keys_with_urls() {
+        let witnesses = Witness::parse_list(&format!(
+            "{VERIFIER_KEY} https://witness.example/submit, \
+            {VERIFIER_KEY} https://other.example/"
+        ))
+        .unwrap();
+        assert_eq!(witnesses.len(), 2);
+        assert_eq!(witnesses[0].name(), "PeterNeumann");
+        assert_eq!(
+            witnesses[0].url.join("add-checkpoint").unwrap().as_str(),
+            "https://witness.example/submit/add-checkpoint"
+        );
+        assert!(Witness::parse_list("").unwrap().is_empty());
+        assert!(Witness::parse_list("https://witness.example/").is_err());
+    }
+
+    #[test]
+    fn requests_carry_the_old_size_and_proof() {
+        let body = add_checkpoint_request(3, &[vec![0; 32], vec![1; 32]], "note\n\n\u{2014} sig\n");
+        assert_eq!(
+            body,
+            format!(
+                "old 3\n{}\n{}\n\nnote\n\n\u{2014} sig\n",
+                BASE64_STANDARD.encode([0; 32]),
+                BASE64_STANDARD.encode([1; 32])
+            )
+        );
+    }
+}
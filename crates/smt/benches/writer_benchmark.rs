@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, SamplingMode};
+use sha2::{Digest, Sha256};
+
+use smt::hasher::rfc6962::Rfc6962Hasher;
+use smt::hasher::{NodeHasher, BIT_LENGTH};
+use smt::hstar3::{HStar3, NodeAccessor};
+use smt::node::id::ID;
+use smt::node::{Node, NodesRow};
+use smt::writer::Writer;
+
+const BATCH_SIZE: u32 = 100_000;
+const SPLIT: usize = 8;
+
+/// A tree that is empty apart from the batch, so that only hashing is measured
+struct EmptyTree<'a>(&'a Rfc6962Hasher);
+
+impl NodeAccessor for EmptyTree<'_> {
+    fn get(&mut self, id: &ID) -> Result<[u8; 32], String> {
+        Ok(self.0.hash_empty(0, id))
+    }
+
+    fn set(&mut self, _: &ID, _: &[u8; 32]) {}
+}
+
+fn batch(hasher: &Rfc6962Hasher) -> NodesRow {
+    let nodes = (0..BATCH_SIZE)
+        .map(|i| {
+            let id = ID::new_id(&Sha256::digest(i.to_be_bytes()), BIT_LENGTH);
+            let hash = hasher.hash_leaf(0, &id, &i.to_be_bytes());
+            Arc::new(Node::new(id, hash))
+        })
+        .collect();
+    NodesRow::try_new(nodes).expect("leaves at one depth")
+}
+
+fn batch_update_benchmark(c: &mut Criterion) {
+    let hasher = Rfc6962Hasher::new();
+    let hash = |left: &[u8; 32], right: &[u8; 32]| hasher.hash_children(left, right);
+    let leaves = batch(&hasher);
+
+    let mut group = c.benchmark_group("batch_update_100k");
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(10);
+
+    group.bench_function("hstar3", |b| {
+        b.iter_batched(
+            || NodesRow(leaves.0.clone()),
+            |nodes| {
+                HStar3::new(nodes, hash, BIT_LENGTH, 0)
+                    .expect("valid depths")
+                    .update(&mut EmptyTree(&hasher))
+                    .expect("empty tree reads succeed")
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    let writer = Writer::new(hash, BIT_LENGTH, SPLIT).expect("valid depths");
+    let max_threads = std::thread::available_parallelism().map_or(1, usize::from);
+    for threads in [1, 2, 4, 8, 16].into_iter().filter(|&t| t <= max_threads) {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("thread pool");
+        group.bench_with_input(BenchmarkId::new("writer", threads), &threads, |b, _| {
+            b.iter_batched(
+                || NodesRow(leaves.0.clone()),
+                |nodes| {
+                    pool.install(|| {
+                        writer
+                            .write(nodes, |_: &ID| EmptyTree(&hasher))
+                            .expect("empty tree reads succeed")
+                    })
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, batch_update_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,373 @@
+//! Canonical byte encodings of IDs, nodes, and tiles, for storing and shipping them.
+//!
+//! Every value has exactly one encoding, and decoding rejects anything else, so
+//! encodings can be compared, hashed, and used as cache keys. Integers are big
+//! endian.
+//!
+//! - ID: the bit length as a u16, then the ID's bytes, the last one with its
+//!   unused bits unset.
+//! - Node: the ID, then the 32-byte hash.
+//! - Tile: the root ID, the depth of the leaves as a u16 (0 if there are none),
+//!   the number of leaves as a u32, then each leaf's ID bytes and hash, in
+//!   ascending ID order.
+//!
+//! The serde implementations use these encodings, as hex strings in human-readable
+//! formats like JSON and as bytes otherwise.
+
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::node::id::ID;
+use crate::node::{Node, NodesRow};
+use crate::tile::Tile;
+
+impl ID {
+    /// to_bytes returns the canonical encoding of the ID.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + id_len(self.bit_length()));
+        bytes.extend_from_slice(&(self.bit_length() as u16).to_be_bytes());
+        write_id_bytes(&mut bytes, self);
+        bytes
+    }
+
+    /// from_bytes decodes the canonical encoding of an ID.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ID, String> {
+        let mut reader = bytes;
+        let id = read_id(&mut reader)?;
+        finish(reader, id)
+    }
+}
+
+impl Node {
+    /// to_bytes returns the canonical encoding of the node.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.to_bytes();
+        bytes.extend_from_slice(self.hash());
+        bytes
+    }
+
+    /// from_bytes decodes the canonical encoding of a node.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Node, String> {
+        let mut reader = bytes;
+        let id = read_id(&mut reader)?;
+        let hash = read_hash(&mut reader)?;
+        finish(reader, Node::new(id, hash))
+    }
+}
+
+impl Tile {
+    /// to_bytes returns the canonical encoding of the tile.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let leaves = &self.leaves.0;
+        let leaf_depth = leaves.first().map_or(0, |n| n.id.bit_length());
+        let mut bytes = self.id.to_bytes();
+        bytes.extend_from_slice(&(leaf_depth as u16).to_be_bytes());
+        bytes.extend_from_slice(&(leaves.len() as u32).to_be_bytes());
+        for leaf in leaves {
+            write_id_bytes(&mut bytes, &leaf.id);
+            bytes.extend_from_slice(leaf.hash());
+        }
+        bytes
+    }
+
+    /// from_bytes decodes the canonical encoding of a tile. The leaves must be
+    /// strictly ascending, all at the same depth below the tile's root, and
+    /// under it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Tile, String> {
+        let mut reader = bytes;
+        let id = read_id(&mut reader)?;
+        let leaf_depth = usize::from(u16::from_be_bytes(read_array(&mut reader)?));
+        let count = u32::from_be_bytes(read_array(&mut reader)?) as usize;
+        if (count == 0) != (leaf_depth == 0) {
+            return Err(format!("{count} leaves at depth {leaf_depth}"));
+        }
+        if count != 0 && leaf_depth <= id.bit_length() {
+            return Err(format!(
+                "leaf depth {leaf_depth} is not below the tile at depth {}",
+                id.bit_length()
+            ));
+        }
+
+        // Each leaf takes at least its hash, so a bad count can't allocate much
+        let mut leaves: Vec<Arc<Node>> = Vec::with_capacity(count.min(reader.len() / 32));
+        for index in 0..count {
+            let leaf_id = read_id_bytes(&mut reader, leaf_depth)?;
+            if leaf_id.prefix(id.bit_length()) != id {
+                return Err(format!("leaf {index} is not in the tile"));
+            }
+            if leaves.last().is_some_and(|last| last.id >= leaf_id) {
+                return Err(format!("leaf {index} is out of order"));
+            }
+            let hash = read_hash(&mut reader)?;
+            leaves.push(Arc::new(Node::new(leaf_id, hash)));
+        }
+        finish(reader, Tile::new(id, NodesRow(leaves)))
+    }
+}
+
+/// Number of bytes holding an ID of the given number of bits
+fn id_len(bits: usize) -> usize {
+    bits.div_ceil(8)
+}
+
+fn write_id_bytes(bytes: &mut Vec<u8>, id: &ID) {
+    bytes.extend_from_slice(&id.full_bytes());
+    let (last, bits) = id.last_byte();
+    if bits != 0 {
+        bytes.push(last);
+    }
+}
+
+fn read_id(reader: &mut &[u8]) -> Result<ID, String> {
+    let bits = usize::from(u16::from_be_bytes(read_array(reader)?));
+    read_id_bytes(reader, bits)
+}
+
+/// read_id_bytes reads the bytes of an ID of the given length, rejecting set
+/// unused bits, which would make the encoding ambiguous.
+fn read_id_bytes(reader: &mut &[u8], bits: usize) -> Result<ID, String> {
+    let bytes = read_slice(reader, id_len(bits))?;
+    let id = ID::new_id(bytes, bits);
+    if bytes.last().is_some_and(|&last| last != id.last_byte().0) {
+        return Err(format!("ID {id} has unused bits set"));
+    }
+    Ok(id)
+}
+
+fn read_hash(reader: &mut &[u8]) -> Result<[u8; 32], String> {
+    read_array(reader)
+}
+
+fn read_array<const N: usize>(reader: &mut &[u8]) -> Result<[u8; N], String> {
+    let mut array = [0_u8; N];
+    array.copy_from_slice(read_slice(reader, N)?);
+    Ok(array)
+}
+
+fn read_slice<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if reader.len() < len {
+        return Err(format!(
+            "truncated: want {len} more bytes, have {}",
+            reader.len()
+        ));
+    }
+    let (slice, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(slice)
+}
+
+/// finish returns the decoded value if all the bytes were used
+fn finish<T>(reader: &[u8], value: T) -> Result<T, String> {
+    if reader.is_empty() {
+        Ok(value)
+    } else {
+        Err(format!("{} trailing bytes", reader.len()))
+    }
+}
+
+macro_rules! serde_via_bytes {
+    ($($type:ty,)*) => {
+    $(
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let bytes = self.to_bytes();
+                if serializer.is_human_readable() {
+                    serializer.serialize_str(&hex::encode(bytes))
+                } else {
+                    serializer.serialize_bytes(&bytes)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let bytes = if deserializer.is_human_readable() {
+                    let encoded = String::deserialize(deserializer)?;
+                    hex::decode(encoded).map_err(D::Error::custom)?
+                } else {
+                    deserializer.deserialize_byte_buf(BytesVisitor)?
+                };
+                <$type>::from_bytes(&bytes).map_err(D::Error::custom)
+            }
+        }
+    )*
+    }
+}
+
+serde_via_bytes! {
+    ID,
+    Node,
+    Tile,
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("canonically encoded bytes")
+    }
+
+    fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn any_id() -> impl Strategy<Value = ID> {
+        (any::<[u8; 32]>(), 0..=256_usize).prop_map(|(bytes, bits)| ID::new_id(&bytes, bits))
+    }
+
+    fn any_node() -> impl Strategy<Value = Node> {
+        (any_id(), any::<[u8; 32]>()).prop_map(|(id, hash)| Node::new(id, hash))
+    }
+
+    /// A tile of the given height at a random position, with random leaves
+    fn any_tile() -> impl Strategy<Value = Tile> {
+        (any::<[u8; 32]>(), 0..=248_usize, 1..=8_usize)
+            .prop_flat_map(|(root, depth, height)| {
+                let leaves = prop::collection::vec((any::<[u8; 32]>(), any::<[u8; 32]>()), 0..20);
+                (Just(root), Just(depth), Just(height), leaves)
+            })
+            .prop_map(|(root, depth, height, leaves)| {
+                let id = ID::new_id(&root, depth);
+                let leaves: BTreeMap<ID, [u8; 32]> = leaves
+                    .into_iter()
+                    .map(|(mut path, hash)| {
+                        // Put the leaf under the tile's root
+                        for (i, byte) in path.iter_mut().enumerate() {
+                            let mask = match depth.saturating_sub(8 * i).min(8) {
+                                0 => 0,
+                                bits => 0xFF_u8 << (8 - bits),
+                            };
+                            *byte = (root[i] & mask) | (*byte & !mask);
+                        }
+                        (ID::new_id(&path, depth + height), hash)
+                    })
+                    .collect();
+                let leaves = leaves
+                    .into_iter()
+                    .map(|(leaf, hash)| Arc::new(Node::new(leaf, hash)))
+                    .collect();
+                Tile::new(id, NodesRow(leaves))
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn id_round_trip(id in any_id()) {
+            let bytes = id.to_bytes();
+            prop_assert_eq!(bytes.len(), 2 + id.bit_length().div_ceil(8));
+            prop_assert_eq!(ID::from_bytes(&bytes).unwrap(), id);
+        }
+
+        #[test]
+        fn node_round_trip(node in any_node()) {
+            prop_assert_eq!(Node::from_bytes(&node.to_bytes()).unwrap(), node);
+        }
+
+        #[test]
+        fn tile_round_trip(tile in any_tile()) {
+            let bytes = tile.to_bytes();
+            prop_assert_eq!(Tile::from_bytes(&bytes).unwrap(), tile);
+        }
+
+        #[test]
+        fn serde_round_trip(tile in any_tile(), node in any_node()) {
+            let json = serde_json::to_string(&tile).unwrap();
+            prop_assert_eq!(&serde_json::from_str::<Tile>(&json).unwrap(), &tile);
+            let binary = bincode::serialize(&tile).unwrap();
+            prop_assert_eq!(bincode::deserialize::<Tile>(&binary).unwrap(), tile);
+
+            let binary = bincode::serialize(&node).unwrap();
+            prop_assert_eq!(&bincode::deserialize::<Node>(&binary).unwrap(), &node);
+            let json = serde_json::to_string(&node.id).unwrap();
+            prop_assert_eq!(serde_json::from_str::<ID>(&json).unwrap(), node.id);
+        }
+
+        #[test]
+        fn truncated_encodings_are_rejected(tile in any_tile(), cut in any::<prop::sample::Index>()) {
+            let bytes = tile.to_bytes();
+            let cut = cut.index(bytes.len());
+            prop_assert!(Tile::from_bytes(&bytes[..cut]).is_err());
+        }
+    }
+
+    #[test]
+    fn id_encoding() {
+        assert_eq!(ID::default().to_bytes(), b"\x00\x00");
+        assert_eq!(
+            ID::new_id(b"\x0A\x0B\xFF", 20).to_bytes(),
+            b"\x00\x14\x0A\x0B\xF0"
+        );
+        assert_eq!(
+            serde_json::to_string(&ID::new_id(b"\x0A\x0B\xFF", 20)).unwrap(),
+            "\"00140a0bf0\""
+        );
+    }
+
+    #[test]
+    fn non_canonical_encodings_are_rejected() {
+        let test_cases: Vec<(&[u8], &str)> = vec![
+            (b"\x00\x14\x0A\x0B\xF8", "unused bits set"),
+            (b"\x00\x14\x0A\x0B", "truncated"),
+            (b"\x00\x14\x0A\x0B\xF0\x00", "trailing bytes"),
+            (b"\x00", "truncated"),
+        ];
+        for (bytes, want_err) in test_cases {
+            let err = ID::from_bytes(bytes).unwrap_err();
+            assert!(err.contains(want_err), "got error {err}, want {want_err}");
+        }
+
+        let tile = |leaves: &[(&[u8], usize)]| {
+            let leaves = leaves
+                .iter()
+                .map(|(path, bits)| Arc::new(Node::new(ID::new_id(path, *bits), [0; 32])))
+                .collect();
+            Tile::new(ID::new_id(b"\xAB", 8), NodesRow(leaves)).to_bytes()
+        };
+        let test_cases: Vec<(Vec<u8>, &str)> = vec![
+            (
+                tile(&[(b"\xAB\x20", 16), (b"\xAB\x10", 16)]),
+                "out of order",
+            ),
+            (
+                tile(&[(b"\xAB\x10", 16), (b"\xAB\x10", 16)]),
+                "out of order",
+            ),
+            (tile(&[(b"\xAC\x10", 16)]), "not in the tile"),
+            (tile(&[(b"\xAB", 8)]), "not below the tile"),
+            (
+                b"\x00\x00\x00\x08\x00\x00\x00\x00".to_vec(),
+                "0 leaves at depth 8",
+            ),
+        ];
+        for (bytes, want_err) in test_cases {
+            let err = Tile::from_bytes(&bytes).unwrap_err();
+            assert!(err.contains(want_err), "got error {err}, want {want_err}");
+        }
+    }
+}
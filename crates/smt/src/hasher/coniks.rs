@@ -0,0 +1,127 @@
+use sha2::{Digest, Sha256};
+
+use crate::hasher::{NodeHasher, BIT_LENGTH};
+use crate::node::id::ID;
+
+/// Domain separation identifier for leaf hashes
+const LEAF_IDENTIFIER: &[u8] = b"L";
+/// Domain separation identifier for empty subtree hashes
+const EMPTY_IDENTIFIER: &[u8] = b"E";
+
+/// ConiksHasher hashes sparse Merkle trees like Trillian's CONIKS_SHA256 map
+/// hasher. Leaves and empty subtrees commit to the tree ID and their position in
+/// the tree, so an empty subtree hashes differently at every node.
+#[derive(Default)]
+pub struct ConiksHasher;
+
+impl NodeHasher for ConiksHasher {
+    /// H(EMPTY_IDENTIFIER || tree_id || masked id || depth)
+    fn hash_empty(&self, tree_id: i64, id: &ID) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(EMPTY_IDENTIFIER)
+            .chain_update(tree_id.to_be_bytes())
+            .chain_update(masked_id(id))
+            .chain_update((id.bit_length() as u32).to_be_bytes())
+            .finalize()
+            .into()
+    }
+
+    /// H(LEAF_IDENTIFIER || tree_id || masked id || depth || leaf)
+    fn hash_leaf(&self, tree_id: i64, id: &ID, leaf: &[u8]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(LEAF_IDENTIFIER)
+            .chain_update(tree_id.to_be_bytes())
+            .chain_update(masked_id(id))
+            .chain_update((id.bit_length() as u32).to_be_bytes())
+            .chain_update(leaf)
+            .finalize()
+            .into()
+    }
+
+    /// H(left || right)
+    fn hash_children(&self, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .into()
+    }
+}
+
+/// masked_id returns the bytes of the node ID padded with zero bytes to the full
+/// index length. The unused bits of the ID's last byte are already unset.
+fn masked_id(id: &ID) -> [u8; BIT_LENGTH / 8] {
+    if id.bit_length() > BIT_LENGTH {
+        panic!("masked_id: bits {} > {}", id.bit_length(), BIT_LENGTH)
+    }
+    let mut masked = [0_u8; BIT_LENGTH / 8];
+    let path = id.full_bytes();
+    masked[..path.len()].copy_from_slice(&path);
+    let (last, bits) = id.last_byte();
+    if bits != 0 {
+        masked[path.len()] = last;
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::testonly::{key_id, root};
+
+    use super::*;
+
+    #[test]
+    fn hash_empty() {
+        let id = key_id(b"key1");
+        assert_eq!(
+            hex::encode(ConiksHasher.hash_empty(42, &id.prefix(8))),
+            "4bd47b7a7c7426fbbf5af8b78441addc2e9d7dc1302c143dd4a041f68ced4b47"
+        );
+        // Empty subtrees commit to their position and tree
+        assert_ne!(
+            ConiksHasher.hash_empty(42, &id.prefix(8)),
+            ConiksHasher.hash_empty(42, &id.prefix(8).sibling())
+        );
+        assert_ne!(
+            ConiksHasher.hash_empty(42, &id.prefix(8)),
+            ConiksHasher.hash_empty(43, &id.prefix(8))
+        );
+        assert_eq!(
+            root(&ConiksHasher, 42, &[]),
+            "af7c79a27443bb1f0be63f95baab67d7cb55e440c2b5dc15b64e0df85276b593"
+        );
+    }
+
+    #[test]
+    fn hash_leaf() {
+        assert_eq!(
+            hex::encode(ConiksHasher.hash_leaf(42, &key_id(b"key1"), b"value1")),
+            "b7da9a4819a4331f2ec4fec1d9cb48281a637b9517fad5b9fb66fe51e102bcc8"
+        );
+    }
+
+    #[test]
+    fn masked_id_pads_and_masks() {
+        let id = ID::new_id(b"\xAB\xCD\xEF", 12);
+        let mut want = [0_u8; 32];
+        want[..2].copy_from_slice(b"\xAB\xC0");
+        assert_eq!(masked_id(&id), want);
+        assert_eq!(masked_id(&ID::default()), [0_u8; 32]);
+    }
+
+    #[test]
+    fn roots() {
+        assert_eq!(
+            root(&ConiksHasher, 42, &[(b"key1", b"value1")]),
+            "0c9f118d454e1ecd49f78df3a9adac4babdc5a6b22e496eaa092fd63668d998a"
+        );
+        assert_eq!(
+            root(
+                &ConiksHasher,
+                42,
+                &[(b"key1", b"value1"), (b"key2", b"value2")]
+            ),
+            "3a87bb65b7bd6b930028ecc1a2729816dc4adedd0ac147fd8ce8ce4bf9e052f0"
+        );
+    }
+}
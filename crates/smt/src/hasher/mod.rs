@@ -0,0 +1,106 @@
+use crate::node::id::ID;
+
+pub mod coniks;
+pub mod rfc6962;
+
+/// NodeHasher computes the hashes of a sparse Merkle tree whose leaves are at
+/// depth 256, i.e. are addressed by 32-byte indices. This mirrors Trillian's map
+/// hashers, so the same tree hashes to the same root here as in Trillian.
+pub trait NodeHasher {
+    /// hash_empty returns the hash of the empty subtree rooted at the given node,
+    /// in the tree with the given ID.
+    fn hash_empty(&self, tree_id: i64, id: &ID) -> [u8; 32];
+
+    /// hash_leaf returns the hash of a leaf with the given value, stored at the
+    /// given full-depth node.
+    fn hash_leaf(&self, tree_id: i64, id: &ID, leaf: &[u8]) -> [u8; 32];
+
+    /// hash_children returns the hash of an inner node from the hashes of its
+    /// left and right children.
+    fn hash_children(&self, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32];
+}
+
+/// Depth of the leaves, one level per bit of a 32-byte index
+pub const BIT_LENGTH: usize = 256;
+
+#[cfg(test)]
+pub(crate) mod testonly {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use sha2::{Digest, Sha256};
+
+    use crate::hasher::{NodeHasher, BIT_LENGTH};
+    use crate::hstar3::{HStar3, NodeAccessor};
+    use crate::node::id::ID;
+    use crate::node::{Node, NodesRow};
+
+    /// A tree stored in memory, whose unset nodes are empty subtrees
+    struct MemoryTree<'a, H> {
+        hasher: &'a H,
+        tree_id: i64,
+        hashes: HashMap<ID, [u8; 32]>,
+    }
+
+    impl<H: NodeHasher> NodeAccessor for MemoryTree<'_, H> {
+        fn get(&mut self, id: &ID) -> Result<[u8; 32], String> {
+            Ok(self
+                .hashes
+                .get(id)
+                .copied()
+                .unwrap_or_else(|| self.hasher.hash_empty(self.tree_id, id)))
+        }
+
+        fn set(&mut self, id: &ID, hash: &[u8; 32]) {
+            self.hashes.insert(id.clone(), *hash);
+        }
+    }
+
+    /// Leaf ID of a key, the SHA-256 hash of the key as Trillian maps index them
+    pub(crate) fn key_id(key: &[u8]) -> ID {
+        ID::new_id(&Sha256::digest(key), BIT_LENGTH)
+    }
+
+    /// Root hash of the tree holding the given (key, value) leaves, computed with HStar3
+    pub(crate) fn root<H: NodeHasher>(
+        hasher: &H,
+        tree_id: i64,
+        leaves: &[(&[u8], &[u8])],
+    ) -> String {
+        hex::encode(build(hasher, tree_id, leaves).0)
+    }
+
+    /// Build the tree holding the given (key, value) leaves with HStar3, returning its root
+    /// hash and the hash of every non-empty node below the root
+    pub(crate) fn build<H: NodeHasher>(
+        hasher: &H,
+        tree_id: i64,
+        leaves: &[(&[u8], &[u8])],
+    ) -> ([u8; 32], HashMap<ID, [u8; 32]>) {
+        if leaves.is_empty() {
+            return (hasher.hash_empty(tree_id, &ID::default()), HashMap::new());
+        }
+        let nodes = leaves
+            .iter()
+            .map(|(key, value)| {
+                let id = key_id(key);
+                let hash = hasher.hash_leaf(tree_id, &id, value);
+                Arc::new(Node::new(id, hash))
+            })
+            .collect();
+        let hstar3 = HStar3::new(
+            NodesRow::try_new(nodes).unwrap(),
+            |left, right| hasher.hash_children(left, right),
+            BIT_LENGTH,
+            0,
+        )
+        .unwrap();
+        let mut tree = MemoryTree {
+            hasher,
+            tree_id,
+            hashes: HashMap::new(),
+        };
+        let top = hstar3.update(&mut tree).unwrap();
+        (*top.0[0].hash(), tree.hashes)
+    }
+}
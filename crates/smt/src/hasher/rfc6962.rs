@@ -0,0 +1,118 @@
+use sha2::{Digest, Sha256};
+
+use crate::hasher::{NodeHasher, BIT_LENGTH};
+use crate::node::id::ID;
+
+/// Domain separation prefix for leaf hashes
+const LEAF_PREFIX: u8 = 0;
+/// Domain separation prefix for inner node hashes
+const NODE_PREFIX: u8 = 1;
+
+/// Rfc6962Hasher hashes sparse Merkle trees like Trillian's RFC6962 map hasher:
+/// leaves and inner nodes are hashed as in RFC 6962, and an empty subtree
+/// hashes as if every leaf under it had an empty value, regardless of the tree
+/// and its position in it.
+pub struct Rfc6962Hasher {
+    /// Hashes of empty subtrees, indexed by their height
+    null_hashes: Vec<[u8; 32]>,
+}
+
+impl Rfc6962Hasher {
+    pub fn new() -> Self {
+        let mut null_hashes = Vec::with_capacity(BIT_LENGTH + 1);
+        null_hashes.push(leaf_hash(&[]));
+        for height in 1..=BIT_LENGTH {
+            let below = &null_hashes[height - 1];
+            null_hashes.push(children_hash(below, below));
+        }
+        Rfc6962Hasher { null_hashes }
+    }
+}
+
+impl Default for Rfc6962Hasher {
+    fn default() -> Self {
+        Rfc6962Hasher::new()
+    }
+}
+
+impl NodeHasher for Rfc6962Hasher {
+    fn hash_empty(&self, _tree_id: i64, id: &ID) -> [u8; 32] {
+        self.null_hashes[BIT_LENGTH - id.bit_length()]
+    }
+
+    fn hash_leaf(&self, _tree_id: i64, _id: &ID, leaf: &[u8]) -> [u8; 32] {
+        leaf_hash(leaf)
+    }
+
+    fn hash_children(&self, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        children_hash(left, right)
+    }
+}
+
+fn leaf_hash(leaf: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(leaf)
+        .finalize()
+        .into()
+}
+
+fn children_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([NODE_PREFIX])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::testonly::{key_id, root};
+
+    use super::*;
+
+    #[test]
+    fn empty_root() {
+        // emptyMapRootB64 from Trillian's maphasher tests, as hex
+        assert_eq!(
+            root(&Rfc6962Hasher::new(), 0, &[]),
+            "c6689f10812a0980976d9533d83875282166159567ec35155716c1413af53d6a"
+        );
+    }
+
+    #[test]
+    fn hashes_ignore_tree_and_position() {
+        let hasher = Rfc6962Hasher::new();
+        let id = key_id(b"key1");
+        assert_eq!(
+            hasher.hash_leaf(0, &id, b"value1"),
+            hasher.hash_leaf(7, &key_id(b"key2"), b"value1")
+        );
+        assert_eq!(
+            hasher.hash_empty(0, &id.prefix(8)),
+            hasher.hash_empty(7, &ID::new_id(&[0], 8))
+        );
+        assert_eq!(
+            hex::encode(hasher.hash_empty(0, &id.prefix(8))),
+            "99323316615471e32158adfc19b38ad70f5140eb08bd582cc0353721d2e9e330"
+        );
+        assert_eq!(
+            hex::encode(hasher.hash_leaf(0, &id, b"value1")),
+            "d7da3a95c85f6573d3e611bac5be34c478a4b7710e9eb0955ab99238f54ccb45"
+        );
+    }
+
+    #[test]
+    fn roots() {
+        let hasher = Rfc6962Hasher::new();
+        assert_eq!(
+            root(&hasher, 0, &[(b"key1", b"value1")]),
+            "3cf23cd7c0f90a251040c66e947e7c2e2923c5e3855b0d856e718cd007551d60"
+        );
+        assert_eq!(
+            root(&hasher, 0, &[(b"key1", b"value1"), (b"key2", b"value2")]),
+            "6579bd04b8efae8ddae85a17d67a8e66c553472c9541955488d69f73a5b6df50"
+        );
+    }
+}
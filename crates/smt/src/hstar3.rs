@@ -0,0 +1,341 @@
+use std::sync::Arc;
+
+use crate::node::id::ID;
+use crate::node::{Node, NodesRow};
+
+/// NodeAccessor provides read and write access to Merkle tree node hashes.
+pub trait NodeAccessor {
+    /// get returns the hash of the given node. Returns an error if the hash is
+    /// undefined, or can't be obtained for another reason.
+    fn get(&mut self, id: &ID) -> Result<[u8; 32], String>;
+    /// set sets the hash of the given node.
+    fn set(&mut self, id: &ID, hash: &[u8; 32]);
+}
+
+/// HStar3 is a faster non-recursive HStar2. It propagates a batch of node
+/// updates at one level of a sparse Merkle tree up to a higher level, reading
+/// the hashes of untouched siblings from a NodeAccessor as it goes.
+pub struct HStar3<F> {
+    nodes: NodesRow,
+    hash: F,
+    depth: usize,
+    top: usize,
+}
+
+impl<F> HStar3<F>
+where
+    F: Fn(&[u8; 32], &[u8; 32]) -> [u8; 32],
+{
+    /// new returns an HStar3 for the given node hash updates at the specified
+    /// tree depth, capable of propagating them up to the passed-in top level of
+    /// the tree. hash computes a node's hash from its left and right children.
+    pub fn new(nodes: NodesRow, hash: F, depth: usize, top: usize) -> Result<Self, String> {
+        if top > depth {
+            return Err(format!("top > depth: {top} vs. {depth}"));
+        }
+        // The row is prepared already, so checking the first node checks them all
+        if let Some(node) = nodes.0.first() {
+            if node.id.bit_length() != depth {
+                return Err(format!(
+                    "node invalid depth {}, want {}",
+                    node.id.bit_length(),
+                    depth
+                ));
+            }
+        }
+        Ok(HStar3 {
+            nodes,
+            hash,
+            depth,
+            top,
+        })
+    }
+
+    /// prepare returns the IDs of all the nodes that update will read from the
+    /// NodeAccessor to propagate the updates up to the top level, from the
+    /// bottom level up. This is useful for batch-reading the nodes from storage
+    /// before they are requested.
+    pub fn prepare(&self) -> Vec<ID> {
+        let mut ids = Vec::new();
+        let mut level: Vec<ID> = self.nodes.0.iter().map(|n| n.id.clone()).collect();
+        for depth in (self.top + 1..=self.depth).rev() {
+            let mut parents = Vec::with_capacity(level.len());
+            let mut i = 0;
+            while i < level.len() {
+                let sibling = level[i].sibling();
+                if level.get(i + 1) == Some(&sibling) {
+                    // Both children are updated, so neither is read
+                    i += 1;
+                } else {
+                    ids.push(sibling.clone());
+                }
+                parents.push(sibling.prefix(depth - 1));
+                i += 1;
+            }
+            level = parents;
+        }
+        ids
+    }
+
+    /// update applies the updates to the sparse Merkle tree, writing every
+    /// updated node below the top level to the NodeAccessor. Returns the updated
+    /// nodes at the top level of the tree, or an error if any of the
+    /// NodeAccessor.get calls does so, e.g. if a node is undefined.
+    pub fn update<A: NodeAccessor>(mut self, accessor: &mut A) -> Result<NodesRow, String> {
+        let mut nodes = std::mem::take(&mut self.nodes.0);
+        for depth in (self.top + 1..=self.depth).rev() {
+            nodes = self
+                .update_at(&nodes, depth, accessor)
+                .map_err(|err| format!("depth {depth}: {err}"))?;
+        }
+        Ok(NodesRow(nodes))
+    }
+
+    /// update_at applies the given node updates at the specified tree level.
+    /// Returns the updated nodes at the level above, still sorted.
+    fn update_at<A: NodeAccessor>(
+        &self,
+        nodes: &[Arc<Node>],
+        depth: usize,
+        accessor: &mut A,
+    ) -> Result<Vec<Arc<Node>>, String> {
+        for node in nodes {
+            accessor.set(&node.id, node.hash());
+        }
+
+        let mut parents = Vec::with_capacity(nodes.len());
+        let mut i = 0;
+        while i < nodes.len() {
+            let sibling = nodes[i].id.sibling();
+            let hash = match nodes.get(i + 1) {
+                // The sibling is the right neighbour, and updated too
+                Some(next) if next.id == sibling => {
+                    i += 1;
+                    (self.hash)(nodes[i - 1].hash(), next.hash())
+                }
+                _ => {
+                    let sibling_hash = accessor.get(&sibling)?;
+                    if sibling.is_left_child() {
+                        (self.hash)(&sibling_hash, nodes[i].hash())
+                    } else {
+                        (self.hash)(nodes[i].hash(), &sibling_hash)
+                    }
+                }
+            };
+            parents.push(Arc::new(Node::new(sibling.prefix(depth - 1), hash)));
+            i += 1;
+        }
+        Ok(parents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    const DEPTH: usize = 16;
+
+    fn hash_children(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update([1_u8])
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .into()
+    }
+
+    fn leaf_hash(index: u16) -> [u8; 32] {
+        Sha256::new()
+            .chain_update([0_u8])
+            .chain_update(index.to_be_bytes())
+            .finalize()
+            .into()
+    }
+
+    /// Hashes of empty subtrees, indexed by height
+    fn empty_hashes() -> Vec<[u8; 32]> {
+        let mut hashes = vec![[0_u8; 32]];
+        for height in 1..=DEPTH {
+            let below = hashes[height - 1];
+            hashes.push(hash_children(&below, &below));
+        }
+        hashes
+    }
+
+    /// An in-memory tree whose unset nodes are empty subtrees, recording every read
+    struct MemoryTree {
+        hashes: HashMap<ID, [u8; 32]>,
+        empty: Vec<[u8; 32]>,
+        reads: Vec<ID>,
+    }
+
+    impl MemoryTree {
+        fn new() -> Self {
+            MemoryTree {
+                hashes: HashMap::new(),
+                empty: empty_hashes(),
+                reads: Vec::new(),
+            }
+        }
+    }
+
+    impl NodeAccessor for MemoryTree {
+        fn get(&mut self, id: &ID) -> Result<[u8; 32], String> {
+            self.reads.push(id.clone());
+            Ok(self
+                .hashes
+                .get(id)
+                .copied()
+                .unwrap_or(self.empty[DEPTH - id.bit_length()]))
+        }
+
+        fn set(&mut self, id: &ID, hash: &[u8; 32]) {
+            self.hashes.insert(id.clone(), *hash);
+        }
+    }
+
+    /// Fails every read, for trees that should need none
+    struct NoReads;
+
+    impl NodeAccessor for NoReads {
+        fn get(&mut self, id: &ID) -> Result<[u8; 32], String> {
+            Err(format!("unexpected read of {id}"))
+        }
+
+        fn set(&mut self, _: &ID, _: &[u8; 32]) {}
+    }
+
+    fn leaf_id(index: u16) -> ID {
+        ID::new_id(&index.to_be_bytes(), DEPTH)
+    }
+
+    fn leaves(indices: &[u16]) -> NodesRow {
+        NodesRow::try_new(
+            indices
+                .iter()
+                .map(|&index| Arc::new(Node::new(leaf_id(index), leaf_hash(index))))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    /// Straightforward recursive root of the subtree over leaves [start, start + 2^height)
+    fn reference_root(
+        leaves: &HashSet<u16>,
+        start: u32,
+        height: usize,
+        empty: &[[u8; 32]],
+    ) -> [u8; 32] {
+        let end = start + (1 << height);
+        if !leaves
+            .iter()
+            .any(|&leaf| (start..end).contains(&u32::from(leaf)))
+        {
+            empty[height]
+        } else if height == 0 {
+            leaf_hash(start as u16)
+        } else {
+            let middle = start + (1 << (height - 1));
+            hash_children(
+                &reference_root(leaves, start, height - 1, empty),
+                &reference_root(leaves, middle, height - 1, empty),
+            )
+        }
+    }
+
+    fn root(tree: &mut MemoryTree, indices: &[u16]) -> [u8; 32] {
+        let hstar3 = HStar3::new(leaves(indices), hash_children, DEPTH, 0).unwrap();
+        let top = hstar3.update(tree).unwrap();
+        assert_eq!(top.len(), 1, "update should return only the root");
+        assert_eq!(top.0[0].id, ID::default());
+        *top.0[0].hash()
+    }
+
+    const INDICES: [u16; 9] = [0, 1, 2, 7, 255, 256, 4097, 40000, 65535];
+
+    #[test]
+    fn new_checks_depths() {
+        let err = HStar3::new(leaves(&[1]), hash_children, 8, 9)
+            .err()
+            .unwrap();
+        assert!(err.contains("top > depth"), "got error {err}");
+        let err = HStar3::new(leaves(&[1]), hash_children, 15, 0)
+            .err()
+            .unwrap();
+        assert!(err.contains("invalid depth"), "got error {err}");
+        assert!(HStar3::new(NodesRow(vec![]), hash_children, 15, 0).is_ok());
+    }
+
+    #[test]
+    fn update_matches_reference_root() {
+        let empty = empty_hashes();
+        for count in 1..=INDICES.len() {
+            let indices = &INDICES[..count];
+            let want = reference_root(&indices.iter().copied().collect(), 0, DEPTH, &empty);
+            let got = root(&mut MemoryTree::new(), indices);
+            assert_eq!(got, want, "root of leaves {indices:?}");
+        }
+    }
+
+    #[test]
+    fn update_to_inner_level() {
+        let empty = empty_hashes();
+        let all: HashSet<u16> = INDICES.iter().copied().collect();
+        let hstar3 = HStar3::new(leaves(&INDICES), hash_children, DEPTH, 8).unwrap();
+        let top = hstar3.update(&mut MemoryTree::new()).unwrap();
+
+        // 0, 1, 2, 7 and 255 share the first subtree at depth 8
+        assert_eq!(top.len(), INDICES.len() - 4);
+        for node in &top.0 {
+            assert_eq!(node.id.bit_length(), 8);
+            let start = u32::from(node.id.last_byte().0) << 8;
+            let want = reference_root(&all, start, 8, &empty);
+            assert_eq!(*node.hash(), want, "subtree {}", node.id);
+        }
+    }
+
+    #[test]
+    fn batches_match_single_update() {
+        let mut tree = MemoryTree::new();
+        root(&mut tree, &INDICES[..4]);
+        let got = root(&mut tree, &INDICES[4..]);
+        let want = root(&mut MemoryTree::new(), &INDICES);
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn prepare_lists_the_reads() {
+        for count in 0..=INDICES.len() {
+            let indices = &INDICES[..count];
+            let hstar3 = HStar3::new(leaves(indices), hash_children, DEPTH, 0).unwrap();
+            let prepared = hstar3.prepare();
+            let mut tree = MemoryTree::new();
+            hstar3.update(&mut tree).unwrap();
+            assert_eq!(prepared, tree.reads, "reads for leaves {indices:?}");
+        }
+    }
+
+    #[test]
+    fn full_tree_needs_no_reads() {
+        let indices: Vec<u16> = (0..256).collect();
+        let hstar3 = HStar3::new(leaves(&indices), hash_children, DEPTH, 8).unwrap();
+        assert!(hstar3.prepare().is_empty());
+        let top = hstar3.update(&mut NoReads).unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top.0[0].id, ID::new_id(&[0], 8));
+    }
+
+    #[test]
+    fn update_reports_read_errors() {
+        let hstar3 = HStar3::new(leaves(&[3]), hash_children, DEPTH, 0).unwrap();
+        let err = hstar3.update(&mut NoReads).err().unwrap();
+        assert!(
+            err.starts_with("depth 16: unexpected read"),
+            "got error {err}"
+        );
+    }
+}
@@ -1,5 +1,11 @@
-mod node;
-mod tile;
+mod encoding;
+pub mod hasher;
+pub mod hstar3;
+pub mod node;
+pub mod proof;
+pub mod revision;
+pub mod tile;
+pub mod writer;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
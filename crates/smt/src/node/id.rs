@@ -27,7 +27,7 @@ type Byte = u8;
 /// - path string contains 1 byte, which is [1010,1111].
 /// - last byte is [0010,0000]. Note the unset lower 5 bits.
 /// - bits is 3, so effectively only the upper 3 bits [001] of last are used.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct ID {
     path: Arc<[u8]>,
     last: Byte,
@@ -145,9 +145,38 @@ impl ID {
         }
     }
 
+    /// parent returns the ID of the node's parent, or None for the root, which
+    /// has none.
+    pub fn parent(&self) -> Option<ID> {
+        match self.bit_length() {
+            0 => None,
+            bits => Some(self.prefix(bits - 1)),
+        }
+    }
+
+    /// ancestors iterates over the prefixes of the ID from its parent up to, and
+    /// including, the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = ID> + '_ {
+        (0..self.bit_length()).rev().map(|bits| self.prefix(bits))
+    }
+
     pub fn full_bytes(&self) -> Arc<[u8]> {
         self.path.clone()
     }
+
+    /// LastByte returns the terminating byte of the ID, with the number of upper
+    /// bits that it uses (between 1 and 8, and 0 if the ID is empty). The
+    /// remaining unused lower bits are always unset.
+    pub fn last_byte(&self) -> (Byte, u8) {
+        (self.last, self.bits)
+    }
+
+    /// is_left_child returns whether this is the left child of its parent, i.e.
+    /// whether its last bit is 0. The empty ID is the root, which is no child,
+    /// and reports false.
+    pub fn is_left_child(&self) -> bool {
+        self.bits != 0 && self.last & safe_shift_left(1, 8 - self.bits) == 0
+    }
 }
 
 impl Display for ID {
@@ -372,4 +401,62 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn id_is_left_child() {
+        const TEST_BYTES: &[u8; 2] = b"\x0A\x0B";
+
+        let test_cases = vec![
+            // (bits, want)
+            (0, false),
+            (1, true),
+            (5, false),
+            (6, true),
+            (7, false),
+            (8, true),
+            (16, false),
+        ];
+
+        for (bits, want) in test_cases {
+            let id = ID::new_id(TEST_BYTES, bits);
+            let got = id.is_left_child();
+            assert_eq!(got, want, "IsLeftChild {}: got {}, want {}", id, got, want);
+            if bits != 0 {
+                assert_eq!(id.sibling().is_left_child(), !want);
+            }
+        }
+    }
+
+    #[test]
+    fn id_parent() {
+        const TEST_BYTES: &[u8; 3] = b"\x0A\x0B\x0C";
+
+        assert_eq!(ID::default().parent(), None);
+        for bits in 1..=24 {
+            let id = ID::new_id(TEST_BYTES, bits);
+            let want = ID::new_id(TEST_BYTES, bits - 1);
+            assert_eq!(id.parent(), Some(want.clone()), "Parent bits={}", bits);
+            assert_eq!(
+                id.sibling().parent(),
+                Some(want),
+                "Sibling's parent bits={}",
+                bits
+            );
+        }
+    }
+
+    #[test]
+    fn id_ancestors() {
+        const TEST_BYTES: &[u8; 2] = b"\x0A\x0B";
+
+        let id = ID::new_id(TEST_BYTES, 10);
+        let ancestors: Vec<ID> = id.ancestors().collect();
+        assert_eq!(ancestors.len(), 10);
+        assert_eq!(ancestors.first(), Some(&ID::new_id(TEST_BYTES, 9)));
+        assert_eq!(ancestors.last(), Some(&ID::default()));
+        for pair in ancestors.windows(2) {
+            assert_eq!(pair[0].parent().as_ref(), Some(&pair[1]));
+        }
+        assert_eq!(ID::default().ancestors().count(), 0);
+    }
 }
@@ -3,7 +3,7 @@ use std::sync::Arc;
 
 use crate::node::id::ID;
 
-pub(crate) mod id;
+pub mod id;
 
 #[derive(Debug, Default, Eq, PartialEq)]
 pub struct Node {
@@ -25,6 +25,10 @@ impl Node {
             hash: Arc::from(hash),
         }
     }
+
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
 }
 
 impl PartialOrd for Node {
@@ -82,6 +86,10 @@ impl NodesRow {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 /// mutably filters, sorts, and de-dupes in preparation for HStar3 algorithm
@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use crate::hasher::{NodeHasher, BIT_LENGTH};
+use crate::node::id::ID;
+use crate::node::Node;
+use crate::tile::Tile;
+
+/// Proof is the audit path of a key in a sparse Merkle tree. It proves that the
+/// key holds a value (inclusion) or that it holds nothing (non-inclusion), given
+/// the root hash of the tree.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Proof {
+    /// Hashes of the siblings of the key's node and of its ancestors, from the
+    /// leaf level up to just below the root. Empty subtrees are None, so that the
+    /// proof stays small; the verifier recomputes their hashes.
+    pub siblings: Vec<Option<[u8; 32]>>,
+}
+
+/// prove returns the proof for the key with the given leaf ID, from the tiles
+/// on its path. The tiles must be ordered from the root of the tree down, each
+/// rooted at the bottom level of the one before. They may stop above the leaf
+/// level where the key's subtree is empty, which is then a proof that the key
+/// is absent.
+pub fn prove<H: NodeHasher>(
+    hasher: &H,
+    tree_id: i64,
+    tiles: &[Tile],
+    key: &ID,
+) -> Result<Proof, String> {
+    if key.bit_length() != BIT_LENGTH {
+        return Err(format!(
+            "key invalid depth {}, want {}",
+            key.bit_length(),
+            BIT_LENGTH
+        ));
+    }
+
+    let mut siblings = vec![None; BIT_LENGTH];
+    let mut depth = 0;
+    for tile in tiles {
+        if tile.id.bit_length() != depth || tile.id != key.prefix(depth) {
+            return Err(format!(
+                "tile {} is not on the path at depth {depth}",
+                tile.id
+            ));
+        }
+        let leaf_depth = match tile.leaves.0.first() {
+            Some(node) => node.id.bit_length(),
+            // The whole tile is empty, and so is everything below it
+            None => return Ok(Proof { siblings }),
+        };
+        if leaf_depth > BIT_LENGTH {
+            return Err(format!("tile {} is below the leaves", tile.id));
+        }
+        for d in depth + 1..=leaf_depth {
+            let sibling = key.prefix(d).sibling();
+            siblings[BIT_LENGTH - d] =
+                subtree_hash(hasher, tree_id, &tile.leaves.0, &sibling, leaf_depth);
+        }
+        let path = key.prefix(leaf_depth);
+        if tile.leaves.0.binary_search_by(|n| n.id.cmp(&path)).is_err() {
+            // The key's subtree is empty below this tile
+            return Ok(Proof { siblings });
+        }
+        depth = leaf_depth;
+    }
+
+    if depth == BIT_LENGTH {
+        Ok(Proof { siblings })
+    } else {
+        Err(format!("missing the tile at depth {depth}"))
+    }
+}
+
+/// verify checks the proof for the key against the root hash of the tree. A
+/// value proves the key holds that value, None proves the key is absent.
+pub fn verify<H: NodeHasher>(
+    hasher: &H,
+    tree_id: i64,
+    root: &[u8; 32],
+    key: &ID,
+    value: Option<&[u8]>,
+    proof: &Proof,
+) -> Result<(), String> {
+    if key.bit_length() != BIT_LENGTH {
+        return Err(format!(
+            "key invalid depth {}, want {}",
+            key.bit_length(),
+            BIT_LENGTH
+        ));
+    }
+    if proof.siblings.len() != BIT_LENGTH {
+        return Err(format!(
+            "proof has {} siblings, want {}",
+            proof.siblings.len(),
+            BIT_LENGTH
+        ));
+    }
+
+    let (mut hash, start) = match value {
+        Some(value) => (hasher.hash_leaf(tree_id, key, value), 0),
+        None => {
+            // The key's subtree is empty up to the first non-empty sibling. Some
+            // hashers hash an empty subtree differently from its empty children,
+            // so start from the root of the whole empty subtree.
+            let height = proof
+                .siblings
+                .iter()
+                .position(Option::is_some)
+                .unwrap_or(BIT_LENGTH);
+            let empty = key.prefix(BIT_LENGTH - height);
+            (hasher.hash_empty(tree_id, &empty), height)
+        }
+    };
+    for (height, sibling_hash) in proof.siblings.iter().enumerate().skip(start) {
+        let id = key.prefix(BIT_LENGTH - height);
+        let sibling_hash = match sibling_hash {
+            Some(sibling_hash) => *sibling_hash,
+            None => hasher.hash_empty(tree_id, &id.sibling()),
+        };
+        hash = if id.is_left_child() {
+            hasher.hash_children(&hash, &sibling_hash)
+        } else {
+            hasher.hash_children(&sibling_hash, &hash)
+        };
+    }
+
+    if hash == *root {
+        Ok(())
+    } else {
+        Err("proof does not lead to the root hash".to_string())
+    }
+}
+
+/// subtree_hash returns the hash of the subtree rooted at the given node, from
+/// the sorted nodes at leaf_depth in a tile, or None if the subtree is empty.
+fn subtree_hash<H: NodeHasher>(
+    hasher: &H,
+    tree_id: i64,
+    nodes: &[Arc<Node>],
+    root: &ID,
+    leaf_depth: usize,
+) -> Option<[u8; 32]> {
+    let depth = root.bit_length();
+    let start = nodes.partition_point(|n| n.id.prefix(depth) < *root);
+    let end = nodes.partition_point(|n| n.id.prefix(depth) <= *root);
+    let nodes = &nodes[start..end];
+    if nodes.is_empty() {
+        return None;
+    }
+    if depth == leaf_depth {
+        return Some(*nodes[0].hash());
+    }
+
+    let child = nodes[0].id.prefix(depth + 1);
+    let (left, right) = if child.is_left_child() {
+        let sibling = child.sibling();
+        (child, sibling)
+    } else {
+        (child.sibling(), child)
+    };
+    let hash = |id: &ID| {
+        subtree_hash(hasher, tree_id, nodes, id, leaf_depth)
+            .unwrap_or_else(|| hasher.hash_empty(tree_id, id))
+    };
+    Some(hasher.hash_children(&hash(&left), &hash(&right)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::hasher::coniks::ConiksHasher;
+    use crate::hasher::rfc6962::Rfc6962Hasher;
+    use crate::hasher::testonly::{build, key_id};
+    use crate::node::NodesRow;
+
+    use super::*;
+
+    const TREE_ID: i64 = 42;
+    const TILE_HEIGHT: usize = 8;
+
+    /// Perceptual hash keys, mapped to crypto hash values
+    const LEAVES: [(&[u8], &[u8]); 3] = [
+        (b"9cfde03dc4198467", b"crypto-hash-1"),
+        (b"9cfde03dc4198468", b"crypto-hash-2"),
+        (b"0000000000000000", b"crypto-hash-3"),
+    ];
+
+    /// The 8-level tiles on the key's path, down to the first one it is absent from
+    fn path_tiles(nodes: &HashMap<ID, [u8; 32]>, key: &ID) -> Vec<Tile> {
+        let mut tiles = Vec::new();
+        for depth in (0..BIT_LENGTH).step_by(TILE_HEIGHT) {
+            let id = key.prefix(depth);
+            let leaves = nodes
+                .iter()
+                .filter(|(n, _)| n.bit_length() == depth + TILE_HEIGHT && n.prefix(depth) == id)
+                .map(|(n, hash)| Arc::new(Node::new(n.clone(), *hash)))
+                .collect();
+            let leaves = NodesRow::try_new(leaves).unwrap();
+            let on_path = leaves
+                .0
+                .iter()
+                .any(|n| n.id == key.prefix(depth + TILE_HEIGHT));
+            tiles.push(Tile::new(id, leaves));
+            if !on_path {
+                break;
+            }
+        }
+        tiles
+    }
+
+    fn check_proofs<H: NodeHasher>(hasher: &H) {
+        let (root, nodes) = build(hasher, TREE_ID, &LEAVES);
+
+        for (key, value) in LEAVES {
+            let id = key_id(key);
+            let proof = prove(hasher, TREE_ID, &path_tiles(&nodes, &id), &id).unwrap();
+            verify(hasher, TREE_ID, &root, &id, Some(value), &proof).unwrap();
+            assert!(verify(hasher, TREE_ID, &root, &id, Some(b"forged"), &proof).is_err());
+            assert!(verify(hasher, TREE_ID, &root, &id, None, &proof).is_err());
+        }
+
+        let absent = key_id(b"ffffffffffffffff");
+        let tiles = path_tiles(&nodes, &absent);
+        assert!(tiles.len() < BIT_LENGTH / TILE_HEIGHT);
+        let proof = prove(hasher, TREE_ID, &tiles, &absent).unwrap();
+        verify(hasher, TREE_ID, &root, &absent, None, &proof).unwrap();
+        assert!(verify(
+            hasher,
+            TREE_ID,
+            &root,
+            &absent,
+            Some(b"crypto-hash-1"),
+            &proof
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn coniks_proofs() {
+        check_proofs(&ConiksHasher);
+
+        // CONIKS leaves commit to the tree they are in
+        let (root, nodes) = build(&ConiksHasher, TREE_ID, &LEAVES);
+        let (key, value) = LEAVES[0];
+        let id = key_id(key);
+        let proof = prove(&ConiksHasher, TREE_ID, &path_tiles(&nodes, &id), &id).unwrap();
+        assert!(verify(&ConiksHasher, TREE_ID + 1, &root, &id, Some(value), &proof).is_err());
+    }
+
+    #[test]
+    fn rfc6962_proofs() {
+        check_proofs(&Rfc6962Hasher::new());
+    }
+
+    #[test]
+    fn empty_tree_proof() {
+        let hasher = ConiksHasher;
+        let (root, _) = build(&hasher, TREE_ID, &[]);
+        let key = key_id(b"9cfde03dc4198467");
+        let tiles = [Tile::new(ID::default(), NodesRow(vec![]))];
+        let proof = prove(&hasher, TREE_ID, &tiles, &key).unwrap();
+        assert!(proof.siblings.iter().all(Option::is_none));
+        verify(&hasher, TREE_ID, &root, &key, None, &proof).unwrap();
+    }
+
+    #[test]
+    fn prove_checks_tiles() {
+        let hasher = Rfc6962Hasher::new();
+        let (_, nodes) = build(&hasher, TREE_ID, &LEAVES);
+        let key = key_id(LEAVES[0].0);
+        let mut tiles = path_tiles(&nodes, &key);
+
+        let err = prove(&hasher, TREE_ID, &tiles[1..], &key).unwrap_err();
+        assert!(
+            err.contains("not on the path at depth 0"),
+            "got error {err}"
+        );
+        let err = prove(
+            &hasher,
+            TREE_ID,
+            &path_tiles(&nodes, &key_id(LEAVES[2].0)),
+            &key,
+        )
+        .unwrap_err();
+        assert!(err.contains("not on the path"), "got error {err}");
+        tiles.pop();
+        let err = prove(&hasher, TREE_ID, &tiles, &key).unwrap_err();
+        assert!(
+            err.contains("missing the tile at depth 248"),
+            "got error {err}"
+        );
+        let err = prove(&hasher, TREE_ID, &tiles, &key.prefix(8)).unwrap_err();
+        assert!(err.contains("key invalid depth"), "got error {err}");
+    }
+
+    #[test]
+    fn verify_checks_proof_length() {
+        let hasher = Rfc6962Hasher::new();
+        let (root, _) = build(&hasher, TREE_ID, &[]);
+        let proof = Proof {
+            siblings: vec![None; BIT_LENGTH - 1],
+        };
+        let err = verify(&hasher, TREE_ID, &root, &key_id(b"key"), None, &proof).unwrap_err();
+        assert!(err.contains("proof has 255 siblings"), "got error {err}");
+    }
+}
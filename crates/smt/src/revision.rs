@@ -0,0 +1,399 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::hasher::{NodeHasher, BIT_LENGTH};
+use crate::hstar3::{HStar3, NodeAccessor};
+use crate::node::id::ID;
+use crate::node::{Node, NodesRow};
+use crate::tile::Tile;
+
+/// Hashes a node was written with, each with its revision, in ascending revision order
+type Versions = Vec<(u64, [u8; 32])>;
+
+/// RevisionedTree is a sparse Merkle tree that keeps every revision of itself,
+/// like a Trillian map. Revision 0 is the empty tree, and each committed Batch
+/// of updates makes the next revision. Any revision can be read, and a Batch is
+/// only visible once committed, so a failed one leaves the tree untouched.
+pub struct RevisionedTree<H> {
+    hasher: H,
+    tree_id: i64,
+    /// Hashes of the non-empty nodes below the root, keyed by depth and ID so
+    /// that the nodes of a level are contiguous.
+    nodes: BTreeMap<(usize, ID), Versions>,
+    /// Root hash at each revision, indexed by revision
+    roots: Vec<[u8; 32]>,
+}
+
+impl<H: NodeHasher> RevisionedTree<H> {
+    /// new returns the empty tree with the given ID, at revision 0.
+    pub fn new(hasher: H, tree_id: i64) -> Self {
+        let root = hasher.hash_empty(tree_id, &ID::default());
+        RevisionedTree {
+            hasher,
+            tree_id,
+            nodes: BTreeMap::new(),
+            roots: vec![root],
+        }
+    }
+
+    pub fn hasher(&self) -> &H {
+        &self.hasher
+    }
+
+    pub fn tree_id(&self) -> i64 {
+        self.tree_id
+    }
+
+    /// revision returns the latest revision of the tree.
+    pub fn revision(&self) -> u64 {
+        self.roots.len() as u64 - 1
+    }
+
+    /// root returns the root hash of the tree at the given revision, or None if
+    /// there is no such revision yet.
+    pub fn root(&self, revision: u64) -> Option<[u8; 32]> {
+        self.roots.get(usize::try_from(revision).ok()?).copied()
+    }
+
+    /// get returns the hash of the node at the given revision, or None if its
+    /// subtree was empty at that revision.
+    pub fn get(&self, id: &ID, revision: u64) -> Option<[u8; 32]> {
+        at_revision(self.nodes.get(&(id.bit_length(), id.clone()))?, revision)
+    }
+
+    /// tile returns the tile of the given height rooted at the given node, as
+    /// it was at the given revision.
+    pub fn tile(&self, id: &ID, height: usize, revision: u64) -> Result<Tile, String> {
+        self.check_revision(revision)?;
+        let depth = id.bit_length() + height;
+        if height == 0 || depth > BIT_LENGTH {
+            return Err(format!(
+                "tile at {id} of height {height} is out of the tree"
+            ));
+        }
+        let (first, last) = subtree_bounds(id, depth);
+        let leaves = self
+            .nodes
+            .range((depth, first)..=(depth, last))
+            .filter_map(|((_, id), versions)| {
+                at_revision(versions, revision).map(|hash| Arc::new(Node::new(id.clone(), hash)))
+            })
+            .collect();
+        Ok(Tile::new(id.clone(), NodesRow(leaves)))
+    }
+
+    /// path_tiles returns the tiles of the given height on the path of the key
+    /// at the given revision, from the root down to the first one that the key
+    /// is absent from, as needed to prove the key's value or absence.
+    pub fn path_tiles(&self, key: &ID, height: usize, revision: u64) -> Result<Vec<Tile>, String> {
+        if key.bit_length() != BIT_LENGTH {
+            return Err(format!(
+                "key invalid depth {}, want {}",
+                key.bit_length(),
+                BIT_LENGTH
+            ));
+        }
+        let mut tiles = Vec::new();
+        let mut depth = 0;
+        while depth < BIT_LENGTH {
+            let tile_height = height.min(BIT_LENGTH - depth);
+            let tile = self.tile(&key.prefix(depth), tile_height, revision)?;
+            let path = key.prefix(depth + tile_height);
+            let on_path = tile.leaves.0.iter().any(|n| n.id == path);
+            tiles.push(tile);
+            if !on_path {
+                break;
+            }
+            depth += tile_height;
+        }
+        Ok(tiles)
+    }
+
+    /// begin starts a batch of updates on top of the latest revision.
+    pub fn begin(&mut self) -> Batch<'_, H> {
+        Batch {
+            tree: self,
+            pending: HashMap::new(),
+            root: None,
+        }
+    }
+
+    fn check_revision(&self, revision: u64) -> Result<(), String> {
+        if revision > self.revision() {
+            Err(format!(
+                "revision {revision} is after the latest revision {}",
+                self.revision()
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Batch is a set of updates to a RevisionedTree, which become its next
+/// revision all at once on commit. Dropping the batch without committing it
+/// rolls all of its updates back.
+pub struct Batch<'a, H> {
+    tree: &'a mut RevisionedTree<H>,
+    /// Nodes written by the successful updates so far
+    pending: HashMap<ID, [u8; 32]>,
+    /// Root after the successful updates so far, if there were any
+    root: Option<[u8; 32]>,
+}
+
+impl<H: NodeHasher> Batch<'_, H> {
+    /// update sets the hashes of the given leaves, as computed by the tree's
+    /// hasher. If it fails, none of the given leaves are updated, but earlier
+    /// updates in the batch are kept.
+    pub fn update(&mut self, leaves: NodesRow) -> Result<(), String> {
+        if leaves.is_empty() {
+            return Ok(());
+        }
+        let hasher = &self.tree.hasher;
+        let hstar3 = HStar3::new(
+            leaves,
+            |left, right| hasher.hash_children(left, right),
+            BIT_LENGTH,
+            0,
+        )?;
+        let mut overlay = Overlay {
+            tree: &*self.tree,
+            pending: &self.pending,
+            written: HashMap::new(),
+        };
+        let top = hstar3.update(&mut overlay)?;
+        let written = overlay.written;
+        self.pending.extend(written);
+        self.root = Some(*top.0[0].hash());
+        Ok(())
+    }
+
+    /// commit stores the batch as the next revision of the tree, and returns
+    /// that revision.
+    pub fn commit(self) -> u64 {
+        let revision = self.tree.revision() + 1;
+        let root = self
+            .root
+            .unwrap_or(self.tree.roots[self.tree.roots.len() - 1]);
+        for (id, hash) in self.pending {
+            self.tree
+                .nodes
+                .entry((id.bit_length(), id))
+                .or_default()
+                .push((revision, hash));
+        }
+        self.tree.roots.push(root);
+        revision
+    }
+
+    /// rollback discards the batch, which is the same as dropping it.
+    pub fn rollback(self) {}
+}
+
+/// Overlay reads the latest revision of the tree under the batch's pending
+/// writes, and collects the writes of one update.
+struct Overlay<'a, H> {
+    tree: &'a RevisionedTree<H>,
+    pending: &'a HashMap<ID, [u8; 32]>,
+    written: HashMap<ID, [u8; 32]>,
+}
+
+impl<H: NodeHasher> NodeAccessor for Overlay<'_, H> {
+    fn get(&mut self, id: &ID) -> Result<[u8; 32], String> {
+        let hash = self
+            .written
+            .get(id)
+            .or_else(|| self.pending.get(id))
+            .copied()
+            .or_else(|| self.tree.get(id, self.tree.revision()))
+            .unwrap_or_else(|| self.tree.hasher.hash_empty(self.tree.tree_id, id));
+        Ok(hash)
+    }
+
+    fn set(&mut self, id: &ID, hash: &[u8; 32]) {
+        self.written.insert(id.clone(), *hash);
+    }
+}
+
+/// at_revision returns the hash a node had at the given revision, if it had
+/// been written by then.
+fn at_revision(versions: &[(u64, [u8; 32])], revision: u64) -> Option<[u8; 32]> {
+    let written = versions.partition_point(|(r, _)| *r <= revision);
+    written.checked_sub(1).map(|index| versions[index].1)
+}
+
+/// subtree_bounds returns the first and last IDs at the given depth under the node.
+fn subtree_bounds(id: &ID, depth: usize) -> (ID, ID) {
+    let mut first = [0_u8; BIT_LENGTH / 8];
+    let mut last = [0xFF_u8; BIT_LENGTH / 8];
+    let path = id.full_bytes();
+    first[..path.len()].copy_from_slice(&path);
+    last[..path.len()].copy_from_slice(&path);
+    let (last_byte, bits) = id.last_byte();
+    if bits != 0 {
+        first[path.len()] = last_byte;
+        last[path.len()] = last_byte | 0xFF_u8.checked_shr(u32::from(bits)).unwrap_or(0);
+    }
+    (ID::new_id(&first, depth), ID::new_id(&last, depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::coniks::ConiksHasher;
+    use crate::hasher::testonly::{build, key_id};
+    use crate::proof::{prove, verify};
+
+    use super::*;
+
+    const TREE_ID: i64 = 42;
+
+    fn leaves(hasher: &ConiksHasher, leaves: &[(&[u8], &[u8])]) -> NodesRow {
+        let nodes = leaves
+            .iter()
+            .map(|(key, value)| {
+                let id = key_id(key);
+                let hash = hasher.hash_leaf(TREE_ID, &id, value);
+                Arc::new(Node::new(id, hash))
+            })
+            .collect();
+        NodesRow::try_new(nodes).unwrap()
+    }
+
+    fn commit(tree: &mut RevisionedTree<ConiksHasher>, batch: &[(&[u8], &[u8])]) -> u64 {
+        let leaves = leaves(tree.hasher(), batch);
+        let mut batch = tree.begin();
+        batch.update(leaves).unwrap();
+        batch.commit()
+    }
+
+    #[test]
+    fn revisions_keep_their_roots() {
+        let mut tree = RevisionedTree::new(ConiksHasher, TREE_ID);
+        assert_eq!(tree.revision(), 0);
+        assert_eq!(Some(build(&ConiksHasher, TREE_ID, &[]).0), tree.root(0));
+
+        let first: [(&[u8], &[u8]); 2] = [(b"key1", b"value1"), (b"key2", b"value2")];
+        let second: [(&[u8], &[u8]); 2] = [(b"key1", b"value1b"), (b"key3", b"value3")];
+        assert_eq!(commit(&mut tree, &first), 1);
+        assert_eq!(commit(&mut tree, &second), 2);
+
+        let (want, _) = build(&ConiksHasher, TREE_ID, &first);
+        assert_eq!(tree.root(1), Some(want));
+        let (want, _) = build(
+            &ConiksHasher,
+            TREE_ID,
+            &[
+                (b"key1", b"value1b"),
+                (b"key2", b"value2"),
+                (b"key3", b"value3"),
+            ],
+        );
+        assert_eq!(tree.root(2), Some(want));
+        assert_eq!(tree.root(3), None);
+
+        let key3 = key_id(b"key3");
+        assert_eq!(tree.get(&key3, 1), None);
+        assert_eq!(
+            tree.get(&key3, 2),
+            Some(ConiksHasher.hash_leaf(TREE_ID, &key3, b"value3"))
+        );
+    }
+
+    #[test]
+    fn proofs_at_old_revisions() {
+        let mut tree = RevisionedTree::new(ConiksHasher, TREE_ID);
+        commit(&mut tree, &[(b"key1", b"value1"), (b"key2", b"value2")]);
+        commit(&mut tree, &[(b"key1", b"value1b"), (b"key3", b"value3")]);
+
+        let test_cases = [
+            (1, &b"key1"[..], Some(&b"value1"[..])),
+            (1, b"key3", None),
+            (2, b"key1", Some(b"value1b")),
+            (2, b"key2", Some(b"value2")),
+            (2, b"key3", Some(b"value3")),
+            (0, b"key1", None),
+        ];
+        for (revision, key, value) in test_cases {
+            let key = key_id(key);
+            let tiles = tree.path_tiles(&key, 8, revision).unwrap();
+            let proof = prove(&ConiksHasher, TREE_ID, &tiles, &key).unwrap();
+            let root = tree.root(revision).unwrap();
+            verify(&ConiksHasher, TREE_ID, &root, &key, value, &proof).unwrap();
+        }
+
+        let err = tree.path_tiles(&key_id(b"key1"), 8, 3).unwrap_err();
+        assert!(err.contains("after the latest revision"), "got error {err}");
+    }
+
+    #[test]
+    fn uneven_tile_heights() {
+        let mut tree = RevisionedTree::new(ConiksHasher, TREE_ID);
+        commit(&mut tree, &[(b"key1", b"value1"), (b"key2", b"value2")]);
+        let key = key_id(b"key2");
+        let tiles = tree.path_tiles(&key, 100, 1).unwrap();
+        assert_eq!(tiles.len(), 3);
+        let proof = prove(&ConiksHasher, TREE_ID, &tiles, &key).unwrap();
+        verify(
+            &ConiksHasher,
+            TREE_ID,
+            &tree.root(1).unwrap(),
+            &key,
+            Some(b"value2"),
+            &proof,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rollback_leaves_the_tree_untouched() {
+        let mut tree = RevisionedTree::new(ConiksHasher, TREE_ID);
+        commit(&mut tree, &[(b"key1", b"value1")]);
+        let root = tree.root(1);
+
+        let update = leaves(tree.hasher(), &[(b"key2", b"value2")]);
+        let mut batch = tree.begin();
+        batch.update(update).unwrap();
+        batch.rollback();
+        assert_eq!(tree.revision(), 1);
+        assert_eq!(tree.root(1), root);
+        assert_eq!(tree.get(&key_id(b"key2"), 1), None);
+        assert_eq!(tree.tile(&ID::default(), 8, 1).unwrap().leaves.len(), 1);
+    }
+
+    #[test]
+    fn failed_update_keeps_earlier_updates() {
+        let mut tree = RevisionedTree::new(ConiksHasher, TREE_ID);
+        let good = leaves(tree.hasher(), &[(b"key1", b"value1")]);
+        let bad =
+            NodesRow::try_new(vec![Arc::new(Node::new(ID::new_id(&[0], 8), [0; 32]))]).unwrap();
+
+        let mut batch = tree.begin();
+        batch.update(good).unwrap();
+        let err = batch.update(bad).unwrap_err();
+        assert!(err.contains("invalid depth"), "got error {err}");
+        assert_eq!(batch.commit(), 1);
+
+        assert_eq!(
+            tree.root(1),
+            Some(build(&ConiksHasher, TREE_ID, &[(b"key1", b"value1")]).0)
+        );
+    }
+
+    #[test]
+    fn empty_commit_keeps_the_root() {
+        let mut tree = RevisionedTree::new(ConiksHasher, TREE_ID);
+        commit(&mut tree, &[(b"key1", b"value1")]);
+        assert_eq!(tree.begin().commit(), 2);
+        assert_eq!(tree.root(2), tree.root(1));
+    }
+
+    #[test]
+    fn subtree_bounds_span_the_subtree() {
+        let (first, last) = subtree_bounds(&ID::new_id(b"\xAB\xCD", 12), 16);
+        assert_eq!(first, ID::new_id(b"\xAB\xC0", 16));
+        assert_eq!(last, ID::new_id(b"\xAB\xCF", 16));
+        let (first, last) = subtree_bounds(&ID::default(), 8);
+        assert_eq!(first, ID::new_id(b"\x00", 8));
+        assert_eq!(last, ID::new_id(b"\xFF", 8));
+    }
+}
@@ -1,17 +1,24 @@
 use std::sync::Arc;
 
 use itertools::{EitherOrBoth, Itertools};
+use rayon::prelude::*;
 
 use crate::node::id::ID;
 use crate::node::{Node, NodesRow};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Tile {
-    id: ID,
-    leaves: NodesRow,
+    pub(crate) id: ID,
+    pub(crate) leaves: NodesRow,
 }
 
 impl Tile {
+    /// new returns the tile rooted at the given node, holding the given nodes at
+    /// its bottom level. Nodes that are not stored are empty subtrees.
+    pub fn new(id: ID, leaves: NodesRow) -> Self {
+        Tile { id, leaves }
+    }
+
     /// Take the updates nodes in the NodesRow and update the Tile leaves
     pub fn merge(&mut self, updates: NodesRow) -> Result<(), String> {
         // Do nothing if there's no update
@@ -42,6 +49,22 @@ impl Tile {
     }
 }
 
+/// Merge each row of updates into the tile at the same position. Tiles are independent of each
+/// other, so they are merged in parallel.
+pub fn merge_all(tiles: &mut [Tile], updates: Vec<NodesRow>) -> Result<(), String> {
+    if tiles.len() != updates.len() {
+        return Err(format!(
+            "{} rows of updates for {} tiles",
+            updates.len(),
+            tiles.len()
+        ));
+    }
+    tiles
+        .par_iter_mut()
+        .zip(updates)
+        .try_for_each(|(tile, updates)| tile.merge(updates))
+}
+
 /// Merge two sorted NodesRow into a new, sorted, NodesRow, taking updated values
 fn merge(nodes: &NodesRow, update: &NodesRow) -> Result<NodesRow, String> {
     let merged = nodes
@@ -137,6 +160,42 @@ mod test {
         }
     }
 
+    #[test]
+    fn merge_all_tiles() {
+        let other_id = ID::new_id(TEST_IDS[5], 15).prefix(8);
+        let mut tiles = vec![
+            Tile::new(test_id(), NodesRow(arc_node(vec![test_node(0, "old0")]))),
+            Tile::new(other_id.clone(), NodesRow(vec![])),
+        ];
+        let updates = vec![
+            NodesRow::try_new(arc_node(vec![test_node(1, "new1")])).unwrap(),
+            NodesRow::try_new(arc_node(vec![test_node(5, "new5")])).unwrap(),
+        ];
+        merge_all(&mut tiles, updates).unwrap();
+        assert_eq!(
+            tiles,
+            vec![
+                Tile::new(
+                    test_id(),
+                    NodesRow(arc_node(vec![test_node(0, "old0"), test_node(1, "new1")]))
+                ),
+                Tile::new(other_id, NodesRow(arc_node(vec![test_node(5, "new5")]))),
+            ]
+        );
+
+        let err = merge_all(&mut tiles, vec![]).unwrap_err();
+        assert!(
+            err.contains("0 rows of updates for 2 tiles"),
+            "got error {err}"
+        );
+        let wrong_tile = vec![
+            NodesRow(vec![]),
+            NodesRow::try_new(arc_node(vec![test_node(2, "new2")])).unwrap(),
+        ];
+        let err = merge_all(&mut tiles, wrong_tile).unwrap_err();
+        assert!(err.contains("not entirely in this tile"), "got error {err}");
+    }
+
     tile_merge_tests! {
         empty_merge_empty: (vec![], vec![], vec![], ""),
         no_updates: (vec![test_node(3, "h")], vec![], vec![test_node(3, "h")], ""),
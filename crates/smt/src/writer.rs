@@ -0,0 +1,205 @@
+use rayon::prelude::*;
+
+use crate::hstar3::{HStar3, NodeAccessor};
+use crate::node::id::ID;
+use crate::node::NodesRow;
+
+/// Writer applies batches of node updates to a sparse Merkle tree in two stages.
+/// The subtrees rooted at the split depth are independent of each other, so
+/// their updates are propagated up to the split depth in parallel on rayon. The
+/// resulting subtree roots are then propagated up to the root of the tree.
+pub struct Writer<F> {
+    hash: F,
+    depth: usize,
+    split: usize,
+}
+
+impl<F> Writer<F>
+where
+    F: Fn(&[u8; 32], &[u8; 32]) -> [u8; 32] + Sync,
+{
+    /// new returns a Writer for updates at the given depth, sharding them by
+    /// their subtree at the split depth. hash computes a node's hash from its
+    /// left and right children.
+    pub fn new(hash: F, depth: usize, split: usize) -> Result<Self, String> {
+        if split > depth {
+            return Err(format!("split > depth: {split} vs. {depth}"));
+        }
+        Ok(Writer { hash, depth, split })
+    }
+
+    /// split partitions the updates into shards, one for each subtree at the
+    /// split depth that they touch, in order.
+    pub fn split(&self, nodes: NodesRow) -> Result<Vec<NodesRow>, String> {
+        if let Some(node) = nodes.0.first() {
+            if node.id.bit_length() != self.depth {
+                return Err(format!(
+                    "node invalid depth {}, want {}",
+                    node.id.bit_length(),
+                    self.depth
+                ));
+            }
+        }
+
+        let mut shards: Vec<NodesRow> = Vec::new();
+        for node in nodes.0 {
+            match shards.last_mut() {
+                Some(shard) if shard.0[0].id.prefix(self.split) == node.id.prefix(self.split) => {
+                    shard.0.push(node)
+                }
+                _ => shards.push(NodesRow(vec![node])),
+            }
+        }
+        Ok(shards)
+    }
+
+    /// write applies the updates to the tree and returns the updated root, or
+    /// an empty row if there were no updates. accessor returns the NodeAccessor
+    /// for the subtree rooted at the given node: once for each shard, possibly
+    /// concurrently, and once with the empty ID for the part of the tree above
+    /// the split depth.
+    pub fn write<A, N>(&self, nodes: NodesRow, accessor: N) -> Result<NodesRow, String>
+    where
+        A: NodeAccessor,
+        N: Fn(&ID) -> A + Sync,
+    {
+        let shards = self.split(nodes)?;
+        let roots = shards
+            .into_par_iter()
+            .map(|shard| {
+                let root = shard.0[0].id.prefix(self.split);
+                let hstar3 = HStar3::new(shard, &self.hash, self.depth, self.split)?;
+                let mut top = hstar3
+                    .update(&mut accessor(&root))
+                    .map_err(|err| format!("shard {root}: {err}"))?;
+                // Each shard propagates up to a single node at the split depth
+                Ok(top.0.remove(0))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let hstar3 = HStar3::new(NodesRow(roots), &self.hash, self.split, 0)?;
+        hstar3.update(&mut accessor(&ID::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use sha2::{Digest, Sha256};
+
+    use crate::hasher::rfc6962::Rfc6962Hasher;
+    use crate::hasher::{NodeHasher, BIT_LENGTH};
+    use crate::node::Node;
+
+    use super::*;
+
+    /// A tree in memory shared by all the shards, whose unset nodes are empty
+    struct SharedTree<'a> {
+        hasher: &'a Rfc6962Hasher,
+        hashes: &'a Mutex<HashMap<ID, [u8; 32]>>,
+    }
+
+    impl NodeAccessor for SharedTree<'_> {
+        fn get(&mut self, id: &ID) -> Result<[u8; 32], String> {
+            let hashes = self.hashes.lock().unwrap();
+            Ok(hashes
+                .get(id)
+                .copied()
+                .unwrap_or_else(|| self.hasher.hash_empty(0, id)))
+        }
+
+        fn set(&mut self, id: &ID, hash: &[u8; 32]) {
+            self.hashes.lock().unwrap().insert(id.clone(), *hash);
+        }
+    }
+
+    fn leaves(hasher: &Rfc6962Hasher, range: std::ops::Range<u32>) -> NodesRow {
+        let nodes = range
+            .map(|i| {
+                let id = ID::new_id(&Sha256::digest(i.to_be_bytes()), BIT_LENGTH);
+                let hash = hasher.hash_leaf(0, &id, &i.to_be_bytes());
+                Arc::new(Node::new(id, hash))
+            })
+            .collect();
+        NodesRow::try_new(nodes).unwrap()
+    }
+
+    /// Apply the batches with the writer, returning every root and the stored nodes
+    fn write_batches(
+        split: usize,
+        batches: &[std::ops::Range<u32>],
+    ) -> (Vec<[u8; 32]>, HashMap<ID, [u8; 32]>) {
+        let hasher = Rfc6962Hasher::new();
+        let hashes = Mutex::new(HashMap::new());
+        let writer = Writer::new(
+            |l: &[u8; 32], r: &[u8; 32]| hasher.hash_children(l, r),
+            BIT_LENGTH,
+            split,
+        )
+        .unwrap();
+        let roots = batches
+            .iter()
+            .map(|batch| {
+                let top = writer
+                    .write(leaves(&hasher, batch.clone()), |_: &ID| SharedTree {
+                        hasher: &hasher,
+                        hashes: &hashes,
+                    })
+                    .unwrap();
+                assert_eq!(top.len(), 1);
+                *top.0[0].hash()
+            })
+            .collect();
+        (roots, hashes.into_inner().unwrap())
+    }
+
+    #[test]
+    fn parallel_write_matches_sequential_update() {
+        let batches = [0..500, 500..520, 10..40];
+        let sequential = write_batches(0, &batches);
+        for split in [1, 8, 16, BIT_LENGTH] {
+            assert_eq!(write_batches(split, &batches), sequential, "split {split}");
+        }
+    }
+
+    #[test]
+    fn split_shards_by_subtree() {
+        let hasher = Rfc6962Hasher::new();
+        let writer = Writer::new(
+            |l: &[u8; 32], r: &[u8; 32]| hasher.hash_children(l, r),
+            BIT_LENGTH,
+            1,
+        )
+        .unwrap();
+        let shards = writer.split(leaves(&hasher, 0..100)).unwrap();
+        assert_eq!(shards.len(), 2);
+        assert_eq!(shards[0].len() + shards[1].len(), 100);
+        assert!(shards[0].0.iter().all(|n| n.id.prefix(1).is_left_child()));
+        assert!(shards[1].0.iter().all(|n| !n.id.prefix(1).is_left_child()));
+
+        assert!(writer.split(NodesRow(vec![])).unwrap().is_empty());
+        let err = writer
+            .split(
+                NodesRow::try_new(vec![Arc::new(Node::new(ID::new_id(&[0], 8), [0; 32]))]).unwrap(),
+            )
+            .unwrap_err();
+        assert!(err.contains("invalid depth"), "got error {err}");
+        assert!(Writer::new(|l: &[u8; 32], _: &[u8; 32]| *l, 8, 9).is_err());
+    }
+
+    #[test]
+    fn empty_write() {
+        let writer = Writer::new(|l: &[u8; 32], _: &[u8; 32]| *l, BIT_LENGTH, 8).unwrap();
+        let hasher = Rfc6962Hasher::new();
+        let hashes = Mutex::new(HashMap::new());
+        let top = writer
+            .write(NodesRow(vec![]), |_: &ID| SharedTree {
+                hasher: &hasher,
+                hashes: &hashes,
+            })
+            .unwrap();
+        assert_eq!(top.len(), 0);
+    }
+}
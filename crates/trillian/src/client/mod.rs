@@ -1,120 +1,230 @@
-use std::process;
-use std::time::Duration;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use dyn_clone::DynClone;
 use eyre::{Report, Result};
+use futures::StreamExt;
+use metrics::{histogram, increment_counter};
 use thiserror::Error;
-use tonic::transport::{Channel, Endpoint, Uri};
-use tonic::{Request, Status};
+use tonic::codegen::http::uri::InvalidUri;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Uri};
+use tonic::{Code, Request, Status};
 use tracing::{debug, error, instrument, trace};
+use veracity_verify::VerifyError;
 
+use crate::log_root::{self, LogRootV1};
 use crate::{
-    protobuf::trillian,
     protobuf::trillian::trillian_admin_client::TrillianAdminClient,
     protobuf::trillian::trillian_log_client::TrillianLogClient,
     protobuf::trillian::{
-        CreateTreeRequest, ListTreesRequest, LogLeaf, QueueLeafRequest, Tree, TreeState, TreeType,
+        AddSequencedLeavesRequest, CreateTreeRequest, DeleteTreeRequest,
+        GetConsistencyProofRequest, GetEntryAndProofRequest, GetInclusionProofByHashRequest,
+        GetInclusionProofRequest, GetLatestSignedLogRootRequest, GetLeavesByRangeRequest,
+        GetTreeRequest, InitLogRequest, ListTreesRequest, LogLeaf, QueueLeafRequest, QueuedLogLeaf,
+        Tree, TreeState, UndeleteTreeRequest, UpdateTreeRequest,
     },
-    TrillianLogLeaf, TrillianTree,
+    TrillianChargeTo, TrillianLogLeaf, TrillianProof, TrillianTree, TrillianTreeState,
+    TrillianTreeType,
 };
 
+/// Most `QueueLeaf` requests [`TrillianClientApiMethods::add_leaves`] keeps in flight at once
+pub const MAX_CONCURRENT_QUEUE_LEAF: usize = 16;
+/// How long an RPC may take unless the client is built with another timeout
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Trillian's gRPC server closes connections that ping more often than every five minutes
+const HTTP2_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(300);
+const HTTP2_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Called with every admin and log request before it is sent, to attach metadata such as auth
+/// tokens or tenant headers. Returning an error fails the call without sending it.
+pub type RequestInterceptor = Arc<dyn Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync>;
+
+type AdminClient = TrillianAdminClient<InterceptedService<Channel, ClientInterceptor>>;
+type LogClient = TrillianLogClient<InterceptedService<Channel, ClientInterceptor>>;
+
 #[derive(Builder)]
 #[builder(custom_constructor, build_fn(private, name = "fallible_build"))]
 pub struct TrillianClient {
-    #[builder(setter(custom))]
-    admin_client: TrillianAdminClient<Channel>,
-    #[builder(setter(custom))]
-    log_client: TrillianLogClient<Channel>,
+    /// Configured as an endpoint on the builder, and connected on first use
+    #[builder(
+        setter(custom),
+        field(type = "Option<Endpoint>", build = "self.connect()")
+    )]
+    channel: Channel,
+    #[builder(default, setter(custom))]
+    interceptor: ClientInterceptor,
+    /// Longest any RPC may take, unlimited when `None`
+    #[builder(default = "Some(DEFAULT_RPC_TIMEOUT)")]
+    timeout: Option<Duration>,
 }
 
 impl Clone for TrillianClient {
     fn clone(&self) -> Self {
-        // Cloning the clients should be lightweight https://github.com/hyperium/tonic/issues/33
+        // Cloning the channel should be lightweight https://github.com/hyperium/tonic/issues/33
         TrillianClient {
-            log_client: self.log_client.clone(),
-            admin_client: self.admin_client.clone(),
+            channel: self.channel.clone(),
+            interceptor: self.interceptor.clone(),
+            timeout: self.timeout,
         }
     }
 }
 
-impl TrillianClient {
-    #[instrument(skip(host))]
-    pub async fn new(host: impl Into<String>) -> Result<TrillianClientBuilder> {
-        let host = host.into();
-        // Create Tonic endpoint
-        trace!("Creating host uri from {}", &host);
-        let host_uri = match Uri::try_from(host.clone()) {
-            Ok(x) => x,
-            Err(err) => {
-                error!("Could not create URI: {}", err.to_string());
-                process::exit(1);
-            }
-        };
-        debug!("Connecting to host uri {}", &host_uri);
-        let endpoint = Endpoint::from(host_uri);
+/// The interceptor installed with [`TrillianClientBuilder::interceptor`], if any
+#[derive(Clone, Default)]
+struct ClientInterceptor(Option<RequestInterceptor>);
 
-        let admin_client = match TrillianAdminClient::connect(endpoint.clone()).await {
-            Ok(x) => {
-                trace!("Successfully connected Admin client");
-                x
-            }
-            Err(err) => {
-                error!("Could not connect Admin client");
-                return Err(Report::from(err));
-            }
-        };
-        let log_client =
-            match trillian::trillian_log_client::TrillianLogClient::connect(endpoint.clone()).await
-            {
-                Ok(x) => {
-                    trace!("Successfully connected Log client");
-                    x
-                }
-                Err(err) => {
-                    error!("Could not connect Admin client");
-                    return Err(Report::from(err));
-                }
-            };
+impl Interceptor for ClientInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match &self.0 {
+            Some(interceptor) => interceptor(request),
+            None => Ok(request),
+        }
+    }
+}
+
+/// TLS settings for `https://` Trillian addresses. The defaults trust the system roots and
+/// present no client certificate.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    /// PEM bundle of CA certificates to trust instead of the system roots
+    pub ca_certificate: Option<Vec<u8>>,
+    /// PEM certificate and private key to present, for servers requiring mutual TLS
+    pub client_identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// Name to send as SNI and expect in the server certificate, the host of the address when
+    /// unset
+    pub domain_name: Option<String>,
+}
+
+impl TrillianClient {
+    /// Create a client without connecting. The connection is made on first use and
+    /// re-established after failures, so an unreachable server does not stop the caller from
+    /// starting; requests fail until it can be reached.
+    #[instrument(skip(host, tls))]
+    pub fn new(
+        host: impl Into<String>,
+        tls: &TlsOptions,
+    ) -> Result<TrillianClientBuilder, TrillianClientError> {
+        let host_uri = Uri::try_from(host.into())?;
+        debug!("Lazily connecting to host uri {}", &host_uri);
+        let endpoint = endpoint(host_uri, tls)?;
         trace!("Created Trillian client builder");
         Ok(TrillianClientBuilder {
-            admin_client: Some(admin_client),
-            log_client: Some(log_client),
+            channel: Some(endpoint),
+            ..TrillianClientBuilder::create_empty()
         })
     }
+
+    fn admin_client(&self) -> AdminClient {
+        TrillianAdminClient::with_interceptor(self.channel.clone(), self.interceptor.clone())
+    }
+
+    fn log_client(&self) -> LogClient {
+        TrillianLogClient::with_interceptor(self.channel.clone(), self.interceptor.clone())
+    }
 }
 
 #[async_trait]
 impl TrillianClientApiMethods for TrillianClient {
-    async fn add_leaf(&mut self, id: &i64, data: &[u8], extra_data: &[u8]) -> Result<LogLeaf> {
-        let request = form_leaf(*id, data, extra_data);
-        let response = match self.log_client.queue_leaf(request).await {
-            Ok(x) => {
-                trace!("Received response {:?}", x);
-                x
-            }
-            Err(err) => {
-                return Err(Report::from(TrillianClientError::BadStatus(err)));
-            }
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    async fn add_leaf(
+        &mut self,
+        id: &i64,
+        data: &[u8],
+        extra_data: &[u8],
+        identity_hash: Option<&[u8]>,
+        charge_to: Option<TrillianChargeTo>,
+    ) -> Result<QueuedLeaf> {
+        queue_leaf(
+            &mut self.log_client(),
+            with_timeout(
+                form_leaf(*id, data, extra_data, identity_hash, charge_to),
+                self.timeout,
+            ),
+        )
+        .await
+    }
+
+    async fn add_leaves(
+        &mut self,
+        id: &i64,
+        leaves: Vec<(Vec<u8>, Vec<u8>)>,
+        charge_to: Option<TrillianChargeTo>,
+    ) -> Vec<Result<QueuedLeaf>> {
+        // Requests share the channel, which multiplexes them over one HTTP/2 connection
+        futures::stream::iter(leaves)
+            .map(|(data, extra_data)| {
+                let mut log_client = self.log_client();
+                let request = with_timeout(
+                    form_leaf(*id, &data, &extra_data, None, charge_to.clone()),
+                    self.timeout,
+                );
+                async move { queue_leaf(&mut log_client, request).await }
+            })
+            .buffered(MAX_CONCURRENT_QUEUE_LEAF)
+            .collect()
+            .await
+    }
+
+    async fn add_sequenced_leaves(
+        &mut self,
+        id: &i64,
+        leaves: Vec<TrillianLogLeaf>,
+        charge_to: Option<TrillianChargeTo>,
+    ) -> Result<Vec<Result<QueuedLeaf>>> {
+        // Only the caller's content and position are sent, Trillian fills in the rest
+        let leaves = leaves
+            .into_iter()
+            .map(|leaf| LogLeaf {
+                leaf_index: leaf.leaf_index,
+                leaf_value: leaf.leaf_value,
+                extra_data: leaf.extra_data,
+                leaf_identity_hash: leaf.leaf_identity_hash,
+                ..LogLeaf::default()
+            })
+            .collect();
+        let request = Request::new(AddSequencedLeavesRequest {
+            log_id: *id,
+            leaves,
+            charge_to,
+        });
+        let request = with_timeout(request, self.timeout);
+        let response = match observe(
+            "AddSequencedLeaves",
+            self.log_client().add_sequenced_leaves(request),
+        )
+        .await
+        {
+            Ok(x) => x,
+            Err(err) => return Err(Report::from(TrillianClientError::BadStatus(err))),
         };
-        let leaf = response.into_inner().queued_leaf.unwrap().leaf.unwrap();
-
-        debug!(
-            "Queued leaf index: {}, Merkle hash:{:x?}, QueueTs:{:?} IntegrateTs:{:?}",
-            &leaf.leaf_index,
-            &leaf.leaf_identity_hash,
-            &leaf.queue_timestamp,
-            &leaf.integrate_timestamp
-        );
-        Ok(leaf)
+        Ok(response
+            .into_inner()
+            .results
+            .into_iter()
+            .map(|result| QueuedLeaf::try_from(result).map_err(Report::from))
+            .collect())
     }
 
-    async fn create_tree(&mut self, name: &str, description: &str) -> Result<Tree> {
+    async fn create_tree(
+        &mut self,
+        name: &str,
+        description: &str,
+        tree_type: TrillianTreeType,
+    ) -> Result<Tree> {
         trace!("Creating create_tree_request");
-        let request = create_tree_request(name, description);
+        let request = create_tree_request(name, description, tree_type);
 
         trace!("Sending request {:?}", request);
-        let response = match self.admin_client.create_tree(request).await {
+        let request = with_timeout(request, self.timeout);
+        let response = match observe("CreateTree", self.admin_client().create_tree(request)).await {
             Ok(x) => {
                 trace!("Received response");
                 x
@@ -128,11 +238,12 @@ impl TrillianClientApiMethods for TrillianClient {
         trace!("Created tree {:?}", &tree);
 
         // New trees must be initialized by a log_client
-        let request = Request::new(trillian::InitLogRequest {
+        let request = Request::new(InitLogRequest {
             log_id: tree.tree_id,
             charge_to: None,
         });
-        match self.log_client.init_log(request).await {
+        let request = with_timeout(request, self.timeout);
+        match observe("InitLog", self.log_client().init_log(request)).await {
             Ok(x) => {
                 debug!("Initialized the new tree");
                 x
@@ -151,7 +262,8 @@ impl TrillianClientApiMethods for TrillianClient {
         let request = list_tree_request();
 
         trace!("Sending request {:?}", request);
-        let response = match self.admin_client.list_trees(request).await {
+        let request = with_timeout(request, self.timeout);
+        let response = match observe("ListTrees", self.admin_client().list_trees(request)).await {
             Ok(x) => {
                 trace!("Received response");
                 x
@@ -170,9 +282,371 @@ impl TrillianClientApiMethods for TrillianClient {
         debug! {"{trees:?}"}
         Ok(trees)
     }
+
+    async fn get_tree(&mut self, id: &i64) -> Result<Tree> {
+        let request = Request::new(GetTreeRequest { tree_id: *id });
+        let request = with_timeout(request, self.timeout);
+        match observe("GetTree", self.admin_client().get_tree(request)).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+
+    async fn update_tree(&mut self, id: &i64, update: TreeUpdate) -> Result<Tree> {
+        let request = update_tree_request(*id, update)?;
+        trace!("Sending request {:?}", request);
+        let request = with_timeout(request, self.timeout);
+        match observe("UpdateTree", self.admin_client().update_tree(request)).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+
+    async fn freeze_tree(&mut self, id: &i64) -> Result<Tree> {
+        self.update_tree(
+            id,
+            TreeUpdate {
+                tree_state: Some(TreeState::Frozen),
+                ..TreeUpdate::default()
+            },
+        )
+        .await
+    }
+
+    async fn delete_tree(&mut self, id: &i64) -> Result<Tree> {
+        let request = Request::new(DeleteTreeRequest { tree_id: *id });
+        let request = with_timeout(request, self.timeout);
+        match observe("DeleteTree", self.admin_client().delete_tree(request)).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+
+    async fn undelete_tree(&mut self, id: &i64) -> Result<Tree> {
+        let request = Request::new(UndeleteTreeRequest { tree_id: *id });
+        let request = with_timeout(request, self.timeout);
+        match observe("UndeleteTree", self.admin_client().undelete_tree(request)).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+
+    async fn get_tree_size(&mut self, id: &i64) -> Result<i64> {
+        Ok(self.get_latest_root(id).await?.tree_size)
+    }
+
+    async fn get_latest_root(&mut self, id: &i64) -> Result<LogRoot> {
+        let signed = self.get_latest_signed_log_root(id, None).await?;
+        Ok(LogRoot::try_from(signed.root)?)
+    }
+
+    async fn get_latest_signed_log_root(
+        &mut self,
+        id: &i64,
+        first_tree_size: Option<i64>,
+    ) -> Result<SignedRoot> {
+        let request = Request::new(GetLatestSignedLogRootRequest {
+            log_id: *id,
+            first_tree_size: first_tree_size.unwrap_or_default(),
+            ..GetLatestSignedLogRootRequest::default()
+        });
+        let request = with_timeout(request, self.timeout);
+        let response = match observe(
+            "GetLatestSignedLogRoot",
+            self.log_client().get_latest_signed_log_root(request),
+        )
+        .await
+        {
+            Ok(x) => x.into_inner(),
+            Err(err) => {
+                return Err(Report::from(TrillianClientError::BadStatus(err)));
+            }
+        };
+        let signed_log_root = response
+            .signed_log_root
+            .ok_or(TrillianClientError::InvalidLogRoot)?;
+        let root = log_root::parse(&signed_log_root).map_err(TrillianClientError::from)?;
+        trace!("Tree {} has size {}", id, root.tree_size);
+        Ok(SignedRoot {
+            root,
+            log_root: signed_log_root.log_root,
+            consistency_proof: response.proof.map(|proof| proof.hashes),
+        })
+    }
+
+    async fn get_inclusion_proof_by_hash(
+        &mut self,
+        id: &i64,
+        leaf_hash: &[u8],
+        tree_size: i64,
+    ) -> Result<Vec<TrillianProof>> {
+        let request = Request::new(GetInclusionProofByHashRequest {
+            log_id: *id,
+            leaf_hash: leaf_hash.to_vec(),
+            tree_size,
+            order_by_sequence: true,
+            ..GetInclusionProofByHashRequest::default()
+        });
+        let request = with_timeout(request, self.timeout);
+        match observe(
+            "GetInclusionProofByHash",
+            self.log_client().get_inclusion_proof_by_hash(request),
+        )
+        .await
+        {
+            Ok(x) => Ok(x.into_inner().proof),
+            // Leaves that are still queued have no proof yet
+            Err(err) if err.code() == Code::NotFound => Ok(vec![]),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+
+    async fn get_inclusion_proof(
+        &mut self,
+        id: &i64,
+        leaf_index: i64,
+        tree_size: i64,
+    ) -> Result<TrillianProof> {
+        let request = Request::new(GetInclusionProofRequest {
+            log_id: *id,
+            leaf_index,
+            tree_size,
+            ..GetInclusionProofRequest::default()
+        });
+        let request = with_timeout(request, self.timeout);
+        match observe(
+            "GetInclusionProof",
+            self.log_client().get_inclusion_proof(request),
+        )
+        .await
+        {
+            Ok(x) => x
+                .into_inner()
+                .proof
+                .ok_or_else(|| Report::from(TrillianClientError::MissingProof)),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+
+    async fn get_entry_and_proof(
+        &mut self,
+        id: &i64,
+        leaf_index: i64,
+        tree_size: i64,
+    ) -> Result<EntryAndProof> {
+        let request = Request::new(GetEntryAndProofRequest {
+            log_id: *id,
+            leaf_index,
+            tree_size,
+            ..GetEntryAndProofRequest::default()
+        });
+        let request = with_timeout(request, self.timeout);
+        let response = match observe(
+            "GetEntryAndProof",
+            self.log_client().get_entry_and_proof(request),
+        )
+        .await
+        {
+            Ok(x) => x.into_inner(),
+            Err(err) => return Err(Report::from(TrillianClientError::BadStatus(err))),
+        };
+        Ok(EntryAndProof {
+            leaf: response.leaf.ok_or(TrillianClientError::MissingLeaf)?,
+            proof: response.proof.ok_or(TrillianClientError::MissingProof)?,
+        })
+    }
+
+    async fn get_consistency_proof(
+        &mut self,
+        id: &i64,
+        first_tree_size: i64,
+        second_tree_size: i64,
+    ) -> Result<Vec<Vec<u8>>> {
+        let request = Request::new(GetConsistencyProofRequest {
+            log_id: *id,
+            first_tree_size,
+            second_tree_size,
+            ..GetConsistencyProofRequest::default()
+        });
+        let request = with_timeout(request, self.timeout);
+        match observe(
+            "GetConsistencyProof",
+            self.log_client().get_consistency_proof(request),
+        )
+        .await
+        {
+            // The proof is missing when the server handling the request has not seen the
+            // second tree size yet
+            Ok(x) => x
+                .into_inner()
+                .proof
+                .map(|proof| proof.hashes)
+                .ok_or_else(|| Report::from(TrillianClientError::MissingProof)),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+
+    async fn get_leaves_by_range(
+        &mut self,
+        id: &i64,
+        start_index: i64,
+        count: i64,
+    ) -> Result<Vec<TrillianLogLeaf>> {
+        let request = Request::new(GetLeavesByRangeRequest {
+            log_id: *id,
+            start_index,
+            count,
+            ..GetLeavesByRangeRequest::default()
+        });
+        let request = with_timeout(request, self.timeout);
+        match observe(
+            "GetLeavesByRange",
+            self.log_client().get_leaves_by_range(request),
+        )
+        .await
+        {
+            Ok(x) => Ok(x.into_inner().leaves),
+            Err(err) => Err(Report::from(TrillianClientError::BadStatus(err))),
+        }
+    }
+}
+
+/// The parts of a signed log root clients act on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogRoot {
+    /// Number of leaves integrated into the tree
+    pub tree_size: i64,
+    /// Merkle root hash over those leaves
+    pub root_hash: Vec<u8>,
+}
+
+/// A log root exactly as Trillian signed it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignedRoot {
+    /// Parsed form of `log_root`
+    pub root: LogRootV1,
+    /// TLS-serialized `LogRootV1` bytes, as returned in `SignedLogRoot.log_root`
+    pub log_root: Vec<u8>,
+    /// Hashes proving this root extends the requested first tree size, when one was requested
+    /// and the tree is at least that large
+    pub consistency_proof: Option<Vec<Vec<u8>>>,
+}
+
+/// A leaf together with the proof that it is included in the tree
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntryAndProof {
+    pub leaf: TrillianLogLeaf,
+    pub proof: TrillianProof,
+}
+
+/// Tree settings to change, fields left as `None` keep their current value
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeUpdate {
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub tree_state: Option<TrillianTreeState>,
+}
+
+/// A leaf Trillian accepted, as it is in the log
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueuedLeaf {
+    /// The leaf in the log, the earlier one when it was already there
+    pub leaf: TrillianLogLeaf,
+    /// Whether the log already had this leaf, so nothing new was added
+    pub already_existed: bool,
+}
+
+impl TryFrom<QueuedLogLeaf> for QueuedLeaf {
+    type Error = TrillianClientError;
+
+    fn try_from(queued: QueuedLogLeaf) -> Result<Self, Self::Error> {
+        let status = queued.status.unwrap_or_default();
+        match (Code::from(status.code), queued.leaf) {
+            (Code::Ok, Some(leaf)) => Ok(QueuedLeaf {
+                leaf,
+                already_existed: false,
+            }),
+            (Code::AlreadyExists, Some(leaf)) => Ok(QueuedLeaf {
+                leaf,
+                already_existed: true,
+            }),
+            (Code::Ok | Code::AlreadyExists, None) => Err(TrillianClientError::MissingLeaf),
+            (Code::FailedPrecondition, _) => Err(TrillianClientError::LeafConflict(status.message)),
+            (code, _) => Err(TrillianClientError::BadStatus(Status::new(
+                code,
+                status.message,
+            ))),
+        }
+    }
+}
+
+impl TryFrom<LogRootV1> for LogRoot {
+    type Error = TrillianClientError;
+
+    fn try_from(root: LogRootV1) -> Result<Self, Self::Error> {
+        Ok(LogRoot {
+            tree_size: i64::try_from(root.tree_size)
+                .map_err(|_| TrillianClientError::InvalidLogRoot)?,
+            root_hash: root.root_hash,
+        })
+    }
 }
 
 impl TrillianClientBuilder {
+    /// Run `interceptor` on every request the client sends, replacing any installed before
+    pub fn interceptor(
+        &mut self,
+        interceptor: impl Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.interceptor = Some(ClientInterceptor(Some(Arc::new(interceptor))));
+        self
+    }
+
+    /// Ping Trillian this often while requests are in flight, to notice dead connections.
+    /// Trillian closes connections that ping more often than every five minutes by default.
+    pub fn keepalive_interval(&mut self, interval: Duration) -> &mut Self {
+        self.map_endpoint(|endpoint| endpoint.http2_keep_alive_interval(interval))
+    }
+
+    /// How long to wait for a keepalive ping to be answered before closing the connection
+    pub fn keepalive_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.map_endpoint(|endpoint| endpoint.keep_alive_timeout(timeout))
+    }
+
+    /// HTTP/2 flow control window of each request, in bytes
+    pub fn initial_stream_window_size(&mut self, size: u32) -> &mut Self {
+        self.map_endpoint(|endpoint| endpoint.initial_stream_window_size(size))
+    }
+
+    /// HTTP/2 flow control window of the whole connection, in bytes
+    pub fn initial_connection_window_size(&mut self, size: u32) -> &mut Self {
+        self.map_endpoint(|endpoint| endpoint.initial_connection_window_size(size))
+    }
+
+    /// Most requests in flight at once, further requests wait for a slot
+    pub fn concurrency_limit(&mut self, limit: usize) -> &mut Self {
+        self.map_endpoint(|endpoint| endpoint.concurrency_limit(limit))
+    }
+
+    /// How long establishing the connection may take, separately from RPC timeouts
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.map_endpoint(|endpoint| endpoint.connect_timeout(timeout))
+    }
+
+    fn map_endpoint(&mut self, configure: impl FnOnce(Endpoint) -> Endpoint) -> &mut Self {
+        self.channel = self.channel.take().map(configure);
+        self
+    }
+
+    /// Channel over the configured endpoint, connecting on first use. The admin and log clients
+    /// share it.
+    fn connect(&self) -> Channel {
+        self.channel
+            .as_ref()
+            .expect("TrillianClient::new sets the endpoint")
+            .connect_lazy()
+    }
+
     #[instrument(skip(self))]
     pub fn build(&self) -> TrillianClient {
         trace!("Created Trillian client");
@@ -181,15 +655,79 @@ impl TrillianClientBuilder {
     }
 }
 
+/// Endpoint for `uri`, over TLS configured by `tls` when its scheme is `https`. Keepalives
+/// notice dead connections, which the channel then replaces.
+fn endpoint(uri: Uri, tls: &TlsOptions) -> Result<Endpoint, TrillianClientError> {
+    let https = uri.scheme_str() == Some("https");
+    let endpoint = Endpoint::from(uri)
+        .tcp_keepalive(Some(TCP_KEEPALIVE))
+        .http2_keep_alive_interval(HTTP2_KEEPALIVE_INTERVAL)
+        .keep_alive_timeout(HTTP2_KEEPALIVE_TIMEOUT);
+    if !https {
+        if *tls != TlsOptions::default() {
+            return Err(TrillianClientError::TlsWithoutHttps);
+        }
+        return Ok(endpoint);
+    }
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca_certificate) = &tls.ca_certificate {
+        config = config.ca_certificate(Certificate::from_pem(ca_certificate));
+    }
+    if let Some((certificate, key)) = &tls.client_identity {
+        config = config.identity(Identity::from_pem(certificate, key));
+    }
+    if let Some(domain_name) = &tls.domain_name {
+        config = config.domain_name(domain_name.clone());
+    }
+    Ok(endpoint.tls_config(config)?)
+}
+
+/// Await the RPC `call`, counting it by `method` and status code and recording its latency
+async fn observe<T>(
+    method: &'static str,
+    call: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    let start = Instant::now();
+    let result = call.await;
+    let code = match &result {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    };
+    histogram!(
+        "trillian_client_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        "method" => method
+    );
+    increment_counter!(
+        "trillian_client_requests_total",
+        "method" => method,
+        "code" => format!("{code:?}")
+    );
+    result
+}
+
+/// Limit `request` to `timeout`. Tonic gives up waiting once it passes and sends it along as
+/// `grpc-timeout`, so Trillian stops working on the request too.
+fn with_timeout<T>(mut request: Request<T>, timeout: Option<Duration>) -> Request<T> {
+    if let Some(timeout) = timeout {
+        request.set_timeout(timeout);
+    }
+    request
+}
+
 fn list_tree_request() -> Request<ListTreesRequest> {
     Request::new(ListTreesRequest { show_deleted: true })
 }
 
-fn create_tree_request(name: &str, description: &str) -> Request<CreateTreeRequest> {
+fn create_tree_request(
+    name: &str,
+    description: &str,
+    tree_type: TrillianTreeType,
+) -> Request<CreateTreeRequest> {
     Request::new(CreateTreeRequest {
         tree: Option::from(Tree {
             tree_state: TreeState::Active.into(),
-            tree_type: TreeType::Log.into(),
+            tree_type: tree_type.into(),
             display_name: name.to_string(),
             description: description.to_string(),
             max_root_duration: Option::from(
@@ -200,16 +738,87 @@ fn create_tree_request(name: &str, description: &str) -> Request<CreateTreeReque
     })
 }
 
-fn form_leaf(tree_id: i64, entry: &[u8], extra_data: &[u8]) -> Request<QueueLeafRequest> {
+/// Request setting the fields of `update` that are given, and only those. An empty mask would
+/// not change anything, so an update without fields is refused.
+fn update_tree_request(
+    tree_id: i64,
+    update: TreeUpdate,
+) -> Result<Request<UpdateTreeRequest>, TrillianClientError> {
+    let mut tree = Tree {
+        tree_id,
+        ..Tree::default()
+    };
+    let mut paths = Vec::new();
+    if let Some(display_name) = update.display_name {
+        tree.display_name = display_name;
+        paths.push("display_name".to_string());
+    }
+    if let Some(description) = update.description {
+        tree.description = description;
+        paths.push("description".to_string());
+    }
+    if let Some(tree_state) = update.tree_state {
+        tree.tree_state = tree_state.into();
+        paths.push("tree_state".to_string());
+    }
+    if paths.is_empty() {
+        return Err(TrillianClientError::EmptyUpdate);
+    }
+    Ok(Request::new(UpdateTreeRequest {
+        tree: Some(tree),
+        update_mask: Some(prost_types::FieldMask { paths }),
+    }))
+}
+
+async fn queue_leaf(
+    log_client: &mut LogClient,
+    request: Request<QueueLeafRequest>,
+) -> Result<QueuedLeaf> {
+    let response = match observe("QueueLeaf", log_client.queue_leaf(request)).await {
+        Ok(x) => {
+            trace!("Received response {:?}", x);
+            x
+        }
+        Err(err) => {
+            return Err(Report::from(TrillianClientError::BadStatus(err)));
+        }
+    };
+    let queued = response
+        .into_inner()
+        .queued_leaf
+        .ok_or(TrillianClientError::MissingLeaf)?;
+    let queued = QueuedLeaf::try_from(queued)?;
+
+    let leaf = &queued.leaf;
+    debug!(
+        "Queued leaf index: {}, Merkle hash:{:x?}, QueueTs:{:?} IntegrateTs:{:?} Existed:{}",
+        &leaf.leaf_index,
+        &leaf.leaf_identity_hash,
+        &leaf.queue_timestamp,
+        &leaf.integrate_timestamp,
+        queued.already_existed
+    );
+    Ok(queued)
+}
+
+fn form_leaf(
+    tree_id: i64,
+    entry: &[u8],
+    extra_data: &[u8],
+    identity_hash: Option<&[u8]>,
+    charge_to: Option<TrillianChargeTo>,
+) -> Request<QueueLeafRequest> {
+    // Trillian falls back to the Merkle leaf hash as the identity when none is given
     let leaf = LogLeaf {
         leaf_value: entry.to_vec(),
         extra_data: extra_data.to_vec(),
+        leaf_identity_hash: identity_hash.map(<[u8]>::to_vec).unwrap_or_default(),
         ..LogLeaf::default()
     };
     let queue = QueueLeafRequest {
         log_id: tree_id,
         leaf: Option::from(leaf),
-        ..QueueLeafRequest::default()
+        charge_to,
     };
     Request::new(queue)
 }
@@ -218,18 +827,281 @@ fn form_leaf(tree_id: i64, entry: &[u8], extra_data: &[u8]) -> Request<QueueLeaf
 pub enum TrillianClientError {
     #[error(transparent)]
     BadStatus(#[from] Status),
+    #[error("signed log root could not be parsed")]
+    InvalidLogRoot,
+    #[error(transparent)]
+    MalformedLogRoot(#[from] VerifyError),
+    #[error("no proof was returned for the requested tree size")]
+    MissingProof,
+    #[error("no leaf was returned for an accepted leaf")]
+    MissingLeaf,
+    #[error("a different leaf is already in the log: {0}")]
+    LeafConflict(String),
+    #[error("invalid Trillian address: {0}")]
+    InvalidUri(#[from] InvalidUri),
+    #[error("a tree update needs at least one field to change")]
+    EmptyUpdate,
+    #[error("TLS options need an https:// Trillian address")]
+    TlsWithoutHttps,
+    #[error(transparent)]
+    Transport(#[from] tonic::transport::Error),
 }
 
 #[async_trait]
 pub trait TrillianClientApiMethods: DynClone {
+    /// Limit the RPCs of this client to `timeout`, or lift the limit with `None`. Set it on a
+    /// clone to change it for a single call.
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+    /// Queue a leaf, charging Trillian quota to the users in `charge_to` when given. Trillian
+    /// treats leaves with the same `identity_hash` as duplicates, defaulting to the Merkle leaf
+    /// hash. A leaf the log already has is not an error, the existing one is returned instead.
     async fn add_leaf(
         &mut self,
         id: &i64,
         data: &[u8],
         extra_data: &[u8],
-    ) -> Result<TrillianLogLeaf>;
-    async fn create_tree(&mut self, name: &str, description: &str) -> Result<TrillianTree>;
+        identity_hash: Option<&[u8]>,
+        charge_to: Option<TrillianChargeTo>,
+    ) -> Result<QueuedLeaf>;
+    /// Queue several leaves, with up to `MAX_CONCURRENT_QUEUE_LEAF` requests in flight at once.
+    /// Each leaf of `(data, extra_data)` gets its own result, in the same order.
+    async fn add_leaves(
+        &mut self,
+        id: &i64,
+        leaves: Vec<(Vec<u8>, Vec<u8>)>,
+        charge_to: Option<TrillianChargeTo>,
+    ) -> Vec<Result<QueuedLeaf>>;
+    /// Add leaves at the indices they carry to a `PREORDERED_LOG` tree, keeping their order
+    /// from another log. Each leaf gets its own result, in the same order, and fails when
+    /// Trillian rejects it, such as for a different leaf already at its index.
+    async fn add_sequenced_leaves(
+        &mut self,
+        id: &i64,
+        leaves: Vec<TrillianLogLeaf>,
+        charge_to: Option<TrillianChargeTo>,
+    ) -> Result<Vec<Result<QueuedLeaf>>>;
+    /// Create and initialize a tree, a `LOG` that orders leaves itself or a `PREORDERED_LOG`
+    /// that takes them at given indices
+    async fn create_tree(
+        &mut self,
+        name: &str,
+        description: &str,
+        tree_type: TrillianTreeType,
+    ) -> Result<TrillianTree>;
+    /// Every tree on the server, including soft-deleted ones
     async fn list_trees(&mut self) -> Result<Vec<TrillianTree>>;
+    /// Tree metadata from the admin service, a cheap way to check Trillian is reachable
+    async fn get_tree(&mut self, id: &i64) -> Result<TrillianTree>;
+    /// Change the name, description, or state of a tree, returning it as updated
+    async fn update_tree(&mut self, id: &i64, update: TreeUpdate) -> Result<TrillianTree>;
+    /// Stop a tree from accepting new leaves while it keeps serving reads. Trillian expects
+    /// queued leaves to be integrated first, so drain the tree before freezing it.
+    async fn freeze_tree(&mut self, id: &i64) -> Result<TrillianTree>;
+    /// Soft-delete a tree, which Trillian keeps for a while before removing it for good
+    async fn delete_tree(&mut self, id: &i64) -> Result<TrillianTree>;
+    /// Restore a soft-deleted tree
+    async fn undelete_tree(&mut self, id: &i64) -> Result<TrillianTree>;
+    /// Number of leaves integrated into the tree according to its latest signed root
+    async fn get_tree_size(&mut self, id: &i64) -> Result<i64>;
+    /// Size and root hash of the tree according to its latest signed root
+    async fn get_latest_root(&mut self, id: &i64) -> Result<LogRoot>;
+    /// Latest signed root in full, with a consistency proof from `first_tree_size` when given
+    async fn get_latest_signed_log_root(
+        &mut self,
+        id: &i64,
+        first_tree_size: Option<i64>,
+    ) -> Result<SignedRoot>;
+    /// Inclusion proofs for a Merkle leaf hash at `tree_size`, empty if the leaf is not integrated
+    async fn get_inclusion_proof_by_hash(
+        &mut self,
+        id: &i64,
+        leaf_hash: &[u8],
+        tree_size: i64,
+    ) -> Result<Vec<TrillianProof>>;
+    /// Inclusion proof for the leaf at `leaf_index` in the tree at `tree_size`
+    async fn get_inclusion_proof(
+        &mut self,
+        id: &i64,
+        leaf_index: i64,
+        tree_size: i64,
+    ) -> Result<TrillianProof>;
+    /// The leaf at `leaf_index` with its inclusion proof in the tree at `tree_size`, in one
+    /// round trip
+    async fn get_entry_and_proof(
+        &mut self,
+        id: &i64,
+        leaf_index: i64,
+        tree_size: i64,
+    ) -> Result<EntryAndProof>;
+    /// Hashes proving the tree at `second_tree_size` extends the tree at `first_tree_size`
+    async fn get_consistency_proof(
+        &mut self,
+        id: &i64,
+        first_tree_size: i64,
+        second_tree_size: i64,
+    ) -> Result<Vec<Vec<u8>>>;
+    /// Up to `count` integrated leaves from `start_index`, fewer at the end of the tree
+    async fn get_leaves_by_range(
+        &mut self,
+        id: &i64,
+        start_index: i64,
+        count: i64,
+    ) -> Result<Vec<TrillianLogLeaf>>;
 }
 
 dyn_clone::clone_trait_object!(TrillianClientApiMethods);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::google::rpc::Status as RpcStatus;
+
+    fn response(code: Code, leaf: Option<LogLeaf>) -> QueuedLogLeaf {
+        QueuedLogLeaf {
+            leaf,
+            status: Some(RpcStatus {
+                code: code as i32,
+                message: format!("{code:?}"),
+                ..RpcStatus::default()
+            }),
+        }
+    }
+
+    fn leaf() -> LogLeaf {
+        LogLeaf {
+            leaf_value: b"leaf".to_vec(),
+            leaf_index: 7,
+            ..LogLeaf::default()
+        }
+    }
+
+    #[test]
+    fn existing_leaves_are_returned_as_already_existed() {
+        let queued = QueuedLeaf::try_from(response(Code::AlreadyExists, Some(leaf()))).unwrap();
+        assert_eq!(
+            queued,
+            QueuedLeaf {
+                leaf: leaf(),
+                already_existed: true,
+            }
+        );
+        let queued = QueuedLeaf::try_from(response(Code::Ok, Some(leaf()))).unwrap();
+        assert!(!queued.already_existed);
+    }
+
+    #[test]
+    fn conflicting_leaves_are_refused() {
+        let err =
+            QueuedLeaf::try_from(response(Code::FailedPrecondition, Some(leaf()))).unwrap_err();
+        let TrillianClientError::LeafConflict(message) = err else {
+            panic!("expected a leaf conflict, got {err:?}");
+        };
+        assert_eq!(message, "FailedPrecondition");
+    }
+
+    #[test]
+    fn accepted_leaves_must_be_returned() {
+        for code in [Code::Ok, Code::AlreadyExists] {
+            let err = QueuedLeaf::try_from(response(code, None)).unwrap_err();
+            assert!(matches!(err, TrillianClientError::MissingLeaf));
+        }
+    }
+
+    #[test]
+    fn tree_updates_mask_only_the_fields_set() {
+        let request = update_tree_request(
+            42,
+            TreeUpdate {
+                description: Some("images".to_string()),
+                tree_state: Some(TrillianTreeState::Frozen),
+                ..TreeUpdate::default()
+            },
+        )
+        .unwrap()
+        .into_inner();
+        let mask = request.update_mask.unwrap();
+        assert_eq!(mask.paths, ["description", "tree_state"]);
+        let tree = request.tree.unwrap();
+        assert_eq!(tree.tree_id, 42);
+        assert_eq!(tree.description, "images");
+        assert_eq!(tree.tree_state, i32::from(TreeState::Frozen));
+        assert!(tree.display_name.is_empty());
+    }
+
+    #[test]
+    fn empty_tree_updates_are_refused() {
+        let err = update_tree_request(42, TreeUpdate::default()).unwrap_err();
+        assert!(matches!(err, TrillianClientError::EmptyUpdate));
+    }
+
+    /// A CA certificate block that does not hold a certificate, which only TLS setup rejects
+    const UNPARSABLE_CA: &[u8] = b"-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n";
+
+    fn uri(address: &str) -> Uri {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn https_endpoints_are_configured_for_tls() {
+        assert!(endpoint(
+            uri("https://trillian.internal:8090"),
+            &TlsOptions::default()
+        )
+        .is_ok());
+        let tls = TlsOptions {
+            ca_certificate: Some(UNPARSABLE_CA.to_vec()),
+            ..TlsOptions::default()
+        };
+        let err = endpoint(uri("https://trillian.internal:8090"), &tls).unwrap_err();
+        assert!(matches!(err, TrillianClientError::Transport(_)));
+    }
+
+    #[test]
+    fn tls_options_need_an_https_endpoint() {
+        assert!(endpoint(uri("http://localhost:8090"), &TlsOptions::default()).is_ok());
+        for tls in [
+            TlsOptions {
+                ca_certificate: Some(UNPARSABLE_CA.to_vec()),
+                ..TlsOptions::default()
+            },
+            TlsOptions {
+                domain_name: Some("trillian.internal".to_string()),
+                ..TlsOptions::default()
+            },
+        ] {
+            let err = endpoint(uri("http://localhost:8090"), &tls).unwrap_err();
+            assert!(matches!(err, TrillianClientError::TlsWithoutHttps));
+        }
+    }
+
+    /// SHA-256 of `veracity`, standing in for an image's crypto hash
+    const CRYPTO_HASH: &str = "32dd0df196f3dbc77a25881fa9ae8dbe8a5919401282a4a4b6786b44dd348dbb";
+
+    #[test]
+    fn leaf_identity_hashes_are_sent_as_given() {
+        // Trillian deduplicates leaves by these bytes, so they must not change for leaves that
+        // are already logged
+        let crypto_hash = hex::decode(CRYPTO_HASH).unwrap();
+        let request = form_leaf(42, &crypto_hash, b"extra", Some(&crypto_hash), None).into_inner();
+        assert_eq!(request.log_id, 42);
+        let leaf = request.leaf.unwrap();
+        assert_eq!(hex::encode(&leaf.leaf_identity_hash), CRYPTO_HASH);
+        assert_eq!(leaf.leaf_value, crypto_hash);
+        assert_eq!(leaf.extra_data, b"extra");
+    }
+
+    #[test]
+    fn leaves_without_identity_hash_are_identified_by_their_merkle_hash() {
+        let crypto_hash = hex::decode(CRYPTO_HASH).unwrap();
+        let leaf = form_leaf(42, &crypto_hash, b"", None, None)
+            .into_inner()
+            .leaf
+            .unwrap();
+        // Left empty for Trillian to fill in with the RFC 6962 leaf hash
+        assert!(leaf.leaf_identity_hash.is_empty());
+        assert_eq!(
+            hex::encode(veracity_verify::leaf_hash(&leaf.leaf_value)),
+            "3ad4f79f01fbc10002f8a8d61352a5e575aaed9e33b05442b21748d77f80f16d"
+        );
+    }
+}
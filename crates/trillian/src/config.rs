@@ -0,0 +1,93 @@
+//! Defaults for the CLI from a TOML file, `~/.config/trillian-cli.toml` unless another is given.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use eyre::{Result, WrapErr};
+use serde::Deserialize;
+
+const FILE_NAME: &str = "trillian-cli.toml";
+
+/// Settings a config file may give, each a fallback for the flag of the same name
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CliConfig {
+    pub address: Option<String>,
+    pub tree_id: Option<i64>,
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub tls_domain: Option<String>,
+}
+
+impl CliConfig {
+    /// Read a config file
+    pub fn load(path: &Path) -> Result<CliConfig> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .wrap_err_with(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Set the environment variables the flags fall back to, skipping those already set so the
+    /// real environment always wins.
+    ///
+    /// Call before any threads are started.
+    pub fn export(&self) {
+        let settings = [
+            ("TRILLIAN_ADDRESS", self.address.clone()),
+            ("TRILLIAN_TREE_ID", self.tree_id.map(|id| id.to_string())),
+            ("TRILLIAN_CA_CERT_PATH", path_string(&self.ca_cert)),
+            ("TRILLIAN_CLIENT_CERT_PATH", path_string(&self.client_cert)),
+            ("TRILLIAN_CLIENT_KEY_PATH", path_string(&self.client_key)),
+            ("TRILLIAN_TLS_DOMAIN", self.tls_domain.clone()),
+        ];
+        for (key, value) in settings {
+            if let Some(value) = value {
+                if env::var_os(key).is_none() {
+                    env::set_var(key, value);
+                }
+            }
+        }
+    }
+}
+
+/// `trillian-cli.toml` in `$XDG_CONFIG_HOME`, or in `~/.config` when that is unset
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join(FILE_NAME))
+}
+
+fn path_string(path: &Option<PathBuf>) -> Option<String> {
+    path.as_ref().map(|path| path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config_files() {
+        let config: CliConfig = toml::from_str(
+            r#"
+            address = "https://trillian.internal:8090"
+            tree_id = 42
+            ca_cert = "/etc/trillian/ca.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            CliConfig {
+                address: Some("https://trillian.internal:8090".to_string()),
+                tree_id: Some(42),
+                ca_cert: Some(PathBuf::from("/etc/trillian/ca.pem")),
+                ..CliConfig::default()
+            }
+        );
+        assert!(toml::from_str::<CliConfig>("adress = \"typo\"").is_err());
+    }
+}
@@ -3,11 +3,19 @@
 #[macro_use]
 extern crate derive_builder;
 
-use crate::protobuf::trillian::{LogLeaf, Tree};
+use crate::protobuf::trillian::{
+    ChargeTo, LogLeaf, Proof, SignedLogRoot, Tree, TreeState, TreeType,
+};
 
 pub mod client;
+pub mod log_root;
 mod protobuf;
 
 // Export some Trillian types
 pub type TrillianLogLeaf = LogLeaf;
 pub type TrillianTree = Tree;
+pub type TrillianProof = Proof;
+pub type TrillianChargeTo = ChargeTo;
+pub type TrillianSignedLogRoot = SignedLogRoot;
+pub type TrillianTreeType = TreeType;
+pub type TrillianTreeState = TreeState;
@@ -0,0 +1,87 @@
+//! Typed log roots, read from the bytes Trillian signs in `SignedLogRoot.log_root`.
+
+use veracity_verify::VerifyError;
+
+pub use veracity_verify::LogRootV1;
+
+use crate::TrillianSignedLogRoot;
+
+/// The tree size, root hash, timestamp, and revision a signed log root commits to
+pub fn parse(signed_log_root: &TrillianSignedLogRoot) -> Result<LogRootV1, VerifyError> {
+    LogRootV1::parse(&signed_log_root.log_root)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn signed(log_root: &str) -> TrillianSignedLogRoot {
+        TrillianSignedLogRoot {
+            log_root: from_hex(log_root),
+        }
+    }
+
+    /// Bytes `types.LogRootV1.MarshalBinary` from github.com/google/trillian produces:
+    /// ```golang
+    /// root := types.LogRootV1{
+    ///     TreeSize:       0,
+    ///     RootHash:       rfc6962.DefaultHasher.EmptyRoot(),
+    ///     TimestampNanos: 1687276455000000000,
+    /// }
+    /// b, _ := root.MarshalBinary()
+    /// fmt.Println(hex.EncodeToString(b))
+    /// ```
+    #[test]
+    fn parses_golang_empty_tree_root() {
+        let root = parse(&signed(
+            "0001000000000000000020e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\
+            176a68ff0a27c60000000000000000000000",
+        ))
+        .unwrap();
+        assert_eq!(root.tree_size, 0);
+        assert_eq!(
+            root.root_hash,
+            from_hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(
+            root.timestamp(),
+            UNIX_EPOCH + Duration::from_secs(1_687_276_455)
+        );
+        assert_eq!(root.revision, 0);
+        assert!(root.metadata.is_empty());
+    }
+
+    /// As above, with `TreeSize: 8`, the RFC 6962 test tree's root, `TimestampNanos:
+    /// 1687276455123456789`, `Revision: 12` and `Metadata: []byte("meta")`
+    #[test]
+    fn parses_golang_root_with_metadata() {
+        let root = parse(&signed(
+            "00010000000000000008205dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328\
+            176a68ff11839315000000000000000c00046d657461",
+        ))
+        .unwrap();
+        assert_eq!(root.tree_size, 8);
+        assert_eq!(
+            root.root_hash,
+            from_hex("5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328")
+        );
+        assert_eq!(root.timestamp_nanos, 1_687_276_455_123_456_789);
+        assert_eq!(root.revision, 12);
+        assert_eq!(root.metadata, b"meta");
+    }
+
+    #[test]
+    fn rejects_roots_that_are_not_version_1() {
+        assert!(parse(&TrillianSignedLogRoot::default()).is_err());
+        assert!(parse(&signed("0002000000000000000000")).is_err());
+    }
+}
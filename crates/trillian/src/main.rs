@@ -1,32 +1,84 @@
-use clap::{Args, Parser, Subcommand};
-use eyre::Result;
+use std::path::PathBuf;
+
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use eyre::{eyre, Result};
+use serde_json::{json, Value};
 use tracing::debug;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use trillian::client::{TrillianClient, TrillianClientApiMethods};
+use trillian::client::{TlsOptions, TreeUpdate, TrillianClient, TrillianClientApiMethods};
+use trillian::log_root::LogRootV1;
+use trillian::{TrillianChargeTo, TrillianTree, TrillianTreeState, TrillianTreeType};
+
+use crate::config::CliConfig;
+
+mod config;
 
 /// Simple Trillian Client CLI
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Address of Trillian instance
-    #[arg(short, long)]
-    address: String,
+    /// TOML file of defaults for the address, tree ID, and TLS settings, by default
+    /// `~/.config/trillian-cli.toml` when it exists
+    #[arg(long, env = "TRILLIAN_CLI_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address of Trillian instance, `https://` to connect over TLS
+    #[arg(short, long, env = "TRILLIAN_ADDRESS")]
+    address: Option<String>,
+
+    /// PEM CA bundle to trust instead of the system roots
+    #[arg(long, env = "TRILLIAN_CA_CERT_PATH")]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate, for servers requiring mutual TLS
+    #[arg(long, env = "TRILLIAN_CLIENT_CERT_PATH", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM private key for the client certificate
+    #[arg(long, env = "TRILLIAN_CLIENT_KEY_PATH", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Name to expect in the server certificate, the host of the address by default
+    #[arg(long, env = "TRILLIAN_TLS_DOMAIN")]
+    tls_domain: Option<String>,
 
     /// Turn debugging information on. Use multiple to increase verbosity level
     #[arg(short, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// How to print results
+    #[arg(short, long, value_enum, global = true, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
     #[command(subcommand)]
     submodule: Submodules,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Readable text
+    Text,
+    /// One JSON document per command, for scripts
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Submodules {
     /// Trillian Admin Client
     Admin(AdminArgs),
     /// Trillian Log Client
     Client(ClientArgs),
+    /// Print a shell completion script
+    Completions(CompletionsArgs),
+}
+
+#[derive(Clone, Args)]
+struct CompletionsArgs {
+    /// Shell to complete commands for
+    #[arg(value_enum)]
+    shell: Shell,
 }
 
 #[derive(Clone, Args)]
@@ -41,6 +93,42 @@ enum AdminCommands {
     ListTrees,
     /// Create a new tree
     CreateTree(CreateTreeArgs),
+    /// Show one tree
+    GetTree(TreeArgs),
+    /// Change the name, description, or state of a tree
+    UpdateTree(UpdateTreeArgs),
+    /// Stop a tree from accepting new leaves
+    #[command(alias = "freeze")]
+    FreezeTree(TreeArgs),
+    /// Soft-delete a tree
+    #[command(alias = "delete")]
+    DeleteTree(TreeArgs),
+    /// Restore a soft-deleted tree
+    #[command(alias = "undelete")]
+    UndeleteTree(TreeArgs),
+}
+
+#[derive(Clone, Debug, Args)]
+struct TreeArgs {
+    #[arg(short, long, env = "TRILLIAN_TREE_ID")]
+    /// ID of the tree
+    tree_id: i64,
+}
+
+#[derive(Clone, Debug, Args)]
+struct UpdateTreeArgs {
+    #[arg(short, long, env = "TRILLIAN_TREE_ID")]
+    /// ID of the tree
+    tree_id: i64,
+    #[arg(short, long)]
+    /// New name of the tree
+    name: Option<String>,
+    #[arg(short, long)]
+    /// New description of the tree
+    description: Option<String>,
+    #[arg(short, long)]
+    /// New state of the tree, such as ACTIVE, DRAINING, or FROZEN
+    state: Option<String>,
 }
 
 #[derive(Clone, Debug, Args)]
@@ -51,6 +139,9 @@ struct CreateTreeArgs {
     #[arg(short, long)]
     /// Short description of the tree
     description: String,
+    #[arg(long)]
+    /// Take leaves at the indices they are given, for mirroring another log
+    preordered: bool,
 }
 
 #[derive(Clone, Args)]
@@ -63,11 +154,17 @@ struct ClientArgs {
 enum ClientCommands {
     /// Add new leaf to tree
     AddLeaf(AddLeafArgs),
+    /// Prove a leaf is included in the latest root of a tree
+    InclusionProof(InclusionProofArgs),
+    /// Show the latest signed root of a tree
+    LatestRoot(LatestRootArgs),
+    /// Prove one size of a tree is consistent with a later one
+    Consistency(ConsistencyArgs),
 }
 
 #[derive(Clone, Debug, Args)]
 struct AddLeafArgs {
-    #[arg(short, long)]
+    #[arg(short, long, env = "TRILLIAN_TREE_ID")]
     /// Tree ID to add new leaf
     tree_id: i64,
     #[arg(short, long)]
@@ -76,12 +173,75 @@ struct AddLeafArgs {
     #[arg(short, long)]
     /// Optional extra data to add with leaf
     extra_data: Option<String>,
+    #[arg(short, long)]
+    /// Optional quota user to charge for the leaf, may be repeated
+    charge_to: Vec<String>,
+}
+
+#[derive(Clone, Debug, Args)]
+struct InclusionProofArgs {
+    #[arg(short, long, env = "TRILLIAN_TREE_ID")]
+    /// ID of the tree
+    tree_id: i64,
+    #[arg(short, long)]
+    /// Merkle leaf hash of the leaf, as hex
+    leaf_hash: String,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+#[derive(Clone, Debug, Args)]
+struct LatestRootArgs {
+    #[arg(short, long, env = "TRILLIAN_TREE_ID")]
+    /// ID of the tree
+    tree_id: i64,
+    #[arg(short, long)]
+    /// Also prove the root consistent with this earlier tree size
+    first_size: Option<i64>,
+}
+
+#[derive(Clone, Debug, Args)]
+struct ConsistencyArgs {
+    #[arg(short, long, env = "TRILLIAN_TREE_ID")]
+    /// ID of the tree
+    tree_id: i64,
+    #[arg(short, long)]
+    /// Earlier tree size
+    first: i64,
+    #[arg(short, long)]
+    /// Later tree size
+    second: i64,
+}
+
+fn main() -> Result<()> {
+    // The config file only sets variables that are unset, so read it before the other options
+    // fall back to the environment, and before the runtime starts threads
+    let config_file = Cli::command()
+        .mut_args(|arg| arg.required(false))
+        .ignore_errors(true)
+        .disable_help_flag(true)
+        .disable_version_flag(true)
+        .get_matches()
+        .get_one::<PathBuf>("config")
+        .cloned();
+    match config_file {
+        Some(path) => CliConfig::load(&path)?.export(),
+        None => {
+            if let Some(path) = config::default_path().filter(|path| path.exists()) {
+                CliConfig::load(&path)?.export();
+            }
+        }
+    }
     let args = Cli::parse();
+    if let Submodules::Completions(CompletionsArgs { shell }) = args.submodule {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(run(args))
+}
 
+async fn run(args: Cli) -> Result<()> {
     // Set verbosity level
     let verbosity_level = match args.verbose {
         0 => "warn",
@@ -101,8 +261,20 @@ async fn main() -> Result<()> {
 
     debug!("Verbosity level: {verbosity_level}");
 
-    let mut trillian = TrillianClient::new(args.address).await?.build();
+    let tls = TlsOptions {
+        ca_certificate: args.ca_cert.map(std::fs::read).transpose()?,
+        client_identity: match (args.client_cert, args.client_key) {
+            (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+            _ => None,
+        },
+        domain_name: args.tls_domain,
+    };
+    let address = args
+        .address
+        .ok_or_else(|| eyre!("no Trillian address, pass --address or set TRILLIAN_ADDRESS"))?;
+    let mut trillian = TrillianClient::new(address, &tls)?.build();
     debug!("Created Trillian client");
+    let output = args.output;
 
     match &args.submodule {
         Submodules::Admin(admin_args) => {
@@ -112,13 +284,69 @@ async fn main() -> Result<()> {
             match admin_command {
                 AdminCommands::ListTrees => {
                     let trees = trillian.list_trees().await?;
-                    for tree in trees {
-                        println!("{tree:#?}")
-                    }
+                    emit(output, trees.iter().map(tree_json).collect(), || {
+                        for tree in &trees {
+                            println!("{tree:#?}")
+                        }
+                    });
                 }
-                AdminCommands::CreateTree(CreateTreeArgs { name, description }) => {
-                    let tree = trillian.create_tree(&name, &description).await?;
-                    println!("New Tree ID: {}", &tree.tree_id);
+                AdminCommands::CreateTree(CreateTreeArgs {
+                    name,
+                    description,
+                    preordered,
+                }) => {
+                    let tree_type = if *preordered {
+                        TrillianTreeType::PreorderedLog
+                    } else {
+                        TrillianTreeType::Log
+                    };
+                    let tree = trillian.create_tree(name, description, tree_type).await?;
+                    emit(output, tree_json(&tree), || {
+                        println!("New Tree ID: {}", &tree.tree_id)
+                    });
+                }
+                AdminCommands::GetTree(TreeArgs { tree_id }) => {
+                    let tree = trillian.get_tree(tree_id).await?;
+                    emit(output, tree_json(&tree), || println!("{tree:#?}"));
+                }
+                AdminCommands::UpdateTree(UpdateTreeArgs {
+                    tree_id,
+                    name,
+                    description,
+                    state,
+                }) => {
+                    let tree_state = match state {
+                        Some(state) => Some(
+                            TrillianTreeState::from_str_name(&state.to_uppercase())
+                                .ok_or_else(|| eyre!("unknown tree state {state}"))?,
+                        ),
+                        None => None,
+                    };
+                    let update = TreeUpdate {
+                        display_name: name.clone(),
+                        description: description.clone(),
+                        tree_state,
+                    };
+                    let tree = trillian.update_tree(tree_id, update).await?;
+                    emit(output, tree_json(&tree), || println!("{tree:#?}"));
+                }
+                AdminCommands::FreezeTree(TreeArgs { tree_id }) => {
+                    let tree = trillian.freeze_tree(tree_id).await?;
+                    emit(output, tree_json(&tree), || {
+                        println!("Froze tree {tree_id}")
+                    });
+                }
+                AdminCommands::DeleteTree(TreeArgs { tree_id }) => {
+                    let tree = trillian.delete_tree(tree_id).await?;
+                    emit(output, tree_json(&tree), || {
+                        println!("Deleted tree {tree_id}")
+                    });
+                }
+                AdminCommands::UndeleteTree(TreeArgs { tree_id }) => {
+                    let tree = trillian.undelete_tree(tree_id).await?;
+                    emit(output, tree_json(&tree), || {
+                        println!("Undeleted tree {tree_id}")
+                    });
                 }
             }
         }
@@ -131,23 +359,167 @@ async fn main() -> Result<()> {
                     tree_id,
                     data,
                     extra_data,
+                    charge_to,
                 }) => {
                     let extra_data_bytes = if let Some(extra) = extra_data {
                         extra.as_bytes()
                     } else {
                         &[]
                     };
-                    let leaf = trillian
-                        .add_leaf(tree_id, data.as_bytes(), extra_data_bytes)
+                    let charge_to = (!charge_to.is_empty()).then(|| TrillianChargeTo {
+                        user: charge_to.clone(),
+                    });
+                    let queued = trillian
+                        .add_leaf(tree_id, data.as_bytes(), extra_data_bytes, None, charge_to)
                         .await?;
-                    println!(
-                        "Queued leaf index {} and hash {:x?}",
-                        &leaf.leaf_index, &leaf.leaf_identity_hash
-                    );
+                    let leaf = &queued.leaf;
+                    let value = json!({
+                        "leaf_index": leaf.leaf_index,
+                        "leaf_identity_hash": hex::encode(&leaf.leaf_identity_hash),
+                        "merkle_leaf_hash": hex::encode(&leaf.merkle_leaf_hash),
+                        "already_existed": queued.already_existed,
+                    });
+                    emit(output, value, || {
+                        if queued.already_existed {
+                            println!(
+                                "Leaf already in the log at index {} with hash {:x?}",
+                                &leaf.leaf_index, &leaf.leaf_identity_hash
+                            );
+                        } else {
+                            println!(
+                                "Queued leaf index {} and hash {:x?}",
+                                &leaf.leaf_index, &leaf.leaf_identity_hash
+                            );
+                        }
+                    });
+                }
+                ClientCommands::InclusionProof(InclusionProofArgs { tree_id, leaf_hash }) => {
+                    let leaf_hash = hex::decode(leaf_hash)?;
+                    let root = trillian
+                        .get_latest_signed_log_root(tree_id, None)
+                        .await?
+                        .root;
+                    let proofs = trillian
+                        .get_inclusion_proof_by_hash(tree_id, &leaf_hash, root.tree_size as i64)
+                        .await?;
+                    if proofs.is_empty() {
+                        return Err(eyre!("leaf is not integrated into tree {tree_id} yet"));
+                    }
+                    let value = json!({
+                        "root": root_json(&root),
+                        "proofs": proofs
+                            .iter()
+                            .map(|proof| json!({
+                                "leaf_index": proof.leaf_index,
+                                "hashes": hashes_json(&proof.hashes),
+                            }))
+                            .collect::<Vec<_>>(),
+                    });
+                    emit(output, value, || {
+                        print_root(&root);
+                        for proof in &proofs {
+                            println!("Leaf index: {}", proof.leaf_index);
+                            println!("Audit path:");
+                            print_hashes(&proof.hashes);
+                        }
+                    });
+                }
+                ClientCommands::LatestRoot(LatestRootArgs {
+                    tree_id,
+                    first_size,
+                }) => {
+                    let signed = trillian
+                        .get_latest_signed_log_root(tree_id, *first_size)
+                        .await?;
+                    let value = json!({
+                        "root": root_json(&signed.root),
+                        "consistency_proof": signed.consistency_proof.as_deref().map(hashes_json),
+                    });
+                    emit(output, value, || {
+                        print_root(&signed.root);
+                        if let Some(hashes) = &signed.consistency_proof {
+                            print_proof(hashes);
+                        }
+                    });
+                }
+                ClientCommands::Consistency(ConsistencyArgs {
+                    tree_id,
+                    first,
+                    second,
+                }) => {
+                    let root = trillian
+                        .get_latest_signed_log_root(tree_id, None)
+                        .await?
+                        .root;
+                    let hashes = trillian
+                        .get_consistency_proof(tree_id, *first, *second)
+                        .await?;
+                    let value = json!({
+                        "root": root_json(&root),
+                        "consistency_proof": hashes_json(&hashes),
+                    });
+                    emit(output, value, || {
+                        print_root(&root);
+                        print_proof(&hashes);
+                    });
                 }
             }
         }
+        // Printed before connecting
+        Submodules::Completions(_) => {}
     }
 
     Ok(())
 }
+
+/// Print `value` as JSON, or call `text` to print it for people
+fn emit(output: OutputFormat, value: Value, text: impl FnOnce()) {
+    match output {
+        OutputFormat::Json => println!("{value}"),
+        OutputFormat::Text => text(),
+    }
+}
+
+fn tree_json(tree: &TrillianTree) -> Value {
+    json!({
+        "tree_id": tree.tree_id,
+        "tree_state": tree.tree_state().as_str_name(),
+        "tree_type": tree.tree_type().as_str_name(),
+        "display_name": tree.display_name,
+        "description": tree.description,
+        "create_time": tree.create_time.as_ref().map(|time| time.seconds),
+        "update_time": tree.update_time.as_ref().map(|time| time.seconds),
+        "deleted": tree.deleted,
+    })
+}
+
+fn root_json(root: &LogRootV1) -> Value {
+    json!({
+        "tree_size": root.tree_size,
+        "root_hash": hex::encode(&root.root_hash),
+        "timestamp_nanos": root.timestamp_nanos,
+        "revision": root.revision,
+    })
+}
+
+fn hashes_json(hashes: &[Vec<u8>]) -> Value {
+    hashes.iter().map(hex::encode).collect()
+}
+
+fn print_root(root: &LogRootV1) {
+    println!("Tree size: {}", root.tree_size);
+    println!("Root hash: {}", hex::encode(&root.root_hash));
+    println!("Timestamp: {} ns", root.timestamp_nanos);
+    println!("Revision: {}", root.revision);
+}
+
+fn print_proof(hashes: &[Vec<u8>]) {
+    println!("Consistency proof:");
+    print_hashes(hashes);
+}
+
+fn print_hashes(hashes: &[Vec<u8>]) {
+    for hash in hashes {
+        println!("  {}", hex::encode(hash));
+    }
+}
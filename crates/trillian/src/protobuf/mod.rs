@@ -1,5 +1,5 @@
 #![allow(warnings)]
 #![allow(clippy)]
 #![allow(unknown_lints)]
-mod google;
+pub(crate) mod google;
 pub mod trillian;
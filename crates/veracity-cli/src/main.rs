@@ -0,0 +1,246 @@
+//! `veracity`, a command line client for image veracity servers.
+//!
+//! Hashes images locally the same way the server does, uploads them, and looks up what the
+//! server has stored, so images can be checked without writing any code.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use eyre::{eyre, Result, WrapErr};
+use reqwest::Url;
+use serde_json::Value;
+
+use veracity_hash::{hash_image, VeracityHash};
+
+use crate::server::{InclusionProof, Server, Submitted};
+use crate::watch::{ManifestFormat, WatchOptions};
+
+mod server;
+mod watch;
+
+/// Hash images and check them against an image veracity server
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Address of the image veracity server
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "VERACITY_SERVER",
+        default_value = "http://localhost:3000"
+    )]
+    server: Url,
+
+    /// API key to send in the `X-Auth-Key` header
+    #[arg(
+        short = 'k',
+        long,
+        global = true,
+        env = "VERACITY_API_KEY",
+        hide_env_values = true
+    )]
+    api_key: Option<String>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the crypto and perceptual hashes of images, without contacting the server
+    Hash(ImagesArgs),
+    /// Upload images to the server
+    Submit(ImagesArgs),
+    /// Look up a stored image
+    Lookup(LookupArgs),
+    /// Check an image is in the log, verifying its inclusion proof locally
+    Verify(VerifyArgs),
+    /// Submit images as they are written to a directory, recording the results in a manifest
+    Watch(WatchArgs),
+    /// Print a shell completion script
+    Completions(CompletionsArgs),
+}
+
+#[derive(Args)]
+struct ImagesArgs {
+    /// JPEG or PNG images
+    #[arg(required = true)]
+    images: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+struct LookupArgs {
+    /// Perceptual hash of the image as hex, as printed by `veracity hash`
+    #[arg(long)]
+    phash: String,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// JPEG or PNG image
+    image: PathBuf,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// Directory new images are written to
+    dir: PathBuf,
+
+    /// File to append a line per image to
+    #[arg(short, long, default_value = "manifest.jsonl")]
+    manifest: PathBuf,
+
+    /// Manifest layout, by default CSV if the manifest ends in `.csv` and JSON lines otherwise
+    #[arg(short, long, value_enum)]
+    format: Option<ManifestFormat>,
+
+    /// Most images hashed and submitted at once
+    #[arg(short, long, default_value_t = 4)]
+    concurrency: usize,
+
+    /// Seconds to collect new images before submitting them as a batch
+    #[arg(short, long, default_value_t = 2)]
+    batch_seconds: u64,
+}
+
+#[derive(Args)]
+struct CompletionsArgs {
+    /// Shell to complete commands for
+    #[arg(value_enum)]
+    shell: Shell,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let server = Server::new(cli.server, cli.api_key);
+
+    match cli.command {
+        Commands::Hash(ImagesArgs { images }) => {
+            for path in &images {
+                let hash = hash_file(path)?;
+                println!("{}", path.display());
+                print_hash(&hash);
+            }
+        }
+        Commands::Submit(ImagesArgs { images }) => {
+            for path in &images {
+                let image = std::fs::read(path)
+                    .wrap_err_with(|| format!("could not read {}", path.display()))?;
+                let submitted = server
+                    .submit(image)
+                    .await
+                    .wrap_err_with(|| format!("could not submit {}", path.display()))?;
+                match submitted {
+                    Submitted::Stored(record) => {
+                        println!("{}: stored", path.display());
+                        print_record(&record);
+                    }
+                    Submitted::AlreadyStored => {
+                        println!("{}: already stored", path.display())
+                    }
+                }
+            }
+        }
+        Commands::Lookup(LookupArgs { phash }) => match server.lookup(&phash).await? {
+            Some(record) => print_record(&record),
+            None => return Err(eyre!("no image with perceptual hash {phash}")),
+        },
+        Commands::Verify(VerifyArgs { image }) => {
+            let hash = hash_file(&image)?;
+            match verify(&server, &hash).await {
+                Ok((origin, proof)) => println!(
+                    "PASS: {} is leaf {} of {} at tree size {}",
+                    image.display(),
+                    proof.leaf_index,
+                    origin,
+                    proof.tree_size
+                ),
+                Err(err) => {
+                    println!("FAIL: {}: {}", image.display(), err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Watch(args) => {
+            let format = args
+                .format
+                .unwrap_or_else(|| ManifestFormat::for_path(&args.manifest));
+            let options = WatchOptions {
+                manifest: args.manifest,
+                format,
+                concurrency: args.concurrency,
+                batch_window: Duration::from_secs(args.batch_seconds),
+            };
+            watch::run(&server, &args.dir, options).await?;
+        }
+        Commands::Completions(CompletionsArgs { shell }) => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        }
+    }
+
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<VeracityHash> {
+    let image =
+        std::fs::read(path).wrap_err_with(|| format!("could not read {}", path.display()))?;
+    hash_image(&image).wrap_err_with(|| format!("could not hash {}", path.display()))
+}
+
+/// Fetch the latest checkpoint and the image's inclusion proof in it, and check the proof leads
+/// from the image's leaf hash to the checkpoint's root hash. Only the hashes come from the
+/// server, the proof is checked here.
+async fn verify(server: &Server, hash: &VeracityHash) -> Result<(String, InclusionProof)> {
+    let checkpoint = server.checkpoint().await?;
+    let proof = server
+        .inclusion_proof(&hash.crypto_hash.to_string(), checkpoint.tree_size)
+        .await?;
+    if proof.tree_size != checkpoint.tree_size {
+        return Err(eyre!(
+            "proof is for tree size {}, not the checkpoint's {}",
+            proof.tree_size,
+            checkpoint.tree_size
+        ));
+    }
+    let hashes = proof
+        .hashes
+        .iter()
+        .map(hex::decode)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| eyre!("proof hashes are not hex"))?;
+    veracity_verify::verify_inclusion(
+        proof.leaf_index,
+        proof.tree_size,
+        &hash.merkle_leaf_hash(),
+        &hashes,
+        &checkpoint.root_hash,
+    )?;
+    Ok((checkpoint.origin, proof))
+}
+
+fn print_hash(hash: &VeracityHash) {
+    println!("  crypto hash:     {}", hash.crypto_hash);
+    println!("  perceptual hash: {}", hash.perceptual_hash);
+}
+
+/// Print the fields of an image record the server returned that people care about
+fn print_record(record: &Value) {
+    for (field, label) in [
+        ("crypto_hash", "crypto hash:    "),
+        ("perceptual_hash", "perceptual hash:"),
+        ("status", "status:         "),
+        ("leaf_index", "leaf index:     "),
+    ] {
+        match record.get(field) {
+            Some(Value::String(value)) => println!("  {label} {value}"),
+            Some(Value::Number(value)) => println!("  {label} {value}"),
+            _ => {}
+        }
+    }
+}
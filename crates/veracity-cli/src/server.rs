@@ -0,0 +1,196 @@
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use eyre::{eyre, Result};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Header carrying the API key, as the server expects it
+const API_KEY_HEADER: &str = "X-Auth-Key";
+/// Path prefix of the API version this client speaks
+const API_VERSION_PREFIX: &str = "v1/";
+
+/// An image veracity server, reached over its HTTP API
+pub struct Server {
+    client: Client,
+    url: Url,
+    api_key: Option<String>,
+}
+
+/// What the server did with an uploaded image
+pub enum Submitted {
+    /// Stored as a new image, with the record the server returned
+    Stored(Value),
+    /// The server already had this image
+    AlreadyStored,
+}
+
+/// The size and root hash of the log, from the server's signed checkpoint note
+#[derive(Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Name of the log
+    pub origin: String,
+    pub tree_size: u64,
+    pub root_hash: Vec<u8>,
+}
+
+impl Checkpoint {
+    /// Read the body of a checkpoint note: the origin, the tree size, and the base64 root hash
+    /// on separate lines. The signature lines after it are not checked.
+    pub fn parse(note: &str) -> Result<Checkpoint> {
+        let mut lines = note.lines();
+        let mut next = |name| {
+            lines
+                .next()
+                .ok_or_else(|| eyre!("checkpoint has no {name}"))
+        };
+        let origin = next("origin")?.to_string();
+        let tree_size = next("tree size")?
+            .parse()
+            .map_err(|_| eyre!("checkpoint tree size is not a number"))?;
+        let root_hash = BASE64_STANDARD
+            .decode(next("root hash")?)
+            .map_err(|_| eyre!("checkpoint root hash is not base64"))?;
+        Ok(Checkpoint {
+            origin,
+            tree_size,
+            root_hash,
+        })
+    }
+}
+
+/// Audit path proving an image is a leaf of the log
+#[derive(Debug, Deserialize)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    /// Hex hashes from the leaf up to the root
+    pub hashes: Vec<String>,
+}
+
+impl Server {
+    pub fn new(mut url: Url, api_key: Option<String>) -> Server {
+        // Keep any path prefix the server is mounted under when joining routes onto it
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Server {
+            client: Client::new(),
+            url,
+            api_key,
+        }
+    }
+
+    /// Upload an image as the raw request body
+    pub async fn submit(&self, image: Vec<u8>) -> Result<Submitted> {
+        let res = self
+            .request(self.client.put(self.endpoint("images")?))
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(image)
+            .send()
+            .await?;
+        match res.status() {
+            StatusCode::CREATED => Ok(Submitted::Stored(res.json().await?)),
+            StatusCode::CONFLICT => Ok(Submitted::AlreadyStored),
+            _ => Err(error_from(res).await),
+        }
+    }
+
+    /// The stored image with this perceptual hash, if there is one
+    pub async fn lookup(&self, perceptual_hash: &str) -> Result<Option<Value>> {
+        let res = self
+            .request(self.client.get(self.endpoint("images")?))
+            .query(&[("p", perceptual_hash)])
+            .send()
+            .await?;
+        match res.status() {
+            StatusCode::OK => Ok(Some(res.json().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(error_from(res).await),
+        }
+    }
+
+    /// The latest checkpoint of the log
+    pub async fn checkpoint(&self) -> Result<Checkpoint> {
+        let res = self
+            .request(self.client.get(self.endpoint("checkpoint")?))
+            .send()
+            .await?;
+        match res.status() {
+            StatusCode::OK => Checkpoint::parse(&res.text().await?),
+            _ => Err(error_from(res).await),
+        }
+    }
+
+    /// Inclusion proof of the image with this crypto hash in the tree of `tree_size` leaves
+    pub async fn inclusion_proof(
+        &self,
+        crypto_hash: &str,
+        tree_size: u64,
+    ) -> Result<InclusionProof> {
+        let res = self
+            .request(
+                self.client
+                    .get(self.endpoint(&format!("images/{crypto_hash}/proof"))?),
+            )
+            .query(&[("tree_size", tree_size)])
+            .send()
+            .await?;
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            StatusCode::NOT_FOUND => Err(eyre!("the server has no record of this image")),
+            _ => Err(error_from(res).await),
+        }
+    }
+
+    /// URL of the route at `path` in the API version this client speaks
+    fn endpoint(&self, path: &str) -> Result<Url> {
+        Ok(self.url.join(API_VERSION_PREFIX)?.join(path)?)
+    }
+
+    fn request(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(key) => request.header(API_KEY_HEADER, key),
+            None => request,
+        }
+    }
+}
+
+/// The message of an error response, which the server sends as JSON with an `error` field
+async fn error_from(res: Response) -> eyre::Report {
+    let status = res.status();
+    let message = res
+        .json::<Value>()
+        .await
+        .ok()
+        .and_then(|body| body.get("error")?.as_str().map(str::to_owned));
+    match message {
+        Some(message) => eyre!("server answered {status}: {message}"),
+        None => eyre!("server answered {status}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_checkpoint_notes() {
+        let note = "veracity.example.com/log\n42\nAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n\n\
+            \u{2014} veracity.example.com/log Az3grlgtzPICa5OS8npVmf1Myq/5IZniMp+ZJurmRDeOoRDe4URYN7u5/Zhcyv2q1gGzGku9nTo+zyWE+xeMcTOAYQ8=\n";
+        let checkpoint = Checkpoint::parse(note).unwrap();
+        assert_eq!(checkpoint.origin, "veracity.example.com/log");
+        assert_eq!(checkpoint.tree_size, 42);
+        assert_eq!(checkpoint.root_hash, (0..32).collect::<Vec<u8>>());
+        assert!(Checkpoint::parse("veracity.example.com/log\nmany\n").is_err());
+    }
+
+    #[test]
+    fn routes_are_joined_under_the_api_version() {
+        let server = Server::new("https://example.com/veracity".parse().unwrap(), None);
+        assert_eq!(
+            server.endpoint("images").unwrap().as_str(),
+            "https://example.com/veracity/v1/images"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! `veracity watch`: submit images as they land in a directory, for ingest pipelines.
+//!
+//! New files are collected for a short while so a burst of arrivals goes out as one batch,
+//! hashed on blocking threads and submitted a few at a time. Every file gets a line in the
+//! manifest, whether it was stored, already known to the server, or failed.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use eyre::{eyre, Result, WrapErr};
+use futures::StreamExt;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use veracity_hash::hash_image;
+
+use crate::server::{Server, Submitted};
+
+/// Layout of the manifest file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ManifestFormat {
+    /// Comma-separated values with a header row
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl ManifestFormat {
+    /// CSV for a `.csv` manifest, JSON lines otherwise
+    pub fn for_path(path: &Path) -> ManifestFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => ManifestFormat::Csv,
+            _ => ManifestFormat::Jsonl,
+        }
+    }
+}
+
+pub struct WatchOptions {
+    pub manifest: PathBuf,
+    pub format: ManifestFormat,
+    /// Most files hashed and submitted at once
+    pub concurrency: usize,
+    /// How long to collect new files before submitting them together
+    pub batch_window: Duration,
+}
+
+/// What happened to one file, as a line of the manifest
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct ManifestEntry {
+    path: String,
+    /// `stored`, `already_stored`, or `failed`
+    result: &'static str,
+    crypto_hash: Option<String>,
+    perceptual_hash: Option<String>,
+    error: Option<String>,
+}
+
+const CSV_HEADER: &str = "path,result,crypto_hash,perceptual_hash,error";
+
+/// Watch `dir` until the process is stopped, submitting each JPEG or PNG written to it
+pub async fn run(server: &Server, dir: &Path, options: WatchOptions) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .wrap_err_with(|| format!("could not watch {}", dir.display()))?;
+    let mut manifest = Manifest::open(&options.manifest, options.format)?;
+    println!("Watching {}", dir.display());
+
+    let mut seen = HashSet::new();
+    while let Some(event) = rx.recv().await {
+        let mut batch = BTreeSet::new();
+        add_images(&mut batch, event?);
+        // Files arriving together, such as a card being copied, go out in one batch
+        let window = tokio::time::sleep(options.batch_window);
+        tokio::pin!(window);
+        loop {
+            tokio::select! {
+                _ = &mut window => break,
+                event = rx.recv() => match event {
+                    Some(event) => add_images(&mut batch, event?),
+                    None => break,
+                },
+            }
+        }
+        batch.retain(|path| seen.insert(path.clone()));
+        if batch.is_empty() {
+            continue;
+        }
+
+        let mut entries = futures::stream::iter(batch)
+            .map(|path| ingest(server, path))
+            .buffer_unordered(options.concurrency.max(1));
+        while let Some(entry) = entries.next().await {
+            println!("{}: {}", entry.path, entry.result);
+            manifest.write(&entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Add the images an event says were written or moved into the directory
+fn add_images(batch: &mut BTreeSet<PathBuf>, event: Event) {
+    let written = matches!(
+        event.kind,
+        EventKind::Create(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both))
+    );
+    if !written {
+        return;
+    }
+    // A rename reports the old path first, so only its last path is where the file is now
+    let paths = match event.kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => event.paths.last().cloned(),
+        _ => None,
+    };
+    let paths = match paths {
+        Some(path) => vec![path],
+        None => event.paths,
+    };
+    batch.extend(paths.into_iter().filter(|path| is_image(path)));
+}
+
+fn is_image(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref(),
+        Some("jpg" | "jpeg" | "png")
+    )
+}
+
+/// Hash and submit one file, recording any failure in its entry
+async fn ingest(server: &Server, path: PathBuf) -> ManifestEntry {
+    let mut entry = ManifestEntry {
+        path: path.display().to_string(),
+        result: "failed",
+        ..ManifestEntry::default()
+    };
+    let image = match tokio::fs::read(&path).await {
+        Ok(image) => image,
+        Err(err) => {
+            entry.error = Some(format!("could not read: {err}"));
+            return entry;
+        }
+    };
+    // Decoding and hashing are CPU bound, keep them off the runtime threads
+    let (image, hash) = match tokio::task::spawn_blocking(move || {
+        let hash = hash_image(&image);
+        (image, hash)
+    })
+    .await
+    {
+        Ok((image, Ok(hash))) => (image, hash),
+        Ok((_, Err(err))) => {
+            entry.error = Some(format!("could not hash: {err}"));
+            return entry;
+        }
+        Err(err) => {
+            entry.error = Some(err.to_string());
+            return entry;
+        }
+    };
+    entry.crypto_hash = Some(hash.crypto_hash.to_string());
+    entry.perceptual_hash = Some(hash.perceptual_hash.to_string());
+    match server.submit(image).await {
+        Ok(Submitted::Stored(_)) => entry.result = "stored",
+        Ok(Submitted::AlreadyStored) => entry.result = "already_stored",
+        Err(err) => entry.error = Some(err.to_string()),
+    }
+    entry
+}
+
+/// Results file, appended to so a restarted watch keeps earlier results
+struct Manifest {
+    file: File,
+    format: ManifestFormat,
+}
+
+impl Manifest {
+    fn open(path: &Path, format: ManifestFormat) -> Result<Manifest> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("could not open manifest {}", path.display()))?;
+        if format == ManifestFormat::Csv && file.metadata()?.len() == 0 {
+            writeln!(file, "{CSV_HEADER}")?;
+        }
+        Ok(Manifest { file, format })
+    }
+
+    fn write(&mut self, entry: &ManifestEntry) -> Result<()> {
+        let line = match self.format {
+            ManifestFormat::Csv => csv_line(entry),
+            ManifestFormat::Jsonl => serde_json::to_string(entry)?,
+        };
+        writeln!(self.file, "{line}").map_err(|err| eyre!("could not write manifest: {err}"))
+    }
+}
+
+fn csv_line(entry: &ManifestEntry) -> String {
+    [
+        Some(entry.path.as_str()),
+        Some(entry.result),
+        entry.crypto_hash.as_deref(),
+        entry.perceptual_hash.as_deref(),
+        entry.error.as_deref(),
+    ]
+    .iter()
+    .map(|field| csv_field(field.unwrap_or_default()))
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// Quote a field when it holds a separator, quote, or line break, doubling inner quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_format_follows_the_extension() {
+        assert_eq!(
+            ManifestFormat::for_path(Path::new("out/manifest.CSV")),
+            ManifestFormat::Csv
+        );
+        assert_eq!(
+            ManifestFormat::for_path(Path::new("manifest.jsonl")),
+            ManifestFormat::Jsonl
+        );
+    }
+
+    #[test]
+    fn csv_lines_quote_awkward_fields() {
+        let entry = ManifestEntry {
+            path: "shoot, day 1/a.jpg".to_string(),
+            result: "failed",
+            error: Some("server answered 503: \"busy\"".to_string()),
+            ..ManifestEntry::default()
+        };
+        assert_eq!(
+            csv_line(&entry),
+            "\"shoot, day 1/a.jpg\",failed,,,\"server answered 503: \"\"busy\"\"\""
+        );
+        assert_eq!(CSV_HEADER.split(',').count(), 5);
+    }
+
+    #[test]
+    fn only_written_images_are_batched() {
+        let mut batch = BTreeSet::new();
+        add_images(
+            &mut batch,
+            Event::new(EventKind::Access(AccessKind::Close(AccessMode::Write)))
+                .add_path("in/a.JPG".into())
+                .add_path("in/notes.txt".into()),
+        );
+        add_images(
+            &mut batch,
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                .add_path("in/.b.png.part".into())
+                .add_path("in/b.png".into()),
+        );
+        add_images(
+            &mut batch,
+            Event::new(EventKind::Remove(notify::event::RemoveKind::File))
+                .add_path("in/c.png".into()),
+        );
+        assert_eq!(
+            batch.into_iter().collect::<Vec<_>>(),
+            vec![PathBuf::from("in/a.JPG"), PathBuf::from("in/b.png")]
+        );
+    }
+}
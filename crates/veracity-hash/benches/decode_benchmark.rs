@@ -0,0 +1,40 @@
+//! Hashing an image from one decoded pixel buffer against hashing the [`DynamicImage`] twice,
+//! once per hash, as `hash_image` used to.
+
+use blockhash::blockhash256;
+use criterion::{criterion_group, criterion_main, Criterion, SamplingMode, Throughput};
+use image::DynamicImage;
+use ring::digest::{digest, SHA256};
+use veracity_hash::hash_image;
+
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../resources/test/test_2890kb.jpg"
+);
+
+fn hash_dynamic_image(image: &DynamicImage) {
+    blockhash256(image);
+    digest(&SHA256, image.as_bytes());
+}
+
+fn decode_benchmark(c: &mut Criterion) {
+    let bytes = std::fs::read(FIXTURE).expect("the 2.8 MB fixture should be readable");
+
+    let mut group = c.benchmark_group("hash_2890kb_jpg");
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("dynamic_image", |b| {
+        b.iter(|| {
+            let image = image::load_from_memory(&bytes).expect("image to be valid");
+            hash_dynamic_image(&image);
+        })
+    });
+    group.bench_function("pixel_buffer", |b| {
+        b.iter(|| hash_image(&bytes).expect("image to be valid"))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode_benchmark);
+criterion_main!(benches);
@@ -9,7 +9,7 @@ use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tracing::error;
 
-use crate::hash::HashError;
+use crate::HashError;
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, JsonSchema)]
 pub struct CryptographicHash([u8; 32]);
@@ -19,7 +19,12 @@ impl Serialize for CryptographicHash {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&self.0.encode_hex::<String>())
+        // Binary formats such as CBOR carry the hash as raw bytes instead of hex
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.0.encode_hex::<String>())
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
     }
 }
 
@@ -63,6 +68,15 @@ impl<'de> Visitor<'de> for CryptographicHashVisitor {
             }
         }
     }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        <[u8; 32]>::try_from(v)
+            .map(CryptographicHash)
+            .map_err(|_| Error::invalid_length(v.len(), &"32 bytes"))
+    }
 }
 
 impl<'de> Deserialize<'de> for CryptographicHash {
@@ -70,7 +84,11 @@ impl<'de> Deserialize<'de> for CryptographicHash {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_str(CryptographicHashVisitor)
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(CryptographicHashVisitor)
+        } else {
+            deserializer.deserialize_bytes(CryptographicHashVisitor)
+        }
     }
 }
 
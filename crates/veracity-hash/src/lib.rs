@@ -0,0 +1,519 @@
+//! The two hashes the image veracity log keeps for every image.
+//!
+//! The crypto hash is SHA-256 over the decoded pixels, so it names an exact image whatever
+//! file it came in, and is the leaf value logged in Trillian. The perceptual hash is a 256-bit
+//! blockhash that stays close for re-encoded or resized copies. Clients hash images with this
+//! crate to get the same values the server logs.
+//!
+//! Both are taken over 8-bit RGB pixels, or RGBA for images with an alpha channel, whatever the
+//! file stored:
+//!
+//! - Baseline and progressive JPEGs decode straight to RGB; CMYK and YCCK JPEGs, as many press
+//!   photos are, are converted to RGB first, the same way whichever `image` release decodes them.
+//! - Grayscale images have their gray copied to red, green and blue.
+//! - 16-bit samples are scaled to 8 bits as `round(v * 255 / 65535)`, so a 16-bit PNG made by
+//!   widening an 8-bit one hashes like the original.
+
+use std::fmt::Debug;
+use std::io::Cursor;
+
+use blockhash::{blockhash256, Image};
+use image::{io::Reader, DynamicImage, ImageBuffer, ImageFormat, Pixel};
+use jpeg_decoder::PixelFormat;
+use ring::digest::{digest, Context, Digest, SHA256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::cryptographic::CryptographicHash;
+use crate::perceptual::PerceptualHash;
+use crate::HashError::{ImageDecodeError, ImageHashError, ImageTypeUnknown, ImageTypeUnsupported};
+
+pub mod cryptographic;
+pub mod perceptual;
+
+/// RFC 6962 domain separation prefix Trillian hashes leaves with
+const LEAF_HASH_PREFIX: u8 = 0;
+/// Largest pixel buffer a CMYK JPEG may decode to, the limit `image` decodes other images with
+const MAX_DECODED_BYTES: usize = 512 * 1024 * 1024;
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct VeracityHash {
+    pub perceptual_hash: PerceptualHash,
+    pub crypto_hash: CryptographicHash,
+}
+
+impl VeracityHash {
+    /// Merkle leaf hash of the image's leaf in the log, SHA-256 over `0x00` and the leaf value,
+    /// the crypto hash, exactly as Trillian computes it. Inclusion proofs are looked up by and
+    /// verified from this hash.
+    pub fn merkle_leaf_hash(&self) -> [u8; 32] {
+        let mut context = Context::new(&SHA256);
+        context.update(&[LEAF_HASH_PREFIX]);
+        context.update(self.crypto_hash.as_ref());
+        context
+            .finish()
+            .as_ref()
+            .try_into()
+            .expect("SHA-256 digest is 32 bytes")
+    }
+}
+
+#[inline]
+pub fn hash_image(buffer: &[u8]) -> Result<VeracityHash, HashError> {
+    let reader = Reader::new(Cursor::new(buffer))
+        .with_guessed_format()
+        .map_err(|_| ImageDecodeError)?;
+    match reader.format() {
+        Some(ImageFormat::Jpeg) => match decode_cmyk_jpeg(buffer)? {
+            Some(image) => hash_decoded(&image),
+            None => decode_and_hash(reader),
+        },
+        Some(ImageFormat::Png) => decode_and_hash(reader),
+        Some(format) => Err(ImageTypeUnsupported(format)),
+        None => Err(ImageTypeUnknown),
+    }
+}
+
+fn decode_and_hash(reader: Reader<Cursor<&[u8]>>) -> Result<VeracityHash, HashError> {
+    match reader.decode() {
+        Ok(image) => hash_decoded(&image),
+        Err(e) => {
+            error!("{}", e.to_string());
+            Err(ImageDecodeError)
+        }
+    }
+}
+
+/// Decode a JPEG stored as CMYK or YCCK to RGB, or `None` for any other JPEG. The conversion is
+/// done here rather than left to `image`, so the hashes of these images are pinned to
+/// [`cmyk_to_rgb`] and cannot change with the `image` release a client happens to build with.
+fn decode_cmyk_jpeg(buffer: &[u8]) -> Result<Option<DynamicImage>, HashError> {
+    let mut decoder = jpeg_decoder::Decoder::new(buffer);
+    decoder.set_max_decoding_buffer_size(MAX_DECODED_BYTES);
+    let info = match decoder.read_info().map(|_| decoder.info()) {
+        Ok(Some(info)) => info,
+        Ok(None) => return Err(ImageDecodeError),
+        Err(e) => {
+            error!("{}", e.to_string());
+            return Err(ImageDecodeError);
+        }
+    };
+    if info.pixel_format != PixelFormat::CMYK32 {
+        return Ok(None);
+    }
+    let cmyk = decoder.decode().map_err(|e| {
+        error!("{}", e.to_string());
+        ImageDecodeError
+    })?;
+    ImageBuffer::from_raw(info.width.into(), info.height.into(), cmyk_to_rgb(&cmyk))
+        .map(|pixels| Some(DynamicImage::ImageRgb8(pixels)))
+        .ok_or(ImageDecodeError)
+}
+
+/// The canonical RGB of CMYK pixels, `R = (255 - C) * (255 - K) / 255` and likewise for green
+/// and blue, in integer arithmetic and without a colour profile. This is the conversion `image`
+/// 0.24 made, so CMYK images logged before it was pinned keep their hashes. The decoder has
+/// already undone the inversion Adobe applications store CMYK and YCCK with.
+fn cmyk_to_rgb(cmyk: &[u8]) -> Vec<u8> {
+    cmyk.chunks_exact(4)
+        .flat_map(|pixel| {
+            let k = 255 - u16::from(pixel[3]);
+            [pixel[0], pixel[1], pixel[2]].map(|c| ((255 - u16::from(c)) * k / 255) as u8)
+        })
+        .collect()
+}
+
+/// Both hashes of an image decoded once. 8-bit RGB and RGBA, nearly every upload, are hashed
+/// straight from their pixel buffer; converting RGB to RGBA first would change the crypto hash of
+/// every image without an alpha channel. Anything else is normalized to one of the two first.
+fn hash_decoded(image: &DynamicImage) -> Result<VeracityHash, HashError> {
+    match image {
+        DynamicImage::ImageRgb8(pixels) => hash_pixels(pixels),
+        DynamicImage::ImageRgba8(pixels) => hash_pixels(pixels),
+        image => hash_decoded(&normalize(image)),
+    }
+}
+
+/// An image as 8-bit RGB, or RGBA if it has an alpha channel. Grayscale and 16-bit images are
+/// converted here rather than by `image`, so their crypto hashes do not depend on its conversions
+/// or on the byte order 16-bit samples are held in.
+fn normalize(image: &DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLuma8(pixels) => to_rgb8(pixels, |sample| sample),
+        DynamicImage::ImageLumaA8(pixels) => to_rgb8(pixels, |sample| sample),
+        DynamicImage::ImageLuma16(pixels) => to_rgb8(pixels, scale_16_bit),
+        DynamicImage::ImageLumaA16(pixels) => to_rgb8(pixels, scale_16_bit),
+        DynamicImage::ImageRgb16(pixels) => to_rgb8(pixels, scale_16_bit),
+        DynamicImage::ImageRgba16(pixels) => to_rgb8(pixels, scale_16_bit),
+        // Neither JPEG nor PNG decodes to anything else
+        image if image.color().has_alpha() => DynamicImage::ImageRgba8(image.to_rgba8()),
+        image => DynamicImage::ImageRgb8(image.to_rgb8()),
+    }
+}
+
+/// Gray or RGB pixels, with or without alpha, as 8-bit RGB or RGBA with each sample scaled by
+/// `to_8_bit`
+fn to_rgb8<P, S>(pixels: &ImageBuffer<P, Vec<S>>, to_8_bit: fn(S) -> u8) -> DynamicImage
+where
+    P: Pixel<Subpixel = S>,
+    S: Copy,
+{
+    let (width, height) = pixels.dimensions();
+    let has_alpha = P::CHANNEL_COUNT % 2 == 0;
+    let mut raw = Vec::with_capacity(pixels.len() / usize::from(P::CHANNEL_COUNT) * 4);
+    for pixel in pixels.pixels() {
+        let samples = pixel.channels();
+        let color = match samples.len() {
+            1 | 2 => [samples[0]; 3],
+            _ => [samples[0], samples[1], samples[2]],
+        };
+        raw.extend(color.map(to_8_bit));
+        if has_alpha {
+            raw.push(to_8_bit(samples[samples.len() - 1]));
+        }
+    }
+    let image = if has_alpha {
+        ImageBuffer::from_raw(width, height, raw).map(DynamicImage::ImageRgba8)
+    } else {
+        ImageBuffer::from_raw(width, height, raw).map(DynamicImage::ImageRgb8)
+    };
+    image.expect("a pixel out for every pixel in")
+}
+
+/// A 16-bit sample rounded to the nearest 8-bit one, so `v * 257` scales back to `v`
+fn scale_16_bit(sample: u16) -> u8 {
+    ((u32::from(sample) * 255 + 32767) / 65535) as u8
+}
+
+/// Both hashes from one typed pixel buffer, which blockhash reads without converting each
+/// pixel through [`DynamicImage`] and SHA-256 reads as the raw bytes
+fn hash_pixels<P>(pixels: &ImageBuffer<P, Vec<u8>>) -> Result<VeracityHash, HashError>
+where
+    P: Pixel<Subpixel = u8>,
+    ImageBuffer<P, Vec<u8>>: Image,
+{
+    Ok(VeracityHash {
+        perceptual_hash: blockhash256(pixels).into(),
+        crypto_hash: default_crypto_hash(pixels.as_raw())
+            .try_into()
+            .map_err(|_| ImageHashError)?,
+    })
+}
+
+fn default_crypto_hash(pixels: &[u8]) -> Digest {
+    digest(&SHA256, pixels)
+}
+
+#[derive(Error, Debug)]
+pub enum HashError {
+    #[error("did not recognize image type")]
+    ImageTypeUnknown,
+    #[error("image format unsupported")]
+    ImageTypeUnsupported(ImageFormat),
+    #[error("could not decode image from format")]
+    ImageDecodeError,
+    #[error("could not hash image")]
+    ImageHashError,
+    #[error("hash string was not valid base64")]
+    InvalidBase64,
+    #[error("hash bytes length not 32")]
+    InvalidLength,
+    #[error("hash string was not valid hex characters")]
+    InvalidHexCharacters,
+}
+
+#[cfg(test)]
+mod tests {
+    use blockhash::Blockhash256;
+    use eyre::Result;
+    use image::EncodableLayout;
+    use ring::test;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const IMAGE_PATH: &str = "resources/test";
+
+    use std::{env, process::Command};
+
+    /// Get the absolute path from the Cargo workspace root for project-level resources
+    pub fn get_workspace_root() -> Result<PathBuf> {
+        let current_dir = env::current_dir()?;
+        let cmd_output = Command::new("cargo")
+            .args(["metadata", "--format-version=1"])
+            .output()?;
+
+        if !cmd_output.status.success() {
+            return Ok(current_dir);
+        }
+
+        let json = serde_json::from_str::<serde_json::Value>(
+            String::from_utf8(cmd_output.stdout)?.as_str(),
+        )?;
+        let path = match json.get("workspace_root") {
+            Some(val) => match val.as_str() {
+                Some(val) => val,
+                None => return Ok(current_dir),
+            },
+            None => return Ok(current_dir),
+        };
+        Ok(fs::canonicalize(PathBuf::from(path)).unwrap())
+    }
+
+    fn crypto_image(image: &DynamicImage) -> Digest {
+        let pixels = image.as_bytes();
+        default_crypto_hash(pixels)
+    }
+
+    fn get_test_image(image_name: &str) -> DynamicImage {
+        let image_path = get_workspace_root()
+            .expect("workspace should have a root")
+            .join(format!("{}/{}", IMAGE_PATH, image_name));
+
+        match image::open(&image_path) {
+            Ok(img) => img,
+            Err(err) => {
+                panic!("could not get image from {:?} err: {:?}", &image_path, err)
+            }
+        }
+    }
+
+    #[test]
+    fn blockhash_persistent_hash() {
+        let known_hash = Blockhash256::from([
+            0x9c, 0xfd, 0xe0, 0x3d, 0xc4, 0x19, 0x84, 0x67, 0xad, 0x67, 0x1d, 0x17, 0x1c, 0x07,
+            0x1c, 0x5b, 0x1f, 0xf8, 0x1b, 0xf9, 0x19, 0xd9, 0x18, 0x18, 0x38, 0xf8, 0xf8, 0x90,
+            0xf8, 0x07, 0xff, 0x01,
+        ]);
+        let known_hex = "9cfde03dc4198467ad671d171c071c5b1ff81bf919d9181838f8f890f807ff01";
+
+        let img = get_test_image("test_495kb.png");
+        let hash = blockhash256(&img);
+
+        assert_eq!(hash, known_hash);
+
+        assert_eq!(hash.to_string(), known_hex);
+
+        let expected: Vec<u8> = test::from_hex(known_hex).unwrap();
+        let hash_bytes: [u8; 32] = hash.into();
+
+        assert_eq!(&hash_bytes, &expected.as_bytes())
+    }
+
+    #[test]
+    fn blockhash_same_between_formats() {
+        // baseline
+        let img_png = get_test_image("test_495kb.png");
+        let hash1 = blockhash256(&img_png);
+
+        let img_jpg = get_test_image("test_from_495kb_png.jpg");
+        let hash2 = blockhash256(&img_jpg);
+
+        assert_eq!(hash1, hash2);
+
+        // monochrome image
+        let img_png = get_test_image("test_1050kb.png");
+        let hash1 = blockhash256(&img_png);
+
+        let img_jpg = get_test_image("test_from_1050kb_png.jpg");
+        let hash2 = blockhash256(&img_jpg);
+
+        assert_eq!(hash1, hash2);
+
+        // large jpg -> larger png
+        let large_png = get_test_image("test_from_2890kb_jpg.png");
+        let hash_large_png = blockhash256(&large_png);
+
+        let large_jpg = get_test_image("test_2890kb.jpg");
+        let hash_large_jpg = blockhash256(&large_jpg);
+
+        assert_eq!(hash_large_png, hash_large_jpg);
+    }
+
+    #[test]
+    fn pixel_buffers_hash_like_the_decoded_image() {
+        let root = get_workspace_root().expect("workspace should have a root");
+        for image_name in ["test_495kb.png", "test_2890kb.jpg", "test_22kb.jpg"] {
+            let bytes = fs::read(root.join(IMAGE_PATH).join(image_name)).unwrap();
+            let image = get_test_image(image_name);
+            let hash = hash_image(&bytes).unwrap();
+            assert_eq!(
+                hash.perceptual_hash,
+                PerceptualHash::from(blockhash256(&image))
+            );
+            assert_eq!(
+                hash.crypto_hash,
+                CryptographicHash::try_from(crypto_image(&image)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    /// Progressive, CMYK and YCCK copies of a JPEG hash as RGB, close to the original
+    fn progressive_and_cmyk_jpegs_hash_as_rgb() {
+        let root = get_workspace_root().expect("workspace should have a root");
+        let read = |image_name: &str| fs::read(root.join(IMAGE_PATH).join(image_name)).unwrap();
+        let original = hash_image(&read("test_22kb.jpg")).unwrap();
+        for (image_name, known_hash) in [
+            (
+                "test_from_22kb_jpg_progressive.jpg",
+                "Q71k7EOdM5q6NSJH5D4LRR71sNux0NghtgtbsPZUSdo",
+            ),
+            (
+                "test_from_22kb_jpg_cmyk.jpg",
+                "RvUsbPMomVslAHYGqOg7hH4tNW8iNiTNHzySmWY6PXI",
+            ),
+            (
+                "test_from_22kb_jpg_ycck.jpg",
+                "oFOJHlwg4iT1DsZ7Vkq99mLUgPZZS6kK9GR9-HWhAm0",
+            ),
+        ] {
+            let hash = hash_image(&read(image_name)).unwrap();
+            assert_eq!(hash.crypto_hash.to_b64(), known_hash, "{image_name}");
+            let distance = hash
+                .perceptual_hash
+                .hamming_distance(&original.perceptual_hash);
+            assert!(distance <= 10, "{image_name} is {distance} bits away");
+        }
+    }
+
+    #[test]
+    /// Grayscale and 16-bit images hash as 8-bit RGB or RGBA, and a 16-bit PNG widened from an
+    /// 8-bit image exactly like the original
+    fn grayscale_and_16_bit_images_are_normalized() {
+        let root = get_workspace_root().expect("workspace should have a root");
+        let read = |image_name: &str| fs::read(root.join(IMAGE_PATH).join(image_name)).unwrap();
+        let original = hash_image(&read("test_22kb.jpg")).unwrap();
+        let widened = hash_image(&read("test_from_22kb_jpg_16bit.png")).unwrap();
+        assert_eq!(widened.crypto_hash, original.crypto_hash);
+        assert_eq!(widened.perceptual_hash, original.perceptual_hash);
+        for (image_name, known_hash) in [
+            (
+                "test_1050kb.png",
+                "6Kpnqdw34zsmfBmd2CO-ckpLFHcUi28VTIA4ZNyEGV8",
+            ),
+            (
+                "test_from_1050kb_png.jpg",
+                "cvcldgNx74QboT0IaWtWiFV6D4uy2hVe4LJcVV1DBVY",
+            ),
+            (
+                "test_from_22kb_jpg_gray_alpha_16bit.png",
+                "zY4mDqO5Sl5U98Inyw50Ayva3xBnHomUxr79UjCo6b8",
+            ),
+        ] {
+            let hash = hash_image(&read(image_name)).unwrap();
+            assert_eq!(hash.crypto_hash.to_b64(), known_hash, "{image_name}");
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_samples_round_to_eight() {
+        assert_eq!(scale_16_bit(0), 0);
+        assert_eq!(scale_16_bit(128), 0);
+        assert_eq!(scale_16_bit(129), 1);
+        assert_eq!(scale_16_bit(200 * 257), 200);
+        assert_eq!(scale_16_bit(u16::MAX), 255);
+    }
+
+    #[test]
+    fn cmyk_converts_to_rgb() {
+        assert_eq!(
+            cmyk_to_rgb(&[0, 0, 0, 0, 0, 0, 0, 255, 255, 0, 0, 0, 0, 128, 255, 128]),
+            vec![255, 255, 255, 0, 0, 0, 0, 255, 255, 127, 63, 0]
+        );
+    }
+
+    #[test]
+    /// Test hashing output does not change across versions
+    fn crypto_persistent_hash() {
+        let known_hash = "oY1OmtqoZ32_nUVGgKzmAAdn6Bo0ndvr-YhnDRYju4U";
+
+        let img = get_test_image("test_495kb.png");
+        let crypt_hash: CryptographicHash = crypto_image(&img)
+            .try_into()
+            .expect("valid, decodable image");
+        assert_eq!(crypt_hash.to_b64(), known_hash)
+    }
+
+    #[test]
+    fn merkle_leaf_hash_prefixes_the_crypto_hash() {
+        let hash = VeracityHash {
+            crypto_hash: CryptographicHash::try_from((0..32).collect::<Vec<u8>>()).unwrap(),
+            ..Default::default()
+        };
+        let mut leaf = vec![0];
+        leaf.extend_from_slice(hash.crypto_hash.as_ref());
+        assert_eq!(
+            hash.merkle_leaf_hash().as_slice(),
+            digest(&SHA256, &leaf).as_ref()
+        );
+        assert_eq!(
+            hex::encode(hash.merkle_leaf_hash()),
+            "699cacdb4c39d8e0bb1223352765a7f7acdc51dec6694f7b54c3d0a47f0cc409"
+        );
+    }
+
+    #[test]
+    /// Test hashing equivalence with known Golang Trillian implementation.
+    /// Trillian hasher uses domain prefix of "0" for leaves and "1" for nodes.
+    /// This domain separation is for second preimage resistance aka collision avoidance.
+    /// Domain separation prefixes:
+    /// ```notrust
+    /// const (
+    ///   RFC6962LeafHashPrefix = 0
+    ///   RFC6962NodeHashPrefix = 1
+    /// )
+    /// ```
+    fn crypto_hash_compare_known_golang() {
+        // So for a value of [u8; 0, 0, 0, 0, 0, 0, 0, 0] it's actually:
+        // let data = [0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let data: Vec<u8> = vec![0; 9];
+        let expected =
+            test::from_hex("3e7077fd2f66d689e0cee6a7cf5b37bf2dca7c979af356d0a31cbc5c85605c7d")
+                .unwrap();
+        let actual = digest(&SHA256, &data);
+        assert_eq!(&expected, &actual.as_ref());
+
+        let image = get_test_image("test_495kb.png");
+
+        // Expected hash created from the test_495kb.png using Golang:
+        // ```golang
+        // package main
+        //
+        // import (
+        // 	"encoding/hex"
+        // 	"github.com/transparency-dev/merkle/rfc6962"
+        // 	"image"
+        // 	"image/png"
+        // 	"os"
+        // )
+        //
+        // func createEntry(img image.RGBA) []byte {
+        // 	return rfc6962.DefaultHasher.HashLeaf(img.Pix)
+        // }
+        //
+        // func main() {
+        //	file1, _ := os.Open("test_495kb.png")
+        //	defer func(file1 *os.File) {
+        //		_ = file1.Close()
+        //	}(file1)
+        //
+        // 	img, _ := png.Decode(file1)
+        // 	rgba, _ := img.(*image.RGBA)
+        // 	entry := createEntry(*rgba)
+        // 	println(hex.EncodeToString(entry))
+        // }
+        // ```
+        let expected =
+            test::from_hex("e40e70d70cde3a0edfdc74bd659869b1dccaf05ef69f897ce78b02ccb33fe227")
+                .unwrap();
+        let mut pixels = image.into_rgba8().as_bytes().to_vec();
+        // Add domain prefix
+        pixels.insert(0, 0);
+        let actual = digest(&SHA256, &pixels);
+        assert_eq!(&expected, &actual.as_ref());
+    }
+}
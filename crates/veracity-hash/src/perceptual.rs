@@ -9,7 +9,7 @@ use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tracing::error;
 
-use crate::hash::HashError;
+use crate::HashError;
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, JsonSchema)]
 pub struct PerceptualHash([u8; 32]);
@@ -19,7 +19,12 @@ impl Serialize for PerceptualHash {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&self.0.encode_hex::<String>())
+        // Binary formats such as CBOR carry the hash as raw bytes instead of hex
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.0.encode_hex::<String>())
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
     }
 }
 
@@ -57,6 +62,15 @@ impl<'de> Visitor<'de> for PerceptualHashVisitor {
             }
         }
     }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        <[u8; 32]>::try_from(v)
+            .map(PerceptualHash)
+            .map_err(|_| Error::invalid_length(v.len(), &"32 bytes"))
+    }
 }
 
 impl<'de> Deserialize<'de> for PerceptualHash {
@@ -64,7 +78,11 @@ impl<'de> Deserialize<'de> for PerceptualHash {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_str(PerceptualHashVisitor)
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(PerceptualHashVisitor)
+        } else {
+            deserializer.deserialize_bytes(PerceptualHashVisitor)
+        }
     }
 }
 
@@ -138,6 +156,15 @@ impl PerceptualHash {
     pub fn to_hex(&self) -> String {
         self.0.encode_hex()
     }
+
+    /// Number of differing bits between two perceptual hashes
+    pub fn hamming_distance(&self, other: &PerceptualHash) -> u32 {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
 }
 
 #[cfg(test)]
@@ -157,4 +184,18 @@ mod tests {
         ]);
         assert_eq!(&crypto, &blockhash);
     }
+
+    #[test]
+    fn hamming_distance() {
+        let zero = PerceptualHash::default();
+        let mut bytes = [0_u8; 32];
+        assert_eq!(zero.hamming_distance(&PerceptualHash(bytes)), 0);
+
+        bytes[0] = 0b1010_0001;
+        bytes[31] = 0xFF;
+        let other = PerceptualHash(bytes);
+        assert_eq!(zero.hamming_distance(&other), 11);
+        assert_eq!(other.hamming_distance(&zero), 11);
+        assert_eq!(other.hamming_distance(&other), 0);
+    }
 }
@@ -0,0 +1,54 @@
+use sha2::{Digest, Sha256};
+
+/// Length of every hash in the tree, SHA-256
+pub const HASH_SIZE: usize = 32;
+
+/// RFC 6962 domain separation prefix for leaf hashes
+const LEAF_HASH_PREFIX: u8 = 0;
+/// RFC 6962 domain separation prefix for interior node hashes
+const NODE_HASH_PREFIX: u8 = 1;
+
+/// Merkle leaf hash of a leaf holding `leaf_value`, as Trillian computes it
+pub fn leaf_hash(leaf_value: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update([LEAF_HASH_PREFIX])
+        .chain_update(leaf_value)
+        .finalize()
+        .to_vec()
+}
+
+/// Hash of an interior node from the hashes of its children
+pub fn node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update([NODE_HASH_PREFIX])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaf_hashes_match_trillian() {
+        assert_eq!(
+            hex::encode(leaf_hash(&[0; 8])),
+            "3e7077fd2f66d689e0cee6a7cf5b37bf2dca7c979af356d0a31cbc5c85605c7d"
+        );
+        assert_eq!(
+            hex::encode(leaf_hash(&[])),
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d"
+        );
+    }
+
+    #[test]
+    fn nodes_are_domain_separated_from_leaves() {
+        let (left, right) = ([1; HASH_SIZE], [2; HASH_SIZE]);
+        let mut concatenated = left.to_vec();
+        concatenated.extend_from_slice(&right);
+        assert_ne!(node_hash(&left, &right), leaf_hash(&concatenated));
+        assert_ne!(node_hash(&left, &right), node_hash(&right, &left));
+    }
+}
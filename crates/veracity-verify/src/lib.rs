@@ -0,0 +1,35 @@
+//! Offline verification for the image veracity log.
+//!
+//! Everything a client needs to check what the server or Trillian tells it, without trusting
+//! either: RFC 6962 leaf and node hashing, inclusion and consistency proof verification as
+//! specified in RFC 9162 section 2.1, and parsing of the TLS-serialized `LogRootV1` Trillian
+//! returns in `SignedLogRoot.log_root`. Pure Rust with no I/O, so it builds anywhere.
+//!
+//! Roots and proofs can also be computed over leaves held in memory, for test fixtures and
+//! vectors to check verifiers against.
+
+use thiserror::Error;
+
+mod hash;
+mod log_root;
+mod proof;
+mod tree;
+
+pub use hash::{leaf_hash, node_hash, HASH_SIZE};
+pub use log_root::LogRootV1;
+pub use proof::{root_from_inclusion_proof, verify_consistency, verify_inclusion};
+pub use tree::{consistency_proof, inclusion_proof, root_hash};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error("leaf index {index} is outside a tree of {tree_size} leaves")]
+    IndexOutOfRange { index: u64, tree_size: u64 },
+    #[error("tree size {second} is smaller than {first}")]
+    SizesOutOfOrder { first: u64, second: u64 },
+    #[error("proof has the wrong number of hashes")]
+    WrongProofLength,
+    #[error("proof does not lead to the expected root hash")]
+    RootMismatch,
+    #[error("log root could not be parsed: {0}")]
+    InvalidLogRoot(&'static str),
+}
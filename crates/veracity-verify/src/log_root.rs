@@ -0,0 +1,178 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::VerifyError;
+
+/// `LogRootFormat` of the only log root version Trillian produces
+const LOG_ROOT_V1: u16 = 1;
+/// Longest root hash the format allows
+const MAX_ROOT_HASH_SIZE: usize = 128;
+
+/// A Trillian log root, the body signed in `SignedLogRoot`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogRootV1 {
+    /// Number of leaves integrated into the tree
+    pub tree_size: u64,
+    /// Merkle root hash over those leaves
+    pub root_hash: Vec<u8>,
+    /// When the root was produced, in nanoseconds since the Unix epoch
+    pub timestamp_nanos: u64,
+    /// Revision of the tree this root describes
+    pub revision: u64,
+    /// Opaque metadata the log attached to the root
+    pub metadata: Vec<u8>,
+}
+
+impl LogRootV1 {
+    /// Read a TLS-serialized `LogRoot`: a two byte version, then for version 1 the tree size,
+    /// the root hash with a one byte length, the timestamp, the revision, and the metadata with
+    /// a two byte length. Trailing bytes are rejected.
+    pub fn parse(log_root: &[u8]) -> Result<Self, VerifyError> {
+        let mut reader = Reader(log_root);
+        if reader.u16()? != LOG_ROOT_V1 {
+            return Err(VerifyError::InvalidLogRoot("unsupported version"));
+        }
+        let tree_size = reader.u64()?;
+        let hash_len = reader.take(1)?[0] as usize;
+        if hash_len > MAX_ROOT_HASH_SIZE {
+            return Err(VerifyError::InvalidLogRoot("root hash is too long"));
+        }
+        let root_hash = reader.take(hash_len)?.to_vec();
+        let timestamp_nanos = reader.u64()?;
+        let revision = reader.u64()?;
+        let metadata_len = reader.u16()? as usize;
+        let metadata = reader.take(metadata_len)?.to_vec();
+        if !reader.0.is_empty() {
+            return Err(VerifyError::InvalidLogRoot("trailing bytes"));
+        }
+        Ok(LogRootV1 {
+            tree_size,
+            root_hash,
+            timestamp_nanos,
+            revision,
+            metadata,
+        })
+    }
+
+    /// When the root was produced
+    pub fn timestamp(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.timestamp_nanos)
+    }
+
+    /// TLS-serialize the root as version 1, the inverse of [`LogRootV1::parse`]. Fails when
+    /// the root hash or metadata is too long for its length prefix.
+    pub fn serialize(&self) -> Result<Vec<u8>, VerifyError> {
+        if self.root_hash.len() > MAX_ROOT_HASH_SIZE {
+            return Err(VerifyError::InvalidLogRoot("root hash is too long"));
+        }
+        let metadata_len = u16::try_from(self.metadata.len())
+            .map_err(|_| VerifyError::InvalidLogRoot("metadata is too long"))?;
+        let mut buffer = Vec::with_capacity(29 + self.root_hash.len() + self.metadata.len());
+        buffer.extend_from_slice(&LOG_ROOT_V1.to_be_bytes());
+        buffer.extend_from_slice(&self.tree_size.to_be_bytes());
+        buffer.push(self.root_hash.len() as u8);
+        buffer.extend_from_slice(&self.root_hash);
+        buffer.extend_from_slice(&self.timestamp_nanos.to_be_bytes());
+        buffer.extend_from_slice(&self.revision.to_be_bytes());
+        buffer.extend_from_slice(&metadata_len.to_be_bytes());
+        buffer.extend_from_slice(&self.metadata);
+        Ok(buffer)
+    }
+}
+
+/// Cursor over the serialized bytes, failing on truncation
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VerifyError> {
+        if self.0.len() < len {
+            return Err(VerifyError::InvalidLogRoot("truncated"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, VerifyError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, VerifyError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> LogRootV1 {
+        LogRootV1 {
+            tree_size: 7,
+            root_hash: vec![0xab; 32],
+            timestamp_nanos: 1_687_276_455_000_000_000,
+            revision: 7,
+            metadata: b"meta".to_vec(),
+        }
+    }
+
+    #[test]
+    fn log_roots_round_trip() {
+        let serialized = example().serialize().unwrap();
+        assert_eq!(&serialized[..2], &[0, 1]);
+        assert_eq!(&serialized[2..10], &7_u64.to_be_bytes());
+        assert_eq!(serialized[10], 32);
+        assert_eq!(LogRootV1::parse(&serialized).unwrap(), example());
+        assert_eq!(
+            example().timestamp(),
+            UNIX_EPOCH + Duration::from_secs(1_687_276_455)
+        );
+    }
+
+    #[test]
+    fn malformed_log_roots_are_rejected() {
+        let serialized = example().serialize().unwrap();
+        for len in 0..serialized.len() {
+            assert!(LogRootV1::parse(&serialized[..len]).is_err(), "{len} bytes");
+        }
+        let mut trailing = serialized.clone();
+        trailing.push(0);
+        assert!(LogRootV1::parse(&trailing).is_err());
+        let mut version = serialized;
+        version[1] = 2;
+        assert_eq!(
+            LogRootV1::parse(&version),
+            Err(VerifyError::InvalidLogRoot("unsupported version"))
+        );
+    }
+
+    #[test]
+    fn oversized_fields_are_not_serialized() {
+        let largest = LogRootV1 {
+            root_hash: vec![0xab; MAX_ROOT_HASH_SIZE],
+            metadata: vec![0; u16::MAX as usize],
+            ..example()
+        };
+        let serialized = largest.serialize().unwrap();
+        assert_eq!(LogRootV1::parse(&serialized).unwrap(), largest);
+
+        let long_hash = LogRootV1 {
+            root_hash: vec![0xab; MAX_ROOT_HASH_SIZE + 1],
+            ..example()
+        };
+        assert_eq!(
+            long_hash.serialize(),
+            Err(VerifyError::InvalidLogRoot("root hash is too long"))
+        );
+        let long_metadata = LogRootV1 {
+            metadata: vec![0; u16::MAX as usize + 1],
+            ..example()
+        };
+        assert_eq!(
+            long_metadata.serialize(),
+            Err(VerifyError::InvalidLogRoot("metadata is too long"))
+        );
+    }
+}
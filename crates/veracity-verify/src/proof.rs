@@ -0,0 +1,263 @@
+use crate::hash::node_hash;
+use crate::VerifyError;
+
+/// Check `proof` shows the leaf with `leaf_hash` is at `leaf_index` in the tree of `tree_size`
+/// leaves with `root_hash`
+pub fn verify_inclusion(
+    leaf_index: u64,
+    tree_size: u64,
+    leaf_hash: &[u8],
+    proof: &[Vec<u8>],
+    root_hash: &[u8],
+) -> Result<(), VerifyError> {
+    if root_from_inclusion_proof(leaf_index, tree_size, leaf_hash, proof)? != root_hash {
+        return Err(VerifyError::RootMismatch);
+    }
+    Ok(())
+}
+
+/// Root hash implied by an inclusion proof, RFC 9162 section 2.1.3.2
+pub fn root_from_inclusion_proof(
+    leaf_index: u64,
+    tree_size: u64,
+    leaf_hash: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<Vec<u8>, VerifyError> {
+    if leaf_index >= tree_size {
+        return Err(VerifyError::IndexOutOfRange {
+            index: leaf_index,
+            tree_size,
+        });
+    }
+    let (mut node, mut last) = (leaf_index, tree_size - 1);
+    let mut root = leaf_hash.to_vec();
+    for sibling in proof {
+        if last == 0 {
+            return Err(VerifyError::WrongProofLength);
+        }
+        if node & 1 == 1 || node == last {
+            root = node_hash(sibling, &root);
+            while node & 1 == 0 && node != 0 {
+                node >>= 1;
+                last >>= 1;
+            }
+        } else {
+            root = node_hash(&root, sibling);
+        }
+        node >>= 1;
+        last >>= 1;
+    }
+    if last != 0 {
+        return Err(VerifyError::WrongProofLength);
+    }
+    Ok(root)
+}
+
+/// Check `proof` shows the tree of `second_size` leaves with `second_root` extends the tree of
+/// `first_size` leaves with `first_root`, RFC 9162 section 2.1.4.2. Every tree extends the empty
+/// tree, and a tree only extends itself if the roots match.
+pub fn verify_consistency(
+    first_size: u64,
+    second_size: u64,
+    first_root: &[u8],
+    second_root: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<(), VerifyError> {
+    if second_size < first_size {
+        return Err(VerifyError::SizesOutOfOrder {
+            first: first_size,
+            second: second_size,
+        });
+    }
+    if first_size == 0 || first_size == second_size {
+        if !proof.is_empty() {
+            return Err(VerifyError::WrongProofLength);
+        }
+        if first_size == second_size && first_root != second_root {
+            return Err(VerifyError::RootMismatch);
+        }
+        return Ok(());
+    }
+    if proof.is_empty() {
+        return Err(VerifyError::WrongProofLength);
+    }
+
+    // A complete first tree is itself a node of the second, so its root starts the path
+    let mut path = Vec::with_capacity(proof.len() + 1);
+    if first_size.is_power_of_two() {
+        path.push(first_root.to_vec());
+    }
+    path.extend_from_slice(proof);
+
+    let (mut first_node, mut second_node) = (first_size - 1, second_size - 1);
+    while first_node & 1 == 1 {
+        first_node >>= 1;
+        second_node >>= 1;
+    }
+    let mut first_hash = path[0].clone();
+    let mut second_hash = path[0].clone();
+    for hash in &path[1..] {
+        if second_node == 0 {
+            return Err(VerifyError::WrongProofLength);
+        }
+        if first_node & 1 == 1 || first_node == second_node {
+            first_hash = node_hash(hash, &first_hash);
+            second_hash = node_hash(hash, &second_hash);
+            while first_node & 1 == 0 && first_node != 0 {
+                first_node >>= 1;
+                second_node >>= 1;
+            }
+        } else {
+            second_hash = node_hash(&second_hash, hash);
+        }
+        first_node >>= 1;
+        second_node >>= 1;
+    }
+    if second_node != 0 {
+        return Err(VerifyError::WrongProofLength);
+    }
+    if first_hash != first_root || second_hash != second_root {
+        return Err(VerifyError::RootMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::leaf_hash;
+    use crate::tree::{consistency_proof, inclusion_proof, root_hash as root};
+
+    /// Leaves of the RFC 6962 test tree used by Certificate Transparency and Trillian
+    const LEAVES: [&str; 8] = [
+        "",
+        "00",
+        "10",
+        "2021",
+        "3031",
+        "40414243",
+        "5051525354555657",
+        "606162636465666768696a6b6c6d6e6f",
+    ];
+    /// Roots of the first 1 to 8 of those leaves
+    const ROOTS: [&str; 8] = [
+        "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+        "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+        "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+        "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+        "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+        "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+        "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+    ];
+
+    fn leaves() -> Vec<Vec<u8>> {
+        LEAVES
+            .iter()
+            .map(|leaf| leaf_hash(&hex::decode(leaf).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn roots_match_the_reference_tree() {
+        let leaves = leaves();
+        for (size, expected) in ROOTS.iter().enumerate() {
+            assert_eq!(hex::encode(root(&leaves[..=size])), *expected);
+        }
+    }
+
+    #[test]
+    fn inclusion_proofs_verify_for_every_leaf() {
+        let leaves = leaves();
+        for size in 1..=leaves.len() {
+            let tree = &leaves[..size];
+            let root_hash = root(tree);
+            for (index, leaf) in tree.iter().enumerate() {
+                let proof = inclusion_proof(index, tree);
+                let (index, size) = (index as u64, size as u64);
+                assert_eq!(
+                    verify_inclusion(index, size, leaf, &proof, &root_hash),
+                    Ok(()),
+                    "leaf {index} of {size}"
+                );
+                if size > 1 {
+                    assert_eq!(
+                        verify_inclusion(
+                            index,
+                            size,
+                            &leaves[7 - index as usize],
+                            &proof,
+                            &root_hash
+                        ),
+                        Err(VerifyError::RootMismatch)
+                    );
+                    assert_eq!(
+                        verify_inclusion(index, size, leaf, &proof[1..], &root_hash),
+                        Err(VerifyError::WrongProofLength)
+                    );
+                }
+            }
+        }
+        assert_eq!(
+            verify_inclusion(8, 8, &leaves[0], &[], &root(&leaves)),
+            Err(VerifyError::IndexOutOfRange {
+                index: 8,
+                tree_size: 8
+            })
+        );
+    }
+
+    #[test]
+    fn consistency_proofs_verify_between_every_pair_of_sizes() {
+        let leaves = leaves();
+        for second in 1..=leaves.len() {
+            for first in 1..second {
+                let proof = consistency_proof(first, &leaves[..second]);
+                let (first_root, second_root) = (root(&leaves[..first]), root(&leaves[..second]));
+                let (first, second) = (first as u64, second as u64);
+                assert_eq!(
+                    verify_consistency(first, second, &first_root, &second_root, &proof),
+                    Ok(()),
+                    "{first} -> {second}"
+                );
+                assert!(
+                    verify_consistency(first, second, &second_root, &second_root, &proof).is_err()
+                );
+                assert!(
+                    verify_consistency(first, second, &first_root, &first_root, &proof).is_err()
+                );
+                let mut extended = proof.clone();
+                extended.push(first_root.clone());
+                assert!(
+                    verify_consistency(first, second, &first_root, &second_root, &extended)
+                        .is_err()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trivial_consistency_needs_no_proof() {
+        let root_hash = root(&leaves());
+        assert_eq!(verify_consistency(0, 8, &[], &root_hash, &[]), Ok(()));
+        assert_eq!(
+            verify_consistency(8, 8, &root_hash, &root_hash, &[]),
+            Ok(())
+        );
+        assert_eq!(
+            verify_consistency(8, 8, &root_hash, &[0; 32], &[]),
+            Err(VerifyError::RootMismatch)
+        );
+        assert_eq!(
+            verify_consistency(3, 8, &root_hash, &root_hash, &[]),
+            Err(VerifyError::WrongProofLength)
+        );
+        assert_eq!(
+            verify_consistency(8, 3, &root_hash, &root_hash, &[]),
+            Err(VerifyError::SizesOutOfOrder {
+                first: 8,
+                second: 3
+            })
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+
+use crate::hash::node_hash;
+
+/// Largest power of two strictly less than `n`, for `n > 1`
+fn split_point(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// Root hash of a tree over `leaf_hashes`, MTH from RFC 6962 section 2.1
+pub fn root_hash(leaf_hashes: &[Vec<u8>]) -> Vec<u8> {
+    match leaf_hashes.len() {
+        0 => Sha256::digest([]).to_vec(),
+        1 => leaf_hashes[0].clone(),
+        n => {
+            let k = split_point(n);
+            node_hash(&root_hash(&leaf_hashes[..k]), &root_hash(&leaf_hashes[k..]))
+        }
+    }
+}
+
+/// Inclusion proof for the leaf at `index` in a tree over `leaf_hashes`, PATH from RFC 6962
+/// section 2.1.1
+pub fn inclusion_proof(index: usize, leaf_hashes: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let n = leaf_hashes.len();
+    if n <= 1 {
+        return vec![];
+    }
+    let k = split_point(n);
+    let (mut path, sibling) = if index < k {
+        (
+            inclusion_proof(index, &leaf_hashes[..k]),
+            root_hash(&leaf_hashes[k..]),
+        )
+    } else {
+        (
+            inclusion_proof(index - k, &leaf_hashes[k..]),
+            root_hash(&leaf_hashes[..k]),
+        )
+    };
+    path.push(sibling);
+    path
+}
+
+/// Consistency proof from the tree over the first `first_size` of `leaf_hashes` to the tree over
+/// all of them, PROOF from RFC 6962 section 2.1.2
+pub fn consistency_proof(first_size: usize, leaf_hashes: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    subproof(first_size, leaf_hashes, true)
+}
+
+/// SUBPROOF from RFC 6962 section 2.1.2
+fn subproof(first: usize, leaf_hashes: &[Vec<u8>], complete: bool) -> Vec<Vec<u8>> {
+    let n = leaf_hashes.len();
+    if first == n {
+        return if complete {
+            vec![]
+        } else {
+            vec![root_hash(leaf_hashes)]
+        };
+    }
+    let k = split_point(n);
+    let (mut proof, sibling) = if first <= k {
+        (
+            subproof(first, &leaf_hashes[..k], complete),
+            root_hash(&leaf_hashes[k..]),
+        )
+    } else {
+        (
+            subproof(first - k, &leaf_hashes[k..], false),
+            root_hash(&leaf_hashes[..k]),
+        )
+    };
+    proof.push(sibling);
+    proof
+}